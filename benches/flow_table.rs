@@ -0,0 +1,134 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// Per-packet cost of the connection lookup of the router, depending on the number of flows: the
+// connection id is built from the headers of the packet, then its key is looked up.
+//
+// Run with: cargo bench --bench flow_table
+
+use relaylib::bench::{ConnectionId, FlowKey, FlowTable, IpPacket};
+use std::cell::RefCell;
+use std::hint::black_box;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// stands for a connection, which the router borrows to read its id
+struct Flow {
+    id: ConnectionId,
+}
+
+const FLOW_COUNTS: [usize; 3] = [10, 1_000, 10_000];
+const LOOKUPS: usize = 1_000_000;
+const CLIENT_STRING: &str = "#0:<emulator-5554>";
+
+// a TCP segment without payload, from a distinct source port (and destination) for each flow
+fn create_packet(i: usize) -> Vec<u8> {
+    let mut raw = vec![0u8; 40];
+    raw[0] = 4 << 4 | 5; // version and IHL
+    raw[2..4].copy_from_slice(&40u16.to_be_bytes()); // total length
+    raw[8] = 64; // TTL
+    raw[9] = 6; // protocol (TCP)
+    raw[12..16].copy_from_slice(&[10, 0, 2, 15]); // source address
+    raw[16..20].copy_from_slice(&(0xc000_0200 + (i as u32 % 256)).to_be_bytes()); // destination
+    raw[20..22].copy_from_slice(&(1024 + i as u16).to_be_bytes()); // source port
+    raw[22..24].copy_from_slice(&443u16.to_be_bytes()); // destination port
+    raw[32] = 5 << 4; // data offset
+    raw
+}
+
+// like the previous Router::connection()
+fn connection_id(ip_packet: &IpPacket) -> ConnectionId {
+    let (ip_header_data, transport_header_data) = ip_packet.headers_data();
+    let transport_header_data = transport_header_data.expect("No transport");
+    let mut id = ConnectionId::from_headers(ip_header_data, transport_header_data);
+    id.set_client_string(Some(CLIENT_STRING.to_string()));
+    id
+}
+
+// like Router::connection(): the id is only built for a new connection
+fn flow_key(ip_packet: &IpPacket) -> FlowKey {
+    let (ip_header_data, transport_header_data) = ip_packet.headers_data();
+    let transport_header_data = transport_header_data.expect("No transport");
+    FlowKey::from_headers(ip_header_data, transport_header_data)
+}
+
+fn create_flow(ip_packet: &IpPacket) -> Rc<RefCell<Flow>> {
+    let id = connection_id(ip_packet);
+    Rc::new(RefCell::new(Flow { id }))
+}
+
+// the previous implementation: a linear scan, borrowing each connection to compare its id
+fn bench_linear_scan(packets: &[IpPacket]) -> Duration {
+    let flows: Vec<Rc<RefCell<Flow>>> = packets.iter().map(create_flow).collect();
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        let id = connection_id(&packets[i % packets.len()]);
+        let index = flows.iter().position(|flow| flow.borrow().id == id);
+        black_box(index);
+    }
+    start.elapsed()
+}
+
+fn bench_flow_table(packets: &[IpPacket]) -> Duration {
+    let mut flows = FlowTable::default();
+    for ip_packet in packets {
+        flows.insert(flow_key(ip_packet), create_flow(ip_packet));
+    }
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        let key = flow_key(&packets[i % packets.len()]);
+        black_box(flows.find(&key));
+    }
+    start.elapsed()
+}
+
+// replace every flow, in the order the router expires them
+fn bench_flow_table_churn(packets: &[IpPacket]) -> Duration {
+    let mut flows = FlowTable::default();
+    for ip_packet in packets {
+        flows.insert(flow_key(ip_packet), create_flow(ip_packet));
+    }
+    let start = Instant::now();
+    for i in 0..LOOKUPS {
+        let key = flow_key(&packets[i % packets.len()]);
+        let index = flows.find(&key).expect("Unknown flow");
+        let flow = flows.remove_at(index);
+        flows.insert(key, flow);
+    }
+    start.elapsed()
+}
+
+fn per_lookup(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / LOOKUPS as f64
+}
+
+fn main() {
+    println!(
+        "{:>8} {:>18} {:>18} {:>18}",
+        "flows", "linear scan", "flow table", "remove + insert"
+    );
+    for &count in &FLOW_COUNTS {
+        let mut raws: Vec<Vec<u8>> = (0..count).map(create_packet).collect();
+        let packets: Vec<IpPacket> = raws.iter_mut().map(|raw| IpPacket::parse(raw)).collect();
+        println!(
+            "{:>8} {:>15.1} ns {:>15.1} ns {:>15.1} ns",
+            count,
+            per_lookup(bench_linear_scan(&packets)),
+            per_lookup(bench_flow_table(&packets)),
+            per_lookup(bench_flow_table_churn(&packets)),
+        );
+    }
+}
@@ -19,6 +19,7 @@ pub const PARAM_SERIAL: u8 = 1;
 pub const PARAM_DNS_SERVERS: u8 = 1 << 1;
 pub const PARAM_ROUTES: u8 = 1 << 2;
 pub const PARAM_PORT: u8 = 1 << 3;
+pub const PARAM_CONFIG: u8 = 1 << 4;
 
 pub const DEFAULT_PORT: u16 = 31416;
 
@@ -27,6 +28,7 @@ pub struct CommandLineArguments {
     dns_servers: Option<String>,
     routes: Option<String>,
     port: u16,
+    config: Option<String>,
 }
 
 impl CommandLineArguments {
@@ -36,6 +38,7 @@ impl CommandLineArguments {
         let mut dns_servers = None;
         let mut routes = None;
         let mut port = 0;
+        let mut config = None;
 
         let mut iter = args.into_iter();
         while let Some(arg) = iter.next() {
@@ -70,6 +73,15 @@ impl CommandLineArguments {
                 } else {
                     return Err(String::from("Missing -p parameter"));
                 }
+            } else if (accepted_parameters & PARAM_CONFIG) != 0 && "-c" == arg {
+                if config.is_some() {
+                    return Err(String::from("Config file already set"));
+                }
+                if let Some(value) = iter.next() {
+                    config = Some(value.into());
+                } else {
+                    return Err(String::from("Missing -c parameter"));
+                }
             } else if (accepted_parameters & PARAM_SERIAL) != 0 && serial.is_none() {
                 serial = Some(arg);
             } else {
@@ -84,6 +96,7 @@ impl CommandLineArguments {
             dns_servers,
             routes,
             port,
+            config,
         })
     }
 
@@ -102,13 +115,17 @@ impl CommandLineArguments {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    pub fn config(&self) -> Option<&str> {
+        self.config.as_deref()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    const ACCEPT_ALL: u8 = PARAM_SERIAL | PARAM_DNS_SERVERS | PARAM_ROUTES;
+    const ACCEPT_ALL: u8 = PARAM_SERIAL | PARAM_DNS_SERVERS | PARAM_ROUTES | PARAM_CONFIG;
 
     #[test]
     fn test_no_args() {
@@ -178,4 +195,17 @@ mod tests {
         let raw_args = vec!["-r"];
         assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
     }
+
+    #[test]
+    fn test_config_parameter() {
+        let raw_args = vec!["-c", "/etc/gnirehtet.conf"];
+        let args = CommandLineArguments::parse(ACCEPT_ALL, raw_args).unwrap();
+        assert_eq!("/etc/gnirehtet.conf", args.config.unwrap());
+    }
+
+    #[test]
+    fn test_no_config_parameter() {
+        let raw_args = vec!["-c"];
+        assert!(CommandLineArguments::parse(ACCEPT_ALL, raw_args).is_err());
+    }
 }
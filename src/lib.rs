@@ -15,11 +15,14 @@
  */
 
 mod relay;
+#[doc(hidden)]
+pub use crate::relay::bench;
 pub use crate::relay::byte_buffer;
+pub use crate::relay::Config;
 
 use crate::relay::Relay;
 use std::io;
 
-pub fn relay(port: u16) -> io::Result<()> {
-    Relay::new(port).run()
+pub fn relay(port: u16, config: Config) -> io::Result<()> {
+    Relay::new(port, config).run()
 }
@@ -28,6 +28,7 @@ mod logger;
 use crate::adb_monitor::AdbMonitor;
 use crate::cli_args::CommandLineArguments;
 use crate::execution_error::{Cmd, CommandExecutionError, ProcessIoError, ProcessStatusError};
+use relaylib::Config;
 use std::env;
 use std::process::{self, exit};
 use std::thread;
@@ -155,6 +156,7 @@ impl Command for RunCommand {
             | cli_args::PARAM_DNS_SERVERS
             | cli_args::PARAM_ROUTES
             | cli_args::PARAM_PORT
+            | cli_args::PARAM_CONFIG
     }
 
     fn description(&self) -> &'static str {
@@ -171,6 +173,7 @@ impl Command for RunCommand {
             args.dns_servers(),
             args.routes(),
             args.port(),
+            args.config(),
         )
     }
 }
@@ -181,7 +184,10 @@ impl Command for AutorunCommand {
     }
 
     fn accepted_parameters(&self) -> u8 {
-        cli_args::PARAM_DNS_SERVERS | cli_args::PARAM_ROUTES | cli_args::PARAM_PORT
+        cli_args::PARAM_DNS_SERVERS
+            | cli_args::PARAM_ROUTES
+            | cli_args::PARAM_PORT
+            | cli_args::PARAM_CONFIG
     }
 
     fn description(&self) -> &'static str {
@@ -191,7 +197,12 @@ impl Command for AutorunCommand {
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_autorun(args.dns_servers(), args.routes(), args.port())
+        cmd_autorun(
+            args.dns_servers(),
+            args.routes(),
+            args.port(),
+            args.config(),
+        )
     }
 }
 
@@ -328,15 +339,17 @@ impl Command for RelayCommand {
     }
 
     fn accepted_parameters(&self) -> u8 {
-        cli_args::PARAM_NONE | cli_args::PARAM_PORT
+        cli_args::PARAM_NONE | cli_args::PARAM_PORT | cli_args::PARAM_CONFIG
     }
 
     fn description(&self) -> &'static str {
-        "Start the relay server in the current terminal."
+        "Start the relay server in the current terminal.\n\
+         If -c is given, then read the relay configuration (upstream\n\
+         HTTP proxy...) from the specified file."
     }
 
     fn execute(&self, args: &CommandLineArguments) -> Result<(), CommandExecutionError> {
-        cmd_relay(args.port())?;
+        cmd_relay(args.port(), args.config())?;
         Ok(())
     }
 }
@@ -362,6 +375,7 @@ fn cmd_run(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    config: Option<&str>,
 ) -> Result<(), CommandExecutionError> {
     // start in parallel so that the relay server is ready when the client connects
     async_start(serial, dns_servers, routes, port);
@@ -379,13 +393,14 @@ fn cmd_run(
     })
     .expect("Error setting Ctrl-C handler");
 
-    cmd_relay(port)
+    cmd_relay(port, config)
 }
 
 fn cmd_autorun(
     dns_servers: Option<&str>,
     routes: Option<&str>,
     port: u16,
+    config: Option<&str>,
 ) -> Result<(), CommandExecutionError> {
     {
         let autostart_dns_servers = dns_servers.map(String::from);
@@ -399,7 +414,7 @@ fn cmd_autorun(
         });
     }
 
-    cmd_relay(port)
+    cmd_relay(port, config)
 }
 
 #[allow(unused_variables)]
@@ -481,9 +496,16 @@ fn cmd_tunnel(serial: Option<&str>, port: u16) -> Result<(), CommandExecutionErr
     )
 }
 
-fn cmd_relay(port: u16) -> Result<(), CommandExecutionError> {
+fn cmd_relay(port: u16, config: Option<&str>) -> Result<(), CommandExecutionError> {
+    let config = match config {
+        Some(path) => {
+            info!(target: TAG, "Loading relay configuration from {}", path);
+            Config::load(path)?
+        }
+        None => Config::default(),
+    };
     info!(target: TAG, "Starting relay server on port {}...", port);
-    relaylib::relay(port)?;
+    relaylib::relay(port, config)?;
     Ok(())
 }
 
@@ -554,7 +576,7 @@ fn must_install_client(serial: Option<&str>) -> Result<bool, CommandExecutionErr
                 // read the versionCode of the installed package
                 if let Some(index) = dumpsys.find("    versionCode=") {
                     let start = index + 16; // size of "    versionCode=\""
-                    if let Some(end) = (&dumpsys[start..]).find(' ') {
+                    if let Some(end) = dumpsys[start..].find(' ') {
                         let installed_version_code = &dumpsys[start..start + end];
                         Ok(installed_version_code != REQUIRED_APK_VERSION_CODE)
                     } else {
@@ -608,6 +630,9 @@ fn append_command_usage(msg: &mut String, command: &dyn Command) {
     if (accepted_parameters & cli_args::PARAM_ROUTES) != 0 {
         msg.push_str(" [-r ROUTE[,ROUTE2,...]]");
     }
+    if (accepted_parameters & cli_args::PARAM_CONFIG) != 0 {
+        msg.push_str(" [-c CONFIG]");
+    }
     msg.push('\n');
     for desc_line in command.description().split('\n') {
         msg.push_str("      ");
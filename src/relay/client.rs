@@ -22,26 +22,35 @@ use std::io::{self, Write};
 use std::mem;
 use std::net::Shutdown;
 use std::rc::Rc;
+use std::time::Instant;
 
 use crate::byte_buffer::ByteBuffer;
 
 use super::binary;
 use super::close_listener::CloseListener;
-use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
-use super::ipv4_packet_buffer::Ipv4PacketBuffer;
+use super::config::Config;
+use super::fragmentation;
+use super::impairment::{Impairment, ImpairmentProfile, Verdict};
+use super::ip_packet::{IpPacket, MAX_PACKET_LENGTH};
+use super::ip_packet_buffer::IpPacketBuffer;
+use super::limits::FdBudget;
 use super::packet_source::PacketSource;
+use super::quota::QuotaStore;
 use super::router::Router;
 use super::selector::Selector;
 use super::stream_buffer::StreamBuffer;
 
 const TAG: &str = "Client";
 
+// same value as GnirehtetService.MTU in the client
+pub const DEFAULT_MTU: u16 = 0x4000;
+
 pub struct Client {
     id: u32,
     stream: TcpStream,
     interests: Ready,
     token: Token,
-    client_to_network: Ipv4PacketBuffer,
+    client_to_network: IpPacketBuffer,
     network_to_client: StreamBuffer,
     router: Router,
     close_listener: Box<dyn CloseListener<Client>>,
@@ -50,6 +59,9 @@ pub struct Client {
     // number of remaining bytes of "id" to send to the client before relaying any data
     pending_id_bytes: usize,
     client_serial: Option<String>,
+    impairment: Impairment,
+    // the largest packet sent to the client, configured for its device
+    mtu: u16,
 }
 
 /// Channel for connections to send back data immediately to the client
@@ -58,6 +70,8 @@ pub struct ClientChannel<'a> {
     stream: &'a TcpStream,
     token: Token,
     interests: &'a mut Ready,
+    impairment: &'a mut Impairment,
+    mtu: u16,
 }
 
 impl<'a> ClientChannel<'a> {
@@ -66,12 +80,16 @@ impl<'a> ClientChannel<'a> {
         stream: &'a TcpStream,
         token: Token,
         interests: &'a mut Ready,
+        impairment: &'a mut Impairment,
+        mtu: u16,
     ) -> Self {
         Self {
             network_to_client,
             stream,
             token,
             interests,
+            impairment,
+            mtu,
         }
     }
 
@@ -80,10 +98,66 @@ impl<'a> ClientChannel<'a> {
     pub fn send_to_client(
         &mut self,
         selector: &mut Selector,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
+    ) -> io::Result<()> {
+        if ip_packet.length() <= self.mtu {
+            return self.send_packet_to_client(selector, ip_packet);
+        }
+        // the client could not write the packet to its TUN device
+        let fragments = if ip_packet.ip_header_data().is_ipv6() {
+            fragmentation::fragment_ipv6(ip_packet, self.mtu, rand::random())
+        } else {
+            fragmentation::fragment(ip_packet, self.mtu, rand::random())
+        };
+        let total: usize = fragments.iter().map(Vec::len).sum();
+        if total > self.network_to_client.remaining() {
+            warn!(target: TAG, "Client buffer full");
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Client buffer full",
+            ));
+        }
+        debug!(
+            target: TAG,
+            "Packet ({} bytes) split into {} fragments",
+            ip_packet.length(),
+            fragments.len()
+        );
+        for mut raw in fragments {
+            let fragment = IpPacket::parse(&mut raw);
+            self.send_packet_to_client(selector, &fragment)?;
+        }
+        Ok(())
+    }
+
+    fn send_packet_to_client(
+        &mut self,
+        selector: &mut Selector,
+        ip_packet: &IpPacket,
     ) -> io::Result<()> {
-        if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
-            self.network_to_client.read_from(ipv4_packet.raw());
+        match self.impairment.verdict_to_client() {
+            Verdict::Forward => (),
+            Verdict::Delay(delay) => {
+                return if self
+                    .impairment
+                    .delay_to_client(selector, delay, ip_packet.raw())
+                {
+                    Ok(())
+                } else {
+                    warn!(target: TAG, "Impairment queue full");
+                    Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "Impairment queue full",
+                    ))
+                };
+            }
+            Verdict::Drop => {
+                debug!(target: TAG, "Impairment: drop packet to client");
+                return Ok(());
+            }
+        }
+        if ip_packet.length() as usize <= self.network_to_client.remaining() {
+            self.network_to_client.read_from(ip_packet.raw());
             self.update_interests(selector);
             Ok(())
         } else {
@@ -116,6 +190,9 @@ impl Client {
         id: u32,
         selector: &mut Selector,
         stream: TcpStream,
+        config: Rc<Config>,
+        quota_store: Rc<RefCell<QuotaStore>>,
+        fd_budget: Rc<FdBudget>,
         close_listener: Box<dyn CloseListener<Client>>,
     ) -> io::Result<Rc<RefCell<Self>>> {
         // on start, we are interested only in writing (we must first send the client id)
@@ -125,20 +202,23 @@ impl Client {
             stream,
             interests,
             token: Token(0), // default value, will be set afterwards
-            client_to_network: Ipv4PacketBuffer::new(),
+            client_to_network: IpPacketBuffer::new(),
             network_to_client: StreamBuffer::new(16 * MAX_PACKET_LENGTH),
-            router: Router::new(),
+            router: Router::new(config, quota_store, fd_budget),
             closed: false,
             close_listener,
             pending_packet_sources: Vec::new(),
             pending_id_bytes: 4,
             client_serial: None,
+            impairment: Impairment::new(),
+            mtu: DEFAULT_MTU,
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
             // set client as router owner
             self_ref.router.set_client(Rc::downgrade(&rc));
+            self_ref.impairment.set_client(Rc::downgrade(&rc));
 
             let rc2 = rc.clone();
             // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
@@ -163,17 +243,20 @@ impl Client {
         &mut self.router
     }
 
-    pub fn channel(&mut self) -> ClientChannel {
+    pub fn channel(&mut self) -> ClientChannel<'_> {
         ClientChannel::new(
             &mut self.network_to_client,
             &self.stream,
             self.token,
             &mut self.interests,
+            &mut self.impairment,
+            self.mtu,
         )
     }
 
     fn close(&mut self, selector: &mut Selector) {
         self.closed = true;
+        self.impairment.cancel(selector);
         selector.deregister(&self.stream, self.token).unwrap();
         // shutdown only (there is no close), the socket will be closed on drop
         if self.stream.shutdown(Shutdown::Both).is_err() {
@@ -231,7 +314,10 @@ impl Client {
             }
         } else {
             match self.write() {
-                Ok(_) => self.process_pending(selector),
+                Ok(_) => {
+                    self.flush_delayed_to_client();
+                    self.process_pending(selector);
+                }
                 Err(err) => {
                     error!(target: TAG, "Cannot write: [{:?}] {}", err.kind(), err);
                     self.close(selector);
@@ -292,18 +378,79 @@ impl Client {
     pub fn send_to_client(
         &mut self,
         selector: &mut Selector,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     ) -> io::Result<()> {
-        if ipv4_packet.length() as usize <= self.network_to_client.remaining() {
-            self.network_to_client.read_from(ipv4_packet.raw());
-            self.update_interests(selector);
-            Ok(())
-        } else {
-            warn!(target: TAG, "Client buffer full");
-            Err(io::Error::new(
-                io::ErrorKind::WouldBlock,
-                "Client buffer full",
-            ))
+        self.channel().send_to_client(selector, ip_packet)
+    }
+
+    /// Take the data buffered for the client, as if it was written to the socket, then pull the
+    /// pending packets into the buffer.
+    #[cfg(test)]
+    pub fn flush_to_client(&mut self, selector: &mut Selector) -> Vec<u8> {
+        let mut data = Vec::new();
+        while self.network_to_client.write_to(&mut data).unwrap() > 0 {}
+        self.process_pending(selector);
+        data
+    }
+
+    /// Apply a reloaded configuration to this client and its connections.
+    pub fn update_config(&mut self, config: Rc<Config>) {
+        self.router.update_config(config);
+        self.apply_device_policy();
+    }
+
+    fn apply_device_policy(&mut self) {
+        let policy = self.router.device().policy();
+        self.mtu = policy.mtu();
+        self.set_impairment_profile(policy.impairment().cloned());
+    }
+
+    /// Switch the network conditions emulated for this client.
+    pub fn set_impairment_profile(&mut self, profile: Option<ImpairmentProfile>) {
+        if self.impairment.profile() != profile.as_ref() {
+            info!(
+                target: TAG,
+                "Client id #{} impairment profile: {:?}", self.id, profile
+            );
+            self.impairment.set_profile(profile);
+        }
+    }
+
+    pub fn on_impairment_timer(&mut self, selector: &mut Selector) {
+        self.impairment.on_timer_triggered();
+        if self.closed {
+            return;
+        }
+        let now = Instant::now();
+        while let Some(mut raw) = self.impairment.pop_to_network(now) {
+            let ip_packet = IpPacket::parse(&mut raw);
+            let mut client_channel = ClientChannel::new(
+                &mut self.network_to_client,
+                &self.stream,
+                self.token,
+                &mut self.interests,
+                &mut self.impairment,
+                self.mtu,
+            );
+            self.router
+                .send_to_network(selector, &mut client_channel, &ip_packet);
+        }
+        self.flush_delayed_to_client();
+        self.impairment.schedule(selector);
+        self.update_interests(selector);
+    }
+
+    // move the delayed packets whose deadline is reached to the client buffer
+    fn flush_delayed_to_client(&mut self) {
+        let now = Instant::now();
+        let to_client = self.impairment.client_queue();
+        while let Some(packet) = to_client.peek_expired(now) {
+            if packet.len() > self.network_to_client.remaining() {
+                // wait for the client buffer to be drained
+                break;
+            }
+            self.network_to_client.read_from(packet);
+            to_client.pop_expired(now);
         }
     }
 
@@ -331,11 +478,13 @@ impl Client {
                 self.client_serial = Some(serial.clone());
                 self.router()
                     .set_client_string(format!("#{}:<{}>", id, &serial));
+                self.router().set_serial(&serial);
+                self.apply_device_policy();
                 Ok(())
             }
             Err(e) => {
                 self.router().set_client_string(format!("#{}:<None>", id));
-                Err(io::Error::new(io::ErrorKind::Other, e))
+                Err(io::Error::other(e))
             }
         }
     }
@@ -360,20 +509,38 @@ impl Client {
     }
 
     fn push_one_packet_to_network(&mut self, selector: &mut Selector) -> bool {
-        match self.client_to_network.as_ipv4_packet() {
+        match self.client_to_network.as_ip_packet() {
             Some(ref packet) => {
+                match self.impairment.verdict_to_network() {
+                    Verdict::Forward => (),
+                    Verdict::Delay(delay) => {
+                        if !self
+                            .impairment
+                            .delay_to_network(selector, delay, packet.raw())
+                        {
+                            warn!(target: TAG, "Impairment queue full, drop packet");
+                        }
+                        return true;
+                    }
+                    Verdict::Drop => {
+                        debug!(target: TAG, "Impairment: drop packet to network");
+                        return true;
+                    }
+                }
                 let mut client_channel = ClientChannel::new(
                     &mut self.network_to_client,
                     &self.stream,
                     self.token,
                     &mut self.interests,
+                    &mut self.impairment,
+                    self.mtu,
                 );
                 trace!(
                     target: TAG,
                     "push packet to network: {}, packet length {}, ip header length: {}, transport header length: {}",
                     self.id,
                     packet.length(),
-                    packet.ipv4_header_data().header_length(),
+                    packet.ip_header_data().header_length(),
                     packet.transport_header().unwrap().header_length()
                 );
                 self.router
@@ -391,10 +558,10 @@ impl Client {
             let consumed = {
                 let mut source = pending.borrow_mut();
                 let result = {
-                    let ipv4_packet = source
+                    let ip_packet = source
                         .get()
                         .expect("Unexpected pending source with no packet");
-                    self.send_to_client(selector, &ipv4_packet)
+                    self.send_to_client(selector, &ip_packet)
                 };
                 #[allow(clippy::match_wild_err_arm)]
                 match result {
@@ -416,7 +583,16 @@ impl Client {
     }
 
     pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
-        self.router.clean_expired_connections(selector);
+        let mut client_channel = ClientChannel::new(
+            &mut self.network_to_client,
+            &self.stream,
+            self.token,
+            &mut self.interests,
+            &mut self.impairment,
+            self.mtu,
+        );
+        self.router
+            .clean_expired_connections(selector, &mut client_channel);
     }
 
     fn must_send_id(&self) -> bool {
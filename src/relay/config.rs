@@ -0,0 +1,613 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::client::DEFAULT_MTU;
+use super::egress::EgressPolicy;
+use super::http_proxy::HttpProxy;
+use super::impairment::{self, ImpairmentProfile};
+use super::limits::ConnectionLimits;
+use super::quota::QuotaPolicy;
+use super::shaper::ShapingPolicy;
+use super::tcp_timeouts::TcpTimeouts;
+use super::udp_group::UdpGroupPolicy;
+use super::udp_nat::UdpNatPolicy;
+use super::udp_timeouts::UdpTimeouts;
+
+// the minimum MTU of IPv6 (RFC 8200 section 5)
+const MIN_MTU: u16 = 1280;
+
+/// Relay configuration, loaded from a simple `key = value` file.
+///
+/// ```text
+/// # comments start with '#'
+/// [relay]
+/// http-proxy = proxy.example.com:3128
+/// http-proxy-credentials = user:password
+/// quota-state-file = /var/lib/gnirehtet/quota
+/// max-open-files = 4096
+///
+/// # default policy, for all devices
+/// [device *]
+/// bind-device = eth0
+/// max-tcp-connections = 256
+/// tcp-idle-timeout = 30m
+/// tcp-keepalive = 60s
+/// udp-timeout = 443 10m
+/// mtu = 1500
+/// udp-checksum = off
+/// udp-mapping = endpoint-independent
+/// udp-multicast = bridge eth0
+///
+/// # overrides the default policy for the device with serial 0123456789abcdef
+/// [device 0123456789abcdef]
+/// bind-address = 192.168.2.10
+/// fwmark = 2
+/// download-rate = 2M
+/// impairment = slow
+/// daily-quota = 500M
+/// quota-action = throttle
+///
+/// # network conditions, which may be referenced by devices
+/// [profile slow]
+/// latency = 200ms
+/// loss = 1%
+/// ```
+#[derive(Default)]
+pub struct Config {
+    // the file the configuration has been loaded from, if any
+    path: Option<PathBuf>,
+    http_proxy: Option<HttpProxy>,
+    quota_state_file: Option<PathBuf>,
+    max_open_files: Option<usize>,
+    default_device: DevicePolicy,
+    devices: HashMap<String, DevicePolicy>,
+}
+
+/// Settings applied to the connections of one device.
+#[derive(Clone, Default)]
+pub struct DevicePolicy {
+    egress: EgressPolicy,
+    shaping: ShapingPolicy,
+    quota: QuotaPolicy,
+    limits: ConnectionLimits,
+    tcp_timeouts: TcpTimeouts,
+    udp_timeouts: UdpTimeouts,
+    udp_nat: UdpNatPolicy,
+    udp_groups: UdpGroupPolicy,
+    impairment: Option<ImpairmentProfile>,
+    mtu: Option<u16>,
+    // do not compute the checksums of the UDP packets sent to the device (IPv4 only)
+    udp_checksum_disabled: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Section {
+    Relay,
+    // None for the default policy ("[device *]")
+    Device(Option<String>),
+    Profile(String),
+}
+
+struct Entry<'a> {
+    line_number: usize,
+    key: &'a str,
+    value: &'a str,
+}
+
+impl<'a> Entry<'a> {
+    fn error<E: std::fmt::Display>(&self, err: E) -> String {
+        format!("Line {}: {}", self.line_number, err)
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let content = fs::read_to_string(path.as_ref())?;
+        let mut config =
+            Self::parse(&content).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        config.path = Some(path.as_ref().to_path_buf());
+        Ok(config)
+    }
+
+    // simple String as errors is sufficient, we never need to inspect them
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut section = Section::Relay;
+        let mut relay_entries = Vec::new();
+        let mut default_device_entries = Vec::new();
+        // keep the order of the sections, a section may be repeated
+        let mut device_entries: Vec<(String, Vec<Entry>)> = Vec::new();
+        let mut profile_entries: Vec<(String, Vec<Entry>)> = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(index) => &line[..index],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let line_number = i + 1;
+            if line.starts_with('[') && line.ends_with(']') {
+                section = Self::parse_section(&line[1..line.len() - 1])
+                    .map_err(|err| format!("Line {}: {}", line_number, err))?;
+                continue;
+            }
+            let entry = match line.split_once('=') {
+                Some((key, value)) => Entry {
+                    line_number,
+                    key: key.trim(),
+                    value: value.trim(),
+                },
+                None => return Err(format!("Line {}: expected \"key = value\"", line_number)),
+            };
+            match section {
+                Section::Relay => relay_entries.push(entry),
+                Section::Device(None) => default_device_entries.push(entry),
+                Section::Device(Some(ref serial)) => {
+                    push_section_entry(&mut device_entries, serial, entry)
+                }
+                Section::Profile(ref name) => push_section_entry(&mut profile_entries, name, entry),
+            }
+        }
+
+        let mut profiles = impairment::builtin_profiles();
+        for (name, entries) in profile_entries {
+            let mut profile = ImpairmentProfile::default();
+            for entry in entries {
+                let known = profile
+                    .set(entry.key, entry.value)
+                    .map_err(|err| entry.error(err))?;
+                if !known {
+                    return Err(entry.error(format!("unknown key \"{}\"", entry.key)));
+                }
+            }
+            profiles.insert(name, profile);
+        }
+
+        let mut config = Self::default();
+        config.set_relay_entries(&relay_entries)?;
+        config
+            .default_device
+            .set_entries(&default_device_entries, &profiles)?;
+        for (serial, entries) in device_entries {
+            // a device section only overrides the keys it defines
+            let mut device = config.default_device.clone();
+            device.set_entries(&entries, &profiles)?;
+            config.devices.insert(serial, device);
+        }
+        Ok(config)
+    }
+
+    fn parse_section(name: &str) -> Result<Section, String> {
+        let mut words = name.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("relay"), None, None) => Ok(Section::Relay),
+            (Some("device"), Some("*"), None) => Ok(Section::Device(None)),
+            (Some("device"), Some(serial), None) => Ok(Section::Device(Some(serial.to_string()))),
+            (Some("profile"), Some(name), None) => Ok(Section::Profile(name.to_string())),
+            _ => Err(format!("unknown section \"{}\"", name.trim())),
+        }
+    }
+
+    fn set_relay_entries(&mut self, entries: &[Entry]) -> Result<(), String> {
+        let mut http_proxy_address = None;
+        let mut http_proxy_credentials = None;
+        for entry in entries {
+            match entry.key {
+                "http-proxy" => {
+                    http_proxy_address =
+                        Some(parse_socket_addr(entry.value).map_err(|err| entry.error(err))?);
+                }
+                "http-proxy-credentials" => {
+                    if !entry.value.contains(':') {
+                        return Err(entry.error("credentials must be \"user:password\""));
+                    }
+                    http_proxy_credentials = Some(entry.value.to_string());
+                }
+                "quota-state-file" => self.quota_state_file = Some(PathBuf::from(entry.value)),
+                "max-open-files" => match entry.value.parse::<usize>() {
+                    Ok(max) if max > 0 => self.max_open_files = Some(max),
+                    _ => return Err(entry.error(format!("invalid count \"{}\"", entry.value))),
+                },
+                key => return Err(entry.error(format!("unknown key \"{}\"", key))),
+            }
+        }
+        match (http_proxy_address, http_proxy_credentials) {
+            (Some(address), credentials) => {
+                self.http_proxy = Some(HttpProxy::new(address, credentials));
+            }
+            (None, Some(_)) => {
+                return Err(String::from(
+                    "http-proxy-credentials set without http-proxy",
+                ))
+            }
+            (None, None) => (),
+        }
+        Ok(())
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn http_proxy(&self) -> Option<&HttpProxy> {
+        self.http_proxy.as_ref()
+    }
+
+    pub fn quota_state_file(&self) -> Option<&Path> {
+        self.quota_state_file.as_deref()
+    }
+
+    /// Return the number of file descriptors the relay may use for clients and connections
+    pub fn max_open_files(&self) -> Option<usize> {
+        self.max_open_files
+    }
+
+    /// Return the policy for the device, or the default policy if it has no specific section
+    pub fn device(&self, serial: Option<&str>) -> &DevicePolicy {
+        serial
+            .and_then(|serial| self.devices.get(serial))
+            .unwrap_or(&self.default_device)
+    }
+}
+
+impl DevicePolicy {
+    fn set_entries(
+        &mut self,
+        entries: &[Entry],
+        profiles: &HashMap<String, ImpairmentProfile>,
+    ) -> Result<(), String> {
+        for entry in entries {
+            if entry.key == "impairment" {
+                self.impairment = match entry.value {
+                    "none" => None,
+                    name => Some(profiles.get(name).cloned().ok_or_else(|| {
+                        entry.error(format!("unknown impairment profile \"{}\"", name))
+                    })?),
+                };
+                continue;
+            }
+            if entry.key == "mtu" {
+                self.mtu = Some(parse_mtu(entry.value).map_err(|err| entry.error(err))?);
+                continue;
+            }
+            if entry.key == "udp-checksum" {
+                self.udp_checksum_disabled = match entry.value {
+                    "on" => false,
+                    "off" => true,
+                    value => {
+                        return Err(entry
+                            .error(format!("invalid value \"{}\" (expected on or off)", value)))
+                    }
+                };
+                continue;
+            }
+            let known = self
+                .egress
+                .set(entry.key, entry.value)
+                .and_then(|known| Ok(known || self.shaping.set(entry.key, entry.value)?))
+                .and_then(|known| Ok(known || self.quota.set(entry.key, entry.value)?))
+                .and_then(|known| Ok(known || self.limits.set(entry.key, entry.value)?))
+                .and_then(|known| Ok(known || self.tcp_timeouts.set(entry.key, entry.value)?))
+                .and_then(|known| Ok(known || self.udp_timeouts.set(entry.key, entry.value)?))
+                .and_then(|known| Ok(known || self.udp_nat.set(entry.key, entry.value)?))
+                .and_then(|known| Ok(known || self.udp_groups.set(entry.key, entry.value)?))
+                .map_err(|err| entry.error(err))?;
+            if !known {
+                return Err(entry.error(format!("unknown key \"{}\"", entry.key)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn egress(&self) -> &EgressPolicy {
+        &self.egress
+    }
+
+    pub fn shaping(&self) -> &ShapingPolicy {
+        &self.shaping
+    }
+
+    pub fn quota(&self) -> &QuotaPolicy {
+        &self.quota
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    pub fn tcp_timeouts(&self) -> &TcpTimeouts {
+        &self.tcp_timeouts
+    }
+
+    pub fn udp_timeouts(&self) -> &UdpTimeouts {
+        &self.udp_timeouts
+    }
+
+    pub fn udp_nat(&self) -> &UdpNatPolicy {
+        &self.udp_nat
+    }
+
+    pub fn udp_groups(&self) -> &UdpGroupPolicy {
+        &self.udp_groups
+    }
+
+    pub fn impairment(&self) -> Option<&ImpairmentProfile> {
+        self.impairment.as_ref()
+    }
+
+    /// Return the size of the largest packet the relay sends to the device
+    pub fn mtu(&self) -> u16 {
+        self.mtu.unwrap_or(DEFAULT_MTU)
+    }
+
+    /// Indicate whether the checksums of the UDP packets sent to the device are computed (they
+    /// are optional for IPv4)
+    pub fn udp_checksum(&self) -> bool {
+        !self.udp_checksum_disabled
+    }
+}
+
+fn push_section_entry<'a>(
+    sections: &mut Vec<(String, Vec<Entry<'a>>)>,
+    name: &str,
+    entry: Entry<'a>,
+) {
+    match sections.iter_mut().find(|(n, _)| n == name) {
+        Some((_, entries)) => entries.push(entry),
+        None => sections.push((name.to_string(), vec![entry])),
+    }
+}
+
+fn parse_mtu(value: &str) -> Result<u16, String> {
+    match value.parse::<u16>() {
+        // the client cannot receive larger packets
+        Ok(mtu) if (MIN_MTU..=DEFAULT_MTU).contains(&mtu) => Ok(mtu),
+        _ => Err(format!(
+            "invalid MTU \"{}\" (expected {} to {})",
+            value, MIN_MTU, DEFAULT_MTU
+        )),
+    }
+}
+
+// "100ms", "1s", "5m" or "2h"
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration \"{}\"", value);
+    let (number, millis_per_unit) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 1)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1000)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 60 * 1000)
+    } else if let Some(number) = value.strip_suffix('h') {
+        (number, 60 * 60 * 1000)
+    } else {
+        return Err(invalid());
+    };
+    let number: u64 = number.trim().parse().map_err(|_| invalid())?;
+    let millis = number.checked_mul(millis_per_unit).ok_or_else(invalid)?;
+    Ok(Duration::from_millis(millis))
+}
+
+// the first resolved address, IPv4 or IPv6 (e.g. "proxy.lan:3128" or "[::1]:3128")
+fn parse_socket_addr(value: &str) -> Result<SocketAddr, String> {
+    value
+        .to_socket_addrs()
+        .map_err(|err| format!("invalid address \"{}\": {}", value, err))?
+        .next()
+        .ok_or_else(|| format!("no address for \"{}\"", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ip_header::Protocol;
+    use crate::relay::udp_group::Group;
+    use crate::relay::udp_nat::Mapping;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(Ok(Duration::from_millis(100)), parse_duration("100ms"));
+        assert_eq!(Ok(Duration::from_secs(2)), parse_duration("2s"));
+        assert_eq!(Ok(Duration::from_secs(300)), parse_duration("5m"));
+        assert_eq!(Ok(Duration::from_secs(7200)), parse_duration("2h"));
+        assert!(parse_duration("100").is_err());
+        assert!(parse_duration("999999999999999h").is_err());
+    }
+
+    #[test]
+    fn parse_empty() {
+        let config = Config::parse("").unwrap();
+        assert!(config.http_proxy().is_none());
+        assert_eq!(None, config.max_open_files());
+    }
+
+    #[test]
+    fn parse_http_proxy() {
+        let content = "# upstream proxy\n\
+                       [relay]\n\
+                       http-proxy = 192.168.1.1:3128 # squid\n\
+                       http-proxy-credentials = user:secret\n\
+                       max-open-files = 1024\n";
+        let config = Config::parse(content).unwrap();
+        assert_eq!(Some(1024), config.max_open_files());
+        let http_proxy = config.http_proxy().unwrap();
+        assert_eq!("192.168.1.1:3128", http_proxy.address().to_string());
+        assert_eq!(Some("user:secret"), http_proxy.credentials());
+    }
+
+    #[test]
+    fn parse_ipv6_http_proxy() {
+        let config = Config::parse("[relay]\nhttp-proxy = [::1]:3128\n").unwrap();
+        let http_proxy = config.http_proxy().unwrap();
+        assert_eq!("[::1]:3128", http_proxy.address().to_string());
+    }
+
+    #[test]
+    fn parse_device_policies() {
+        let content = "[device *]\n\
+                       bind-device = eth0\n\
+                       max-tcp-connections = 64\n\
+                       tcp-keepalive = 60s\n\
+                       udp-timeout = 53 10s\n\
+                       [device abc]\n\
+                       bind-address = 192.168.2.10, 2001:db8::10\n\
+                       fwmark = 2\n\
+                       download-rate = 2M\n\
+                       [device def]\n\
+                       bind-device = eth1\n\
+                       daily-quota = 100M\n\
+                       tcp-idle-timeout = 30m\n\
+                       udp-timeout = 53 5s\n\
+                       mtu = 1500\n\
+                       udp-checksum = off\n\
+                       udp-mapping = endpoint-independent\n\
+                       udp-broadcast = bridge eth1\n";
+        let config = Config::parse(content).unwrap();
+
+        let egress = config.device(Some("abc")).egress();
+        assert_eq!(Some("eth0"), egress.bind_device());
+        assert_eq!("192.168.2.10", egress.bind_address().to_string());
+        assert_eq!("2001:db8::10", egress.bind_address6().to_string());
+        assert_eq!(Some(2), egress.fwmark());
+        assert_eq!(None, config.device(Some("abc")).shaping().upload_rate());
+        assert_eq!(
+            Some(250_000),
+            config.device(Some("abc")).shaping().download_rate()
+        );
+        assert!(!config.device(Some("abc")).quota().is_enabled());
+        assert!(config.device(Some("def")).quota().is_enabled());
+        assert_eq!(
+            Some(64),
+            config
+                .device(Some("def"))
+                .limits()
+                .max_connections(Protocol::Tcp)
+        );
+
+        let tcp_timeouts = config.device(Some("def")).tcp_timeouts();
+        assert_eq!(Duration::from_secs(30 * 60), tcp_timeouts.idle());
+        assert_eq!(Some(Duration::from_secs(60)), tcp_timeouts.keepalive());
+        assert_eq!(
+            TcpTimeouts::default().handshake(),
+            config.device(Some("abc")).tcp_timeouts().handshake()
+        );
+
+        assert_eq!(
+            Some("eth1"),
+            config
+                .device(Some("def"))
+                .udp_groups()
+                .bridge(Group::Broadcast)
+        );
+        assert_eq!(
+            None,
+            config
+                .device(Some("abc"))
+                .udp_groups()
+                .bridge(Group::Broadcast)
+        );
+
+        // a device section adds rules, which take precedence
+        let dns_server = "192.0.2.1:53".parse().unwrap();
+        assert_eq!(
+            Duration::from_secs(5),
+            config.device(Some("def")).udp_timeouts().idle(&dns_server)
+        );
+        assert_eq!(
+            Duration::from_secs(10),
+            config.device(Some("abc")).udp_timeouts().idle(&dns_server)
+        );
+
+        assert_eq!(1500, config.device(Some("def")).mtu());
+        assert_eq!(DEFAULT_MTU, config.device(Some("abc")).mtu());
+        assert!(!config.device(Some("def")).udp_checksum());
+        assert!(config.device(Some("abc")).udp_checksum());
+        assert_eq!(
+            Mapping::EndpointIndependent,
+            config.device(Some("def")).udp_nat().mapping()
+        );
+        assert_eq!(
+            Mapping::EndpointDependent,
+            config.device(Some("abc")).udp_nat().mapping()
+        );
+
+        let egress = config.device(Some("def")).egress();
+        assert_eq!(Some("eth1"), egress.bind_device());
+        assert!(egress.bind_address().is_unspecified());
+        assert_eq!(None, egress.fwmark());
+
+        let egress = config.device(Some("unknown")).egress();
+        assert_eq!(Some("eth0"), egress.bind_device());
+        assert_eq!(Some("eth0"), config.device(None).egress().bind_device());
+    }
+
+    #[test]
+    fn parse_impairment_profiles() {
+        let content = "[device *]\n\
+                       impairment = 3g\n\
+                       [device abc]\n\
+                       impairment = slow\n\
+                       [device def]\n\
+                       impairment = none\n\
+                       [profile slow]\n\
+                       latency = 200ms\n\
+                       loss = 1%\n";
+        let config = Config::parse(content).unwrap();
+
+        let slow = config.device(Some("abc")).impairment().unwrap();
+        assert_eq!(200, slow.latency().as_millis());
+        assert_eq!(0.01, slow.loss());
+        assert!(config.device(Some("def")).impairment().is_none());
+        assert_eq!(
+            100,
+            config
+                .device(None)
+                .impairment()
+                .unwrap()
+                .latency()
+                .as_millis()
+        );
+
+        assert!(Config::parse("[device *]\nimpairment = unknown").is_err());
+        assert!(Config::parse("[profile slow]\nlatency = 1").is_err());
+    }
+
+    #[test]
+    fn parse_credentials_without_proxy() {
+        assert!(Config::parse("http-proxy-credentials = user:secret").is_err());
+    }
+
+    #[test]
+    fn parse_invalid_line() {
+        assert!(Config::parse("http-proxy").is_err());
+        assert!(Config::parse("unknown = 42").is_err());
+        assert!(Config::parse("[unknown]").is_err());
+        assert!(Config::parse("[device]").is_err());
+        assert!(Config::parse("[device abc]\nhttp-proxy = 127.0.0.1:3128").is_err());
+        assert!(Config::parse("[device abc]\nfwmark = -1").is_err());
+        assert!(Config::parse("[device abc]\nmtu = 576").is_err());
+        assert!(Config::parse("[device abc]\nmtu = 65535").is_err());
+        assert!(Config::parse("[device abc]\nudp-checksum = 0").is_err());
+    }
+}
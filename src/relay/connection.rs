@@ -15,17 +15,15 @@
  */
 
 use std::fmt;
-use std::net::SocketAddrV4;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use super::client::ClientChannel;
-use super::ipv4_header::{Ipv4HeaderData, Protocol};
-use super::ipv4_packet::Ipv4Packet;
-use super::net;
+use super::ip_header::{IpHeaderData, Protocol};
+use super::ip_packet::IpPacket;
 use super::selector::Selector;
 use super::transport_header::TransportHeaderData;
 
-const LOCALHOST_FORWARD: u32 = 0x0A_00_02_02; // 10.0.2.2
-const LOCALHOST: u32 = 0x7F_00_00_01; // 127.0.0.1
+const LOCALHOST_FORWARD: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
 
 pub trait Connection {
     fn id(&self) -> &ConnectionId;
@@ -33,19 +31,31 @@ pub trait Connection {
         &mut self,
         selector: &mut Selector,
         client_channel: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     );
     fn close(&mut self, selector: &mut Selector);
+    /// Close the connection because it is expired, notifying the client if necessary.
+    fn expire(&mut self, selector: &mut Selector, _client_channel: &mut ClientChannel) {
+        self.close(selector);
+    }
     fn is_expired(&self) -> bool;
     fn is_closed(&self) -> bool;
 }
 
+/// The 5-tuple of a connection, to index the connections of a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    protocol: Protocol,
+    source: SocketAddr,
+    destination: SocketAddr,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ConnectionId {
     protocol: Protocol,
-    source_ip: u32,
+    source_ip: IpAddr,
     source_port: u16,
-    destination_ip: u32,
+    destination_ip: IpAddr,
     destination_port: u16,
     id_string: String,
     client_string: Option<String>,
@@ -53,44 +63,125 @@ pub struct ConnectionId {
 
 impl ConnectionId {
     pub fn from_headers(
-        ipv4_header_data: &Ipv4HeaderData,
+        ip_header_data: &IpHeaderData,
         transport_header_data: &TransportHeaderData,
     ) -> Self {
-        let source_ip = ipv4_header_data.source();
-        let source_port = transport_header_data.source_port();
-        let destination_ip = ipv4_header_data.destination();
-        let destination_port = transport_header_data.destination_port();
-        let id_string = format!(
-            "{} -> {}",
-            net::to_socket_addr(source_ip, source_port),
-            net::to_socket_addr(destination_ip, destination_port)
-        );
+        let key = FlowKey::from_headers(ip_header_data, transport_header_data);
+        let id_string = format!("{} -> {}", key.source, key.destination);
         Self {
-            protocol: ipv4_header_data.protocol(),
-            source_ip,
-            source_port,
-            destination_ip,
-            destination_port,
+            protocol: key.protocol,
+            source_ip: key.source.ip(),
+            source_port: key.source.port(),
+            destination_ip: key.destination.ip(),
+            destination_port: key.destination.port(),
             id_string,
             client_string: None,
         }
     }
 
+    /// Identify the flows from the same source to any destination (for an endpoint-independent
+    /// mapping).
+    pub fn any_destination(&self) -> Self {
+        let destination_ip = match self.source_ip {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let id_string = format!("{} -> *", SocketAddr::new(self.source_ip, self.source_port));
+        Self {
+            destination_ip,
+            destination_port: 0,
+            id_string,
+            ..self.clone()
+        }
+    }
+
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
 
+    pub fn flow_key(&self) -> FlowKey {
+        FlowKey {
+            protocol: self.protocol,
+            source: SocketAddr::new(self.source_ip, self.source_port),
+            destination: SocketAddr::new(self.destination_ip, self.destination_port),
+        }
+    }
+
     pub fn set_client_string(&mut self, client_string: Option<String>) {
         self.client_string = client_string;
     }
 
-    pub fn rewritten_destination(&self) -> SocketAddrV4 {
-        let ip = if self.destination_ip == LOCALHOST_FORWARD {
-            LOCALHOST
-        } else {
+    pub fn rewritten_destination(&self) -> SocketAddr {
+        rewrite_destination(SocketAddr::new(self.destination_ip, self.destination_port))
+    }
+
+    /// The sender of an error about this connection, as seen by the client.
+    ///
+    /// An error from the rewritten destination must come from the destination the client knows.
+    pub fn error_source(&self, source: IpAddr) -> IpAddr {
+        if source == self.rewritten_destination().ip() {
             self.destination_ip
+        } else {
+            source
+        }
+    }
+}
+
+impl FlowKey {
+    /// Return the key of the flow of a packet (cheaper than building its `ConnectionId`).
+    pub fn from_headers(
+        ip_header_data: &IpHeaderData,
+        transport_header_data: &TransportHeaderData,
+    ) -> Self {
+        let (source_port, destination_port) = match transport_header_data {
+            // no ports: the echo identifier distinguishes the pings, as on a NAT (RFC 5508)
+            TransportHeaderData::Icmp(icmp_header_data) => (icmp_header_data.identifier(), 0),
+            _ => (
+                transport_header_data.source_port(),
+                transport_header_data.destination_port(),
+            ),
         };
-        net::to_socket_addr(ip, self.destination_port)
+        Self {
+            protocol: ip_header_data.protocol(),
+            source: SocketAddr::new(ip_header_data.source(), source_port),
+            destination: SocketAddr::new(ip_header_data.destination(), destination_port),
+        }
+    }
+
+    /// Same as `ConnectionId::any_destination()`.
+    pub fn any_destination(&self) -> Self {
+        let destination_ip = match self.source.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        Self {
+            destination: SocketAddr::new(destination_ip, 0),
+            ..*self
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+}
+
+/// The address to reach a destination of the client from the relay.
+pub fn rewrite_destination(destination: SocketAddr) -> SocketAddr {
+    match destination.ip() {
+        IpAddr::V4(ip) if ip == LOCALHOST_FORWARD => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), destination.port())
+        }
+        _ => destination,
+    }
+}
+
+/// The address of a remote host as seen by the client (the reverse of `rewrite_destination()`).
+pub fn client_address(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip == Ipv4Addr::LOCALHOST => {
+            SocketAddr::new(IpAddr::V4(LOCALHOST_FORWARD), address.port())
+        }
+        _ => address,
     }
 }
 
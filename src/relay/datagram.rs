@@ -17,6 +17,7 @@
 use mio::net::UdpSocket;
 use std::cmp;
 use std::io;
+use std::net::SocketAddr;
 
 pub const MAX_DATAGRAM_LENGTH: usize = 1 << 16;
 
@@ -28,6 +29,11 @@ pub trait DatagramReceiver {
     fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
 }
 
+// Receive datagrams from any sender (unconnected socket)
+pub trait DatagramFromReceiver {
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
 // Expose UdpSocket as DatagramSender
 impl DatagramSender for UdpSocket {
     fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
@@ -44,6 +50,35 @@ impl DatagramReceiver for UdpSocket {
     }
 }
 
+// Expose UdpSocket as DatagramFromReceiver
+impl DatagramFromReceiver for UdpSocket {
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        // call the Self implementation
+        (self as &Self).recv_from(buf)
+    }
+}
+
+// Send datagrams to a given destination through an unconnected socket
+pub struct SendToAdapter<'a> {
+    socket: &'a UdpSocket,
+    destination: SocketAddr,
+}
+
+impl<'a> SendToAdapter<'a> {
+    pub fn new(socket: &'a UdpSocket, destination: SocketAddr) -> Self {
+        Self {
+            socket,
+            destination,
+        }
+    }
+}
+
+impl DatagramSender for SendToAdapter<'_> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, &self.destination)
+    }
+}
+
 // Convert a Read to a DatagramReceiver
 pub struct ReadAdapter<'a, R>
 where
@@ -87,6 +122,8 @@ pub mod tests {
     pub struct MockDatagramSocket {
         buf: [u8; MAX_DATAGRAM_LENGTH],
         len: usize,
+        // the sender reported by recv_from()
+        sender: Option<SocketAddr>,
     }
 
     impl MockDatagramSocket {
@@ -94,6 +131,7 @@ pub mod tests {
             Self {
                 buf: [0; MAX_DATAGRAM_LENGTH],
                 len: 0,
+                sender: None,
             }
         }
 
@@ -103,6 +141,12 @@ pub mod tests {
             mock
         }
 
+        pub fn from_sender(data: &[u8], sender: SocketAddr) -> Self {
+            let mut mock = MockDatagramSocket::from_data(data);
+            mock.sender = Some(sender);
+            mock
+        }
+
         pub fn data(&self) -> &[u8] {
             &self.buf[..self.len]
         }
@@ -111,7 +155,7 @@ pub mod tests {
     impl DatagramSender for MockDatagramSocket {
         fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
             let len = cmp::min(self.buf.len(), buf.len());
-            let _ = self.buf[..len].copy_from_slice(&buf[..len]);
+            self.buf[..len].copy_from_slice(&buf[..len]);
             self.len = len;
             Ok(len)
         }
@@ -120,11 +164,18 @@ pub mod tests {
     impl DatagramReceiver for MockDatagramSocket {
         fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             let len = cmp::min(self.len, buf.len());
-            let _ = buf[..len].copy_from_slice(&self.buf[..len]);
+            buf[..len].copy_from_slice(&self.buf[..len]);
             Ok(len)
         }
     }
 
+    impl DatagramFromReceiver for MockDatagramSocket {
+        fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let sender = self.sender.expect("No sender");
+            Ok((self.recv(buf)?, sender))
+        }
+    }
+
     #[test]
     fn mock_send() {
         let mut mock = MockDatagramSocket::new();
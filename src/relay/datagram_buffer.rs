@@ -67,7 +67,8 @@ impl DatagramBuffer {
         HEADER_LENGTH + datagram_length < remaining
     }
 
-    pub fn write_to<S: DatagramSender>(&mut self, destination: &mut S) -> io::Result<()> {
+    /// Send the next datagram, and return its length.
+    pub fn write_to<S: DatagramSender>(&mut self, destination: &mut S) -> io::Result<usize> {
         assert!(
             !self.is_empty(),
             "DatagramBuffer.write_to() called while empty"
@@ -84,12 +85,9 @@ impl DatagramBuffer {
                 target: TAG,
                 "Cannot write the whole datagram to the buffer (only {}/{})", w, length
             );
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Cannot write the whole datagram",
-            ));
+            return Err(io::Error::other("Cannot write the whole datagram"));
         }
-        Ok(())
+        Ok(length)
     }
 
     pub fn read_from(&mut self, source: &[u8]) -> io::Result<()> {
@@ -100,10 +98,7 @@ impl DatagramBuffer {
             MAX_DATAGRAM_LENGTH
         );
         if !self.has_enough_space_for(length) {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Datagram buffer is full",
-            ));
+            return Err(io::Error::other("Datagram buffer is full"));
         }
         self.write_length(length as u16);
         let target_slice = &mut self.buf[self.head..self.head + length];
@@ -0,0 +1,163 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::config::DevicePolicy;
+use super::quota::{self, QuotaAction, Usage};
+use super::shaper::{Direction, Shaper};
+
+const TAG: &str = "Device";
+
+// a blocked device is checked again periodically, for the quota reset
+const QUOTA_RECHECK_DELAY: Duration = Duration::from_secs(10);
+
+/// State shared by all the connections of a device.
+pub struct Device {
+    // None until the serial is received
+    serial: Option<String>,
+    // replaced on configuration reload
+    policy: RefCell<Rc<DevicePolicy>>,
+    shaper: RefCell<Shaper>,
+    usage: Rc<RefCell<Usage>>,
+    quota_exceeded: Cell<bool>,
+}
+
+impl Device {
+    pub fn new(serial: Option<String>, policy: DevicePolicy, usage: Rc<RefCell<Usage>>) -> Self {
+        let shaper = RefCell::new(Shaper::new(policy.shaping()));
+        let device = Self {
+            serial,
+            policy: RefCell::new(Rc::new(policy)),
+            shaper,
+            usage,
+            quota_exceeded: Cell::new(false),
+        };
+        // the quota may already be exhausted on connection
+        device.check_quota();
+        device
+    }
+
+    pub fn serial(&self) -> Option<&str> {
+        self.serial.as_deref()
+    }
+
+    pub fn policy(&self) -> Rc<DevicePolicy> {
+        self.policy.borrow().clone()
+    }
+
+    /// Apply a new policy to the device and its open connections (on configuration reload).
+    ///
+    /// The sockets already created keep their egress settings.
+    pub fn set_policy(&self, policy: DevicePolicy) {
+        *self.shaper.borrow_mut() = Shaper::new(policy.shaping());
+        *self.policy.borrow_mut() = Rc::new(policy);
+        // the throttling (if any) is applied again to the new shaper
+        self.quota_exceeded.set(false);
+        self.check_quota();
+    }
+
+    /// Count bytes transferred in `direction`.
+    pub fn account(&self, direction: Direction, bytes: usize) {
+        self.shaper.borrow_mut().consume(direction, bytes);
+        if self.policy.borrow().quota().is_enabled() {
+            self.usage.borrow_mut().add(bytes as u64, quota::today());
+            self.check_quota();
+        }
+    }
+
+    /// Return the number of bytes which may be transferred now.
+    pub fn quota(&self, direction: Direction) -> usize {
+        if self.is_blocked() {
+            0
+        } else {
+            self.shaper.borrow_mut().quota(direction)
+        }
+    }
+
+    /// Return the time to wait before some bytes may be transferred, or `None` if they may be
+    /// transferred now.
+    pub fn delay(&self, direction: Direction) -> Option<Duration> {
+        if self.is_blocked() {
+            Some(QUOTA_RECHECK_DELAY)
+        } else {
+            self.shaper.borrow_mut().delay(direction)
+        }
+    }
+
+    /// Return `true` if the device has exhausted its quota, and must not transfer anything.
+    pub fn is_blocked(&self) -> bool {
+        self.check_quota();
+        self.quota_exceeded.get() && self.policy().quota().action() == QuotaAction::Block
+    }
+
+    fn check_quota(&self) {
+        let policy = self.policy();
+        let quota = policy.quota();
+        if !quota.is_enabled() {
+            return;
+        }
+        let exceeded = self.usage.borrow_mut().is_exceeded(quota, quota::today());
+        if exceeded == self.quota_exceeded.get() {
+            return;
+        }
+        self.quota_exceeded.set(exceeded);
+        let serial = self.serial.as_deref().unwrap_or("<None>");
+        if exceeded {
+            warn!(
+                target: TAG,
+                "Device {} exhausted its data quota ({:?})",
+                serial,
+                quota.action()
+            );
+        } else {
+            info!(target: TAG, "Device {} data quota reset", serial);
+        }
+        if quota.action() == QuotaAction::Throttle {
+            *self.shaper.borrow_mut() = if exceeded {
+                Shaper::throttled(quota.throttle_rate())
+            } else {
+                Shaper::new(policy.shaping())
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::config::Config;
+
+    #[test]
+    fn reload_policy() {
+        let usage = Rc::new(RefCell::new(Usage::new(quota::today())));
+        usage.borrow_mut().add(2048, quota::today());
+        let device = Device::new(None, DevicePolicy::default(), usage);
+        assert!(!device.is_blocked());
+
+        // the connections sharing the device see the new policy
+        let config = Config::parse("[device *]\ndaily-quota = 1k\nmtu = 1500\n").unwrap();
+        device.set_policy(config.device(None).clone());
+        assert!(device.is_blocked());
+        assert_eq!(1500, device.policy().mtu());
+
+        device.set_policy(DevicePolicy::default());
+        assert!(!device.is_blocked());
+    }
+}
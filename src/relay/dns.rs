@@ -0,0 +1,106 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+
+pub const DNS_PORT: u16 = 53;
+
+// RFC 1035 section 4.1.1
+const HEADER_LENGTH: usize = 12;
+const QR_FLAG: u8 = 0x80;
+
+/// Queries sent over a UDP flow, waiting for their response.
+#[derive(Default)]
+pub struct PendingQueries {
+    ids: Vec<u16>,
+}
+
+impl PendingQueries {
+    /// Track the query in `payload`, if it is a DNS query.
+    pub fn on_query(&mut self, payload: &[u8]) {
+        if let Some((id, false)) = parse_header(payload) {
+            if !self.ids.contains(&id) {
+                self.ids.push(id);
+            }
+        }
+    }
+
+    /// Match the response in `payload` to its query, and return whether all the queries are
+    /// answered.
+    ///
+    /// A datagram which does not answer a pending query is ignored.
+    pub fn on_response(&mut self, payload: &[u8]) -> bool {
+        match parse_header(payload) {
+            Some((id, true)) => match self.ids.iter().position(|&pending| pending == id) {
+                Some(index) => {
+                    self.ids.swap_remove(index);
+                    self.ids.is_empty()
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+// return the transaction ID and whether the message is a response
+fn parse_header(payload: &[u8]) -> Option<(u16, bool)> {
+    if payload.len() < HEADER_LENGTH {
+        return None;
+    }
+    Some((
+        BigEndian::read_u16(&payload[0..2]),
+        payload[2] & QR_FLAG != 0,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u16, response: bool) -> Vec<u8> {
+        let mut raw = vec![0; HEADER_LENGTH];
+        BigEndian::write_u16(&mut raw[0..2], id);
+        if response {
+            raw[2] = QR_FLAG;
+        }
+        raw
+    }
+
+    #[test]
+    fn answer_all_queries() {
+        let mut queries = PendingQueries::default();
+        // A and AAAA queries
+        queries.on_query(&message(0x1234, false));
+        queries.on_query(&message(0x5678, false));
+
+        assert!(!queries.on_response(&message(0x5678, true)));
+        // unknown transaction
+        assert!(!queries.on_response(&message(0x9999, true)));
+        // not a response
+        assert!(!queries.on_response(&message(0x1234, false)));
+        // truncated
+        assert!(!queries.on_response(&[0x12, 0x34, QR_FLAG]));
+        assert!(queries.on_response(&message(0x1234, true)));
+    }
+
+    #[test]
+    fn ignore_non_dns_payload() {
+        let mut queries = PendingQueries::default();
+        queries.on_query(b"ping");
+        assert!(!queries.on_response(b"pong"));
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use mio::net::{TcpStream, UdpSocket};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// How the upstream sockets of a device leave the host: source address, interface and routing
+/// mark.
+#[derive(Clone, Debug, Default)]
+pub struct EgressPolicy {
+    bind_address: Option<Ipv4Addr>,
+    bind_address6: Option<Ipv6Addr>,
+    bind_device: Option<String>,
+    fwmark: Option<u32>,
+}
+
+impl EgressPolicy {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not an egress key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "bind-address" => {
+                // one address per family, e.g. "192.168.2.10, 2001:db8::10"
+                for item in value.split(',').map(str::trim) {
+                    match item.parse() {
+                        Ok(IpAddr::V4(address)) => self.bind_address = Some(address),
+                        Ok(IpAddr::V6(address)) => self.bind_address6 = Some(address),
+                        Err(_) => return Err(format!("invalid IP address \"{}\"", item)),
+                    }
+                }
+            }
+            "bind-device" => {
+                if value.is_empty() {
+                    return Err(String::from("empty interface name"));
+                }
+                self.bind_device = Some(value.to_string());
+            }
+            "fwmark" => {
+                let mark = value
+                    .parse()
+                    .map_err(|_| format!("invalid mark \"{}\"", value))?;
+                self.fwmark = Some(mark);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Local IPv4 address to bind the upstream sockets to (unspecified if none is configured)
+    pub fn bind_address(&self) -> Ipv4Addr {
+        self.bind_address.unwrap_or(Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// Local IPv6 address to bind the upstream sockets to (unspecified if none is configured)
+    pub fn bind_address6(&self) -> Ipv6Addr {
+        self.bind_address6.unwrap_or(Ipv6Addr::UNSPECIFIED)
+    }
+
+    /// Configured local address of the same family as `destination`, if any
+    fn configured_bind_address(&self, destination: &SocketAddr) -> Option<IpAddr> {
+        match destination {
+            SocketAddr::V4(_) => self.bind_address.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.bind_address6.map(IpAddr::V6),
+        }
+    }
+
+    pub fn bind_device(&self) -> Option<&str> {
+        self.bind_device.as_deref()
+    }
+
+    pub fn fwmark(&self) -> Option<u32> {
+        self.fwmark
+    }
+
+    /// Apply the interface and the mark to a socket, before it is bound or connected.
+    pub fn set_socket_options(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(bind_device) = self.bind_device.as_ref() {
+            bind_to_device(socket, bind_device)?;
+        }
+        if let Some(fwmark) = self.fwmark {
+            set_mark(socket, fwmark)?;
+        }
+        Ok(())
+    }
+
+    /// Open a non-blocking TCP connection to `address`.
+    pub fn connect_tcp(&self, address: &SocketAddr) -> io::Result<TcpStream> {
+        let domain = Domain::for_address(*address);
+        let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+        self.set_socket_options(&socket)?;
+        if let Some(bind_address) = self.configured_bind_address(address) {
+            socket.bind(&SocketAddr::new(bind_address, 0).into())?;
+        }
+        TcpStream::connect_stream(socket.into(), address)
+    }
+
+    /// Create a non-blocking UDP socket to reach `destination`, bound to the configured address
+    /// of the same family (on a random port).
+    pub fn bind_udp(&self, destination: &SocketAddr) -> io::Result<UdpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(*destination),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        self.set_socket_options(&socket)?;
+        let bind_address = match destination {
+            SocketAddr::V4(_) => IpAddr::V4(self.bind_address()),
+            SocketAddr::V6(_) => IpAddr::V6(self.bind_address6()),
+        };
+        socket.bind(&SocketAddr::new(bind_address, 0).into())?;
+        UdpSocket::from_socket(socket.into())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "bind-device is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "fwmark is only supported on Linux",
+    ))
+}
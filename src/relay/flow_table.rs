@@ -0,0 +1,175 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+
+/// Flows indexed by their key, with O(1) insertion, lookup and removal.
+///
+/// The values are stored contiguously (for fast iteration) and addressed by index. Like
+/// `Vec::swap_remove()`, removing a value moves the last one to its index.
+pub struct FlowTable<K, V> {
+    entries: Vec<(K, V)>,
+    // the index of each key in entries
+    indices: HashMap<K, usize, BuildHasherDefault<FlowHasher>>,
+}
+
+/// A fast hasher for the small keys of the flows (the default SipHash costs more than a linear
+/// scan of a few connections).
+///
+/// It is not resistant to collision attacks, but the keys only come from the client itself.
+#[derive(Default)]
+struct FlowHasher {
+    hash: u64,
+}
+
+impl Hasher for FlowHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.write_u64(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u8(&mut self, value: u8) {
+        self.write_u64(u64::from(value));
+    }
+
+    fn write_u16(&mut self, value: u16) {
+        self.write_u64(u64::from(value));
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write_u64(u64::from(value));
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        // same mixing as FxHash (used by rustc)
+        const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+        self.hash = (self.hash.rotate_left(5) ^ value).wrapping_mul(SEED);
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl<K, V> Default for FlowTable<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            indices: HashMap::default(),
+        }
+    }
+}
+
+impl<K: Copy + Eq + Hash, V> FlowTable<K, V> {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return the index of the value for `key`, if any.
+    pub fn find(&self, key: &K) -> Option<usize> {
+        self.indices.get(key).copied()
+    }
+
+    /// Insert a value for a new `key`, and return its index.
+    pub fn insert(&mut self, key: K, value: V) -> usize {
+        let index = self.entries.len();
+        let previous = self.indices.insert(key, index);
+        assert!(previous.is_none(), "Inserting a duplicate flow");
+        self.entries.push((key, value));
+        index
+    }
+
+    pub fn key(&self, index: usize) -> &K {
+        &self.entries[index].0
+    }
+
+    pub fn get(&self, index: usize) -> &V {
+        &self.entries[index].1
+    }
+
+    /// Remove the value at `index`, replaced by the last value.
+    pub fn remove_at(&mut self, index: usize) -> V {
+        let (key, value) = self.entries.swap_remove(index);
+        self.indices.remove(&key);
+        if let Some((moved_key, _)) = self.entries.get(index) {
+            self.indices.insert(*moved_key, index);
+        }
+        value
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.indices.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_find_and_remove() {
+        let mut table = FlowTable::default();
+        for key in 0..5 {
+            assert_eq!(key, table.insert(key, key * 10));
+        }
+        assert_eq!(Some(3), table.find(&3));
+        assert_eq!(None, table.find(&7));
+
+        // the last value moves to the removed index
+        assert_eq!(10, table.remove_at(1));
+        assert_eq!(Some(1), table.find(&4));
+        assert_eq!(40, *table.get(1));
+        assert_eq!(4, *table.key(1));
+        assert_eq!(None, table.find(&1));
+
+        // removing the last value moves nothing
+        assert_eq!(30, table.remove_at(3));
+        assert_eq!(3, table.len());
+        for key in [0, 2, 4] {
+            let index = table.find(&key).unwrap();
+            assert_eq!(key * 10, *table.get(index));
+        }
+
+        table.clear();
+        assert!(table.is_empty());
+        assert_eq!(None, table.find(&0));
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate")]
+    fn reject_duplicate_key() {
+        let mut table = FlowTable::default();
+        table.insert(42, ());
+        table.insert(42, ());
+    }
+}
@@ -0,0 +1,479 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use std::cmp;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::ip_header::IpHeaderData;
+use super::ip_packet::{IpPacket, MAX_PACKET_LENGTH};
+use super::ipv4_header::Ipv4HeaderData;
+
+const TAG: &str = "Fragmentation";
+
+// same value as the default ipfrag_time on Linux
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+// per client
+const MAX_PENDING_DATAGRAMS: usize = 64;
+const MAX_PENDING_BYTES: usize = 1 << 20;
+
+const IPV4_HEADER_LENGTH: usize = 20;
+const IPV6_HEADER_LENGTH: usize = 40;
+const IPV6_FRAGMENT_HEADER_LENGTH: usize = 8;
+// next header value of the fragment header (RFC 8200 section 4.5)
+const IPV6_FRAGMENT: u8 = 44;
+
+// the fragments of a datagram are identified by (source, destination, protocol, identification)
+// (RFC 791)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    source: u32,
+    destination: u32,
+    protocol: u8,
+    identification: u16,
+}
+
+struct PartialDatagram {
+    // header of the first fragment, once received
+    header: Option<Vec<u8>>,
+    data: Vec<u8>,
+    // sorted and disjoint ranges of data received
+    received: Vec<(usize, usize)>,
+    // known once the last fragment is received
+    data_length: Option<usize>,
+    created: Instant,
+}
+
+impl PartialDatagram {
+    fn new(now: Instant) -> Self {
+        Self {
+            header: None,
+            data: Vec::new(),
+            received: Vec::new(),
+            data_length: None,
+            created: now,
+        }
+    }
+
+    fn add_range(&mut self, start: usize, end: usize) {
+        self.received.push((start, end));
+        self.received.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.received.len());
+        for &(start, end) in &self.received {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = cmp::max(last.1, end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.received = merged;
+    }
+
+    fn is_complete(&self) -> bool {
+        match (self.header.as_ref(), self.data_length) {
+            (Some(_), Some(data_length)) => self.received == [(0, data_length)],
+            _ => false,
+        }
+    }
+
+    fn memory(&self) -> usize {
+        self.data.len() + self.header.as_ref().map_or(0, Vec::len)
+    }
+}
+
+/// Reassemble the IPv4 fragments sent by the client (RFC 815).
+///
+/// Incomplete datagrams are dropped after a timeout, or when they exceed the memory limits
+/// (oldest first).
+pub struct Reassembler {
+    datagrams: HashMap<FragmentKey, PartialDatagram>,
+    pending_bytes: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            datagrams: HashMap::new(),
+            pending_bytes: 0,
+        }
+    }
+
+    /// Add a fragment, and return the reassembled packet if it was the missing part.
+    pub fn push(&mut self, ip_packet: &IpPacket, now: Instant) -> Option<Vec<u8>> {
+        let header_data = match ip_packet.ip_header_data() {
+            IpHeaderData::V4(header_data) => header_data,
+            IpHeaderData::V6(_) => return None,
+        };
+        let raw = ip_packet.raw();
+        let header_length = header_data.header_length() as usize;
+        let fragment_data = &raw[header_length..];
+        let start = header_data.fragment_offset() as usize;
+        let end = start + fragment_data.len();
+        if header_data.more_fragments() && !fragment_data.len().is_multiple_of(8) {
+            warn!(target: TAG, "Invalid fragment length, dropping fragment");
+            return None;
+        }
+        if header_length + end > MAX_PACKET_LENGTH - 1 {
+            warn!(target: TAG, "Reassembled packet too long, dropping fragment");
+            return None;
+        }
+
+        self.remove_expired(now);
+        let key = FragmentKey {
+            source: header_data.source(),
+            destination: header_data.destination(),
+            protocol: raw[9],
+            identification: header_data.identification(),
+        };
+        if !self.datagrams.contains_key(&key) && self.datagrams.len() == MAX_PENDING_DATAGRAMS {
+            self.remove_oldest();
+        }
+        let datagram = self
+            .datagrams
+            .entry(key)
+            .or_insert_with(|| PartialDatagram::new(now));
+        let memory_before = datagram.memory();
+
+        if start == 0 {
+            datagram.header = Some(raw[..header_length].to_vec());
+        }
+        if !header_data.more_fragments() {
+            datagram.data_length = Some(end);
+        }
+        if datagram.data.len() < end {
+            datagram.data.resize(end, 0);
+        }
+        datagram.data[start..end].copy_from_slice(fragment_data);
+        datagram.add_range(start, end);
+
+        if datagram.is_complete() {
+            let datagram = self.datagrams.remove(&key).unwrap();
+            self.pending_bytes -= memory_before;
+            return Some(Self::build(datagram));
+        }
+
+        self.pending_bytes += datagram.memory() - memory_before;
+        while self.pending_bytes > MAX_PENDING_BYTES {
+            self.remove_oldest();
+        }
+        None
+    }
+
+    fn build(datagram: PartialDatagram) -> Vec<u8> {
+        let mut raw = datagram.header.unwrap();
+        let data_length = datagram.data_length.unwrap();
+        raw.extend_from_slice(&datagram.data[..data_length]);
+        let total_length = raw.len() as u16;
+        let mut header_data = Ipv4HeaderData::parse(&raw);
+        let mut header = header_data.bind_mut(&mut raw);
+        header.set_total_length(total_length);
+        header.set_fragment(false, 0);
+        header.update_checksum();
+        raw
+    }
+
+    /// Drop the incomplete datagrams whose fragments did not arrive in time.
+    pub fn remove_expired(&mut self, now: Instant) {
+        let before = self.datagrams.len();
+        let mut released = 0;
+        self.datagrams.retain(|_, datagram| {
+            let expired = now.duration_since(datagram.created) > REASSEMBLY_TIMEOUT;
+            if expired {
+                released += datagram.memory();
+            }
+            !expired
+        });
+        self.pending_bytes -= released;
+        let removed = before - self.datagrams.len();
+        if removed > 0 {
+            debug!(
+                target: TAG,
+                "Dropping {} incomplete datagram(s) (reassembly timeout)", removed
+            );
+        }
+    }
+
+    fn remove_oldest(&mut self) {
+        let oldest = self
+            .datagrams
+            .iter()
+            .min_by_key(|(_, datagram)| datagram.created)
+            .map(|(key, _)| *key);
+        if let Some(key) = oldest {
+            let datagram = self.datagrams.remove(&key).unwrap();
+            self.pending_bytes -= datagram.memory();
+            debug!(
+                target: TAG,
+                "Dropping incomplete datagram (reassembly limits reached)"
+            );
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.datagrams.clear();
+        self.pending_bytes = 0;
+    }
+}
+
+/// Split an IPv4 packet into fragments of at most `mtu` bytes (RFC 791).
+///
+/// The first fragment keeps the IP options, the others only carry the fixed header. The
+/// fragments share a new identification.
+pub fn fragment(ip_packet: &IpPacket, mtu: u16, identification: u16) -> Vec<Vec<u8>> {
+    let raw = ip_packet.raw();
+    let header_length = ip_packet.ip_header_data().header_length() as usize;
+    let data = &raw[header_length..];
+    let mut fragments = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = if offset == 0 {
+            &raw[..header_length]
+        } else {
+            &raw[..IPV4_HEADER_LENGTH]
+        };
+        // except for the last one, the fragment data length must be a multiple of 8
+        let max_data_length = (mtu as usize - header.len()) & !7;
+        let end = cmp::min(offset + max_data_length, data.len());
+
+        let mut fragment = Vec::with_capacity(header.len() + end - offset);
+        fragment.extend_from_slice(header);
+        fragment.extend_from_slice(&data[offset..end]);
+        let total_length = fragment.len() as u16;
+        let mut header_data = Ipv4HeaderData::parse(&fragment);
+        {
+            let mut header = header_data.bind_mut(&mut fragment);
+            if offset != 0 {
+                header.raw_mut()[0] = 4 << 4 | (IPV4_HEADER_LENGTH / 4) as u8;
+            }
+            header.set_total_length(total_length);
+            header.set_identification(identification);
+            // the sender cannot be told the packet is too big for the client, so fragment it
+            // anyway
+            header.set_dont_fragment(false);
+            header.set_fragment(end < data.len(), offset as u16);
+        }
+        // the header length may have changed
+        Ipv4HeaderData::parse(&fragment)
+            .bind_mut(&mut fragment)
+            .update_checksum();
+        fragments.push(fragment);
+        offset = end;
+    }
+    fragments
+}
+
+/// Split an IPv6 packet into fragments of at most `mtu` bytes, each with a fragment header
+/// (RFC 8200 section 4.5).
+///
+/// The packet must have no extension headers (the relay never forges any), so the whole payload
+/// is fragmentable.
+pub fn fragment_ipv6(ip_packet: &IpPacket, mtu: u16, identification: u32) -> Vec<Vec<u8>> {
+    let raw = ip_packet.raw();
+    let header = &raw[..IPV6_HEADER_LENGTH];
+    let data = &raw[IPV6_HEADER_LENGTH..];
+    // except for the last one, the fragment data length must be a multiple of 8
+    let max_data_length = (mtu as usize - IPV6_HEADER_LENGTH - IPV6_FRAGMENT_HEADER_LENGTH) & !7;
+    let mut fragments = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let end = cmp::min(offset + max_data_length, data.len());
+        let payload_length = IPV6_FRAGMENT_HEADER_LENGTH + end - offset;
+
+        let mut fragment = Vec::with_capacity(IPV6_HEADER_LENGTH + payload_length);
+        fragment.extend_from_slice(header);
+        fragment[4..6].copy_from_slice(&(payload_length as u16).to_be_bytes());
+        fragment[6] = IPV6_FRAGMENT;
+        // the fragment header carries the upper-layer protocol
+        fragment.extend_from_slice(&[header[6], 0]);
+        // the offset is a multiple of 8, the lowest bit is the "more fragments" flag
+        let offset_flags = offset as u16 | u16::from(end < data.len());
+        fragment.extend_from_slice(&offset_flags.to_be_bytes());
+        fragment.extend_from_slice(&identification.to_be_bytes());
+        fragment.extend_from_slice(&data[offset..end]);
+        fragments.push(fragment);
+        offset = end;
+    }
+    fragments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+    fn create_packet(data_length: usize) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>((20 + 8 + data_length) as u16)
+            .unwrap(); // total length
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x08080808).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(53).unwrap(); // destination port
+        raw.write_u16::<BigEndian>((8 + data_length) as u16)
+            .unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.extend((0..data_length).map(|i| i as u8)); // payload
+        Ipv4HeaderData::parse(&raw)
+            .bind_mut(&mut raw)
+            .update_checksum();
+        raw
+    }
+
+    #[test]
+    fn fragment_and_reassemble() {
+        let mut raw = create_packet(3000);
+        let original = raw.clone();
+        let packet = IpPacket::parse(&mut raw);
+        let mut fragments = fragment(&packet, 1500, 0x4242);
+        assert_eq!(3, fragments.len());
+        assert_eq!(1500, fragments[0].len());
+        assert_eq!(1500, fragments[1].len());
+        assert_eq!(20 + 3008 - 2 * 1480, fragments[2].len());
+
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        // out of order
+        for index in [2, 0] {
+            let fragment = IpPacket::parse(&mut fragments[index]);
+            assert!(fragment.ip_header_data().is_fragment());
+            assert!(!fragment.is_valid());
+            assert!(reassembler.push(&fragment, now).is_none());
+        }
+        let mut reassembled = reassembler
+            .push(&IpPacket::parse(&mut fragments[1]), now)
+            .unwrap();
+        assert_eq!(0, reassembler.pending_bytes);
+
+        // identical except for the identification (and thus the checksum)
+        assert_eq!(original[..4], reassembled[..4]);
+        assert_eq!(original[6..10], reassembled[6..10]);
+        assert_eq!(original[12..], reassembled[12..]);
+        let packet = IpPacket::parse(&mut reassembled);
+        assert!(packet.is_valid());
+        assert_eq!(3000, packet.payload().unwrap().len());
+    }
+
+    #[test]
+    fn fragment_despite_dont_fragment() {
+        let mut raw = create_packet(3000);
+        raw[6] = 0x40; // don't fragment
+        let packet = IpPacket::parse(&mut raw);
+        let fragments = fragment(&packet, 1500, 1);
+        assert_eq!(3, fragments.len());
+        assert!(fragments.iter().all(|fragment| fragment[6] & 0x40 == 0));
+    }
+
+    fn create_ipv6_packet(data_length: usize) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, traffic class, flow label
+        raw.write_u16::<BigEndian>((8 + data_length) as u16)
+            .unwrap(); // payload length
+        raw.write_u8(17).unwrap(); // next header (UDP)
+        raw.write_u8(64).unwrap(); // hop limit
+        raw.extend_from_slice(&[0xfd; 16]); // source address
+        raw.extend_from_slice(&[0x20; 16]); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(53).unwrap(); // destination port
+        raw.write_u16::<BigEndian>((8 + data_length) as u16)
+            .unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.extend((0..data_length).map(|i| i as u8)); // payload
+        raw
+    }
+
+    #[test]
+    fn fragment_ipv6() {
+        let mut raw = create_ipv6_packet(3000);
+        let original = raw.clone();
+        let packet = IpPacket::parse(&mut raw);
+        let mut fragments = super::fragment_ipv6(&packet, 1280, 0x42424242);
+        // 1232 bytes of data per fragment (a multiple of 8)
+        assert_eq!(3, fragments.len());
+        assert_eq!(1280, fragments[0].len());
+        assert_eq!(1280, fragments[1].len());
+        assert_eq!(40 + 8 + 3008 - 2 * 1232, fragments[2].len());
+
+        let mut data = Vec::new();
+        for (i, fragment) in fragments.iter_mut().enumerate() {
+            assert_eq!(original[..4], fragment[..4]);
+            assert_eq!(
+                fragment.len() - 40,
+                BigEndian::read_u16(&fragment[4..6]) as usize
+            );
+            assert_eq!(IPV6_FRAGMENT, fragment[6]);
+            assert_eq!(original[7..40], fragment[7..40]);
+            // next header, offset and "more fragments" flag, identification
+            assert_eq!(17, fragment[40]);
+            let offset_flags = BigEndian::read_u16(&fragment[42..44]);
+            assert_eq!(i * 1232, (offset_flags & !7) as usize);
+            assert_eq!(i < 2, offset_flags & 1 == 1);
+            assert_eq!(0x42424242, BigEndian::read_u32(&fragment[44..48]));
+            data.extend_from_slice(&fragment[48..]);
+
+            // the transport header cannot be parsed from a fragment
+            assert!(IpPacket::parse(fragment).transport_header().is_none());
+        }
+        assert_eq!(original[40..], data[..]);
+    }
+
+    #[test]
+    fn drop_expired_fragments() {
+        let mut raw = create_packet(3000);
+        let packet = IpPacket::parse(&mut raw);
+        let mut fragments = fragment(&packet, 1500, 1);
+
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        assert!(reassembler
+            .push(&IpPacket::parse(&mut fragments[0]), now)
+            .is_none());
+        assert!(reassembler.pending_bytes > 0);
+
+        let later = now + REASSEMBLY_TIMEOUT + Duration::from_secs(1);
+        reassembler.remove_expired(later);
+        assert_eq!(0, reassembler.pending_bytes);
+        assert!(reassembler
+            .push(&IpPacket::parse(&mut fragments[1]), later)
+            .is_none());
+        assert!(reassembler
+            .push(&IpPacket::parse(&mut fragments[2]), later)
+            .is_none());
+    }
+
+    #[test]
+    fn limit_pending_datagrams() {
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        for identification in 0..=MAX_PENDING_DATAGRAMS as u16 {
+            let mut raw = create_packet(3000);
+            let packet = IpPacket::parse(&mut raw);
+            let mut fragments = fragment(&packet, 1500, identification);
+            let fragment = IpPacket::parse(&mut fragments[0]);
+            assert!(reassembler.push(&fragment, now).is_none());
+        }
+        assert_eq!(MAX_PENDING_DATAGRAMS, reassembler.datagrams.len());
+    }
+}
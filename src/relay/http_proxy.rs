@@ -0,0 +1,219 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use mio::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+
+// a CONNECT response is only a status line and a few headers
+const MAX_RESPONSE_HEADER_LENGTH: usize = 8192;
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+/// Upstream HTTP proxy, used to open TCP connections with `CONNECT`.
+pub struct HttpProxy {
+    address: SocketAddr,
+    // "user:password", for Basic authentication
+    credentials: Option<String>,
+}
+
+impl HttpProxy {
+    pub fn new(address: SocketAddr, credentials: Option<String>) -> Self {
+        Self {
+            address,
+            credentials,
+        }
+    }
+
+    pub fn address(&self) -> &SocketAddr {
+        &self.address
+    }
+
+    pub fn credentials(&self) -> Option<&str> {
+        self.credentials.as_deref()
+    }
+
+    pub fn connect_request(&self, destination: &SocketAddr) -> Vec<u8> {
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", destination);
+        if let Some(credentials) = self.credentials.as_ref() {
+            request.push_str("Proxy-Authorization: Basic ");
+            request.push_str(&base64_encode(credentials.as_bytes()));
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        request.into_bytes()
+    }
+}
+
+/// State of a `CONNECT` exchange with the proxy, before the tunnel is established.
+pub struct HttpConnectHandshake {
+    request: Vec<u8>,
+    written: usize,
+    response: Vec<u8>,
+}
+
+impl HttpConnectHandshake {
+    pub fn new(http_proxy: &HttpProxy, destination: &SocketAddr) -> Self {
+        Self {
+            request: http_proxy.connect_request(destination),
+            written: 0,
+            response: Vec::new(),
+        }
+    }
+
+    pub fn is_request_sent(&self) -> bool {
+        self.written == self.request.len()
+    }
+
+    pub fn write_request<W: Write>(&mut self, destination: &mut W) -> io::Result<()> {
+        let w = destination.write(&self.request[self.written..])?;
+        self.written += w;
+        Ok(())
+    }
+
+    /// Read the response header from the proxy.
+    ///
+    /// Never consume bytes beyond the end of the header: they belong to the tunnel.
+    ///
+    /// `Ok(Some(status))` when the whole header is received
+    /// `Ok(None)` when more data is needed
+    /// `Err(_)` on error or malformed response
+    pub fn read_response(&mut self, stream: &mut TcpStream) -> io::Result<Option<u16>> {
+        let mut buf = [0u8; 1024];
+        let r = stream.peek(&mut buf)?;
+        if r == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Proxy closed the connection",
+            ));
+        }
+        // the header end may overlap the previous chunk
+        let search_start = self.response.len().saturating_sub(HEADER_END.len() - 1);
+        self.response.extend_from_slice(&buf[..r]);
+        let header_end = self.response[search_start..]
+            .windows(HEADER_END.len())
+            .position(|window| window == HEADER_END)
+            .map(|index| search_start + index + HEADER_END.len());
+
+        let previous_length = self.response.len() - r;
+        let consumed = match header_end {
+            Some(end) => end - previous_length,
+            None => r,
+        };
+        // the bytes have already been peeked, so they are immediately available
+        stream.read_exact(&mut buf[..consumed])?;
+
+        match header_end {
+            Some(end) => {
+                self.response.truncate(end);
+                parse_status(&self.response).map(Some)
+            }
+            None if self.response.len() > MAX_RESPONSE_HEADER_LENGTH => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Proxy response header too large",
+            )),
+            None => Ok(None),
+        }
+    }
+}
+
+// parse the status code from "HTTP/1.1 200 Connection established\r\n..."
+fn parse_status(header: &[u8]) -> io::Result<u16> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid proxy response");
+    let status_line_end = header
+        .iter()
+        .position(|&b| b == b'\r')
+        .ok_or_else(invalid)?;
+    let status_line = std::str::from_utf8(&header[..status_line_end]).map_err(|_| invalid())?;
+    let mut parts = status_line.split(' ');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/1.") && status.len() == 3 => {
+            status.parse().map_err(|_| invalid())
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+
+    #[test]
+    fn encode_base64() {
+        assert_eq!("", base64_encode(b""));
+        assert_eq!("Zg==", base64_encode(b"f"));
+        assert_eq!("Zm8=", base64_encode(b"fo"));
+        assert_eq!("Zm9v", base64_encode(b"foo"));
+        assert_eq!("dXNlcjpzZWNyZXQ=", base64_encode(b"user:secret"));
+    }
+
+    #[test]
+    fn build_connect_request() {
+        let address = "127.0.0.1:3128".parse().unwrap();
+        let http_proxy = HttpProxy::new(address, Some(String::from("user:secret")));
+        let destination = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 443).into();
+        let request = http_proxy.connect_request(&destination);
+        assert_eq!(
+            &b"CONNECT 1.2.3.4:443 HTTP/1.1\r\n\
+               Host: 1.2.3.4:443\r\n\
+               Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n"[..],
+            &request[..]
+        );
+    }
+
+    #[test]
+    fn build_connect_request_ipv6() {
+        let address = "127.0.0.1:3128".parse().unwrap();
+        let http_proxy = HttpProxy::new(address, None);
+        let destination = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443);
+        let request = http_proxy.connect_request(&destination);
+        assert_eq!(
+            &b"CONNECT [::1]:443 HTTP/1.1\r\nHost: [::1]:443\r\n\r\n"[..],
+            &request[..]
+        );
+    }
+
+    #[test]
+    fn parse_response_status() {
+        let header = b"HTTP/1.1 200 Connection established\r\n\r\n";
+        assert_eq!(200, parse_status(header).unwrap());
+        let header = b"HTTP/1.0 407 Proxy Authentication Required\r\nX: y\r\n\r\n";
+        assert_eq!(407, parse_status(header).unwrap());
+        assert!(parse_status(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+        assert!(parse_status(b"HTTP/1.1 2000 OK\r\n\r\n").is_err());
+    }
+}
@@ -2,6 +2,7 @@ use log::*;
 use mio::{Event, PollOpt};
 use mio::{Ready, Token};
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io;
 use std::net::IpAddr;
 use std::rc::Rc;
@@ -13,27 +14,44 @@ use super::{
     client::{Client, ClientChannel},
     connection::Connection,
     connection::ConnectionId,
+    datagram_buffer::DatagramBuffer,
+    egress::EgressPolicy,
+    icmp_error::{self, IcmpError},
     icmp_socket::IcmpSocket,
-    ipv4_header::Ipv4Header,
-    ipv4_packet::Ipv4Packet,
-    ipv4_packet::MAX_PACKET_LENGTH,
+    ip_header::IpHeader,
+    ip_packet::IpPacket,
+    ip_packet::MAX_PACKET_LENGTH,
     packetizer::Packetizer,
+    path_mtu,
     selector::Selector,
-    stream_buffer::StreamBuffer,
+    socket_error::{self, SocketError},
     transport_header::TransportHeader,
 };
 
 const TAG: &str = "IcmpConnection";
 const IDLE_TIMEOUT_SECONDS: u64 = 2;
 
+// an ICMP error quotes the IP header and the first 8 bytes of the payload (the echo header)
+const QUOTED_PAYLOAD_LENGTH: usize = 8;
+// the requests recently sent, to quote them in the errors
+const MAX_SENT_REQUESTS: usize = 16;
+
 pub struct IcmpConnection {
     id: ConnectionId,
     client: Weak<RefCell<Client>>,
     interests: Ready,
     socket: IcmpSocket,
     token: Token,
-    client_to_network: StreamBuffer,
+    client_to_network: DatagramBuffer,
     network_to_client: Packetizer,
+    // the TTL of the socket, copied from the client packets
+    ttl: Option<u8>,
+    // the "don't fragment" flag of the socket, copied from the client packets
+    dont_fragment: Option<bool>,
+    // the IPv4 options of the socket, copied from the client packets
+    ip_options: Vec<u8>,
+    // the headers of the requests recently sent
+    sent_requests: VecDeque<Vec<u8>>,
     closed: bool,
     idle_since: Instant,
 }
@@ -43,14 +61,15 @@ impl IcmpConnection {
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
-        ipv4_header: Ipv4Header,
+        egress: &EgressPolicy,
+        ip_header: IpHeader,
         transport_header: TransportHeader,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
 
         let interests = Ready::readable();
-        let packetizer = Packetizer::new(&ipv4_header, &transport_header);
-        let socket = Self::create_socket(&id)?;
+        let packetizer = Packetizer::new(&ip_header, &transport_header);
+        let socket = Self::create_socket(&id, egress)?;
 
         let rc = Rc::new(RefCell::new(Self {
             id,
@@ -58,8 +77,12 @@ impl IcmpConnection {
             interests,
             socket,
             token: Token(0),
-            client_to_network: StreamBuffer::new(MAX_PACKET_LENGTH),
+            client_to_network: DatagramBuffer::new(MAX_PACKET_LENGTH),
             network_to_client: packetizer,
+            ttl: None,
+            dont_fragment: None,
+            ip_options: Vec::new(),
+            sent_requests: VecDeque::new(),
             closed: false,
             idle_since: Instant::now(),
         }));
@@ -78,13 +101,13 @@ impl IcmpConnection {
         Ok(rc)
     }
 
-    fn create_socket(id: &ConnectionId) -> io::Result<IcmpSocket> {
-        let socket = IcmpSocket::bind(
-            "0.0.0.0"
-                .parse::<IpAddr>()
-                .expect("IP address parse failed"),
-        )?;
-        socket.connect(&id.rewritten_destination().into())?;
+    fn create_socket(id: &ConnectionId, egress: &EgressPolicy) -> io::Result<IcmpSocket> {
+        let mut socket = IcmpSocket::bind(IpAddr::V4(egress.bind_address()), egress)?;
+        cx_debug!(target: TAG, id, "{:?} socket", socket.kind());
+        socket.connect(&id.rewritten_destination())?;
+        if let Err(err) = socket.enable_errors() {
+            cx_debug!(target: TAG, id, "Cannot receive ICMP errors: {}", err);
+        }
         Ok(socket)
     }
 
@@ -116,17 +139,20 @@ impl IcmpConnection {
                 ready.is_writable(),
                 self.closed
             );
+            // a ping socket signals errors as such, a raw socket receives them as messages
+            let errors_received = socket_error::is_error(ready) && self.process_errors(selector);
             if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
                     self.process_send(selector)?;
                 }
                 if !self.closed && ready.is_readable() {
                     self.process_receive(selector)?;
+                    self.process_errors(selector);
                 }
                 if !self.closed {
                     self.update_interests(selector);
                 }
-            } else {
+            } else if !errors_received {
                 self.close(selector);
             }
             if self.closed {
@@ -143,6 +169,10 @@ impl IcmpConnection {
                 cx_debug!(target: TAG, self.id, "Spurious event, ignoring");
                 return Err(err);
             }
+            Err(ref err) if path_mtu::is_too_big(err) => {
+                // the path MTU decreased since the request was checked
+                cx_debug!(target: TAG, self.id, "Request exceeds the path MTU, dropped");
+            }
             Err(ref err) => {
                 cx_error!(
                     target: TAG,
@@ -163,6 +193,8 @@ impl IcmpConnection {
             Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
                 return Err(err);
             }
+            // the read reports the errno of an error just queued
+            Err(_) if self.process_errors(selector) => (),
             Err(ref err) => {
                 cx_error!(
                     target: TAG,
@@ -178,29 +210,32 @@ impl IcmpConnection {
     }
 
     fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
-        let ipv4_packet = self
+        let ip_packet = match self
             .network_to_client
-            .packetize_read(&mut self.socket, None)?
-            .expect("Packetzer reader failed");
+            .packetize_read_with_ip_options(&mut self.socket, IcmpSocket::ip_options)?
+        {
+            Some(ip_packet) => ip_packet,
+            None => {
+                // the socket dropped a message unrelated to this connection
+                return Ok(());
+            }
+        };
         let client_rc = self.client.upgrade().expect("Expected client not found");
 
-        match client_rc
-            .borrow_mut()
-            .send_to_client(selector, &ipv4_packet)
-        {
+        match client_rc.borrow_mut().send_to_client(selector, &ip_packet) {
             Ok(_) => {
                 cx_debug!(
                     target: TAG,
                     self.id,
                     "Packet ({} bytes) send to client",
-                    ipv4_packet.length()
+                    ip_packet.length()
                 );
                 if log_enabled!(target: TAG, Level::Trace) {
                     cx_trace!(
                         target: TAG,
                         self.id,
                         "send to client: {}",
-                        binary::build_packet_string(ipv4_packet.raw())
+                        binary::build_packet_string(ip_packet.raw())
                     );
                 }
             }
@@ -216,6 +251,126 @@ impl IcmpConnection {
         Ok(())
     }
 
+    /// Report the ICMP errors received about the requests sent, and return whether there was
+    /// any.
+    fn process_errors(&mut self, selector: &mut Selector) -> bool {
+        let mut received = false;
+        loop {
+            match self.socket.recv_error() {
+                Ok(Some((error, sequence_number))) => {
+                    received = true;
+                    self.report_error(selector, error, sequence_number);
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    cx_warn!(target: TAG, self.id, "Cannot receive ICMP error: {}", err);
+                    break;
+                }
+            }
+        }
+        received
+    }
+
+    fn report_error(&mut self, selector: &mut Selector, error: SocketError, sequence_number: u16) {
+        let request = self.sent_requests.iter().find(|headers| {
+            let offset = headers.len() - QUOTED_PAYLOAD_LENGTH;
+            headers[offset + 6..offset + 8] == sequence_number.to_be_bytes()
+        });
+        let (icmp_error, request) = match (error.icmp_error(), request) {
+            (Some(icmp_error), Some(request)) => (icmp_error, request),
+            _ => {
+                cx_debug!(target: TAG, self.id, "Ignore ICMP error {:?}", error);
+                return;
+            }
+        };
+        let source = self.id.error_source(error.source());
+        cx_debug!(target: TAG, self.id, "{:?} from {}", icmp_error, source);
+        let mut raw = icmp_error::forge_from(request, source, icmp_error);
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let reply = IpPacket::parse(&mut raw);
+        let result = client_rc.borrow_mut().send_to_client(selector, &reply);
+        if let Err(err) = result {
+            cx_warn!(target: TAG, self.id, "Cannot send ICMP error to client: {}", err);
+        }
+    }
+
+    /// Copy the TTL, the "don't fragment" flag and the IPv4 options (e.g. record route) of a
+    /// client packet to the socket.
+    ///
+    /// They apply to the whole socket, so the requests already queued are sent first.
+    fn copy_header_fields(&mut self, ip_header: &IpHeader) -> io::Result<()> {
+        let ip_header_data = ip_header.data_clone();
+        let ttl = ip_header_data.ttl();
+        let dont_fragment = path_mtu::dont_fragment(&ip_header_data);
+        let ip_options = ip_header.ipv4_options();
+        if self.ttl == Some(ttl)
+            && self.dont_fragment == dont_fragment
+            && self.ip_options == ip_options
+        {
+            return Ok(());
+        }
+        while !self.client_to_network.is_empty() {
+            self.write()?;
+        }
+        if self.ttl != Some(ttl) {
+            self.socket.set_ttl(ttl)?;
+            self.ttl = Some(ttl);
+        }
+        if self.dont_fragment != dont_fragment {
+            if let Some(dont_fragment) = dont_fragment {
+                if let Err(err) = self.socket.set_dont_fragment(dont_fragment) {
+                    cx_debug!(target: TAG, self.id, "Cannot copy the DF flag: {}", err);
+                }
+            }
+            self.dont_fragment = dont_fragment;
+        }
+        if self.ip_options != ip_options {
+            if let Err(err) = self.socket.set_ip_options(ip_options) {
+                cx_debug!(target: TAG, self.id, "Cannot copy the IP options: {}", err);
+            }
+            self.ip_options = ip_options.to_vec();
+        }
+        Ok(())
+    }
+
+    /// Reply "fragmentation needed" to the client, if the request does not fit in the path MTU.
+    ///
+    /// Return whether the request must be dropped.
+    fn check_path_mtu(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
+    ) -> bool {
+        let mtu = match self.socket.check_path_mtu(ip_packet) {
+            Some(mtu) => mtu,
+            None => return false,
+        };
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Request ({} bytes) exceeds the path MTU ({}), drop packet",
+            ip_packet.length(),
+            mtu
+        );
+        let mut raw = icmp_error::forge(ip_packet, IcmpError::FragmentationNeeded(mtu));
+        let reply = IpPacket::parse(&mut raw);
+        if let Err(err) = client_channel.send_to_client(selector, &reply) {
+            cx_warn!(target: TAG, self.id, "Cannot send ICMP error to client: {}", err);
+        }
+        true
+    }
+
+    fn remember_request(&mut self, ip_packet: &IpPacket) {
+        let headers_length =
+            ip_packet.ip_header_data().header_length() as usize + QUOTED_PAYLOAD_LENGTH;
+        if self.sent_requests.len() == MAX_SENT_REQUESTS {
+            self.sent_requests.pop_front();
+        }
+        self.sent_requests
+            .push_back(ip_packet.raw()[..headers_length].to_vec());
+    }
+
     fn update_interests(&mut self, selector: &mut Selector) {
         let ready = if self.client_to_network.is_empty() {
             Ready::readable()
@@ -250,21 +405,41 @@ impl Connection for IcmpConnection {
     fn send_to_network(
         &mut self,
         selector: &mut Selector,
-        _: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
     ) {
-        if ipv4_packet.length() as usize <= self.client_to_network.remaining() {
-            let payload = ipv4_packet.payload().expect("No Payload");
-            cx_trace!(
+        if let Err(err) = self.copy_header_fields(&ip_packet.ip_header()) {
+            cx_warn!(
                 target: TAG,
                 self.id,
-                "send to network {}",
-                binary::build_packet_string(payload)
+                "Cannot set socket options, drop packet: {}",
+                err
             );
-            self.client_to_network.read_from(payload);
-            self.update_interests(selector);
-        } else {
-            cx_warn!(target: TAG, self.id, "Cannot send to network, drop packet");
+            return;
+        }
+        if self.check_path_mtu(selector, client_channel, ip_packet) {
+            return;
+        }
+        let payload = ip_packet.payload().expect("No Payload");
+        match self.client_to_network.read_from(payload) {
+            Ok(_) => {
+                cx_trace!(
+                    target: TAG,
+                    self.id,
+                    "send to network {}",
+                    binary::build_packet_string(payload)
+                );
+                self.remember_request(ip_packet);
+                self.update_interests(selector);
+            }
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send to network, drop packet: {}",
+                    err
+                );
+            }
         }
     }
 
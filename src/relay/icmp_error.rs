@@ -0,0 +1,375 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io;
+use std::net::IpAddr;
+
+use super::icmp_header::checksum;
+use super::ip_header::IpHeaderData;
+use super::ip_packet::IpPacket;
+
+const IPV4_HEADER_LENGTH: usize = 20;
+const IPV6_HEADER_LENGTH: usize = 40;
+const ICMP_HEADER_LENGTH: usize = 8;
+const DEFAULT_TTL: u8 = 64;
+// an ICMP error quotes the original IP header and the first 8 bytes of its payload (RFC 792)
+const QUOTED_PAYLOAD_LENGTH: usize = 8;
+// an ICMPv6 error quotes as much of the original packet as fits in the minimum MTU (RFC 4443)
+const IPV6_MIN_MTU: usize = 1280;
+
+/// The errors the relay reports to the client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IcmpError {
+    NetworkUnreachable,
+    HostUnreachable,
+    PortUnreachable,
+    AdministrativelyProhibited,
+    TimeExceeded,
+    // with the MTU of the next hop
+    FragmentationNeeded(u16),
+}
+
+impl IcmpError {
+    /// The error to report for a failure to reach the destination from the relay, if any.
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        match err.kind() {
+            io::ErrorKind::NetworkUnreachable => Some(IcmpError::NetworkUnreachable),
+            io::ErrorKind::HostUnreachable => Some(IcmpError::HostUnreachable),
+            io::ErrorKind::ConnectionRefused => Some(IcmpError::PortUnreachable),
+            // rejected by the firewall of the host
+            io::ErrorKind::PermissionDenied => Some(IcmpError::AdministrativelyProhibited),
+            _ => None,
+        }
+    }
+
+    /// The error to report for an ICMP (or ICMPv6) error received from the network, if any.
+    ///
+    /// `info` is the type-specific data of the message (e.g. the MTU for "fragmentation needed").
+    pub fn from_icmp(ipv6: bool, icmp_type: u8, code: u8, info: u32) -> Option<Self> {
+        if ipv6 {
+            match (icmp_type, code) {
+                (1, 0) => Some(IcmpError::NetworkUnreachable),
+                (1, 3) => Some(IcmpError::HostUnreachable),
+                (1, 4) => Some(IcmpError::PortUnreachable),
+                (1, 1) | (1, 5) | (1, 6) => Some(IcmpError::AdministrativelyProhibited),
+                (3, _) => Some(IcmpError::TimeExceeded),
+                // a Packet Too Big is not reported: the host fragments the IPv6 packets itself,
+                // while the relay drops the fragments sent by the client
+                _ => None,
+            }
+        } else {
+            match (icmp_type, code) {
+                (3, 0) | (3, 6) | (3, 11) => Some(IcmpError::NetworkUnreachable),
+                (3, 1) | (3, 7) | (3, 12) => Some(IcmpError::HostUnreachable),
+                (3, 3) => Some(IcmpError::PortUnreachable),
+                (3, 9) | (3, 10) | (3, 13) => Some(IcmpError::AdministrativelyProhibited),
+                (3, 4) => Some(IcmpError::FragmentationNeeded(info as u16)),
+                (11, _) => Some(IcmpError::TimeExceeded),
+                _ => None,
+            }
+        }
+    }
+
+    /// ICMP type and code of the error
+    pub fn icmp_type_and_code(self) -> (u8, u8) {
+        match self {
+            IcmpError::NetworkUnreachable => (3, 0),
+            IcmpError::HostUnreachable => (3, 1),
+            IcmpError::PortUnreachable => (3, 3),
+            IcmpError::AdministrativelyProhibited => (3, 13),
+            // TTL exceeded in transit
+            IcmpError::TimeExceeded => (11, 0),
+            IcmpError::FragmentationNeeded(_) => (3, 4),
+        }
+    }
+
+    /// ICMPv6 type and code of the error
+    pub fn icmpv6_type_and_code(self) -> (u8, u8) {
+        match self {
+            // no route to destination
+            IcmpError::NetworkUnreachable => (1, 0),
+            // address unreachable
+            IcmpError::HostUnreachable => (1, 3),
+            IcmpError::PortUnreachable => (1, 4),
+            IcmpError::AdministrativelyProhibited => (1, 1),
+            // hop limit exceeded in transit
+            IcmpError::TimeExceeded => (3, 0),
+            // packet too big
+            IcmpError::FragmentationNeeded(_) => (2, 0),
+        }
+    }
+}
+
+/// Forge an ICMP (or ICMPv6) error message, sent to the client, about a packet it sent.
+pub fn forge(original: &IpPacket, error: IcmpError) -> Vec<u8> {
+    // the error comes from the destination of the original packet
+    let source = original.ip_header_data().destination();
+    forge_from(original.raw(), source, error)
+}
+
+/// Forge an ICMP (or ICMPv6) error message, sent to the client by `source`, about a packet it
+/// sent.
+///
+/// `original` is the start of the packet, at least its IP header and 8 bytes of its payload.
+pub fn forge_from(original: &[u8], source: IpAddr, error: IcmpError) -> Vec<u8> {
+    match source {
+        IpAddr::V4(source) => forge_v4(original, &source.octets(), error),
+        IpAddr::V6(source) => forge_v6(original, &source.octets(), error),
+    }
+}
+
+fn forge_v4(original_raw: &[u8], source: &[u8], error: IcmpError) -> Vec<u8> {
+    let original_header_length = usize::from(original_raw[0] & 0xf) * 4;
+    let quoted_length = original_raw
+        .len()
+        .min(original_header_length + QUOTED_PAYLOAD_LENGTH);
+    let total_length = IPV4_HEADER_LENGTH + ICMP_HEADER_LENGTH + quoted_length;
+
+    let mut raw = vec![0; total_length];
+    {
+        let ip = &mut raw[..IPV4_HEADER_LENGTH];
+        ip[0] = 4 << 4 | (IPV4_HEADER_LENGTH / 4) as u8;
+        BigEndian::write_u16(&mut ip[2..4], total_length as u16);
+        ip[8] = DEFAULT_TTL;
+        ip[9] = 1; // ICMP
+
+        ip[12..16].copy_from_slice(source);
+        ip[16..20].copy_from_slice(&original_raw[12..16]);
+        let checksum = checksum(0, ip);
+        BigEndian::write_u16(&mut ip[10..12], checksum);
+    }
+    {
+        let (icmp_type, code) = error.icmp_type_and_code();
+        let icmp = &mut raw[IPV4_HEADER_LENGTH..];
+        icmp[0] = icmp_type;
+        icmp[1] = code;
+        if let IcmpError::FragmentationNeeded(mtu) = error {
+            // next-hop MTU (RFC 1191 section 4)
+            BigEndian::write_u16(&mut icmp[6..8], mtu);
+        }
+        icmp[ICMP_HEADER_LENGTH..].copy_from_slice(&original_raw[..quoted_length]);
+        let checksum = checksum(0, icmp);
+        BigEndian::write_u16(&mut icmp[2..4], checksum);
+    }
+    raw
+}
+
+fn forge_v6(original_raw: &[u8], source: &[u8], error: IcmpError) -> Vec<u8> {
+    let quoted_length = original_raw
+        .len()
+        .min(IPV6_MIN_MTU - IPV6_HEADER_LENGTH - ICMP_HEADER_LENGTH);
+    let payload_length = ICMP_HEADER_LENGTH + quoted_length;
+
+    let mut raw = vec![0; IPV6_HEADER_LENGTH + payload_length];
+    {
+        let ip = &mut raw[..IPV6_HEADER_LENGTH];
+        ip[0] = 6 << 4;
+        BigEndian::write_u16(&mut ip[4..6], payload_length as u16);
+        ip[6] = 58; // ICMPv6
+        ip[7] = DEFAULT_TTL;
+
+        ip[8..24].copy_from_slice(source);
+        ip[24..40].copy_from_slice(&original_raw[8..24]);
+    }
+    // the ICMPv6 checksum covers a pseudo-header (RFC 4443 section 2.3)
+    let pseudo_header_sum = IpHeaderData::parse(&raw).pseudo_header_sum(58);
+    {
+        let (icmp_type, code) = error.icmpv6_type_and_code();
+        let icmp = &mut raw[IPV6_HEADER_LENGTH..];
+        icmp[0] = icmp_type;
+        icmp[1] = code;
+        if let IcmpError::FragmentationNeeded(mtu) = error {
+            BigEndian::write_u32(&mut icmp[4..8], u32::from(mtu));
+        }
+        icmp[ICMP_HEADER_LENGTH..].copy_from_slice(&original_raw[..quoted_length]);
+        let checksum = checksum(pseudo_header_sum, icmp);
+        BigEndian::write_u16(&mut icmp[2..4], checksum);
+    }
+    raw
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::ip_header::Protocol;
+    use byteorder::WriteBytesExt;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn create_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(40).unwrap(); // total length 20 + 8 + 12
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x08080808).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(53).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(20).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.extend_from_slice(&[0x42; 12]); // payload
+        raw
+    }
+
+    #[test]
+    fn forge_destination_unreachable() {
+        let raw = &mut create_packet()[..];
+        let packet = IpPacket::parse(raw);
+        let error = forge(&packet, IcmpError::AdministrativelyProhibited);
+
+        // IP header + ICMP header + quoted IP header + 8 bytes of UDP
+        assert_eq!(20 + 8 + 20 + 8, error.len());
+        let header = IpHeaderData::parse(&error);
+        assert_eq!(56, header.total_length());
+        assert_eq!(Protocol::Icmp, header.protocol());
+        assert_eq!(Ipv4Addr::from(0x08080808), header.source());
+        assert_eq!(Ipv4Addr::from(0x0A000002), header.destination());
+
+        assert_eq!([3, 13], error[20..22]);
+        assert_eq!(&packet.raw()[..28], &error[28..]);
+
+        // a valid checksum sums to 0
+        assert_eq!(0, checksum(0, &error[..20]));
+        assert_eq!(0, checksum(0, &error[20..]));
+    }
+
+    fn create_ipv6_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, traffic class, flow label
+        raw.write_u16::<BigEndian>(20).unwrap(); // payload length 8 + 12
+        raw.write_u8(17).unwrap(); // next header (UDP)
+        raw.write_u8(64).unwrap(); // hop limit
+        raw.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        raw.extend_from_slice(&Ipv6Addr::new(0x2001, 0x4860, 0, 0, 0, 0, 0, 0x8888).octets());
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(53).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(20).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.extend_from_slice(&[0x42; 12]); // payload
+        raw
+    }
+
+    #[test]
+    fn forge_ipv6_destination_unreachable() {
+        let raw = &mut create_ipv6_packet()[..];
+        let packet = IpPacket::parse(raw);
+        let error = forge(&packet, IcmpError::AdministrativelyProhibited);
+
+        // IPv6 header + ICMPv6 header + the whole original packet
+        assert_eq!(40 + 8 + 60, error.len());
+        let header = IpHeaderData::parse(&error);
+        assert_eq!(108, header.total_length());
+        assert_eq!(
+            Ipv6Addr::new(0x2001, 0x4860, 0, 0, 0, 0, 0, 0x8888),
+            header.source()
+        );
+        assert_eq!(
+            Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2),
+            header.destination()
+        );
+
+        assert_eq!([1, 1], error[40..42]);
+        assert_eq!(packet.raw(), &error[48..]);
+
+        assert_eq!(0, checksum(header.pseudo_header_sum(58), &error[40..]));
+    }
+
+    #[test]
+    fn forge_time_exceeded() {
+        let raw = &mut create_packet()[..];
+        let router = Ipv4Addr::new(192, 168, 1, 1);
+        let error = forge_from(&raw[..28], IpAddr::V4(router), IcmpError::TimeExceeded);
+
+        assert_eq!(20 + 8 + 20 + 8, error.len());
+        let header = IpHeaderData::parse(&error);
+        assert_eq!(router, header.source());
+        assert_eq!(Ipv4Addr::from(0x0A000002), header.destination());
+        assert_eq!([11, 0], error[20..22]);
+        assert_eq!(&raw[..28], &error[28..]);
+        assert_eq!(0, checksum(0, &error[20..]));
+    }
+
+    #[test]
+    fn map_icmp_errors() {
+        assert_eq!(
+            Some(IcmpError::TimeExceeded),
+            IcmpError::from_icmp(false, 11, 0, 0)
+        );
+        assert_eq!(
+            Some(IcmpError::PortUnreachable),
+            IcmpError::from_icmp(false, 3, 3, 0)
+        );
+        assert_eq!(
+            Some(IcmpError::TimeExceeded),
+            IcmpError::from_icmp(true, 3, 0, 0)
+        );
+        assert_eq!(
+            Some(IcmpError::HostUnreachable),
+            IcmpError::from_icmp(true, 1, 3, 0)
+        );
+        assert_eq!(
+            Some(IcmpError::FragmentationNeeded(1400)),
+            IcmpError::from_icmp(false, 3, 4, 1400)
+        );
+        // packet too big
+        assert_eq!(None, IcmpError::from_icmp(true, 2, 0, 1400));
+        // echo reply
+        assert_eq!(None, IcmpError::from_icmp(false, 0, 0, 0));
+    }
+
+    #[test]
+    fn forge_fragmentation_needed() {
+        let raw = &mut create_packet()[..];
+        let packet = IpPacket::parse(raw);
+        let error = forge(&packet, IcmpError::FragmentationNeeded(1400));
+        assert_eq!([3, 4], error[20..22]);
+        // unused, then the next-hop MTU
+        assert_eq!([0, 0, 0x05, 0x78], error[24..28]);
+        assert_eq!(0, checksum(0, &error[20..]));
+
+        let raw = &mut create_ipv6_packet()[..];
+        let packet = IpPacket::parse(raw);
+        let error = forge(&packet, IcmpError::FragmentationNeeded(1280));
+        assert_eq!([2, 0], error[40..42]);
+        assert_eq!([0, 0, 0x05, 0x00], error[44..48]);
+    }
+
+    #[test]
+    fn map_io_errors() {
+        let error = |kind| IcmpError::from_io_error(&io::Error::from(kind));
+        assert_eq!(
+            Some(IcmpError::NetworkUnreachable),
+            error(io::ErrorKind::NetworkUnreachable)
+        );
+        assert_eq!(
+            Some(IcmpError::HostUnreachable),
+            error(io::ErrorKind::HostUnreachable)
+        );
+        assert_eq!(
+            Some(IcmpError::PortUnreachable),
+            error(io::ErrorKind::ConnectionRefused)
+        );
+        assert_eq!(None, error(io::ErrorKind::OutOfMemory));
+    }
+}
@@ -1,3 +1,13 @@
+use byteorder::{BigEndian, ByteOrder};
+
+// echo message types (RFC 792 and RFC 4443)
+pub const TYPE_ECHO_REPLY: u8 = 0;
+pub const TYPE_ECHO_REQUEST: u8 = 8;
+pub const TYPE_ECHO_REQUEST_V6: u8 = 128;
+pub const TYPE_ECHO_REPLY_V6: u8 = 129;
+
+pub const ICMP_HEADER_LENGTH: usize = 8;
+
 #[derive(Debug)]
 pub struct IcmpHeader<'a> {
     raw: &'a [u8],
@@ -10,13 +20,62 @@ pub struct IcmpHeaderMut<'a> {
     data: &'a mut IcmpHeaderData,
 }
 
+/// The first 8 bytes of an ICMP (or ICMPv6) message.
+///
+/// The whole message, including this header, is relayed as the payload of the IP packet (the
+/// transport header length is 0).
 #[derive(Clone, Debug)]
-pub struct IcmpHeaderData {}
+pub struct IcmpHeaderData {
+    icmp_type: u8,
+    code: u8,
+    // only meaningful for echo messages
+    identifier: u16,
+    sequence_number: u16,
+}
 
 #[allow(dead_code)]
 impl IcmpHeaderData {
-    pub fn parse(_: &[u8]) -> Self {
-        Self {}
+    pub fn parse(raw: &[u8]) -> Self {
+        let mut header = [0; ICMP_HEADER_LENGTH];
+        let length = raw.len().min(ICMP_HEADER_LENGTH);
+        header[..length].copy_from_slice(&raw[..length]);
+        Self {
+            icmp_type: header[0],
+            code: header[1],
+            identifier: BigEndian::read_u16(&header[4..6]),
+            sequence_number: BigEndian::read_u16(&header[6..8]),
+        }
+    }
+
+    #[inline]
+    pub fn icmp_type(&self) -> u8 {
+        self.icmp_type
+    }
+
+    #[inline]
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+
+    #[inline]
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    #[inline]
+    pub fn sequence_number(&self) -> u16 {
+        self.sequence_number
+    }
+
+    /// Indicate whether the message is an echo request (for ICMP or ICMPv6).
+    #[inline]
+    pub fn is_echo_request(&self, ipv6: bool) -> bool {
+        let echo_request = if ipv6 {
+            TYPE_ECHO_REQUEST_V6
+        } else {
+            TYPE_ECHO_REQUEST
+        };
+        self.icmp_type == echo_request
     }
 
     #[inline]
@@ -66,3 +125,44 @@ impl<'a> IcmpHeaderMut<'a> {
         self.data
     }
 }
+
+// internet checksum (RFC 1071), the checksum field must be 0
+pub fn checksum(initial_sum: u32, data: &[u8]) -> u16 {
+    let mut sum = initial_sum
+        + data
+            .chunks(2)
+            .map(|chunk| {
+                let high = u32::from(chunk[0]) << 8;
+                high | chunk.get(1).copied().map_or(0, u32::from)
+            })
+            .sum::<u32>();
+    while (sum & !0xffff) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_echo_request() {
+        let raw = [8, 0, 0xf7, 0xfd, 0x12, 0x34, 0x00, 0x02, 0x42, 0x42];
+        let header = IcmpHeaderData::parse(&raw);
+        assert_eq!(TYPE_ECHO_REQUEST, header.icmp_type());
+        assert_eq!(0, header.code());
+        assert_eq!(0x1234, header.identifier());
+        assert_eq!(2, header.sequence_number());
+        assert!(header.is_echo_request(false));
+        assert!(!header.is_echo_request(true));
+    }
+
+    #[test]
+    fn parse_truncated() {
+        let header = IcmpHeaderData::parse(&[3, 1]);
+        assert_eq!(3, header.icmp_type());
+        assert_eq!(1, header.code());
+        assert_eq!(0, header.identifier());
+    }
+}
@@ -1,5 +1,4 @@
-use std::borrow::BorrowMut;
-use std::intrinsics::transmute;
+use std::collections::VecDeque;
 /**
  * The ICMP socket
  *
@@ -9,59 +8,462 @@ use std::intrinsics::transmute;
 use std::io;
 use std::io::Read;
 use std::io::Write;
+use std::mem::{self, transmute, MaybeUninit};
 use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+use byteorder::{BigEndian, ByteOrder};
 use mio::Evented;
+use rand::random;
+
+use super::datagram::DatagramSender;
+use super::egress::EgressPolicy;
+use super::icmp_header::{
+    ICMP_HEADER_LENGTH, TYPE_ECHO_REPLY, TYPE_ECHO_REPLY_V6, TYPE_ECHO_REQUEST,
+    TYPE_ECHO_REQUEST_V6,
+};
+use super::ip_options;
+use super::ip_packet::{IpPacket, MAX_PACKET_LENGTH};
+use super::path_mtu;
+use super::socket_error::{self, SocketError};
 use socket2::Domain;
 use socket2::Protocol;
+use socket2::SockRef;
 use socket2::Socket;
 use socket2::Type;
 
-pub struct IcmpSocket(Socket, SelectorId);
+const IPV4_HEADER_LENGTH: usize = 20;
+const IPV6_HEADER_LENGTH: usize = 40;
+const NEXT_HEADER_ICMPV6: u8 = 58;
+
+// the echo requests recently sent, to match the replies
+const MAX_SEQUENCE_NUMBERS: usize = 16;
+
+/// The kind of socket used to send the echo requests.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IcmpSocketKind {
+    /// Unprivileged ping socket (`SOCK_DGRAM`), allowed by `net.ipv4.ping_group_range` on Linux.
+    ///
+    /// The kernel replaces the echo identifier by its own, and only delivers the replies.
+    Ping,
+    /// Raw socket, requires root or `CAP_NET_RAW`.
+    Raw,
+}
+
+impl IcmpSocketKind {
+    // ping sockets rewrite the identifier the same way on other systems, but deliver the IP
+    // header on some of them, so they are only used on Linux
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const PREFERRED: &'static [IcmpSocketKind] = &[IcmpSocketKind::Ping, IcmpSocketKind::Raw];
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const PREFERRED: &'static [IcmpSocketKind] = &[IcmpSocketKind::Raw];
+
+    fn socket_type(self) -> Type {
+        match self {
+            IcmpSocketKind::Ping => Type::DGRAM,
+            IcmpSocketKind::Raw => Type::RAW,
+        }
+    }
+}
+
+/// An ICMP socket to relay the echo requests of one client connection (one echo identifier).
+///
+/// The identifier differs on the network: the kernel chooses one for a ping socket, the relay
+/// chooses a random one for a raw socket (which receives the ICMP messages of the whole host).
+/// It is restored in the messages received, and the messages unrelated to the socket are dropped.
+pub struct IcmpSocket {
+    socket: Socket,
+    selector_id: SelectorId,
+    kind: IcmpSocketKind,
+    ipv6: bool,
+    // None for a ping socket, the kernel rewrites it
+    network_identifier: Option<u16>,
+    client_identifier: Option<u16>,
+    sequence_numbers: VecDeque<u16>,
+    // whether the errors are queued by the system (otherwise a raw socket receives them as
+    // messages, but only from the destination once connected)
+    errors_enabled: bool,
+    // the errors received as messages, with the sequence number of the request they are about
+    errors: VecDeque<(SocketError, u16)>,
+    // large enough for any packet, the IPv4 header included
+    receive_buffer: Box<[u8]>,
+    // the IPv4 options of the last message received
+    ip_options: Vec<u8>,
+}
 
 impl IcmpSocket {
-    pub fn bind(ip: IpAddr) -> io::Result<IcmpSocket> {
+    /// Open an ICMP (or ICMPv6) socket, preferably a ping socket, falling back to a raw socket.
+    pub fn bind(ip: IpAddr, egress: &EgressPolicy) -> io::Result<IcmpSocket> {
+        let mut last_error = None;
+        for &kind in IcmpSocketKind::PREFERRED {
+            match Self::open(ip, kind) {
+                Ok(socket) => {
+                    egress.set_socket_options(&socket)?;
+                    let address = SocketAddr::new(ip, 0);
+                    if !ip.is_unspecified() {
+                        socket.bind(&address.into())?;
+                    }
+                    socket.set_nonblocking(true)?;
+                    if kind == IcmpSocketKind::Ping && ip.is_ipv4() && cfg!(target_os = "linux") {
+                        // a raw socket receives them in the IP header
+                        ip_options::enable_receive(SockRef::from(&socket))?;
+                    }
+                    let network_identifier = match kind {
+                        IcmpSocketKind::Ping => None,
+                        IcmpSocketKind::Raw => Some(random()),
+                    };
+                    return Ok(IcmpSocket {
+                        socket,
+                        selector_id: SelectorId::new(),
+                        kind,
+                        ipv6: ip.is_ipv6(),
+                        network_identifier,
+                        client_identifier: None,
+                        sequence_numbers: VecDeque::new(),
+                        errors_enabled: false,
+                        errors: VecDeque::new(),
+                        receive_buffer: vec![0; MAX_PACKET_LENGTH].into_boxed_slice(),
+                        ip_options: Vec::new(),
+                    });
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(last_error.expect("No ICMP socket kind"))
+    }
+
+    fn open(ip: IpAddr, kind: IcmpSocketKind) -> io::Result<Socket> {
         let protocol = match ip {
             IpAddr::V4(_) => Some(Protocol::ICMPV4),
             IpAddr::V6(_) => Some(Protocol::ICMPV6),
         };
-        Socket::new(
-            Domain::for_address(SocketAddr::new(ip, 0)),
-            Type::RAW,
-            protocol,
-        )
-        .map(|socket| {
-            socket
-                .set_nonblocking(true)
-                .expect("socket set non blocking failed");
-            IcmpSocket(socket, SelectorId::new())
-        })
+        let domain = Domain::for_address(SocketAddr::new(ip, 0));
+        Socket::new(domain, kind.socket_type(), protocol)
+    }
+
+    /// Return the kind of ICMP sockets the relay can open, if any.
+    pub fn probe() -> Option<IcmpSocketKind> {
+        let ip = IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED);
+        IcmpSocketKind::PREFERRED
+            .iter()
+            .copied()
+            .find(|&kind| Self::open(ip, kind).is_ok())
+    }
+
+    pub fn kind(&self) -> IcmpSocketKind {
+        self.kind
     }
 
     pub fn connect(&self, addr: &SocketAddr) -> io::Result<()> {
-        self.0.connect(&(*addr).into())
+        self.socket.connect(&(*addr).into())
+    }
+
+    /// Set the TTL (or the hop limit) of the requests sent.
+    pub fn set_ttl(&self, ttl: u8) -> io::Result<()> {
+        if self.ipv6 {
+            self.socket.set_unicast_hops_v6(u32::from(ttl))
+        } else {
+            self.socket.set_ttl(u32::from(ttl))
+        }
+    }
+
+    /// Set the "don't fragment" flag of the IPv4 requests sent.
+    pub fn set_dont_fragment(&self, dont_fragment: bool) -> io::Result<()> {
+        path_mtu::set_dont_fragment(SockRef::from(&self.socket), dont_fragment)
+    }
+
+    /// Set the IPv4 options of the requests sent (e.g. record route).
+    pub fn set_ip_options(&self, options: &[u8]) -> io::Result<()> {
+        ip_options::set(SockRef::from(&self.socket), options)
+    }
+
+    /// Return the IPv4 options of the last message read.
+    pub fn ip_options(&self) -> &[u8] {
+        &self.ip_options
+    }
+
+    /// Return the MTU to report to the client if the request does not fit in the path MTU (once
+    /// connected).
+    pub fn check_path_mtu(&self, ip_packet: &IpPacket) -> Option<u16> {
+        path_mtu::check(SockRef::from(&self.socket), ip_packet)
+    }
+
+    /// Receive the errors sent by any host about the requests through `recv_error()`.
+    ///
+    /// Otherwise, only a raw socket receives the errors, and only from the destination once
+    /// connected.
+    pub fn enable_errors(&mut self) -> io::Result<()> {
+        socket_error::enable(&self.socket, self.ipv6)?;
+        self.errors_enabled = true;
+        Ok(())
+    }
+
+    /// Receive an ICMP error about a request sent through this socket, and the sequence number
+    /// of the request.
+    pub fn recv_error(&mut self) -> io::Result<Option<(SocketError, u16)>> {
+        if let Some(error) = self.errors.pop_front() {
+            return Ok(Some(error));
+        }
+        // the payload is the request, as sent on the network
+        let mut request = [0; ICMP_HEADER_LENGTH];
+        while let Some((error, length)) = socket_error::recv(&self.socket, &mut request)? {
+            if let Some(error) = error {
+                if length == ICMP_HEADER_LENGTH && self.is_own_echo(&request) {
+                    let sequence_number = BigEndian::read_u16(&request[6..8]);
+                    return Ok(Some((error, sequence_number)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        let mut message = buf.to_vec();
+        self.translate_request(&mut message);
+        self.socket.send_to(&message, &(*addr).into())
+    }
+
+    /// Receive a message and the address it comes from.
+    ///
+    /// For ICMPv6, the message starts with the ICMPv6 header (there is no IP header). A message
+    /// unrelated to this socket is dropped, and its length is 0.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (size, addr) = {
+            // safe: recv_from() never writes uninitialized bytes into the buffer
+            let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+            self.socket.recv_from(buf)?
+        };
+        let addr = addr
+            .as_socket()
+            .ok_or_else(|| io::Error::other("Unexpected address family"))?;
+        if self.translate_response(&mut buf[..size]) {
+            Ok((size, addr))
+        } else {
+            Ok((0, addr))
+        }
+    }
+
+    fn echo_types(&self) -> (u8, u8) {
+        if self.ipv6 {
+            (TYPE_ECHO_REQUEST_V6, TYPE_ECHO_REPLY_V6)
+        } else {
+            (TYPE_ECHO_REQUEST, TYPE_ECHO_REPLY)
+        }
+    }
+
+    fn is_error(&self, icmp_type: u8) -> bool {
+        if self.ipv6 {
+            // destination unreachable, packet too big, time exceeded, parameter problem
+            (1..=4).contains(&icmp_type)
+        } else {
+            // destination unreachable, source quench, redirect, time exceeded, parameter problem
+            matches!(icmp_type, 3..=5 | 11 | 12)
+        }
+    }
+
+    /// Remember an echo request of the client, and set the network identifier.
+    fn translate_request(&mut self, message: &mut [u8]) {
+        let (echo_request, _) = self.echo_types();
+        if message.len() < ICMP_HEADER_LENGTH || message[0] != echo_request {
+            return;
+        }
+        self.client_identifier = Some(BigEndian::read_u16(&message[4..6]));
+        if self.sequence_numbers.len() == MAX_SEQUENCE_NUMBERS {
+            self.sequence_numbers.pop_front();
+        }
+        self.sequence_numbers
+            .push_back(BigEndian::read_u16(&message[6..8]));
+        if let Some(network_identifier) = self.network_identifier {
+            replace_word(message, 4, network_identifier, 2);
+        }
+    }
+
+    /// Indicate whether a received message answers a request sent through this socket, and
+    /// restore the identifier of the client.
+    fn translate_response(&self, message: &mut [u8]) -> bool {
+        let client_identifier = match self.client_identifier {
+            Some(client_identifier) => client_identifier,
+            None => return false,
+        };
+        let (echo_request, echo_reply) = self.echo_types();
+        if message.len() < ICMP_HEADER_LENGTH {
+            return false;
+        }
+        if message[0] == echo_reply {
+            if !self.is_own_echo(&message[..ICMP_HEADER_LENGTH]) {
+                return false;
+            }
+            replace_word(message, 4, client_identifier, 2);
+            return true;
+        }
+        if !self.is_error(message[0]) {
+            return false;
+        }
+        // the error quotes the echo request that caused it
+        let offset = match self.quoted_ip_header_length(&message[ICMP_HEADER_LENGTH..]) {
+            Some(length) => ICMP_HEADER_LENGTH + length,
+            None => return false,
+        };
+        if message.len() < offset + ICMP_HEADER_LENGTH
+            || message[offset] != echo_request
+            || !self.is_own_echo(&message[offset..offset + ICMP_HEADER_LENGTH])
+        {
+            return false;
+        }
+        // the quoted identifier and checksum change, so does the checksum of the error
+        let old_quoted_checksum = BigEndian::read_u16(&message[offset + 2..offset + 4]);
+        let old_identifier = replace_word(message, offset + 4, client_identifier, offset + 2);
+        let quoted_checksum = BigEndian::read_u16(&message[offset + 2..offset + 4]);
+        let checksum = BigEndian::read_u16(&message[2..4]);
+        let checksum = adjust_checksum(checksum, old_identifier, client_identifier);
+        let checksum = adjust_checksum(checksum, old_quoted_checksum, quoted_checksum);
+        BigEndian::write_u16(&mut message[2..4], checksum);
+        true
+    }
+
+    fn is_own_echo(&self, header: &[u8]) -> bool {
+        let identifier = BigEndian::read_u16(&header[4..6]);
+        let sequence_number = BigEndian::read_u16(&header[6..8]);
+        self.network_identifier.is_none_or(|id| id == identifier)
+            && self.sequence_numbers.contains(&sequence_number)
+    }
+
+    /// Length of the IP header quoted in an error, if it quotes an ICMP packet.
+    fn quoted_ip_header_length(&self, quoted: &[u8]) -> Option<usize> {
+        if self.ipv6 {
+            let is_icmpv6 = quoted.len() >= IPV6_HEADER_LENGTH
+                && quoted[0] >> 4 == 6
+                && quoted[6] == NEXT_HEADER_ICMPV6;
+            is_icmpv6.then_some(IPV6_HEADER_LENGTH)
+        } else {
+            let is_icmp =
+                quoted.len() >= IPV4_HEADER_LENGTH && quoted[0] >> 4 == 4 && quoted[9] == 1;
+            is_icmp.then(|| usize::from(quoted[0] & 0xf) * 4)
+        }
+    }
+
+    /// Receive a message into `bytes`, and copy it to `buf` if it is related to this socket.
+    fn receive(&mut self, bytes: &mut [u8], buf: &mut [u8]) -> io::Result<usize> {
+        // a raw IPv4 socket receives the IP header, a ping socket does not
+        let has_ip_header = self.kind == IcmpSocketKind::Raw && !self.ipv6;
+        let size = if self.kind == IcmpSocketKind::Ping && !self.ipv6 {
+            ip_options::recv(SockRef::from(&self.socket), bytes, &mut self.ip_options)?
+        } else {
+            self.socket.read(bytes)?
+        };
+        let source = if has_ip_header && size >= IPV4_HEADER_LENGTH {
+            Some(IpAddr::V4(BigEndian::read_u32(&bytes[12..16]).into()))
+        } else {
+            None
+        };
+        let message = if has_ip_header {
+            match strip_ipv4_header(&mut bytes[..size]) {
+                Some((options, message)) => {
+                    self.ip_options.clear();
+                    self.ip_options.extend_from_slice(options);
+                    message
+                }
+                None => return Ok(0),
+            }
+        } else {
+            &mut bytes[..size]
+        };
+        if !self.translate_response(message) {
+            return Ok(0);
+        }
+        let (_, echo_reply) = self.echo_types();
+        if message[0] != echo_reply {
+            // an error, delivered by recv_error() along with its sender (unless the system
+            // queues it as well)
+            if let (Some(source), false) = (source, self.errors_enabled) {
+                self.queue_error(source, message);
+            }
+            return Ok(0);
+        }
+        if message.len() > buf.len() {
+            // never deliver a truncated message
+            return Ok(0);
+        }
+        buf[..message.len()].copy_from_slice(message);
+        Ok(message.len())
+    }
+
+    // the error has been validated by translate_response()
+    fn queue_error(&mut self, source: IpAddr, message: &[u8]) {
+        let info = BigEndian::read_u32(&message[4..8]);
+        let error = SocketError::new(source, message[0], message[1], info);
+        let offset = ICMP_HEADER_LENGTH
+            + self
+                .quoted_ip_header_length(&message[ICMP_HEADER_LENGTH..])
+                .expect("Invalid quoted header");
+        let sequence_number = BigEndian::read_u16(&message[offset + 6..offset + 8]);
+        self.errors.push_back((error, sequence_number));
     }
 }
 
+/// Return the options of the IPv4 header and the message following it.
+fn strip_ipv4_header(packet: &mut [u8]) -> Option<(&[u8], &mut [u8])> {
+    if packet.len() < IPV4_HEADER_LENGTH || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_length = usize::from(packet[0] & 0xf) * 4;
+    if header_length < IPV4_HEADER_LENGTH || header_length > packet.len() {
+        return None;
+    }
+    let (header, message) = packet.split_at_mut(header_length);
+    Some((&header[IPV4_HEADER_LENGTH..], message))
+}
+
+/// Replace the 16-bit word at `index` by `value`, and update the checksum at `checksum_index`.
+///
+/// Return the previous value.
+fn replace_word(message: &mut [u8], index: usize, value: u16, checksum_index: usize) -> u16 {
+    let old = BigEndian::read_u16(&message[index..index + 2]);
+    let checksum = BigEndian::read_u16(&message[checksum_index..checksum_index + 2]);
+    BigEndian::write_u16(&mut message[index..index + 2], value);
+    BigEndian::write_u16(
+        &mut message[checksum_index..checksum_index + 2],
+        adjust_checksum(checksum, old, value),
+    );
+    old
+}
+
+// incremental update of the checksum for a 16-bit word changed from `old` to `new` (RFC 1624)
+fn adjust_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while (sum & !0xffff) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !sum as u16
+}
+
 impl Write for IcmpSocket {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.0.write(buf)
+        let mut message = buf.to_vec();
+        self.translate_request(&mut message);
+        self.socket.write(&message)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.0.flush()
+        self.socket.flush()
+    }
+}
+
+impl DatagramSender for IcmpSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
     }
 }
 
 impl Read for IcmpSocket {
+    /// Read a message, or nothing (0) if it is unrelated to this socket.
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut bytes = vec![0u8; 512];
-        let mut size = self.0.read(&mut bytes)?;
-        // Drop IPV4 Header
-        size = buf.borrow_mut().write(&bytes[20..size])?;
-        Ok(size)
+        // the buffer is borrowed while the message is translated
+        let mut bytes = mem::take(&mut self.receive_buffer);
+        let result = self.receive(&mut bytes, buf);
+        self.receive_buffer = bytes;
+        result
     }
 }
 
@@ -86,8 +488,8 @@ impl Evented for IcmpSocket {
         interest: mio::Ready,
         opts: mio::PollOpt,
     ) -> io::Result<()> {
-        self.1.associate_selector(poll)?;
-        EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+        self.selector_id.associate_selector(poll)?;
+        EventedFd(&self.socket.as_raw_fd()).register(poll, token, interest, opts)
     }
     fn reregister(
         &self,
@@ -96,15 +498,14 @@ impl Evented for IcmpSocket {
         interest: mio::Ready,
         opts: mio::PollOpt,
     ) -> io::Result<()> {
-        EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+        EventedFd(&self.socket.as_raw_fd()).reregister(poll, token, interest, opts)
     }
     fn deregister(&self, poll: &Poll) -> io::Result<()> {
-        EventedFd(&self.0.as_raw_fd()).deregister(poll)
+        EventedFd(&self.socket.as_raw_fd()).deregister(poll)
     }
 }
 
 #[cfg(any(
-    target_os = "bitrig",
     target_os = "dragonfly",
     target_os = "freebsd",
     target_os = "ios",
@@ -112,7 +513,6 @@ impl Evented for IcmpSocket {
     target_os = "netbsd",
     target_os = "openbsd"
 ))]
-
 #[allow(dead_code)]
 struct Selector {
     id: usize,
@@ -181,10 +581,7 @@ impl SelectorId {
         let selector_id = self.id.load(Ordering::SeqCst);
         let poll_exposed: &ExPoll = unsafe { transmute(poll) };
         if selector_id != 0 && selector_id != poll_exposed.selector.id() {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "socket already registered",
-            ))
+            Err(io::Error::other("socket already registered"))
         } else {
             self.id.store(poll_exposed.selector.id(), Ordering::SeqCst);
             Ok(())
@@ -199,3 +596,248 @@ impl Clone for SelectorId {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn checksum(raw: &[u8]) -> u16 {
+        let mut sum = 0u32;
+        for word in raw.chunks(2) {
+            sum += u32::from(word[0]) << 8 | word.get(1).copied().map_or(0, u32::from);
+        }
+        while (sum & !0xffff) != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !sum as u16
+    }
+
+    fn create_echo_request(
+        identifier: u16,
+        sequence_number: u16,
+        payload_length: usize,
+    ) -> Vec<u8> {
+        let mut raw = vec![TYPE_ECHO_REQUEST, 0, 0, 0];
+        raw.extend_from_slice(&identifier.to_be_bytes());
+        raw.extend_from_slice(&sequence_number.to_be_bytes());
+        raw.extend((0..payload_length).map(|i| i as u8));
+        let sum = checksum(&raw);
+        BigEndian::write_u16(&mut raw[2..4], sum);
+        raw
+    }
+
+    // the local host answers the echo requests itself
+    fn open_local_socket() -> IcmpSocket {
+        let socket = IcmpSocket::bind(IpAddr::V4(Ipv4Addr::LOCALHOST), &EgressPolicy::default())
+            .expect("No ICMP socket available");
+        socket
+            .connect(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+            .unwrap();
+        socket
+    }
+
+    fn read_message(socket: &mut IcmpSocket) -> Vec<u8> {
+        let mut buf = vec![0; MAX_PACKET_LENGTH];
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            match socket.read(&mut buf) {
+                // the messages unrelated to the socket are dropped
+                Ok(0) => (),
+                Ok(size) => return buf[..size].to_vec(),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => panic!("Cannot read: {}", err),
+            }
+        }
+        panic!("No reply received");
+    }
+
+    #[test]
+    fn strip_header_with_options() {
+        let mut packet = vec![0u8; 40 + ICMP_HEADER_LENGTH];
+        packet[0] = 4 << 4 | 10;
+        packet[20] = 7; // record route
+        packet[40] = TYPE_ECHO_REPLY;
+        let (options, message) = strip_ipv4_header(&mut packet).unwrap();
+        assert_eq!(20, options.len());
+        assert_eq!(7, options[0]);
+        assert_eq!(ICMP_HEADER_LENGTH, message.len());
+        assert_eq!(TYPE_ECHO_REPLY, message[0]);
+    }
+
+    #[test]
+    fn strip_invalid_header() {
+        // shorter than the header length
+        let mut packet = vec![0u8; 30];
+        packet[0] = 4 << 4 | 10;
+        assert!(strip_ipv4_header(&mut packet).is_none());
+
+        // header length less than the fixed header
+        packet[0] = 4 << 4 | 4;
+        assert!(strip_ipv4_header(&mut packet).is_none());
+
+        packet[0] = 6 << 4;
+        assert!(strip_ipv4_header(&mut packet).is_none());
+    }
+
+    // the translation does not use the socket, any socket will do
+    fn create_translating_socket(kind: IcmpSocketKind) -> IcmpSocket {
+        let network_identifier = match kind {
+            IcmpSocketKind::Ping => None,
+            IcmpSocketKind::Raw => Some(0xabcd),
+        };
+        IcmpSocket {
+            socket: Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap(),
+            selector_id: SelectorId::new(),
+            kind,
+            ipv6: false,
+            network_identifier,
+            client_identifier: None,
+            sequence_numbers: VecDeque::new(),
+            errors_enabled: false,
+            errors: VecDeque::new(),
+            receive_buffer: vec![0; MAX_PACKET_LENGTH].into_boxed_slice(),
+            ip_options: Vec::new(),
+        }
+    }
+
+    fn create_echo_reply(identifier: u16, sequence_number: u16, payload_length: usize) -> Vec<u8> {
+        let mut raw = create_echo_request(identifier, sequence_number, payload_length);
+        raw[0] = TYPE_ECHO_REPLY;
+        raw[2..4].fill(0);
+        let sum = checksum(&raw);
+        BigEndian::write_u16(&mut raw[2..4], sum);
+        raw
+    }
+
+    // a "port unreachable" quoting the IPv4 header and the beginning of the request
+    fn create_unreachable(request: &[u8]) -> Vec<u8> {
+        let mut raw = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        let mut ip_header = [0u8; IPV4_HEADER_LENGTH];
+        ip_header[0] = 4 << 4 | 5;
+        ip_header[9] = 1; // ICMP
+        raw.extend_from_slice(&ip_header);
+        raw.extend_from_slice(&request[..ICMP_HEADER_LENGTH + 8]);
+        let sum = checksum(&raw);
+        BigEndian::write_u16(&mut raw[2..4], sum);
+        raw
+    }
+
+    #[test]
+    fn rewrite_identifier_with_raw_socket() {
+        let mut socket = create_translating_socket(IcmpSocketKind::Raw);
+        let mut request = create_echo_request(0x1234, 1, 56);
+        socket.translate_request(&mut request);
+        // the identifier of the relay, with the checksum of a full computation
+        assert_eq!(create_echo_request(0xabcd, 1, 56), request);
+
+        let mut reply = create_echo_reply(0xabcd, 1, 56);
+        assert!(socket.translate_response(&mut reply));
+        assert_eq!(create_echo_reply(0x1234, 1, 56), reply);
+
+        // not a reply to this socket
+        assert!(!socket.translate_response(&mut create_echo_reply(0x5678, 1, 56)));
+        assert!(!socket.translate_response(&mut create_echo_reply(0xabcd, 2, 56)));
+    }
+
+    #[test]
+    fn restore_identifier_with_ping_socket() {
+        let mut socket = create_translating_socket(IcmpSocketKind::Ping);
+        let mut request = create_echo_request(0x1234, 1, 56);
+        socket.translate_request(&mut request);
+        // the kernel rewrites it
+        assert_eq!(create_echo_request(0x1234, 1, 56), request);
+
+        // the identifier chosen by the kernel
+        let mut reply = create_echo_reply(0x0042, 1, 56);
+        assert!(socket.translate_response(&mut reply));
+        assert_eq!(create_echo_reply(0x1234, 1, 56), reply);
+    }
+
+    #[test]
+    fn restore_identifier_in_error() {
+        let mut socket = create_translating_socket(IcmpSocketKind::Raw);
+        let mut request = create_echo_request(0x1234, 1, 56);
+        socket.translate_request(&mut request);
+
+        let mut error = create_unreachable(&request);
+        assert!(socket.translate_response(&mut error));
+        // the quoted request is the one of the client, and both checksums are updated
+        let expected = create_unreachable(&create_echo_request(0x1234, 1, 56));
+        assert_eq!(expected, error);
+    }
+
+    #[test]
+    fn adjust_checksum_incrementally() {
+        let mut message = create_echo_request(0x1234, 1, 56);
+        for identifier in [0, 1, 0x1234, 0x8000, 0xfffe, 0xffff] {
+            replace_word(&mut message, 4, identifier, 2);
+            assert_eq!(create_echo_request(identifier, 1, 56), message);
+        }
+    }
+
+    #[test]
+    fn probe_socket_kind() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        match IcmpSocket::probe() {
+            Some(kind) => {
+                let socket = IcmpSocket::bind(ip, &EgressPolicy::default()).unwrap();
+                assert_eq!(kind, socket.kind());
+            }
+            None => assert!(IcmpSocket::bind(ip, &EgressPolicy::default()).is_err()),
+        }
+    }
+
+    // like "ping -s 1400 127.0.0.1"
+    #[test]
+    #[ignore = "requires a ping socket (net.ipv4.ping_group_range) or a raw socket (CAP_NET_RAW)"]
+    fn echo_large_payload() {
+        let mut socket = open_local_socket();
+        let request = create_echo_request(0x1234, 1, 1400);
+        assert_eq!(request.len(), socket.write(&request).unwrap());
+
+        let reply = read_message(&mut socket);
+        assert_eq!(request.len(), reply.len());
+        assert_eq!(TYPE_ECHO_REPLY, reply[0]);
+        // the identifier of the client is restored
+        assert_eq!(0x1234, BigEndian::read_u16(&reply[4..6]));
+        assert_eq!(1, BigEndian::read_u16(&reply[6..8]));
+        assert_eq!(&request[8..], &reply[8..]);
+        assert_eq!(0, checksum(&reply));
+        assert!(socket.ip_options().is_empty());
+    }
+
+    // like "ping -R 127.0.0.1": the request and the reply carry the record route option
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "requires a ping socket (net.ipv4.ping_group_range) or a raw socket (CAP_NET_RAW)"]
+    fn echo_with_record_route() {
+        let mut socket = open_local_socket();
+        // record route: type, length, pointer, then 9 empty slots, padded by "end of options"
+        let mut options = vec![0u8; 40];
+        options[..3].copy_from_slice(&[7, 39, 4]);
+        socket.set_ip_options(&options).unwrap();
+
+        let request = create_echo_request(0x1234, 7, 56);
+        assert_eq!(request.len(), socket.write(&request).unwrap());
+
+        let reply = read_message(&mut socket);
+        assert_eq!(request.len(), reply.len());
+        assert_eq!(TYPE_ECHO_REPLY, reply[0]);
+        assert_eq!(0x1234, BigEndian::read_u16(&reply[4..6]));
+        assert_eq!(7, BigEndian::read_u16(&reply[6..8]));
+        assert_eq!(&request[8..], &reply[8..]);
+        assert_eq!(0, checksum(&reply));
+
+        let reply_options = socket.ip_options();
+        assert_eq!(40, reply_options.len());
+        assert_eq!(&[7, 39], &reply_options[..2]);
+        // the route is recorded
+        assert!(reply_options[2] > 4);
+        assert_eq!(&Ipv4Addr::LOCALHOST.octets(), &reply_options[3..7]);
+    }
+}
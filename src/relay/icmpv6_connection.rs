@@ -0,0 +1,537 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use log::*;
+use mio::{Event, PollOpt, Ready, Token};
+use std::cell::RefCell;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::rc::{Rc, Weak};
+use std::time::Instant;
+
+use super::binary;
+use super::client::{Client, ClientChannel};
+use super::connection::{Connection, ConnectionId};
+use super::datagram::DatagramSender;
+use super::datagram_buffer::DatagramBuffer;
+use super::egress::EgressPolicy;
+use super::icmp_header;
+use super::icmp_socket::IcmpSocket;
+use super::ip_header::IpHeader;
+use super::ip_packet::{IpPacket, MAX_PACKET_LENGTH};
+use super::packetizer::Packetizer;
+use super::selector::Selector;
+use super::transport_header::TransportHeader;
+
+const TAG: &str = "Icmpv6Connection";
+const IDLE_TIMEOUT_SECONDS: u64 = 2;
+
+// ICMPv6 message types (RFC 4443)
+const TYPE_DESTINATION_UNREACHABLE: u8 = 1;
+const TYPE_PARAMETER_PROBLEM: u8 = 4;
+const TYPE_ECHO_REQUEST: u8 = 128;
+const TYPE_ECHO_REPLY: u8 = 129;
+
+const ICMPV6_HEADER_LENGTH: usize = 8;
+const IPV6_HEADER_LENGTH: usize = 40;
+const NEXT_HEADER_ICMPV6: u8 = 58;
+
+// the identifiers of the echo requests recently sent by the client
+const MAX_ECHO_IDENTIFIERS: usize = 16;
+
+/// Relay the echo requests of the client to an IPv6 destination.
+///
+/// The socket is not connected, so that the errors sent by the routers on the path (Destination
+/// Unreachable, Packet Too Big, Time Exceeded) are received along with the replies.
+pub struct Icmpv6Connection {
+    id: ConnectionId,
+    client: Weak<RefCell<Client>>,
+    interests: Ready,
+    socket: IcmpSocket,
+    token: Token,
+    destination: SocketAddr,
+    client_address: Ipv6Addr,
+    echo_identifiers: Vec<u16>,
+    client_to_network: DatagramBuffer,
+    network_to_client: Packetizer,
+    // the hop limit of the socket, copied from the client packets
+    hop_limit: Option<u8>,
+    receive_buffer: Box<[u8]>,
+    closed: bool,
+    idle_since: Instant,
+}
+
+// send the datagrams to the destination of the unconnected socket
+struct SendTo<'a> {
+    socket: &'a mut IcmpSocket,
+    destination: &'a SocketAddr,
+}
+
+impl<'a> DatagramSender for SendTo<'a> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, self.destination)
+    }
+}
+
+impl Icmpv6Connection {
+    pub fn create(
+        selector: &mut Selector,
+        id: ConnectionId,
+        client: Weak<RefCell<Client>>,
+        egress: &EgressPolicy,
+        ip_header: IpHeader,
+        transport_header: TransportHeader,
+    ) -> io::Result<Rc<RefCell<Self>>> {
+        cx_info!(target: TAG, id, "Open");
+
+        let client_address = match ip_header.source() {
+            IpAddr::V6(address) => address,
+            IpAddr::V4(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "ICMPv6 over IPv4",
+                ))
+            }
+        };
+        let interests = Ready::readable();
+        let packetizer = Packetizer::new(&ip_header, &transport_header);
+        let socket = IcmpSocket::bind(IpAddr::V6(egress.bind_address6()), egress)?;
+        cx_debug!(target: TAG, id, "{:?} socket", socket.kind());
+
+        let rc = Rc::new(RefCell::new(Self {
+            destination: id.rewritten_destination(),
+            id,
+            client,
+            interests,
+            socket,
+            token: Token(0),
+            client_address,
+            echo_identifiers: Vec::new(),
+            client_to_network: DatagramBuffer::new(MAX_PACKET_LENGTH),
+            network_to_client: packetizer,
+            hop_limit: None,
+            receive_buffer: vec![0; MAX_PACKET_LENGTH].into_boxed_slice(),
+            closed: false,
+            idle_since: Instant::now(),
+        }));
+
+        {
+            let mut self_ref = rc.borrow_mut();
+
+            let rc2 = rc.clone();
+            // must annotate selector type: https://stackoverflow.com/a/44004103/1987178
+            let handler =
+                move |selector: &mut Selector, event| rc2.borrow_mut().on_ready(selector, event);
+            let token =
+                selector.register(&self_ref.socket, handler, interests, PollOpt::level())?;
+            self_ref.token = token;
+        }
+        Ok(rc)
+    }
+
+    fn remove_from_router(&self) {
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let mut client = client_rc.borrow_mut();
+        client.router().remove(self);
+    }
+
+    fn on_ready(&mut self, selector: &mut Selector, event: Event) {
+        match self.process(selector, event) {
+            Ok(_) => (),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                cx_debug!(target: TAG, self.id, "Spurious event, ignoring");
+            }
+            Err(_) => panic!("Unexpected unhandled error"),
+        }
+    }
+
+    fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
+        if !self.closed {
+            let ready = event.readiness();
+            if ready.is_readable() || ready.is_writable() {
+                if ready.is_writable() {
+                    self.process_send(selector)?;
+                }
+                if !self.closed && ready.is_readable() {
+                    self.process_receive(selector)?;
+                }
+                if !self.closed {
+                    self.update_interests(selector);
+                }
+            } else {
+                self.close(selector);
+            }
+            if self.closed {
+                self.remove_from_router();
+            }
+        }
+        Ok(())
+    }
+
+    fn process_send(&mut self, selector: &mut Selector) -> io::Result<()> {
+        let mut send_to = SendTo {
+            socket: &mut self.socket,
+            destination: &self.destination,
+        };
+        match self.client_to_network.write_to(&mut send_to) {
+            Ok(_) => self.touch(),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                cx_debug!(target: TAG, self.id, "Spurious event, ignoring");
+                return Err(err);
+            }
+            Err(ref err) => {
+                cx_error!(
+                    target: TAG,
+                    self.id,
+                    "Cannot write: [{:?}] {}",
+                    err.kind(),
+                    err
+                );
+                self.close(selector);
+            }
+        }
+        Ok(())
+    }
+
+    fn process_receive(&mut self, selector: &mut Selector) -> io::Result<()> {
+        match self.read(selector) {
+            Ok(_) => (),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Err(err);
+            }
+            Err(ref err) => {
+                cx_error!(
+                    target: TAG,
+                    self.id,
+                    "Cannot read: [{:?}] {}",
+                    err.kind(),
+                    err
+                );
+                self.close(selector);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
+        let (length, source) = self.socket.recv_from(&mut self.receive_buffer)?;
+        let source = match source.ip() {
+            IpAddr::V6(source) => source,
+            IpAddr::V4(_) => return Ok(()),
+        };
+        let message = &mut self.receive_buffer[..length];
+        // the raw socket receives all the ICMPv6 messages of the host
+        if !is_for_connection(message, source, &self.destination, &self.echo_identifiers) {
+            return Ok(());
+        }
+        prepare_for_client(message, source, self.client_address);
+        self.idle_since = Instant::now();
+
+        // errors come from the routers on the path, not from the destination
+        self.network_to_client
+            .ip_header_mut()
+            .set_source(IpAddr::V6(source));
+        let mut message: &[u8] = message;
+        let ip_packet = self
+            .network_to_client
+            .packetize_read(&mut message, None)?
+            .expect("Packetizer reader failed");
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+
+        match client_rc.borrow_mut().send_to_client(selector, &ip_packet) {
+            Ok(_) => {
+                cx_debug!(
+                    target: TAG,
+                    self.id,
+                    "Packet ({} bytes) sent to client",
+                    ip_packet.length()
+                );
+                if log_enabled!(target: TAG, Level::Trace) {
+                    cx_trace!(
+                        target: TAG,
+                        self.id,
+                        "send to client: {}",
+                        binary::build_packet_string(ip_packet.raw())
+                    );
+                }
+            }
+            Err(_) => {
+                cx_warn!(target: TAG, self.id, "Cannot send to client, drop packet");
+            }
+        }
+        Ok(())
+    }
+
+    fn remember_echo_identifier(&mut self, message: &[u8]) {
+        if message.len() >= ICMPV6_HEADER_LENGTH && message[0] == TYPE_ECHO_REQUEST {
+            let identifier = BigEndian::read_u16(&message[4..6]);
+            if !self.echo_identifiers.contains(&identifier) {
+                if self.echo_identifiers.len() == MAX_ECHO_IDENTIFIERS {
+                    self.echo_identifiers.remove(0);
+                }
+                self.echo_identifiers.push(identifier);
+            }
+        }
+    }
+
+    /// Copy the hop limit of a client packet to the socket.
+    ///
+    /// It applies to the whole socket, so the requests already queued are sent first.
+    fn set_hop_limit(&mut self, hop_limit: u8) -> io::Result<()> {
+        if self.hop_limit == Some(hop_limit) {
+            return Ok(());
+        }
+        let mut send_to = SendTo {
+            socket: &mut self.socket,
+            destination: &self.destination,
+        };
+        while !self.client_to_network.is_empty() {
+            self.client_to_network.write_to(&mut send_to)?;
+        }
+        self.socket.set_ttl(hop_limit)?;
+        self.hop_limit = Some(hop_limit);
+        Ok(())
+    }
+
+    fn update_interests(&mut self, selector: &mut Selector) {
+        let ready = if self.client_to_network.is_empty() {
+            Ready::readable()
+        } else {
+            Ready::readable() | Ready::writable()
+        };
+        if self.interests != ready {
+            self.interests = ready;
+            selector
+                .reregister(&self.socket, self.token, ready, PollOpt::level())
+                .expect("Cannot register on poll");
+        }
+    }
+
+    fn touch(&mut self) {
+        self.idle_since = Instant::now();
+    }
+}
+
+/// Indicate whether a message received by the raw socket answers an echo request of the client.
+fn is_for_connection(
+    message: &[u8],
+    source: Ipv6Addr,
+    destination: &SocketAddr,
+    echo_identifiers: &[u16],
+) -> bool {
+    if message.len() < ICMPV6_HEADER_LENGTH {
+        return false;
+    }
+    match message[0] {
+        TYPE_ECHO_REPLY => {
+            IpAddr::V6(source) == destination.ip()
+                && echo_identifiers.contains(&BigEndian::read_u16(&message[4..6]))
+        }
+        TYPE_DESTINATION_UNREACHABLE..=TYPE_PARAMETER_PROBLEM => {
+            // the error quotes the echo request that caused it
+            let quoted = &message[ICMPV6_HEADER_LENGTH..];
+            quoted.len() >= IPV6_HEADER_LENGTH + ICMPV6_HEADER_LENGTH
+                && quoted[0] >> 4 == 6
+                && quoted[6] == NEXT_HEADER_ICMPV6
+                && IpAddr::V6(read_address(&quoted[24..40])) == destination.ip()
+                && quoted[IPV6_HEADER_LENGTH] == TYPE_ECHO_REQUEST
+                && echo_identifiers.contains(&BigEndian::read_u16(
+                    &quoted[IPV6_HEADER_LENGTH + 4..IPV6_HEADER_LENGTH + 6],
+                ))
+        }
+        _ => false,
+    }
+}
+
+/// Adapt a message received from `source` to be forwarded to the client.
+///
+/// An error quotes the request as sent by the relay: its source is replaced by the client
+/// address. The checksum covers a pseudo-header, so it is computed again.
+fn prepare_for_client(message: &mut [u8], source: Ipv6Addr, client_address: Ipv6Addr) {
+    if message[0] != TYPE_ECHO_REPLY {
+        let quoted = &mut message[ICMPV6_HEADER_LENGTH..];
+        quoted[8..24].copy_from_slice(&client_address.octets());
+    }
+
+    // pseudo-header checksum (cf rfc8200 section 8.1)
+    let mut pseudo_header_sum = u32::from(NEXT_HEADER_ICMPV6) + u32::from(message.len() as u16);
+    for address in [source, client_address] {
+        for word in address.segments() {
+            pseudo_header_sum += u32::from(word);
+        }
+    }
+    BigEndian::write_u16(&mut message[2..4], 0);
+    let checksum = icmp_header::checksum(pseudo_header_sum, message);
+    BigEndian::write_u16(&mut message[2..4], checksum);
+}
+
+fn read_address(raw: &[u8]) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(raw);
+    Ipv6Addr::from(octets)
+}
+
+impl Connection for Icmpv6Connection {
+    fn id(&self) -> &ConnectionId {
+        &self.id
+    }
+
+    fn send_to_network(
+        &mut self,
+        selector: &mut Selector,
+        _: &mut ClientChannel,
+        ip_packet: &IpPacket,
+    ) {
+        if let Err(err) = self.set_hop_limit(ip_packet.ip_header_data().ttl()) {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot set hop limit, drop packet: {}",
+                err
+            );
+            return;
+        }
+        let payload = ip_packet.payload().expect("No Payload");
+        match self.client_to_network.read_from(payload) {
+            Ok(_) => {
+                cx_trace!(
+                    target: TAG,
+                    self.id,
+                    "send to network {}",
+                    binary::build_packet_string(payload)
+                );
+                self.remember_echo_identifier(payload);
+                self.update_interests(selector);
+            }
+            Err(err) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Cannot send to network, drop packet: {}",
+                    err
+                );
+            }
+        }
+    }
+
+    fn close(&mut self, selector: &mut Selector) {
+        cx_info!(target: TAG, self.id, "Close");
+        self.closed = true;
+        if let Err(err) = selector.deregister(&self.socket, self.token) {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Fail to deregister ICMPv6 socket: {}",
+                err
+            );
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.idle_since.elapsed().as_secs() > IDLE_TIMEOUT_SECONDS
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_echo(icmp_type: u8, identifier: u16) -> Vec<u8> {
+        let mut raw = vec![icmp_type, 0, 0, 0];
+        raw.extend_from_slice(&identifier.to_be_bytes());
+        raw.extend_from_slice(&[0, 1]); // sequence number
+        raw.extend_from_slice(b"ping");
+        raw
+    }
+
+    fn create_error(icmp_type: u8, relay: Ipv6Addr, destination: Ipv6Addr, id: u16) -> Vec<u8> {
+        let request = create_echo(TYPE_ECHO_REQUEST, id);
+        let mut raw = vec![icmp_type, 0, 0, 0, 0, 0, 0x05, 0x00]; // e.g. the MTU for Packet Too Big
+        raw.extend_from_slice(&[6 << 4, 0, 0, 0]);
+        raw.extend_from_slice(&(request.len() as u16).to_be_bytes());
+        raw.extend_from_slice(&[NEXT_HEADER_ICMPV6, 64]);
+        raw.extend_from_slice(&relay.octets());
+        raw.extend_from_slice(&destination.octets());
+        raw.extend_from_slice(&request);
+        raw
+    }
+
+    #[test]
+    fn filter_messages() {
+        let destination: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let router: Ipv6Addr = "2001:db8:ffff::1".parse().unwrap();
+        let relay: Ipv6Addr = "2001:db8:1::2".parse().unwrap();
+        let destination_addr = SocketAddr::new(IpAddr::V6(destination), 0);
+        let ids = [42];
+
+        let reply = create_echo(TYPE_ECHO_REPLY, 42);
+        assert!(is_for_connection(
+            &reply,
+            destination,
+            &destination_addr,
+            &ids
+        ));
+        // reply to another process of the host
+        let reply = create_echo(TYPE_ECHO_REPLY, 43);
+        assert!(!is_for_connection(
+            &reply,
+            destination,
+            &destination_addr,
+            &ids
+        ));
+        // reply from another host
+        let reply = create_echo(TYPE_ECHO_REPLY, 42);
+        assert!(!is_for_connection(&reply, router, &destination_addr, &ids));
+
+        let error = create_error(2, relay, destination, 42); // Packet Too Big
+        assert!(is_for_connection(&error, router, &destination_addr, &ids));
+        let error = create_error(1, relay, router, 42); // about a request to another host
+        assert!(!is_for_connection(&error, router, &destination_addr, &ids));
+
+        // neighbor solicitation
+        let ndp = [135, 0, 0, 0, 0, 0, 0, 0];
+        assert!(!is_for_connection(
+            &ndp,
+            destination,
+            &destination_addr,
+            &ids
+        ));
+    }
+
+    #[test]
+    fn rewrite_error_for_client() {
+        let destination: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let router: Ipv6Addr = "2001:db8:ffff::1".parse().unwrap();
+        let relay: Ipv6Addr = "2001:db8:1::2".parse().unwrap();
+        let client: Ipv6Addr = "fd00::2".parse().unwrap();
+
+        let mut error = create_error(1, relay, destination, 42);
+        prepare_for_client(&mut error, router, client);
+        assert_eq!(&client.octets(), &error[16..32]);
+
+        // a valid checksum sums to 0
+        let mut sum = u32::from(NEXT_HEADER_ICMPV6) + error.len() as u32;
+        for word in router.segments().iter().chain(client.segments().iter()) {
+            sum += u32::from(*word);
+        }
+        assert_eq!(0, icmp_header::checksum(sum, &error));
+    }
+}
@@ -0,0 +1,345 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use rand::random;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Weak;
+use std::time::{Duration, Instant};
+
+use super::client::Client;
+use super::config::parse_duration;
+use super::ip_packet::MAX_PACKET_LENGTH;
+use super::selector::{Selector, TimerToken};
+
+// same capacity as the client buffer
+const DELAY_QUEUE_CAPACITY: usize = 16 * MAX_PACKET_LENGTH;
+
+/// Network conditions to emulate, applied in each direction.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImpairmentProfile {
+    latency: Duration,
+    jitter: Duration,
+    // probabilities, in [0; 1]
+    loss: f64,
+    reorder: f64,
+}
+
+/// What to do with a packet crossing an impaired link.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Forward,
+    Delay(Duration),
+    Drop,
+}
+
+/// Packets waiting for their deadline, sorted by deadline.
+pub struct DelayQueue {
+    packets: BTreeMap<(Instant, u64), Vec<u8>>,
+    size: usize,
+    capacity: usize,
+    // disambiguate packets having the same deadline, keeping their order
+    next_id: u64,
+}
+
+/// Impairment state of a client, for both directions.
+pub struct Impairment {
+    client: Weak<RefCell<Client>>,
+    profile: Option<ImpairmentProfile>,
+    to_network: DelayQueue,
+    to_client: DelayQueue,
+    timer: Option<TimerToken>,
+}
+
+impl ImpairmentProfile {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not an impairment key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "latency" => self.latency = parse_duration(value)?,
+            "jitter" => self.jitter = parse_duration(value)?,
+            "loss" => self.loss = parse_probability(value)?,
+            "reorder" => self.reorder = parse_probability(value)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    pub fn loss(&self) -> f64 {
+        self.loss
+    }
+
+    fn verdict(&self) -> Verdict {
+        if self.loss > 0.0 && random::<f64>() < self.loss {
+            return Verdict::Drop;
+        }
+        if self.reorder > 0.0 && random::<f64>() < self.reorder {
+            // sent immediately, so it overtakes the delayed packets
+            return Verdict::Forward;
+        }
+        let delay = if self.jitter > Duration::from_secs(0) {
+            // uniformly distributed in [latency - jitter; latency + jitter]
+            let jitter = self.jitter.mul_f64(2.0 * random::<f64>());
+            (self.latency + jitter).saturating_sub(self.jitter)
+        } else {
+            self.latency
+        };
+        if delay > Duration::from_secs(0) {
+            Verdict::Delay(delay)
+        } else {
+            Verdict::Forward
+        }
+    }
+}
+
+/// Profiles which can be referenced without being defined in the configuration file.
+pub fn builtin_profiles() -> HashMap<String, ImpairmentProfile> {
+    let profile = |latency, jitter, loss, reorder| ImpairmentProfile {
+        latency: Duration::from_millis(latency),
+        jitter: Duration::from_millis(jitter),
+        loss,
+        reorder,
+    };
+    let mut profiles = HashMap::new();
+    profiles.insert(String::from("3g"), profile(100, 30, 0.01, 0.0));
+    profiles.insert(String::from("lossy-wifi"), profile(5, 10, 0.05, 0.01));
+    profiles.insert(String::from("satellite"), profile(300, 10, 0.005, 0.0));
+    profiles
+}
+
+impl DelayQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            packets: BTreeMap::new(),
+            size: 0,
+            capacity,
+            next_id: 0,
+        }
+    }
+
+    /// Return `false` if the queue is full.
+    pub fn push(&mut self, deadline: Instant, packet: &[u8]) -> bool {
+        if self.size + packet.len() > self.capacity {
+            return false;
+        }
+        self.packets
+            .insert((deadline, self.next_id), packet.to_vec());
+        self.next_id += 1;
+        self.size += packet.len();
+        true
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.packets.keys().next().map(|&(deadline, _)| deadline)
+    }
+
+    pub fn peek_expired(&self, now: Instant) -> Option<&[u8]> {
+        match self.packets.iter().next() {
+            Some((&(deadline, _), packet)) if deadline <= now => Some(packet),
+            _ => None,
+        }
+    }
+
+    pub fn pop_expired(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let key = match self.packets.keys().next() {
+            Some(&key) if key.0 <= now => key,
+            _ => return None,
+        };
+        let packet = self.packets.remove(&key).unwrap();
+        self.size -= packet.len();
+        Some(packet)
+    }
+}
+
+impl Impairment {
+    pub fn new() -> Self {
+        Self {
+            client: Weak::new(),
+            profile: None,
+            to_network: DelayQueue::new(DELAY_QUEUE_CAPACITY),
+            to_client: DelayQueue::new(DELAY_QUEUE_CAPACITY),
+            timer: None,
+        }
+    }
+
+    // expose client initialization after construction to break cyclic initialization dependencies
+    pub fn set_client(&mut self, client: Weak<RefCell<Client>>) {
+        self.client = client;
+    }
+
+    /// Switch the profile. The packets already delayed are still delivered on time.
+    pub fn set_profile(&mut self, profile: Option<ImpairmentProfile>) {
+        self.profile = profile;
+    }
+
+    pub fn profile(&self) -> Option<&ImpairmentProfile> {
+        self.profile.as_ref()
+    }
+
+    pub fn verdict_to_network(&self) -> Verdict {
+        self.profile
+            .as_ref()
+            .map_or(Verdict::Forward, ImpairmentProfile::verdict)
+    }
+
+    pub fn verdict_to_client(&self) -> Verdict {
+        self.profile
+            .as_ref()
+            .map_or(Verdict::Forward, ImpairmentProfile::verdict)
+    }
+
+    /// Return `false` if the queue is full.
+    pub fn delay_to_network(
+        &mut self,
+        selector: &mut Selector,
+        delay: Duration,
+        packet: &[u8],
+    ) -> bool {
+        let pushed = self.to_network.push(Instant::now() + delay, packet);
+        self.schedule(selector);
+        pushed
+    }
+
+    /// Return `false` if the queue is full.
+    pub fn delay_to_client(
+        &mut self,
+        selector: &mut Selector,
+        delay: Duration,
+        packet: &[u8],
+    ) -> bool {
+        let pushed = self.to_client.push(Instant::now() + delay, packet);
+        self.schedule(selector);
+        pushed
+    }
+
+    pub fn pop_to_network(&mut self, now: Instant) -> Option<Vec<u8>> {
+        self.to_network.pop_expired(now)
+    }
+
+    pub fn client_queue(&mut self) -> &mut DelayQueue {
+        &mut self.to_client
+    }
+
+    /// Make sure a timer is scheduled for the next deadline.
+    pub fn schedule(&mut self, selector: &mut Selector) {
+        let now = Instant::now();
+        // an expired packet for the client is waiting for space in the client buffer, not for
+        // a timer
+        let to_client_deadline = self.to_client.next_deadline().filter(|&d| d > now);
+        let next_deadline = match (self.to_network.next_deadline(), to_client_deadline) {
+            (Some(d1), Some(d2)) => d1.min(d2),
+            (d1, d2) => match d1.or(d2) {
+                Some(deadline) => deadline,
+                None => return,
+            },
+        };
+        if let Some(timer) = self.timer {
+            if timer.deadline() <= next_deadline {
+                // it will reschedule on trigger
+                return;
+            }
+            selector.cancel(timer);
+        }
+        let client = self.client.clone();
+        let handler = move |selector: &mut Selector| {
+            if let Some(client) = client.upgrade() {
+                client.borrow_mut().on_impairment_timer(selector);
+            }
+        };
+        let delay = next_deadline.saturating_duration_since(now);
+        self.timer = Some(selector.schedule(delay, handler));
+    }
+
+    pub fn on_timer_triggered(&mut self) {
+        self.timer = None;
+    }
+
+    pub fn cancel(&mut self, selector: &mut Selector) {
+        if let Some(timer) = self.timer.take() {
+            selector.cancel(timer);
+        }
+    }
+}
+
+// "1.5%" or "0.015"
+fn parse_probability(value: &str) -> Result<f64, String> {
+    let invalid = || format!("invalid probability \"{}\"", value);
+    let probability = match value.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map_err(|_| invalid())? / 100.0,
+        None => value.parse::<f64>().map_err(|_| invalid())?,
+    };
+    if !(0.0..=1.0).contains(&probability) {
+        return Err(invalid());
+    }
+    Ok(probability)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(Ok(0.015), parse_probability("1.5%"));
+        assert_eq!(Ok(0.2), parse_probability("0.2"));
+        assert!(parse_probability("120%").is_err());
+    }
+
+    #[test]
+    fn verdicts() {
+        let mut profile = ImpairmentProfile::default();
+        assert_eq!(Verdict::Forward, profile.verdict());
+        profile.set("latency", "50ms").unwrap();
+        assert_eq!(Verdict::Delay(Duration::from_millis(50)), profile.verdict());
+        profile.set("jitter", "10ms").unwrap();
+        for _ in 0..100 {
+            match profile.verdict() {
+                Verdict::Delay(delay) => {
+                    assert!(delay >= Duration::from_millis(40));
+                    assert!(delay <= Duration::from_millis(60));
+                }
+                verdict => panic!("Unexpected verdict: {:?}", verdict),
+            }
+        }
+        profile.set("loss", "100%").unwrap();
+        assert_eq!(Verdict::Drop, profile.verdict());
+    }
+
+    #[test]
+    fn delay_queue_order() {
+        let now = Instant::now();
+        let mut queue = DelayQueue::new(10);
+        assert!(queue.push(now + Duration::from_millis(20), &[1, 1]));
+        assert!(queue.push(now + Duration::from_millis(10), &[2, 2, 2]));
+        assert!(queue.push(now + Duration::from_millis(10), &[3]));
+        // full
+        assert!(!queue.push(now, &[4, 4, 4, 4, 4]));
+
+        assert_eq!(Some(now + Duration::from_millis(10)), queue.next_deadline());
+        assert_eq!(None, queue.pop_expired(now));
+        let later = now + Duration::from_millis(15);
+        assert_eq!(Some(&[2, 2, 2][..]), queue.peek_expired(later));
+        assert_eq!(Some(vec![2, 2, 2]), queue.pop_expired(later));
+        assert_eq!(Some(vec![3]), queue.pop_expired(later));
+        assert_eq!(None, queue.pop_expired(later));
+        assert!(queue.push(now, &[4, 4, 4, 4, 4]));
+    }
+}
@@ -0,0 +1,362 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::net::IpAddr;
+
+use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut};
+use super::ipv6_header::{self, Ipv6Header, Ipv6HeaderData, Ipv6HeaderMut};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+    Other,
+}
+
+impl Protocol {
+    pub fn from_number(number: u8) -> Self {
+        match number {
+            1 => Protocol::Icmp,
+            6 => Protocol::Tcp,
+            17 => Protocol::Udp,
+            58 => Protocol::Icmpv6,
+            _ => Protocol::Other,
+        }
+    }
+
+    pub fn number(self) -> Option<u8> {
+        match self {
+            Protocol::Icmp => Some(1),
+            Protocol::Tcp => Some(6),
+            Protocol::Udp => Some(17),
+            Protocol::Icmpv6 => Some(58),
+            Protocol::Other => None,
+        }
+    }
+}
+
+pub enum IpHeader<'a> {
+    V4(Ipv4Header<'a>),
+    V6(Ipv6Header<'a>),
+}
+
+pub enum IpHeaderMut<'a> {
+    V4(Ipv4HeaderMut<'a>),
+    V6(Ipv6HeaderMut<'a>),
+}
+
+#[derive(Clone)]
+pub enum IpHeaderData {
+    V4(Ipv4HeaderData),
+    V6(Ipv6HeaderData),
+}
+
+impl IpHeaderData {
+    /// Parse the header of an IPv4 or IPv6 packet (the version must have been checked).
+    pub fn parse(raw: &[u8]) -> Self {
+        match raw[0] >> 4 {
+            4 => IpHeaderData::V4(Ipv4HeaderData::parse(raw)),
+            6 => IpHeaderData::V6(Ipv6HeaderData::parse(raw)),
+            version => panic!("Unsupported IP version: {}", version),
+        }
+    }
+
+    #[inline]
+    pub fn bind<'c, 'a: 'c, 'b: 'c>(&'a self, raw: &'b [u8]) -> IpHeader<'c> {
+        match *self {
+            IpHeaderData::V4(ref data) => IpHeader::V4(data.bind(raw)),
+            IpHeaderData::V6(ref data) => IpHeader::V6(data.bind(raw)),
+        }
+    }
+
+    #[inline]
+    pub fn bind_mut<'c, 'a: 'c, 'b: 'c>(&'a mut self, raw: &'b mut [u8]) -> IpHeaderMut<'c> {
+        match *self {
+            IpHeaderData::V4(ref mut data) => IpHeaderMut::V4(data.bind_mut(raw)),
+            IpHeaderData::V6(ref mut data) => IpHeaderMut::V6(data.bind_mut(raw)),
+        }
+    }
+
+    /// Length of the headers preceding the transport header (including IPv6 extension headers)
+    #[inline]
+    pub fn header_length(&self) -> u16 {
+        match *self {
+            IpHeaderData::V4(ref data) => u16::from(data.header_length()),
+            IpHeaderData::V6(ref data) => data.header_length(),
+        }
+    }
+
+    #[inline]
+    pub fn total_length(&self) -> u16 {
+        match *self {
+            IpHeaderData::V4(ref data) => data.total_length(),
+            IpHeaderData::V6(ref data) => data.total_length(),
+        }
+    }
+
+    /// The TTL of an IPv4 packet, or the hop limit of an IPv6 packet
+    #[inline]
+    pub fn ttl(&self) -> u8 {
+        match *self {
+            IpHeaderData::V4(ref data) => data.ttl(),
+            IpHeaderData::V6(ref data) => data.hop_limit(),
+        }
+    }
+
+    #[inline]
+    pub fn protocol(&self) -> Protocol {
+        match *self {
+            IpHeaderData::V4(ref data) => data.protocol(),
+            IpHeaderData::V6(ref data) => data.protocol(),
+        }
+    }
+
+    #[inline]
+    pub fn source(&self) -> IpAddr {
+        match *self {
+            IpHeaderData::V4(ref data) => IpAddr::V4(data.source().into()),
+            IpHeaderData::V6(ref data) => IpAddr::V6(data.source()),
+        }
+    }
+
+    #[inline]
+    pub fn destination(&self) -> IpAddr {
+        match *self {
+            IpHeaderData::V4(ref data) => IpAddr::V4(data.destination().into()),
+            IpHeaderData::V6(ref data) => IpAddr::V6(data.destination()),
+        }
+    }
+
+    /// Indicate whether the packet is an IPv4 fragment (fragmented IPv6 packets are not parsed)
+    #[inline]
+    pub fn is_fragment(&self) -> bool {
+        match *self {
+            IpHeaderData::V4(ref data) => data.is_fragment(),
+            IpHeaderData::V6(_) => false,
+        }
+    }
+
+    #[inline]
+    pub fn is_ipv6(&self) -> bool {
+        matches!(*self, IpHeaderData::V6(_))
+    }
+
+    /// Sum of the 16-bit words of the pseudo-header, for the transport checksums (RFC 793
+    /// section 3.1 and RFC 8200 section 8.1).
+    pub fn pseudo_header_sum(&self, protocol_number: u8) -> u32 {
+        let transport_length = self.total_length() - self.header_length();
+        let mut sum = u32::from(protocol_number) + u32::from(transport_length);
+        let mut add_address = |octets: &[u8]| {
+            for word in octets.chunks(2) {
+                sum += u32::from(BigEndian::read_u16(word));
+            }
+        };
+        match (self.source(), self.destination()) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                add_address(&source.octets());
+                add_address(&destination.octets());
+            }
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                add_address(&source.octets());
+                add_address(&destination.octets());
+            }
+            _ => unreachable!("Source and destination of different families"),
+        }
+        sum
+    }
+}
+
+/// Return the IP version and the total length of the packet at the start of `raw`
+///
+/// The length of an IPv6 packet may not fit in 16 bits.
+pub fn peek_version_length(raw: &[u8]) -> Option<(u8, usize)> {
+    // version is stored in the 4 first bits
+    let version = raw.first()? >> 4;
+    if version == 6 {
+        // IPv6 stores the payload length (without the fixed header) at offset 4
+        raw.get(4..6).map(|length| {
+            let length = usize::from(ipv6_header::FIXED_HEADER_LENGTH)
+                + usize::from(BigEndian::read_u16(length));
+            (version, length)
+        })
+    } else {
+        // packet length is 16 bits starting at offset 2
+        raw.get(2..4)
+            .map(|length| (version, usize::from(BigEndian::read_u16(length))))
+    }
+}
+
+// shared definition for IpHeader and IpHeaderMut
+macro_rules! ip_header_common {
+    ($name:ident) => {
+        #[allow(dead_code)]
+        impl<'a> $name<'a> {
+            #[inline]
+            pub fn raw(&self) -> &[u8] {
+                match *self {
+                    $name::V4(ref header) => header.raw(),
+                    $name::V6(ref header) => header.raw(),
+                }
+            }
+
+            #[inline]
+            pub fn data_clone(&self) -> IpHeaderData {
+                match *self {
+                    $name::V4(ref header) => IpHeaderData::V4(header.data().clone()),
+                    $name::V6(ref header) => IpHeaderData::V6(header.data().clone()),
+                }
+            }
+
+            #[inline]
+            pub fn header_length(&self) -> u16 {
+                match *self {
+                    $name::V4(ref header) => u16::from(header.header_length()),
+                    $name::V6(ref header) => header.header_length(),
+                }
+            }
+
+            /// The IPv4 options (IPv6 has extension headers instead)
+            #[inline]
+            pub fn ipv4_options(&self) -> &[u8] {
+                match *self {
+                    $name::V4(ref header) => header.options(),
+                    $name::V6(_) => &[],
+                }
+            }
+
+            #[inline]
+            pub fn total_length(&self) -> u16 {
+                match *self {
+                    $name::V4(ref header) => header.total_length(),
+                    $name::V6(ref header) => header.total_length(),
+                }
+            }
+
+            #[inline]
+            pub fn protocol(&self) -> Protocol {
+                match *self {
+                    $name::V4(ref header) => header.protocol(),
+                    $name::V6(ref header) => header.protocol(),
+                }
+            }
+
+            #[inline]
+            pub fn source(&self) -> IpAddr {
+                match *self {
+                    $name::V4(ref header) => IpAddr::V4(header.source().into()),
+                    $name::V6(ref header) => IpAddr::V6(header.source()),
+                }
+            }
+
+            #[inline]
+            pub fn destination(&self) -> IpAddr {
+                match *self {
+                    $name::V4(ref header) => IpAddr::V4(header.destination().into()),
+                    $name::V6(ref header) => IpAddr::V6(header.destination()),
+                }
+            }
+        }
+    };
+}
+
+ip_header_common!(IpHeader);
+ip_header_common!(IpHeaderMut);
+
+// additional methods for the mutable version
+impl<'a> IpHeaderMut<'a> {
+    #[inline]
+    pub fn set_total_length(&mut self, total_length: u16) {
+        match *self {
+            IpHeaderMut::V4(ref mut header) => header.set_total_length(total_length),
+            IpHeaderMut::V6(ref mut header) => header.set_total_length(total_length),
+        }
+    }
+
+    /// Set the source address (of the same family as the header)
+    pub fn set_source(&mut self, source: IpAddr) {
+        match (self, source) {
+            (IpHeaderMut::V4(header), IpAddr::V4(source)) => header.set_source(source.into()),
+            (IpHeaderMut::V6(header), IpAddr::V6(source)) => header.set_source(source),
+            _ => panic!("Source address of another family: {}", source),
+        }
+    }
+
+    #[inline]
+    pub fn swap_source_and_destination(&mut self) {
+        match *self {
+            IpHeaderMut::V4(ref mut header) => header.swap_source_and_destination(),
+            IpHeaderMut::V6(ref mut header) => header.swap_source_and_destination(),
+        }
+    }
+
+    /// Remove the IPv4 options or the IPv6 extension headers
+    #[inline]
+    pub fn shrink_extension_headers(&mut self) {
+        match *self {
+            IpHeaderMut::V4(ref mut header) => header.shrink_options(),
+            IpHeaderMut::V6(ref mut header) => header.shrink_extension_headers(),
+        }
+    }
+
+    #[inline]
+    pub fn update_checksum(&mut self) {
+        // IPv6 has no header checksum
+        if let IpHeaderMut::V4(ref mut header) = *self {
+            header.update_checksum();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peek_version_length_unavailable() {
+        let raw: [u8; 0] = [];
+        assert!(peek_version_length(&raw).is_none());
+        let raw = [0x40, 2];
+        assert!(peek_version_length(&raw).is_none());
+        let raw = [0x60, 0, 0, 0, 0];
+        assert!(peek_version_length(&raw).is_none());
+    }
+
+    #[test]
+    fn peek_version_length_available() {
+        let raw = [4u8 << 4 | 5, 0, 0x01, 0x23];
+        let (version, length) = peek_version_length(&raw).unwrap();
+        assert_eq!(4, version);
+        assert_eq!(0x123, length);
+    }
+
+    #[test]
+    fn peek_ipv6_version_length_available() {
+        let raw = [6u8 << 4, 0, 0, 0, 0x01, 0x23];
+        let (version, length) = peek_version_length(&raw).unwrap();
+        assert_eq!(6, version);
+        assert_eq!(40 + 0x123, length);
+    }
+
+    #[test]
+    fn peek_ipv6_length_over_16_bits() {
+        let raw = [6u8 << 4, 0, 0, 0, 0xff, 0xff];
+        let (_, length) = peek_version_length(&raw).unwrap();
+        assert_eq!(40 + 0xffff, length);
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use socket2::SockRef;
+use std::io;
+
+/// The maximal length of the IPv4 options (the header length is at most 60 bytes).
+pub const MAX_OPTIONS_LENGTH: usize = 40;
+
+/// Indicate whether `options` may be used in an IPv4 header (padded to 32-bit words).
+pub fn is_valid(options: &[u8]) -> bool {
+    options.len() <= MAX_OPTIONS_LENGTH && options.len().is_multiple_of(4)
+}
+
+/// Set the options of the IPv4 packets sent through the socket (e.g. record route).
+///
+/// The system fills in the options on the way, like for the packets it generates itself.
+#[cfg(target_os = "linux")]
+pub fn set(socket: SockRef, options: &[u8]) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // safe: the option value is a byte array, of the given length
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_OPTIONS,
+            options.as_ptr() as *const libc::c_void,
+            options.len() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive the options of the IPv4 packets along with their payload, through `recv()`.
+///
+/// Only needed for the sockets which do not receive the IP header (e.g. ping sockets).
+#[cfg(target_os = "linux")]
+pub fn enable_receive(socket: SockRef) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    // safe: the option value is a valid int, of the given length
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_RECVOPTS,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive a packet payload into `buf`, and its IPv4 options into `options` (cleared if there
+/// are none).
+#[cfg(target_os = "linux")]
+pub fn recv(socket: SockRef, buf: &mut [u8], options: &mut Vec<u8>) -> io::Result<usize> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 for the alignment of the control messages
+    let mut control = [0u64; 16];
+    // safe: a zeroed msghdr is valid
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    // safe: the buffers outlive the call
+    let size = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+    if size == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    options.clear();
+    // safe: the control messages were written by the kernel, and are bounded by msg_controllen
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            if header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_RECVOPTS {
+                let length = header.cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = std::slice::from_raw_parts(libc::CMSG_DATA(cmsg), length);
+                options.extend_from_slice(data);
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok(size as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set(_socket: SockRef, _options: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IP options are only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_receive(_socket: SockRef) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "IP options are only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv(socket: SockRef, buf: &mut [u8], options: &mut Vec<u8>) -> io::Result<usize> {
+    use std::mem::MaybeUninit;
+
+    options.clear();
+    // safe: recv() never writes uninitialized bytes into the buffer
+    let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+    socket.recv(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_options() {
+        assert!(is_valid(&[]));
+        // record route with 9 slots, padded
+        assert!(is_valid(&[7; 40]));
+        assert!(!is_valid(&[7, 3, 4]));
+        assert!(!is_valid(&[1; 44]));
+    }
+}
@@ -14,39 +14,45 @@
  * limitations under the License.
  */
 
-use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut};
+use super::ip_header::{IpHeader, IpHeaderData, IpHeaderMut};
 use super::transport_header::{TransportHeader, TransportHeaderData, TransportHeaderMut};
 
 pub const MAX_PACKET_LENGTH: usize = 1 << 16;
 
-pub struct Ipv4Packet<'a> {
+pub struct IpPacket<'a> {
     raw: &'a mut [u8],
-    ipv4_header_data: Ipv4HeaderData,
+    ip_header_data: IpHeaderData,
     transport_header_data: Option<TransportHeaderData>,
 }
 
-impl<'a> Ipv4Packet<'a> {
+impl<'a> IpPacket<'a> {
     pub fn parse(raw: &'a mut [u8]) -> Self {
-        let ipv4_header_data = Ipv4HeaderData::parse(raw);
-        let transport_header_data = {
-            let payload = &raw[ipv4_header_data.header_length() as usize..];
-            TransportHeaderData::parse(ipv4_header_data.protocol(), payload)
+        let ip_header_data = IpHeaderData::parse(raw);
+        let transport_header_data = if ip_header_data.is_fragment() {
+            // the transport header is in the first fragment only, reassemble the packet first
+            None
+        } else {
+            // a transport header overrunning the packet is invalid
+            let start = ip_header_data.header_length() as usize;
+            let end = ip_header_data.total_length() as usize;
+            raw.get(start..end)
+                .and_then(|payload| TransportHeaderData::parse(ip_header_data.protocol(), payload))
         };
         Self {
-            raw: &mut raw[..ipv4_header_data.total_length() as usize],
-            ipv4_header_data,
+            raw: &mut raw[..ip_header_data.total_length() as usize],
+            ip_header_data,
             transport_header_data,
         }
     }
 
     pub fn new(
         raw: &'a mut [u8],
-        ipv4_header_data: Ipv4HeaderData,
+        ip_header_data: IpHeaderData,
         transport_header_data: TransportHeaderData,
     ) -> Self {
         Self {
             raw,
-            ipv4_header_data,
+            ip_header_data,
             transport_header_data: Some(transport_header_data),
         }
     }
@@ -57,45 +63,45 @@ impl<'a> Ipv4Packet<'a> {
     }
 
     #[inline]
-    pub fn headers_data(&self) -> (&Ipv4HeaderData, Option<&TransportHeaderData>) {
-        (&self.ipv4_header_data, self.transport_header_data.as_ref())
+    pub fn headers_data(&self) -> (&IpHeaderData, Option<&TransportHeaderData>) {
+        (&self.ip_header_data, self.transport_header_data.as_ref())
     }
 
-    pub fn headers(&self) -> (Ipv4Header, Option<TransportHeader>) {
-        let transport_index = self.ipv4_header_data.header_length() as usize;
+    pub fn headers(&self) -> (IpHeader<'_>, Option<TransportHeader<'_>>) {
+        let transport_index = self.ip_header_data.header_length() as usize;
         if let Some(ref transport_header_data) = self.transport_header_data {
-            let (ipv4_header_slice, transport_slice) = self.raw.split_at(transport_index);
+            let (ip_header_slice, transport_slice) = self.raw.split_at(transport_index);
             // payload_index is relative to transport
             let payload_index = transport_header_data.header_length() as usize;
             let transport_header_slice = &transport_slice[..payload_index];
-            let ipv4_header = self.ipv4_header_data.bind(ipv4_header_slice);
+            let ip_header = self.ip_header_data.bind(ip_header_slice);
             let transport_header = transport_header_data.bind(transport_header_slice);
-            (ipv4_header, Some(transport_header))
+            (ip_header, Some(transport_header))
         } else {
-            let ipv4_header_slice = &self.raw[..transport_index];
-            let ipv4_header = self.ipv4_header_data.bind(ipv4_header_slice);
-            (ipv4_header, None)
+            let ip_header_slice = &self.raw[..transport_index];
+            let ip_header = self.ip_header_data.bind(ip_header_slice);
+            (ip_header, None)
         }
     }
 
     #[inline]
     #[allow(dead_code)]
-    pub fn ipv4_header_data(&self) -> &Ipv4HeaderData {
-        &self.ipv4_header_data
+    pub fn ip_header_data(&self) -> &IpHeaderData {
+        &self.ip_header_data
     }
 
     #[inline]
     #[allow(dead_code)]
-    pub fn ipv4_header(&self) -> Ipv4Header {
-        let slice = &self.raw[..self.ipv4_header_data.header_length() as usize];
-        self.ipv4_header_data.bind(slice)
+    pub fn ip_header(&self) -> IpHeader<'_> {
+        let slice = &self.raw[..self.ip_header_data.header_length() as usize];
+        self.ip_header_data.bind(slice)
     }
 
     #[inline]
     #[allow(dead_code)]
-    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut {
-        let slice = &mut self.raw[..self.ipv4_header_data.header_length() as usize];
-        self.ipv4_header_data.bind_mut(slice)
+    pub fn ip_header_mut(&mut self) -> IpHeaderMut<'_> {
+        let slice = &mut self.raw[..self.ip_header_data.header_length() as usize];
+        self.ip_header_data.bind_mut(slice)
     }
 
     #[inline]
@@ -105,9 +111,9 @@ impl<'a> Ipv4Packet<'a> {
     }
 
     #[inline]
-    pub fn transport_header(&self) -> Option<TransportHeader> {
+    pub fn transport_header(&self) -> Option<TransportHeader<'_>> {
         if let Some(ref transport_header_data) = self.transport_header_data {
-            let start = self.ipv4_header_data.header_length() as usize;
+            let start = self.ip_header_data.header_length() as usize;
             let end = start + transport_header_data.header_length() as usize;
             let slice = &self.raw[start..end];
             Some(transport_header_data.bind(slice))
@@ -115,7 +121,7 @@ impl<'a> Ipv4Packet<'a> {
             None
         }
         /*        self.transport_header_data.as_ref().map(|transport_header_data| {
-            let start = self.ipv4_header_data.header_length() as usize;
+            let start = self.ip_header_data.header_length() as usize;
             let end = start + transport_header_data.header_length() as usize;
             let slice = &self.raw[start..end];
             transport_header_data.bind(slice)
@@ -124,9 +130,9 @@ impl<'a> Ipv4Packet<'a> {
 
     #[inline]
     #[allow(dead_code)]
-    fn transport_header_mut(&mut self) -> Option<TransportHeaderMut> {
+    fn transport_header_mut(&mut self) -> Option<TransportHeaderMut<'_>> {
         if let Some(ref mut transport_header_data) = self.transport_header_data {
-            let start = self.ipv4_header_data.header_length() as usize;
+            let start = self.ip_header_data.header_length() as usize;
             let end = start + transport_header_data.header_length() as usize;
             let slice = &mut self.raw[start..end];
             Some(transport_header_data.bind_mut(slice))
@@ -134,7 +140,7 @@ impl<'a> Ipv4Packet<'a> {
             None
         }
         /*        self.transport_header_data.as_mut().map(|transport_header_data| {
-            let start = self.ipv4_header_data.header_length() as usize;
+            let start = self.ip_header_data.header_length() as usize;
             let end = start + transport_header_data.header_length() as usize;
             let slice = &mut self.raw[start..end];
             transport_header_data.bind_mut(slice)
@@ -142,46 +148,47 @@ impl<'a> Ipv4Packet<'a> {
     }
 
     /// Devide the packet into parts:
-    ///  - the IP v4 header
+    ///  - the IP header
     ///  - the transport header (if any)
     ///  - the payload (if there is a transport at all)
     #[allow(dead_code)]
-    pub fn split(&self) -> (Ipv4Header, Option<(TransportHeader, &[u8])>) {
-        let transport_index = self.ipv4_header_data.header_length() as usize;
+    pub fn split(&self) -> (IpHeader<'_>, Option<(TransportHeader<'_>, &[u8])>) {
+        let transport_index = self.ip_header_data.header_length() as usize;
         if let Some(ref transport_header_data) = self.transport_header_data {
             // payload_index is relative to transport
             let payload_index = transport_header_data.header_length() as usize;
-            let (ipv4_header_slice, transport_slice) = self.raw.split_at(transport_index);
+            let (ip_header_slice, transport_slice) = self.raw.split_at(transport_index);
             let (transport_header_slice, payload_slice) = transport_slice.split_at(payload_index);
-            let ipv4_header = self.ipv4_header_data.bind(ipv4_header_slice);
+            let ip_header = self.ip_header_data.bind(ip_header_slice);
             let transport_header = transport_header_data.bind(transport_header_slice);
-            (ipv4_header, Some((transport_header, payload_slice)))
+            (ip_header, Some((transport_header, payload_slice)))
         } else {
-            let ipv4_header_slice = &self.raw[..transport_index];
-            let ipv4_header = self.ipv4_header_data.bind(ipv4_header_slice);
-            (ipv4_header, None)
+            let ip_header_slice = &self.raw[..transport_index];
+            let ip_header = self.ip_header_data.bind(ip_header_slice);
+            (ip_header, None)
         }
     }
 
     /// Devide the packet into mutable parts:
-    ///  - the IP v4 header
+    ///  - the IP header
     ///  - the transport header (if any)
     ///  - the payload (if there is a transport at all)
-    pub fn split_mut(&mut self) -> (Ipv4HeaderMut, Option<(TransportHeaderMut, &mut [u8])>) {
-        let transport_index = self.ipv4_header_data.header_length() as usize;
+    #[allow(dead_code)]
+    pub fn split_mut(&mut self) -> (IpHeaderMut<'_>, Option<(TransportHeaderMut<'_>, &mut [u8])>) {
+        let transport_index = self.ip_header_data.header_length() as usize;
         if let Some(ref mut transport_header_data) = self.transport_header_data {
             // payload_index is relative to transport
             let payload_index = transport_header_data.header_length() as usize;
-            let (ipv4_header_slice, transport_slice) = self.raw.split_at_mut(transport_index);
+            let (ip_header_slice, transport_slice) = self.raw.split_at_mut(transport_index);
             let (transport_header_slice, payload_slice) =
                 transport_slice.split_at_mut(payload_index);
-            let ipv4_header = self.ipv4_header_data.bind_mut(ipv4_header_slice);
+            let ip_header = self.ip_header_data.bind_mut(ip_header_slice);
             let transport_header = transport_header_data.bind_mut(transport_header_slice);
-            (ipv4_header, Some((transport_header, payload_slice)))
+            (ip_header, Some((transport_header, payload_slice)))
         } else {
-            let ipv4_header_slice = &mut self.raw[..transport_index];
-            let ipv4_header = self.ipv4_header_data.bind_mut(ipv4_header_slice);
-            (ipv4_header, None)
+            let ip_header_slice = &mut self.raw[..transport_index];
+            let ip_header = self.ip_header_data.bind_mut(ip_header_slice);
+            (ip_header, None)
         }
     }
 
@@ -192,30 +199,44 @@ impl<'a> Ipv4Packet<'a> {
 
     #[inline]
     pub fn length(&self) -> u16 {
-        self.ipv4_header_data.total_length()
+        self.ip_header_data.total_length()
     }
 
     pub fn payload(&self) -> Option<&[u8]> {
         self.transport_header_data
             .as_ref()
             .map(|transport_header_data| {
-                let range = self.ipv4_header_data.header_length() as usize
+                let range = self.ip_header_data.header_length() as usize
                     + transport_header_data.header_length() as usize..;
                 &self.raw[range]
             })
     }
 
     pub fn compute_checksums(&mut self) {
-        let (mut ipv4_header, transport) = self.split_mut();
-        ipv4_header.update_checksum();
-        if let Some((mut transport_header, payload)) = transport {
-            transport_header.update_checksum(ipv4_header.data(), payload);
+        self.compute_ip_checksum();
+        let transport_index = self.ip_header_data.header_length() as usize;
+        let transport_slice = &mut self.raw[transport_index..];
+        if let Some(ref mut transport_header_data) = self.transport_header_data {
+            // payload_index is relative to transport
+            let payload_index = transport_header_data.header_length() as usize;
+            let (transport_header_slice, payload_slice) =
+                transport_slice.split_at_mut(payload_index);
+            let mut transport_header = transport_header_data.bind_mut(transport_header_slice);
+            transport_header.update_checksum(&self.ip_header_data, payload_slice);
         }
     }
 
+    /// Compute the checksum of the IP header only (the transport checksum is left unchanged).
+    pub fn compute_ip_checksum(&mut self) {
+        let transport_index = self.ip_header_data.header_length() as usize;
+        self.ip_header_data
+            .bind_mut(&mut self.raw[..transport_index])
+            .update_checksum();
+    }
+
     /*#[inline]
     pub fn swap_source_and_destination(&mut self) {
-        self.ipv4_header_mut().swap_source_and_destination();
+        self.ip_header_mut().swap_source_and_destination();
         if let Some(mut transport_header) = self.transport_header_mut() {
             transport_header.swap_source_and_destination();
         }
@@ -225,12 +246,12 @@ impl<'a> Ipv4Packet<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::relay::ipv4_header::Protocol;
+    use crate::relay::ip_header::Protocol;
     use byteorder::{BigEndian, WriteBytesExt};
+    use std::net::Ipv4Addr;
 
     fn create_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(32);
+        let mut raw = Vec::with_capacity(32);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -255,19 +276,17 @@ mod tests {
     #[test]
     fn parse_headers() {
         let raw = &mut create_packet()[..];
-        let ipv4_packet = Ipv4Packet::parse(raw);
+        let ip_packet = IpPacket::parse(raw);
 
         {
-            let ipv4_header = ipv4_packet.ipv4_header();
-            assert_eq!(20, ipv4_header.header_length());
-            assert_eq!(32, ipv4_header.total_length());
-            assert_eq!(Protocol::Udp, ipv4_header.protocol());
-            assert_eq!(0x12345678, ipv4_header.source());
-            assert_eq!(0x42424242, ipv4_header.destination());
-
-            if let Some(&TransportHeaderData::Udp(ref udp_header)) =
-                ipv4_packet.transport_header_data()
-            {
+            let ip_header = ip_packet.ip_header();
+            assert_eq!(20, ip_header.header_length());
+            assert_eq!(32, ip_header.total_length());
+            assert_eq!(Protocol::Udp, ip_header.protocol());
+            assert_eq!(Ipv4Addr::from(0x12345678), ip_header.source());
+            assert_eq!(Ipv4Addr::from(0x42424242), ip_header.destination());
+
+            if let Some(TransportHeaderData::Udp(udp_header)) = ip_packet.transport_header_data() {
                 assert_eq!(1234, udp_header.source_port());
                 assert_eq!(5678, udp_header.destination_port());
             } else {
@@ -279,7 +298,7 @@ mod tests {
     #[test]
     fn payload() {
         let raw = &mut create_packet()[..];
-        let ipv4_packet = Ipv4Packet::parse(raw);
-        assert_eq!([0x11, 0x22, 0x33, 0x44], ipv4_packet.payload().unwrap());
+        let ip_packet = IpPacket::parse(raw);
+        assert_eq!([0x11, 0x22, 0x33, 0x44], ip_packet.payload().unwrap());
     }
 }
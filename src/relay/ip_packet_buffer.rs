@@ -16,35 +16,68 @@
 
 use super::binary;
 use super::byte_buffer::ByteBuffer;
-use super::ipv4_header;
-use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::ip_header;
+use super::ip_packet::{IpPacket, MAX_PACKET_LENGTH};
 
 use log::*;
 use std::io;
 
-pub struct Ipv4PacketBuffer {
+const TAG: &str = "IpPacketBuffer";
+
+pub struct IpPacketBuffer {
     buf: ByteBuffer,
+    // remaining bytes of a packet too long to be relayed, dropped as they are received
+    discarding: usize,
 }
 
-impl Ipv4PacketBuffer {
+impl IpPacketBuffer {
     pub fn new() -> Self {
         Self {
             buf: ByteBuffer::new(MAX_PACKET_LENGTH),
+            discarding: 0,
         }
     }
 
     pub fn read_from<R: io::Read>(&mut self, source: &mut R) -> io::Result<bool> {
-        self.buf.read_from(source)
+        let read = self.buf.read_from(source)?;
+        self.discard_too_long_packets();
+        Ok(read)
+    }
+
+    // an IPv6 payload length may exceed the 16-bit length of the packets handled by the relay
+    fn discard_too_long_packets(&mut self) {
+        loop {
+            if self.discarding > 0 {
+                let length = self.discarding.min(self.buf.peek().len());
+                self.buf.consume(length);
+                self.discarding -= length;
+                if self.discarding > 0 {
+                    // wait for the rest of the packet
+                    return;
+                }
+            }
+            match ip_header::peek_version_length(self.buf.peek()) {
+                Some((_, length)) if length > usize::from(u16::MAX) => {
+                    warn!(target: TAG, "Dropping packet too long ({} bytes)", length);
+                    self.discarding = length;
+                }
+                _ => return,
+            }
+        }
     }
 
     fn available_packet_length(&self) -> Option<u16> {
         let data = self.buf.peek();
         trace!("Parse packet: {}", binary::build_packet_string(data));
-        if let Some((version, length)) = ipv4_header::peek_version_length(data) {
-            assert!(version == 4, "Not an Ipv4 packet, version={}", version);
-            if length as usize <= data.len() {
-                // full packet available
-                Some(length)
+        if let Some((version, length)) = ip_header::peek_version_length(data) {
+            assert!(
+                version == 4 || version == 6,
+                "Not an IP packet, version={}",
+                version
+            );
+            if length <= data.len() {
+                // full packet available (too long packets are discarded on reception)
+                Some(length as u16)
             } else {
                 // no full packet available
                 None
@@ -55,10 +88,10 @@ impl Ipv4PacketBuffer {
         }
     }
 
-    pub fn as_ipv4_packet(&mut self) -> Option<Ipv4Packet> {
+    pub fn as_ip_packet(&mut self) -> Option<IpPacket<'_>> {
         if self.available_packet_length().is_some() {
             let data = self.buf.peek_mut();
-            Some(Ipv4Packet::parse(data))
+            Some(IpPacket::parse(data))
         } else {
             None
         }
@@ -70,16 +103,18 @@ impl Ipv4PacketBuffer {
             .available_packet_length()
             .expect("next() called while there was no packet") as usize;
         self.buf.consume(length);
+        self.discard_too_long_packets();
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::relay::ipv4_header::Protocol;
+    use crate::relay::ip_header::Protocol;
     use crate::relay::transport_header::TransportHeaderData;
     use byteorder::{BigEndian, WriteBytesExt};
     use std::io;
+    use std::net::Ipv4Addr;
 
     fn create_packet() -> Vec<u8> {
         let mut raw = Vec::new();
@@ -125,16 +160,15 @@ mod tests {
         raw.write_u8(0x99).unwrap(); // payload
     }
 
-    fn check_packet_headers(ipv4_packet: &Ipv4Packet) {
-        let ipv4_header = ipv4_packet.ipv4_header();
-        assert_eq!(20, ipv4_header.header_length());
-        assert_eq!(32, ipv4_header.total_length());
-        assert_eq!(Protocol::Udp, ipv4_header.protocol());
-        assert_eq!(0x12345678, ipv4_header.source());
-        assert_eq!(0x42424242, ipv4_header.destination());
+    fn check_packet_headers(ip_packet: &IpPacket) {
+        let ip_header = ip_packet.ip_header();
+        assert_eq!(20, ip_header.header_length());
+        assert_eq!(32, ip_header.total_length());
+        assert_eq!(Protocol::Udp, ip_header.protocol());
+        assert_eq!(Ipv4Addr::from(0x12345678), ip_header.source());
+        assert_eq!(Ipv4Addr::from(0x42424242), ip_header.destination());
 
-        if let Some(&TransportHeaderData::Udp(ref udp_header)) = ipv4_packet.transport_header_data()
-        {
+        if let Some(TransportHeaderData::Udp(udp_header)) = ip_packet.transport_header_data() {
             assert_eq!(1234, udp_header.source_port());
             assert_eq!(5678, udp_header.destination_port());
         } else {
@@ -142,16 +176,15 @@ mod tests {
         }
     }
 
-    fn check_another_packet_headers(ipv4_packet: &Ipv4Packet) {
-        let ipv4_header = ipv4_packet.ipv4_header();
-        assert_eq!(20, ipv4_header.header_length());
-        assert_eq!(29, ipv4_header.total_length());
-        assert_eq!(Protocol::Udp, ipv4_header.protocol());
-        assert_eq!(0x11111111, ipv4_header.source());
-        assert_eq!(0x22222222, ipv4_header.destination());
+    fn check_another_packet_headers(ip_packet: &IpPacket) {
+        let ip_header = ip_packet.ip_header();
+        assert_eq!(20, ip_header.header_length());
+        assert_eq!(29, ip_header.total_length());
+        assert_eq!(Protocol::Udp, ip_header.protocol());
+        assert_eq!(Ipv4Addr::from(0x11111111), ip_header.source());
+        assert_eq!(Ipv4Addr::from(0x22222222), ip_header.destination());
 
-        if let Some(&TransportHeaderData::Udp(ref udp_header)) = ipv4_packet.transport_header_data()
-        {
+        if let Some(TransportHeaderData::Udp(udp_header)) = ip_packet.transport_header_data() {
             assert_eq!(1111, udp_header.source_port());
             assert_eq!(2222, udp_header.destination_port());
         } else {
@@ -160,31 +193,31 @@ mod tests {
     }
 
     #[test]
-    fn parse_ipv4_packet_buffer() {
+    fn parse_ip_packet_buffer() {
         let raw = create_packet();
-        let mut packet_buffer = Ipv4PacketBuffer::new();
+        let mut packet_buffer = IpPacketBuffer::new();
 
         let mut cursor = io::Cursor::new(raw);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        let packet = packet_buffer.as_ipv4_packet().unwrap();
+        let packet = packet_buffer.as_ip_packet().unwrap();
         check_packet_headers(&packet);
     }
 
     #[test]
-    fn parse_fragmented_ipv4_packet_buffer() {
+    fn parse_fragmented_ip_packet_buffer() {
         let raw = create_packet();
-        let mut packet_buffer = Ipv4PacketBuffer::new();
+        let mut packet_buffer = IpPacketBuffer::new();
 
         let mut cursor = io::Cursor::new(&raw[..14]);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        assert!(packet_buffer.as_ipv4_packet().is_none());
+        assert!(packet_buffer.as_ip_packet().is_none());
 
         let mut cursor = io::Cursor::new(&raw[14..]);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        let packet = packet_buffer.as_ipv4_packet().unwrap();
+        let packet = packet_buffer.as_ip_packet().unwrap();
         check_packet_headers(&packet);
     }
 
@@ -199,18 +232,34 @@ mod tests {
     #[test]
     fn parse_multi_packets() {
         let raw = create_multi_packets();
-        let mut packet_buffer = Ipv4PacketBuffer::new();
+        let mut packet_buffer = IpPacketBuffer::new();
 
         let mut cursor = io::Cursor::new(raw);
         packet_buffer.read_from(&mut cursor).unwrap();
 
-        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        check_packet_headers(&packet_buffer.as_ip_packet().unwrap());
         packet_buffer.next();
-        check_another_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        check_another_packet_headers(&packet_buffer.as_ip_packet().unwrap());
         packet_buffer.next();
-        check_packet_headers(&packet_buffer.as_ipv4_packet().unwrap());
+        check_packet_headers(&packet_buffer.as_ip_packet().unwrap());
         packet_buffer.next();
 
-        assert!(packet_buffer.as_ipv4_packet().is_none());
+        assert!(packet_buffer.as_ip_packet().is_none());
+    }
+
+    #[test]
+    fn discard_too_long_ipv6_packet() {
+        // the payload length (65535) does not fit in a 16-bit total length
+        let mut raw = vec![6 << 4, 0, 0, 0, 0xff, 0xff, 17, 64];
+        raw.resize(40 + 0xffff, 0);
+        write_packet_to(&mut raw);
+
+        let mut packet_buffer = IpPacketBuffer::new();
+        let mut cursor = io::Cursor::new(raw);
+        // the buffer cannot hold the whole packet at once
+        while packet_buffer.as_ip_packet().is_none() {
+            assert!(packet_buffer.read_from(&mut cursor).unwrap());
+        }
+        check_packet_headers(&packet_buffer.as_ip_packet().unwrap());
     }
 }
@@ -17,6 +17,8 @@
 use byteorder::{BigEndian, ByteOrder};
 use std::mem;
 
+use super::ip_header::Protocol;
+
 pub struct Ipv4Header<'a> {
     raw: &'a [u8],
     data: &'a Ipv4HeaderData,
@@ -33,18 +35,19 @@ pub struct Ipv4HeaderData {
     version: u8,
     header_length: u8,
     total_length: u16,
+    identification: u16,
+    flags_fragment_offset: u16,
+    ttl: u8,
     protocol: Protocol,
     source: u32,
     destination: u32,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum Protocol {
-    Tcp,
-    Udp,
-    Icmp,
-    Other,
-}
+const FIXED_HEADER_LENGTH: u8 = 20;
+
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
 
 #[allow(dead_code)]
 impl Ipv4HeaderData {
@@ -53,12 +56,10 @@ impl Ipv4HeaderData {
             version: raw[0] >> 4,
             header_length: (raw[0] & 0xf) << 2,
             total_length: BigEndian::read_u16(&raw[2..4]),
-            protocol: match raw[9] {
-                1 => Protocol::Icmp,
-                6 => Protocol::Tcp,
-                17 => Protocol::Udp,
-                _ => Protocol::Other,
-            },
+            identification: BigEndian::read_u16(&raw[4..6]),
+            flags_fragment_offset: BigEndian::read_u16(&raw[6..8]),
+            ttl: raw[8],
+            protocol: Protocol::from_number(raw[9]),
             source: BigEndian::read_u32(&raw[12..16]),
             destination: BigEndian::read_u32(&raw[16..20]),
         }
@@ -80,6 +81,32 @@ impl Ipv4HeaderData {
         self.total_length
     }
 
+    pub fn identification(&self) -> u16 {
+        self.identification
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.flags_fragment_offset & FLAG_DONT_FRAGMENT != 0
+    }
+
+    pub fn more_fragments(&self) -> bool {
+        self.flags_fragment_offset & FLAG_MORE_FRAGMENTS != 0
+    }
+
+    /// Offset of the fragment data in the original datagram, in bytes
+    pub fn fragment_offset(&self) -> u16 {
+        (self.flags_fragment_offset & FRAGMENT_OFFSET_MASK) << 3
+    }
+
+    /// Indicate whether the packet is only a part of a datagram
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
     pub fn protocol(&self) -> Protocol {
         self.protocol
     }
@@ -93,18 +120,6 @@ impl Ipv4HeaderData {
     }
 }
 
-pub fn peek_version_length(raw: &[u8]) -> Option<(u8, u16)> {
-    if raw.len() >= 4 {
-        // version is stored in the 4 first bits
-        let version = raw[0] >> 4;
-        // packet length is 16 bits starting at offset 2
-        let length = BigEndian::read_u16(&raw[2..4]);
-        Some((version, length))
-    } else {
-        None
-    }
-}
-
 // shared definition for Ipv4Header and Ipv4HeaderMut
 macro_rules! ipv4_header_common {
     ($name:ident, $raw_type:ty, $data_type:ty) => {
@@ -127,6 +142,10 @@ macro_rules! ipv4_header_common {
                 self.data.header_length
             }
 
+            pub fn options(&self) -> &[u8] {
+                &self.raw[FIXED_HEADER_LENGTH as usize..self.data.header_length as usize]
+            }
+
             pub fn total_length(&self) -> u16 {
                 self.data.total_length
             }
@@ -165,6 +184,33 @@ impl<'a> Ipv4HeaderMut<'a> {
         BigEndian::write_u16(&mut self.raw[2..4], total_length);
     }
 
+    pub fn set_identification(&mut self, identification: u16) {
+        self.data.identification = identification;
+        BigEndian::write_u16(&mut self.raw[4..6], identification);
+    }
+
+    pub fn set_dont_fragment(&mut self, dont_fragment: bool) {
+        let mut value = self.data.flags_fragment_offset & !FLAG_DONT_FRAGMENT;
+        if dont_fragment {
+            value |= FLAG_DONT_FRAGMENT;
+        }
+        self.data.flags_fragment_offset = value;
+        BigEndian::write_u16(&mut self.raw[6..8], value);
+    }
+
+    /// Set the fragment offset (in bytes, a multiple of 8) and the "more fragments" flag, keeping
+    /// the "don't fragment" flag.
+    pub fn set_fragment(&mut self, more_fragments: bool, fragment_offset: u16) {
+        debug_assert!(fragment_offset.is_multiple_of(8));
+        let mut value = self.data.flags_fragment_offset & FLAG_DONT_FRAGMENT;
+        if more_fragments {
+            value |= FLAG_MORE_FRAGMENTS;
+        }
+        value |= fragment_offset >> 3;
+        self.data.flags_fragment_offset = value;
+        BigEndian::write_u16(&mut self.raw[6..8], value);
+    }
+
     pub fn set_source(&mut self, source: u32) {
         self.data.source = source;
         BigEndian::write_u32(&mut self.raw[12..16], source);
@@ -182,6 +228,27 @@ impl<'a> Ipv4HeaderMut<'a> {
         }
     }
 
+    /// Write the options after the fixed header, the header being exactly large enough.
+    pub fn set_options(&mut self, options: &[u8]) {
+        let header_length = FIXED_HEADER_LENGTH + options.len() as u8;
+        assert_eq!(
+            header_length as usize,
+            self.raw.len(),
+            "Invalid options length"
+        );
+        self.raw[FIXED_HEADER_LENGTH as usize..].copy_from_slice(options);
+        self.raw[0] = (self.raw[0] & 0xf0) | (header_length >> 2);
+        self.data.header_length = header_length;
+    }
+
+    /// Remove the options, so that the payload follows the fixed header.
+    ///
+    /// The bytes after the fixed header are left untouched.
+    pub fn shrink_options(&mut self) {
+        self.raw[0] = (self.raw[0] & 0xf0) | (FIXED_HEADER_LENGTH >> 2);
+        self.data.header_length = FIXED_HEADER_LENGTH;
+    }
+
     fn checksum(&self) -> u16 {
         BigEndian::read_u16(&self.raw[10..12])
     }
@@ -208,13 +275,13 @@ impl<'a> Ipv4HeaderMut<'a> {
 }
 
 #[cfg(test)]
+#[allow(clippy::identity_op)] // checksums are written word by word for readability
 mod tests {
     use super::*;
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_header() -> Vec<u8> {
-        let mut raw: Vec<u8> = Vec::new();
-        raw.reserve(20);
+        let mut raw: Vec<u8> = Vec::with_capacity(20);
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
         raw.write_u16::<BigEndian>(28).unwrap(); // total length
@@ -271,6 +338,63 @@ mod tests {
         assert_eq!(0x87654321, raw_destination);
     }
 
+    #[test]
+    fn edit_fragment() {
+        let raw = &mut create_header()[..];
+        raw[6] = 0x40; // don't fragment
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        assert!(header_data.dont_fragment());
+        assert!(!header_data.is_fragment());
+
+        let mut header = header_data.bind_mut(raw);
+        header.set_identification(0x1234);
+        header.set_fragment(true, 1480);
+        assert_eq!([0x12, 0x34, 0x60, 0xB9], header.raw[4..8]);
+
+        let header_data = Ipv4HeaderData::parse(raw);
+        assert_eq!(0x1234, header_data.identification());
+        assert!(header_data.dont_fragment());
+        assert!(header_data.more_fragments());
+        assert_eq!(1480, header_data.fragment_offset());
+        assert!(header_data.is_fragment());
+
+        let mut header_data = Ipv4HeaderData::parse(raw);
+        header_data.bind_mut(raw).set_dont_fragment(false);
+        assert_eq!([0x20, 0xB9], raw[6..8]);
+        assert!(!header_data.dont_fragment());
+        assert!(header_data.more_fragments());
+    }
+
+    #[test]
+    fn shrink_options() {
+        let mut raw = create_header();
+        raw[0] = 4 << 4 | 8;
+        // record route option, padded
+        raw.extend_from_slice(&[7, 11, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut header_data = Ipv4HeaderData::parse(&raw);
+        assert_eq!(32, header_data.header_length());
+
+        let mut header = header_data.bind_mut(&mut raw);
+        header.shrink_options();
+        assert_eq!(20, header.header_length());
+        assert_eq!(0x45, raw[0]);
+        assert_eq!(20, Ipv4HeaderData::parse(&raw).header_length());
+    }
+
+    #[test]
+    fn set_options() {
+        let mut raw = create_header();
+        raw.extend_from_slice(&[0; 8]);
+        let mut header_data = Ipv4HeaderData::parse(&raw);
+
+        let options = [7, 7, 4, 0, 0, 0, 0, 0];
+        let mut header = header_data.bind_mut(&mut raw);
+        header.set_options(&options);
+        assert_eq!(28, header.header_length());
+        assert_eq!(&options, header.options());
+        assert_eq!(0x47, raw[0]);
+    }
+
     #[test]
     fn compute_checksum() {
         let raw = &mut create_header()[..];
@@ -290,20 +414,4 @@ mod tests {
         let sum = !sum as u16;
         assert_eq!(sum, header.checksum());
     }
-
-    #[test]
-    fn peek_version_length_unavailable() {
-        let raw: [u8; 0] = [];
-        assert!(peek_version_length(&raw).is_none());
-        let raw = [0x40, 2];
-        assert!(peek_version_length(&raw).is_none());
-    }
-
-    #[test]
-    fn peek_version_length_available() {
-        let raw = [4u8 << 4 | 5, 0, 0x01, 0x23];
-        let (version, length) = peek_version_length(&raw).unwrap();
-        assert_eq!(4, version);
-        assert_eq!(0x123, length);
-    }
 }
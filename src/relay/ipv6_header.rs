@@ -0,0 +1,325 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use byteorder::{BigEndian, ByteOrder};
+use std::mem;
+use std::net::Ipv6Addr;
+
+use super::ip_header::Protocol;
+
+pub const FIXED_HEADER_LENGTH: u16 = 40;
+
+// extension headers (RFC 8200 section 4)
+const HOP_BY_HOP_OPTIONS: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION_OPTIONS: u8 = 60;
+
+pub struct Ipv6Header<'a> {
+    raw: &'a [u8],
+    data: &'a Ipv6HeaderData,
+}
+
+pub struct Ipv6HeaderMut<'a> {
+    raw: &'a mut [u8],
+    data: &'a mut Ipv6HeaderData,
+}
+
+#[derive(Clone)]
+pub struct Ipv6HeaderData {
+    payload_length: u16,
+    // fixed header and extension headers
+    header_length: u16,
+    hop_limit: u8,
+    // the upper-layer protocol, after the extension headers
+    protocol: Protocol,
+    source: Ipv6Addr,
+    destination: Ipv6Addr,
+}
+
+#[allow(dead_code)]
+impl Ipv6HeaderData {
+    pub fn parse(raw: &[u8]) -> Self {
+        let payload_length = BigEndian::read_u16(&raw[4..6]);
+        // raw may contain the following packets, the extension headers must not overrun the packet
+        let packet_length = raw
+            .len()
+            .min(FIXED_HEADER_LENGTH as usize + payload_length as usize);
+        let (header_length, protocol) = Self::walk_extension_headers(&raw[..packet_length]);
+        Self {
+            payload_length,
+            header_length,
+            hop_limit: raw[7],
+            protocol,
+            source: read_address(&raw[8..24]),
+            destination: read_address(&raw[24..40]),
+        }
+    }
+
+    /// Skip the extension headers, to find the upper-layer header.
+    ///
+    /// Return its offset and its protocol (`Protocol::Other` if it cannot be reached, e.g. in a
+    /// non-first fragment or if an extension header overruns the packet).
+    fn walk_extension_headers(raw: &[u8]) -> (u16, Protocol) {
+        let mut next_header = raw[6];
+        let mut offset = FIXED_HEADER_LENGTH as usize;
+        loop {
+            let extension_length = match next_header {
+                HOP_BY_HOP_OPTIONS | ROUTING | DESTINATION_OPTIONS => {
+                    raw.get(offset + 1).map(|&len| (len as usize + 1) * 8)
+                }
+                AUTHENTICATION => raw.get(offset + 1).map(|&len| (len as usize + 2) * 4),
+                FRAGMENT => {
+                    // only an unfragmented packet (offset 0, no more fragments) is supported
+                    raw.get(offset + 2..offset + 4)
+                        .filter(|fragment| BigEndian::read_u16(fragment) & 0xFFF9 == 0)
+                        .map(|_| 8)
+                }
+                _ => return (offset as u16, Protocol::from_number(next_header)),
+            };
+            match extension_length {
+                Some(length) if offset + length <= raw.len() => {
+                    next_header = raw[offset];
+                    offset += length;
+                }
+                _ => return (offset as u16, Protocol::Other),
+            }
+        }
+    }
+
+    #[inline]
+    pub fn bind<'c, 'a: 'c, 'b: 'c>(&'a self, raw: &'b [u8]) -> Ipv6Header<'c> {
+        Ipv6Header::new(raw, self)
+    }
+
+    #[inline]
+    pub fn bind_mut<'c, 'a: 'c, 'b: 'c>(&'a mut self, raw: &'b mut [u8]) -> Ipv6HeaderMut<'c> {
+        Ipv6HeaderMut::new(raw, self)
+    }
+
+    #[inline]
+    pub fn header_length(&self) -> u16 {
+        self.header_length
+    }
+
+    /// The length of the packet, which must fit in 16 bits (jumbograms are not supported, the
+    /// longer packets from the client are discarded by `IpPacketBuffer`).
+    #[inline]
+    pub fn total_length(&self) -> u16 {
+        FIXED_HEADER_LENGTH
+            .checked_add(self.payload_length)
+            .expect("IPv6 packet too long")
+    }
+
+    #[inline]
+    pub fn hop_limit(&self) -> u8 {
+        self.hop_limit
+    }
+
+    #[inline]
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    #[inline]
+    pub fn source(&self) -> Ipv6Addr {
+        self.source
+    }
+
+    #[inline]
+    pub fn destination(&self) -> Ipv6Addr {
+        self.destination
+    }
+}
+
+fn read_address(raw: &[u8]) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(raw);
+    Ipv6Addr::from(octets)
+}
+
+// shared definition for Ipv6Header and Ipv6HeaderMut
+macro_rules! ipv6_header_common {
+    ($name:ident, $raw_type:ty, $data_type:ty) => {
+        // for readability, declare structs manually outside the macro
+        #[allow(dead_code)]
+        impl<'a> $name<'a> {
+            pub fn new(raw: $raw_type, data: $data_type) -> Self {
+                Self { raw, data }
+            }
+
+            pub fn raw(&self) -> &[u8] {
+                self.raw
+            }
+
+            pub fn data(&self) -> &Ipv6HeaderData {
+                self.data
+            }
+
+            pub fn header_length(&self) -> u16 {
+                self.data.header_length
+            }
+
+            pub fn total_length(&self) -> u16 {
+                self.data.total_length()
+            }
+
+            pub fn protocol(&self) -> Protocol {
+                self.data.protocol
+            }
+
+            pub fn source(&self) -> Ipv6Addr {
+                self.data.source
+            }
+
+            pub fn destination(&self) -> Ipv6Addr {
+                self.data.destination
+            }
+        }
+    };
+}
+
+ipv6_header_common!(Ipv6Header, &'a [u8], &'a Ipv6HeaderData);
+ipv6_header_common!(Ipv6HeaderMut, &'a mut [u8], &'a mut Ipv6HeaderData);
+
+// additional methods for the mutable version
+#[allow(dead_code)]
+impl<'a> Ipv6HeaderMut<'a> {
+    pub fn raw_mut(&mut self) -> &mut [u8] {
+        self.raw
+    }
+
+    pub fn data_mut(&mut self) -> &mut Ipv6HeaderData {
+        self.data
+    }
+
+    pub fn set_total_length(&mut self, total_length: u16) {
+        let payload_length = total_length - FIXED_HEADER_LENGTH;
+        self.data.payload_length = payload_length;
+        BigEndian::write_u16(&mut self.raw[4..6], payload_length);
+    }
+
+    pub fn set_source(&mut self, source: Ipv6Addr) {
+        self.data.source = source;
+        self.raw[8..24].copy_from_slice(&source.octets());
+    }
+
+    pub fn swap_source_and_destination(&mut self) {
+        mem::swap(&mut self.data.source, &mut self.data.destination);
+        for i in 8..24 {
+            self.raw.swap(i, i + 16);
+        }
+    }
+
+    /// Remove the extension headers, so that the upper-layer header follows the fixed header.
+    ///
+    /// The bytes after the fixed header are left untouched.
+    pub fn shrink_extension_headers(&mut self) {
+        if let Some(number) = self.data.protocol.number() {
+            self.raw[6] = number;
+        }
+        self.data.header_length = FIXED_HEADER_LENGTH;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn create_header(next_header: u8, payload_length: u16) -> Vec<u8> {
+        let mut raw: Vec<u8> = Vec::with_capacity(40);
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, traffic class, flow label
+        raw.write_u16::<BigEndian>(payload_length).unwrap();
+        raw.write_u8(next_header).unwrap();
+        raw.write_u8(64).unwrap(); // hop limit
+        raw.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        raw.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        raw
+    }
+
+    #[test]
+    fn parse_header() {
+        let raw = create_header(17, 8);
+        let data = Ipv6HeaderData::parse(&raw);
+        assert_eq!(40, data.header_length());
+        assert_eq!(48, data.total_length());
+        assert_eq!(64, data.hop_limit());
+        assert_eq!(Protocol::Udp, data.protocol());
+        assert_eq!("2001:db8::1".parse::<Ipv6Addr>().unwrap(), data.source());
+        assert_eq!(
+            "2001:db8::2".parse::<Ipv6Addr>().unwrap(),
+            data.destination()
+        );
+    }
+
+    #[test]
+    fn walk_extension_headers() {
+        let mut raw = create_header(HOP_BY_HOP_OPTIONS, 8 + 8 + 20);
+        // hop-by-hop options (8 bytes), followed by destination options
+        raw.extend_from_slice(&[DESTINATION_OPTIONS, 0, 1, 4, 0, 0, 0, 0]);
+        // destination options (8 bytes), followed by TCP
+        raw.extend_from_slice(&[6, 0, 1, 4, 0, 0, 0, 0]);
+        raw.extend_from_slice(&[0; 20]);
+
+        let mut data = Ipv6HeaderData::parse(&raw);
+        assert_eq!(56, data.header_length());
+        assert_eq!(Protocol::Tcp, data.protocol());
+
+        let mut header = data.bind_mut(&mut raw);
+        header.shrink_extension_headers();
+        assert_eq!(40, header.header_length());
+        assert_eq!(6, header.raw()[6]);
+    }
+
+    #[test]
+    fn truncated_hop_by_hop_options() {
+        // the hop-by-hop options claim 16 bytes, but the payload is only 8 bytes long
+        let mut raw = create_header(HOP_BY_HOP_OPTIONS, 8);
+        raw.extend_from_slice(&[17, 1, 1, 4, 0, 0, 0, 0]);
+        // the following packet in the buffer
+        raw.extend_from_slice(&create_header(17, 0));
+
+        let data = Ipv6HeaderData::parse(&raw);
+        assert_eq!(Protocol::Other, data.protocol());
+        assert!(data.header_length() <= data.total_length());
+    }
+
+    #[test]
+    fn ignore_non_first_fragment() {
+        let mut raw = create_header(FRAGMENT, 8 + 8);
+        // fragment offset 1 (8 bytes)
+        raw.extend_from_slice(&[17, 0, 0, 8, 0, 0, 0, 42]);
+        raw.extend_from_slice(&[0; 8]);
+        let data = Ipv6HeaderData::parse(&raw);
+        assert_eq!(Protocol::Other, data.protocol());
+    }
+
+    #[test]
+    fn edit_header() {
+        let mut raw = create_header(17, 8);
+        let mut data = Ipv6HeaderData::parse(&raw);
+        let mut header = data.bind_mut(&mut raw);
+        header.set_total_length(100);
+        header.swap_source_and_destination();
+        assert_eq!(100, header.total_length());
+        assert_eq!("2001:db8::2".parse::<Ipv6Addr>().unwrap(), header.source());
+        assert_eq!(60, BigEndian::read_u16(&raw[4..6]));
+        assert_eq!(2, raw[23]);
+        assert_eq!(1, raw[39]);
+    }
+}
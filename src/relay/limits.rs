@@ -0,0 +1,280 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+
+use super::ip_header::Protocol;
+
+// from errno.h (identical on Linux and macOS) and winsock2.h
+const ENFILE: i32 = 23;
+const EMFILE: i32 = 24;
+const WSAEMFILE: i32 = 10024;
+
+/// Limits on the connections a client may open.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionLimits {
+    max_tcp: Option<usize>,
+    max_udp: Option<usize>,
+    max_icmp: Option<usize>,
+    // new connections per second
+    max_rate: Option<u64>,
+}
+
+/// Number of open connections of a client, counted like the limits (ICMP includes ICMPv6).
+#[derive(Debug, Default)]
+pub struct ConnectionCounts {
+    tcp: usize,
+    udp: usize,
+    icmp: usize,
+}
+
+/// Number of file descriptors the relay may use, shared by all the clients and connections.
+#[derive(Debug)]
+pub struct FdBudget {
+    max: Cell<Option<usize>>,
+    used: Cell<usize>,
+}
+
+/// Reason why a new connection is refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    // too many concurrent connections of the same protocol
+    TooManyConnections,
+    // too many new connections per second
+    TooFast,
+    // the global file descriptor budget (or the system limit) is exhausted
+    OutOfFiles,
+}
+
+/// Count the refused connections, for periodic reporting.
+#[derive(Debug, Default)]
+pub struct RejectionCounters {
+    too_many_connections: u64,
+    too_fast: u64,
+    out_of_files: u64,
+}
+
+impl ConnectionLimits {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not a limit key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "max-tcp-connections" => self.max_tcp = Some(parse_count(value)?),
+            "max-udp-connections" => self.max_udp = Some(parse_count(value)?),
+            "max-icmp-connections" => self.max_icmp = Some(parse_count(value)?),
+            "max-connection-rate" => self.max_rate = Some(parse_count(value)? as u64),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn max_connections(&self, protocol: Protocol) -> Option<usize> {
+        match protocol {
+            Protocol::Tcp => self.max_tcp,
+            Protocol::Udp => self.max_udp,
+            Protocol::Icmp | Protocol::Icmpv6 => self.max_icmp,
+            Protocol::Other => None,
+        }
+    }
+
+    pub fn max_rate(&self) -> Option<u64> {
+        self.max_rate
+    }
+}
+
+impl ConnectionCounts {
+    pub fn get(&self, protocol: Protocol) -> usize {
+        match protocol {
+            Protocol::Tcp => self.tcp,
+            Protocol::Udp => self.udp,
+            Protocol::Icmp | Protocol::Icmpv6 => self.icmp,
+            Protocol::Other => 0,
+        }
+    }
+
+    pub fn add(&mut self, protocol: Protocol) {
+        if let Some(count) = self.count_mut(protocol) {
+            *count += 1;
+        }
+    }
+
+    pub fn remove(&mut self, protocol: Protocol) {
+        if let Some(count) = self.count_mut(protocol) {
+            *count = count
+                .checked_sub(1)
+                .expect("Removing an uncounted connection");
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    fn count_mut(&mut self, protocol: Protocol) -> Option<&mut usize> {
+        match protocol {
+            Protocol::Tcp => Some(&mut self.tcp),
+            Protocol::Udp => Some(&mut self.udp),
+            Protocol::Icmp | Protocol::Icmpv6 => Some(&mut self.icmp),
+            Protocol::Other => None,
+        }
+    }
+}
+
+impl FdBudget {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max: Cell::new(max),
+            used: Cell::new(0),
+        }
+    }
+
+    pub fn set_max(&self, max: Option<usize>) {
+        self.max.set(max);
+    }
+
+    /// Reserve a file descriptor, if the budget allows it.
+    pub fn try_acquire(&self) -> bool {
+        let used = self.used.get();
+        if self.max.get().is_some_and(|max| used >= max) {
+            return false;
+        }
+        self.used.set(used + 1);
+        true
+    }
+
+    pub fn release(&self, count: usize) {
+        let used = self.used.get();
+        assert!(
+            count <= used,
+            "Releasing more file descriptors than acquired"
+        );
+        self.used.set(used - count);
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            Rejection::TooManyConnections => "too many connections",
+            Rejection::TooFast => "too many new connections per second",
+            Rejection::OutOfFiles => "out of file descriptors",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl RejectionCounters {
+    pub fn count(&mut self, rejection: Rejection) {
+        match rejection {
+            Rejection::TooManyConnections => self.too_many_connections += 1,
+            Rejection::TooFast => self.too_fast += 1,
+            Rejection::OutOfFiles => self.out_of_files += 1,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.too_many_connections + self.too_fast + self.out_of_files
+    }
+}
+
+impl fmt::Display for RejectionCounters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} over the connection limit, {} over the rate limit, {} out of file descriptors",
+            self.too_many_connections, self.too_fast, self.out_of_files
+        )
+    }
+}
+
+/// Return `true` if the error means that the process or the system cannot open more files.
+pub fn is_out_of_files(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(ENFILE) | Some(EMFILE) | Some(WSAEMFILE)
+    )
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("invalid count \"{}\"", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_limits() {
+        let mut limits = ConnectionLimits::default();
+        assert_eq!(Ok(true), limits.set("max-tcp-connections", "64"));
+        assert_eq!(Ok(true), limits.set("max-connection-rate", "20"));
+        assert_eq!(Ok(false), limits.set("upload-rate", "1M"));
+        assert!(limits.set("max-udp-connections", "0").is_err());
+        assert!(limits.set("max-icmp-connections", "many").is_err());
+        assert_eq!(Some(64), limits.max_connections(Protocol::Tcp));
+        assert_eq!(None, limits.max_connections(Protocol::Udp));
+        assert_eq!(Some(20), limits.max_rate());
+    }
+
+    #[test]
+    fn count_connections() {
+        let mut counts = ConnectionCounts::default();
+        counts.add(Protocol::Tcp);
+        counts.add(Protocol::Tcp);
+        counts.add(Protocol::Icmp);
+        counts.add(Protocol::Icmpv6);
+        counts.remove(Protocol::Tcp);
+        assert_eq!(1, counts.get(Protocol::Tcp));
+        assert_eq!(0, counts.get(Protocol::Udp));
+        // ICMP and ICMPv6 share the same limit
+        assert_eq!(2, counts.get(Protocol::Icmpv6));
+
+        counts.clear();
+        assert_eq!(0, counts.get(Protocol::Icmp));
+    }
+
+    #[test]
+    fn acquire_and_release() {
+        let budget = FdBudget::new(Some(2));
+        assert!(budget.try_acquire());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+        budget.release(1);
+        assert!(budget.try_acquire());
+
+        budget.set_max(None);
+        assert!(budget.try_acquire());
+        assert_eq!(3, budget.used());
+    }
+
+    #[test]
+    fn detect_out_of_files() {
+        assert!(is_out_of_files(&io::Error::from_raw_os_error(EMFILE)));
+        assert!(!is_out_of_files(&io::Error::from(
+            io::ErrorKind::ConnectionRefused
+        )));
+    }
+}
@@ -14,35 +14,67 @@
  * limitations under the License.
  */
 
+pub use self::config::Config;
 pub use self::relay::Relay;
 pub mod byte_buffer;
 
+/// The internals exercised by the benchmarks, not part of the API.
+#[doc(hidden)]
+pub mod bench {
+    pub use super::connection::{ConnectionId, FlowKey};
+    pub use super::flow_table::FlowTable;
+    pub use super::ip_packet::IpPacket;
+}
+
 mod binary;
 mod client;
 mod close_listener;
+mod config;
 #[macro_use]
 mod connection;
 mod datagram;
 mod datagram_buffer;
+mod device;
+mod dns;
+mod egress;
+mod flow_table;
+mod fragmentation;
 #[macro_use]
 mod interrupt;
+mod http_proxy;
+mod icmp_connection;
+mod icmp_error;
+mod icmp_header;
+mod icmp_socket;
+mod icmpv6_connection;
+mod impairment;
+mod ip_header;
+mod ip_options;
+mod ip_packet;
+mod ip_packet_buffer;
 mod ipv4_header;
-mod ipv4_packet;
-mod ipv4_packet_buffer;
-mod net;
+mod ipv6_header;
+mod limits;
+mod out_of_order_queue;
 mod packet_source;
 mod packetizer;
+mod path_mtu;
+mod quota;
 #[allow(clippy::module_inception)] // relay.rs is in relay/
 mod relay;
 mod router;
 mod selector;
+mod send_queue;
+mod shaper;
+mod socket_error;
 mod stream_buffer;
 mod tcp_connection;
 mod tcp_header;
+mod tcp_timeouts;
 mod transport_header;
 mod tunnel_server;
 mod udp_connection;
+mod udp_group;
 mod udp_header;
-mod icmp_socket;
-mod icmp_connection;
-mod icmp_header;
+mod udp_nat;
+mod udp_timeouts;
@@ -0,0 +1,171 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::num::Wrapping;
+
+struct Segment {
+    sequence_number: u32,
+    payload: Vec<u8>,
+    fin: bool,
+}
+
+impl Segment {
+    // the FIN counts for 1 byte
+    fn end(&self) -> u32 {
+        (Wrapping(self.sequence_number)
+            + Wrapping(self.payload.len() as u32)
+            + Wrapping(self.fin as u32))
+        .0
+    }
+}
+
+// signed distance from a to b, correct as long as it is less than 2^31 (RFC 1982)
+fn distance(a: u32, b: u32) -> i32 {
+    (Wrapping(b) - Wrapping(a)).0 as i32
+}
+
+/// Bounded queue of the TCP segments received from the client ahead of the expected sequence
+/// number, until the missing data arrives.
+pub struct OutOfOrderQueue {
+    segments: Vec<Segment>,
+    max_segments: usize,
+}
+
+impl OutOfOrderQueue {
+    pub fn new(max_segments: usize) -> Self {
+        Self {
+            segments: Vec::new(),
+            max_segments,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Queue a future segment.
+    ///
+    /// The segment must end at most `window` bytes after `expected`. Return `false` if it is
+    /// dropped (outside the window, or queue full).
+    pub fn insert(
+        &mut self,
+        expected: u32,
+        sequence_number: u32,
+        payload: &[u8],
+        fin: bool,
+        window: usize,
+    ) -> bool {
+        let segment = Segment {
+            sequence_number,
+            payload: payload.to_vec(),
+            fin,
+        };
+        let offset = distance(expected, sequence_number);
+        let end = distance(expected, segment.end());
+        if offset <= 0 || end as usize > window {
+            return false;
+        }
+        if self
+            .segments
+            .iter()
+            .any(|s| s.sequence_number == sequence_number && s.end() == segment.end())
+        {
+            // retransmission of a queued segment
+            return true;
+        }
+        if self.segments.len() == self.max_segments {
+            return false;
+        }
+        self.segments.push(segment);
+        true
+    }
+
+    /// Remove and return the data starting at `expected`, if it has been queued, along with its
+    /// FIN flag.
+    ///
+    /// The segments entirely before `expected` are discarded.
+    pub fn pop(&mut self, expected: u32) -> Option<(Vec<u8>, bool)> {
+        self.segments
+            .retain(|segment| distance(expected, segment.end()) > 0);
+        let index = self
+            .segments
+            .iter()
+            .position(|segment| distance(expected, segment.sequence_number) <= 0)?;
+        let segment = self.segments.swap_remove(index);
+        // the beginning may have already been received (overlapping segments)
+        let skip = (-distance(expected, segment.sequence_number)) as usize;
+        let mut payload = segment.payload;
+        payload.drain(..skip);
+        Some((payload, segment.fin))
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deliver_in_order() {
+        let mut queue = OutOfOrderQueue::new(8);
+        assert!(queue.insert(1000, 1020, &[3; 10], true, 100));
+        assert!(queue.insert(1000, 1010, &[2; 10], false, 100));
+        assert!(queue.pop(1000).is_none());
+
+        // the missing segment [1000, 1010) has been received
+        assert_eq!(Some((vec![2; 10], false)), queue.pop(1010));
+        assert_eq!(Some((vec![3; 10], true)), queue.pop(1020));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn trim_overlapping_segments() {
+        let mut queue = OutOfOrderQueue::new(8);
+        assert!(queue.insert(1000, 1010, &[1, 2, 3, 4, 5], false, 100));
+        assert!(queue.insert(1000, 1020, &[6; 5], false, 100));
+        assert_eq!(Some((vec![4, 5], false)), queue.pop(1013));
+        // [1010, 1015) is stale now
+        assert_eq!(Some((vec![6; 5], false)), queue.pop(1020));
+        assert!(queue.pop(1025).is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn respect_window_and_capacity() {
+        let mut queue = OutOfOrderQueue::new(2);
+        // not a future segment
+        assert!(!queue.insert(1000, 1000, &[0; 10], false, 100));
+        assert!(!queue.insert(1000, 990, &[0; 10], false, 100));
+        // beyond the window
+        assert!(!queue.insert(1000, 1095, &[0; 10], false, 100));
+
+        assert!(queue.insert(1000, 1010, &[0; 10], false, 100));
+        // retransmission
+        assert!(queue.insert(1000, 1010, &[0; 10], false, 100));
+        assert!(queue.insert(1000, 1030, &[0; 10], false, 100));
+        assert!(!queue.insert(1000, 1050, &[0; 10], false, 100));
+    }
+
+    #[test]
+    fn wrap_sequence_numbers() {
+        let mut queue = OutOfOrderQueue::new(8);
+        assert!(queue.insert(0xFFFF_FFF0, 0xFFFF_FFFA, &[7; 10], false, 100));
+        assert_eq!(Some((vec![7; 10], false)), queue.pop(0xFFFF_FFFA));
+    }
+}
@@ -16,42 +16,50 @@
 
 use log::*;
 use std::io;
+use std::net::SocketAddr;
 
 use super::binary;
-use super::datagram::{DatagramReceiver, ReadAdapter};
-use super::ipv4_header::{Ipv4Header, Ipv4HeaderData, Ipv4HeaderMut};
-use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::datagram::{DatagramFromReceiver, DatagramReceiver, ReadAdapter};
+use super::ip_header::{IpHeader, IpHeaderData, IpHeaderMut};
+use super::ip_options;
+use super::ip_packet::{IpPacket, MAX_PACKET_LENGTH};
 use super::transport_header::{TransportHeader, TransportHeaderData, TransportHeaderMut};
 
+const IPV4_HEADER_LENGTH: usize = 20;
+
 /// Convert from level 5 to level 3 by appending correct IP and transport headers.
 pub struct Packetizer {
     buffer: Box<[u8; MAX_PACKET_LENGTH]>,
     transport_index: usize,
     payload_index: usize,
-    ipv4_header_data: Ipv4HeaderData,
+    ip_header_data: IpHeaderData,
     transport_header_data: TransportHeaderData,
+    // the transport checksum is disabled (only possible for UDP over IPv4)
+    checksum_disabled: bool,
 }
 
 impl Packetizer {
     pub fn new(
-        reference_ipv4_header: &Ipv4Header,
+        reference_ip_header: &IpHeader,
         reference_transport_header: &TransportHeader,
     ) -> Self {
         let mut buffer = Box::new([0; MAX_PACKET_LENGTH]);
 
-        let transport_index = reference_ipv4_header.header_length() as usize;
-        let payload_index = transport_index + reference_transport_header.header_length() as usize;
-
-        let mut ipv4_header_data = reference_ipv4_header.data().clone();
+        let mut ip_header_data = reference_ip_header.data_clone();
         let mut transport_header_data = reference_transport_header.data_clone();
 
         {
-            let ipv4_header_raw = &mut buffer[..transport_index];
-            ipv4_header_raw.copy_from_slice(reference_ipv4_header.raw());
-            let mut ipv4_header = ipv4_header_data.bind_mut(ipv4_header_raw);
-            ipv4_header.swap_source_and_destination();
+            let ip_header_raw = &mut buffer[..reference_ip_header.raw().len()];
+            ip_header_raw.copy_from_slice(reference_ip_header.raw());
+            let mut ip_header = ip_header_data.bind_mut(ip_header_raw);
+            ip_header.swap_source_and_destination();
+            // IPv4 options and IPv6 extension headers only concern the packets from the client
+            ip_header.shrink_extension_headers();
         }
 
+        let transport_index = ip_header_data.header_length() as usize;
+        let payload_index = transport_index + reference_transport_header.header_length() as usize;
+
         {
             let transport_header_raw = &mut buffer[transport_index..payload_index];
             transport_header_raw.copy_from_slice(reference_transport_header.raw());
@@ -63,20 +71,57 @@ impl Packetizer {
             buffer,
             transport_index,
             payload_index,
-            ipv4_header_data,
+            ip_header_data,
             transport_header_data,
+            checksum_disabled: false,
         }
     }
 
-    pub fn packetize_empty_payload(&mut self) -> Ipv4Packet {
+    /// Do not compute the UDP checksum of the packets, if it is optional (for IPv4).
+    pub fn disable_udp_checksum(&mut self) {
+        if self.ip_header_data.is_ipv6() {
+            return;
+        }
+        if let TransportHeaderMut::Udp(mut udp_header) = self.transport_header_mut() {
+            udp_header.disable_checksum();
+            self.checksum_disabled = true;
+        }
+    }
+
+    pub fn packetize_empty_payload(&mut self) -> IpPacket<'_> {
         self.build(0)
     }
 
-    pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<Ipv4Packet> {
+    pub fn packetize<R: DatagramReceiver>(&mut self, source: &mut R) -> io::Result<IpPacket<'_>> {
         let r = source.recv(&mut self.buffer[self.payload_index..])?;
         debug!(target: "PACK", "payload index {}, length {}, raw: {}", self.payload_index, r, binary::build_packet_string(&self.buffer[self.payload_index..]));
-        let ipv4_packet = self.build(r as u16);
-        Ok(ipv4_packet)
+        let ip_packet = self.build(r as u16);
+        Ok(ip_packet)
+    }
+
+    /// Packetize a UDP datagram received from any remote host.
+    ///
+    /// `source` converts the sender to the source address of the packet, or rejects it: then
+    /// `Ok(None)` is returned and the datagram is dropped.
+    pub fn packetize_from<R, F>(
+        &mut self,
+        socket: &mut R,
+        source: F,
+    ) -> io::Result<Option<IpPacket<'_>>>
+    where
+        R: DatagramFromReceiver,
+        F: FnOnce(SocketAddr) -> Option<SocketAddr>,
+    {
+        let (r, sender) = socket.recv_from(&mut self.buffer[self.payload_index..])?;
+        let source = match source(sender) {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        self.ip_header_mut().set_source(source.ip());
+        if let TransportHeaderMut::Udp(mut udp_header) = self.transport_header_mut() {
+            udp_header.set_source_port(source.port());
+        }
+        Ok(Some(self.build(r as u16)))
     }
 
     /// Packetize from stream (`Read`) source.
@@ -88,49 +133,107 @@ impl Packetizer {
         &mut self,
         source: &mut R,
         max_chunk_size: Option<usize>,
-    ) -> io::Result<Option<Ipv4Packet>> {
+    ) -> io::Result<Option<IpPacket<'_>>> {
         let mut adapter = ReadAdapter::new(source, max_chunk_size);
         let r = adapter.recv(&mut self.buffer[self.payload_index..])?;
         debug!(target: "PACK", "payload index {}, length {}, raw: {}", self.payload_index, r, binary::build_packet_string(&self.buffer[self.payload_index..]));
         let option = if r > 0 {
-            let ipv4_packet = self.build(r as u16);
-            Some(ipv4_packet)
+            let ip_packet = self.build(r as u16);
+            Some(ip_packet)
         } else {
             None
         };
         Ok(option)
     }
 
-    pub fn ipv4_header_mut(&mut self) -> Ipv4HeaderMut {
+    /// Packetize from stream (`Read`) source, with the IPv4 options given by `ip_options` once
+    /// the payload is read (typically, the options of the packet just received).
+    ///
+    /// Same results as `packetize_read()`.
+    pub fn packetize_read_with_ip_options<R, F>(
+        &mut self,
+        source: &mut R,
+        ip_options: F,
+    ) -> io::Result<Option<IpPacket<'_>>>
+    where
+        R: io::Read,
+        F: FnOnce(&R) -> &[u8],
+    {
+        let mut adapter = ReadAdapter::new(source, None);
+        let r = adapter.recv(&mut self.buffer[self.payload_index..])?;
+        if r == 0 {
+            return Ok(None);
+        }
+        self.set_ip_options(ip_options(source), r);
+        Ok(Some(self.build(r as u16)))
+    }
+
+    /// Replace the IPv4 options, moving the transport header and the `payload_length` bytes of
+    /// payload already in the buffer.
+    ///
+    /// The options are dropped if they are invalid or if the packet would not fit.
+    fn set_ip_options(&mut self, options: &[u8], payload_length: usize) {
+        if self.ip_header_data.is_ipv6() || self.ip_header_mut().ipv4_options() == options {
+            return;
+        }
+        let end = self.payload_index + payload_length;
+        let fits =
+            end - self.transport_index + IPV4_HEADER_LENGTH + options.len() <= MAX_PACKET_LENGTH;
+        let options = if ip_options::is_valid(options) && fits {
+            options
+        } else {
+            &[]
+        };
+        let transport_index = IPV4_HEADER_LENGTH + options.len();
+        self.buffer
+            .copy_within(self.transport_index..end, transport_index);
+        self.payload_index = self.payload_index + transport_index - self.transport_index;
+        self.transport_index = transport_index;
+        if let IpHeaderMut::V4(mut ip_header) = self.ip_header_mut() {
+            ip_header.set_options(options);
+        }
+    }
+
+    /// Length of the IP and transport headers prepended to every payload
+    pub fn headers_length(&self) -> u16 {
+        self.payload_index as u16
+    }
+
+    pub fn ip_header_mut(&mut self) -> IpHeaderMut<'_> {
         let raw = &mut self.buffer[..self.transport_index];
-        self.ipv4_header_data.bind_mut(raw)
+        self.ip_header_data.bind_mut(raw)
     }
 
-    pub fn transport_header_mut(&mut self) -> TransportHeaderMut {
+    pub fn transport_header_mut(&mut self) -> TransportHeaderMut<'_> {
         let raw = &mut self.buffer[self.transport_index..self.payload_index];
         self.transport_header_data.bind_mut(raw)
     }
 
-    fn build(&mut self, payload_length: u16) -> Ipv4Packet {
+    fn build(&mut self, payload_length: u16) -> IpPacket<'_> {
         let total_length = self.payload_index as u16 + payload_length;
 
-        self.ipv4_header_mut().set_total_length(total_length);
+        self.ip_header_mut().set_total_length(total_length);
         self.transport_header_mut()
             .set_payload_length(payload_length);
 
-        let mut ipv4_packet = Ipv4Packet::new(
+        let mut ip_packet = IpPacket::new(
             &mut self.buffer[..total_length as usize],
-            self.ipv4_header_data.clone(),
+            self.ip_header_data.clone(),
             self.transport_header_data.clone(),
         );
-        ipv4_packet.compute_checksums();
-        ipv4_packet
+        if self.checksum_disabled {
+            ip_packet.compute_ip_checksum();
+        } else {
+            ip_packet.compute_checksums();
+        }
+        ip_packet
     }
 
-    pub fn inflate(&mut self, packet_length: u16) -> Ipv4Packet {
-        Ipv4Packet::new(
+    #[allow(dead_code)]
+    pub fn inflate(&mut self, packet_length: u16) -> IpPacket<'_> {
+        IpPacket::new(
             &mut self.buffer[..packet_length as usize],
-            self.ipv4_header_data.clone(),
+            self.ip_header_data.clone(),
             self.transport_header_data.clone(),
         )
     }
@@ -140,8 +243,9 @@ impl Packetizer {
 mod tests {
     use super::*;
     use crate::relay::datagram::tests::MockDatagramSocket;
-    use byteorder::{BigEndian, WriteBytesExt};
+    use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
     use std::io;
+    use std::net::Ipv6Addr;
 
     fn create_packet() -> Vec<u8> {
         let mut raw = Vec::new();
@@ -164,59 +268,227 @@ mod tests {
         raw
     }
 
+    fn create_ipv6_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, traffic class, flow label
+        raw.write_u16::<BigEndian>(20).unwrap(); // payload length 8 + 8 + 4
+        raw.write_u8(0).unwrap(); // next header (hop-by-hop options)
+        raw.write_u8(64).unwrap(); // hop limit
+        raw.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        raw.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+
+        raw.write_u8(17).unwrap(); // next header (UDP)
+        raw.write_u8(0).unwrap(); // extension header length (in 8 bytes units, minus 1)
+        raw.extend_from_slice(&[1, 4, 0, 0, 0, 0]); // PadN option
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
+        raw.write_u16::<BigEndian>(12).unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.write_u32::<BigEndian>(0x11223344).unwrap(); // payload
+        raw
+    }
+
     #[test]
     fn merge_headers_and_payload() {
         let raw = &mut create_packet()[..];
-        let reference_packet = Ipv4Packet::parse(raw);
+        let reference_packet = IpPacket::parse(raw);
 
         let data = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
         let mut mock = MockDatagramSocket::from_data(&data);
 
-        let ipv4_header = reference_packet.ipv4_header();
+        let ip_header = reference_packet.ip_header();
         let transport_header = reference_packet.transport_header().unwrap();
-        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let mut packetizer = Packetizer::new(&ip_header, &transport_header);
 
         let packet = packetizer.packetize(&mut mock).unwrap();
-        assert_eq!(36, packet.ipv4_header_data().total_length());
+        assert_eq!(36, packet.ip_header_data().total_length());
         assert_eq!(data, &packet.raw()[28..36]);
+        // the UDP checksum is computed
+        assert_ne!(0, BigEndian::read_u16(&packet.raw()[26..28]));
+    }
+
+    #[test]
+    fn disable_udp_checksum() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = IpPacket::parse(raw);
+
+        let data = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        let mut mock = MockDatagramSocket::from_data(&data);
+
+        let ip_header = reference_packet.ip_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ip_header, &transport_header);
+        packetizer.disable_udp_checksum();
+
+        let packet = packetizer.packetize(&mut mock).unwrap();
+        assert_eq!(0, BigEndian::read_u16(&packet.raw()[26..28]));
+        // the IPv4 header checksum is still computed
+        assert_ne!(0, BigEndian::read_u16(&packet.raw()[10..12]));
+    }
+
+    #[test]
+    fn packetize_from_any_sender() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = IpPacket::parse(raw);
+
+        let data = [0x11u8, 0x22, 0x33, 0x44];
+        let sender = "192.0.2.7:3478".parse().unwrap();
+        let mut mock = MockDatagramSocket::from_sender(&data, sender);
+
+        let ip_header = reference_packet.ip_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ip_header, &transport_header);
+
+        {
+            let packet = packetizer.packetize_from(&mut mock, Some).unwrap().unwrap();
+            assert_eq!(sender.ip(), packet.ip_header_data().source());
+            assert_eq!(
+                Some(3478),
+                packet.transport_header_data().map(|t| t.source_port())
+            );
+            assert_eq!(data, packet.payload().unwrap());
+        }
+
+        // rejected sender
+        assert!(packetizer
+            .packetize_from(&mut mock, |_| None)
+            .unwrap()
+            .is_none());
+    }
+
+    // a source receiving a payload and the IPv4 options of its packet
+    struct OptionsSource<'a> {
+        payload: &'a [u8],
+        options: Vec<u8>,
+    }
+
+    impl io::Read for OptionsSource<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.payload.read(buf)
+        }
+    }
+
+    impl OptionsSource<'_> {
+        fn options(&self) -> &[u8] {
+            &self.options
+        }
+    }
+
+    #[test]
+    fn packetize_with_ip_options() {
+        let raw = &mut create_packet()[..];
+        let reference_packet = IpPacket::parse(raw);
+
+        let ip_header = reference_packet.ip_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ip_header, &transport_header);
+
+        // record route, with one address recorded
+        let options = vec![7, 7, 8, 127, 0, 0, 1, 0];
+        let data = [0x11u8, 0x22, 0x33, 0x44];
+        let mut source = OptionsSource {
+            payload: &data,
+            options: options.clone(),
+        };
+        {
+            let packet = packetizer
+                .packetize_read_with_ip_options(&mut source, OptionsSource::options)
+                .unwrap()
+                .unwrap();
+            assert_eq!(28, packet.ip_header_data().header_length());
+            assert_eq!(&options[..], packet.ip_header().ipv4_options());
+            assert_eq!(5678, packet.transport_header_data().unwrap().source_port());
+            assert_eq!(data, packet.payload().unwrap());
+        }
+
+        // the next packet has no options
+        let mut source = OptionsSource {
+            payload: &data,
+            options: Vec::new(),
+        };
+        let packet = packetizer
+            .packetize_read_with_ip_options(&mut source, OptionsSource::options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(20, packet.ip_header_data().header_length());
+        assert_eq!(32, packet.ip_header_data().total_length());
+        assert_eq!(data, packet.payload().unwrap());
+    }
+
+    #[test]
+    fn merge_ipv6_headers_and_payload() {
+        let raw = &mut create_ipv6_packet()[..];
+        let reference_packet = IpPacket::parse(raw);
+
+        let data = [0x11u8, 0x22, 0x33, 0x44, 0x55];
+        let mut mock = MockDatagramSocket::from_data(&data);
+
+        let ip_header = reference_packet.ip_header();
+        let transport_header = reference_packet.transport_header().unwrap();
+        let mut packetizer = Packetizer::new(&ip_header, &transport_header);
+        assert_eq!(48, packetizer.headers_length());
+
+        let packet = packetizer.packetize(&mut mock).unwrap();
+        // the extension header is not sent back
+        let ip_header_data = packet.ip_header_data();
+        assert_eq!(40, ip_header_data.header_length());
+        assert_eq!(53, ip_header_data.total_length());
+        assert_eq!(17, packet.raw()[6]);
+        assert_eq!(
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+            ip_header_data.source()
+        );
+        assert_eq!(data, &packet.raw()[48..]);
+
+        // the UDP checksum is mandatory for IPv6
+        let mut sum = ip_header_data.pseudo_header_sum(17);
+        for word in packet.raw()[40..].chunks(2) {
+            sum += u32::from(word[0]) << 8 | word.get(1).copied().map_or(0, u32::from);
+        }
+        while (sum & !0xFFFF) != 0 {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        assert_eq!(0xFFFF, sum);
     }
 
     #[test]
     fn last_packet() {
         let raw = &mut create_packet()[..];
-        let reference_packet = Ipv4Packet::parse(raw);
+        let reference_packet = IpPacket::parse(raw);
 
         let data = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
         let mut mock = MockDatagramSocket::from_data(&data);
 
-        let ipv4_header = reference_packet.ipv4_header();
+        let ip_header = reference_packet.ip_header();
         let transport_header = reference_packet.transport_header().unwrap();
-        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let mut packetizer = Packetizer::new(&ip_header, &transport_header);
 
         let packet_length = packetizer.packetize(&mut mock).unwrap().length();
         let packet = packetizer.inflate(packet_length);
-        assert_eq!(36, packet.ipv4_header_data().total_length());
+        assert_eq!(36, packet.ip_header_data().total_length());
         assert_eq!(data, &packet.raw()[28..36]);
     }
 
     #[test]
     fn packetize_chunks() {
         let raw = &mut create_packet()[..];
-        let reference_packet = Ipv4Packet::parse(raw);
+        let reference_packet = IpPacket::parse(raw);
 
         let data = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
         let mut cursor = io::Cursor::new(&data);
 
-        let ipv4_header = reference_packet.ipv4_header();
+        let ip_header = reference_packet.ip_header();
         let transport_header = reference_packet.transport_header().unwrap();
-        let mut packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let mut packetizer = Packetizer::new(&ip_header, &transport_header);
 
         {
             let packet = packetizer
                 .packetize_read(&mut cursor, Some(2))
                 .unwrap()
                 .unwrap();
-            assert_eq!(30, packet.ipv4_header_data().total_length());
+            assert_eq!(30, packet.ip_header_data().total_length());
             assert_eq!([0x11, 0x22], packet.payload().unwrap());
         }
 
@@ -225,7 +497,7 @@ mod tests {
                 .packetize_read(&mut cursor, Some(3))
                 .unwrap()
                 .unwrap();
-            assert_eq!(31, packet.ipv4_header_data().total_length());
+            assert_eq!(31, packet.ip_header_data().total_length());
             assert_eq!([0x33, 0x44, 0x55], packet.payload().unwrap());
         }
 
@@ -234,7 +506,7 @@ mod tests {
                 .packetize_read(&mut cursor, Some(1024))
                 .unwrap()
                 .unwrap();
-            assert_eq!(31, packet.ipv4_header_data().total_length());
+            assert_eq!(31, packet.ip_header_data().total_length());
             assert_eq!([0x66, 0x77, 0x88], packet.payload().unwrap());
         }
     }
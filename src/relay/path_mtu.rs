@@ -0,0 +1,183 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use socket2::SockRef;
+use std::io;
+
+use super::ip_header::IpHeaderData;
+use super::ip_packet::IpPacket;
+
+const IPV4_HEADER_LENGTH: usize = 20;
+
+/// Return the "don't fragment" flag of an IPv4 packet, or `None` for an IPv6 packet (which is
+/// never fragmented on the path).
+pub fn dont_fragment(ip_header: &IpHeaderData) -> Option<bool> {
+    match *ip_header {
+        IpHeaderData::V4(ref data) => Some(data.dont_fragment()),
+        IpHeaderData::V6(_) => None,
+    }
+}
+
+/// Return the MTU to report to the client if the IPv4 packet must not be fragmented, but does not
+/// fit in the path MTU of the (connected) socket.
+pub fn check(socket: SockRef, ip_packet: &IpPacket) -> Option<u16> {
+    if dont_fragment(ip_packet.ip_header_data()) != Some(true) {
+        return None;
+    }
+    let mtu = get(socket).ok()?;
+    // the host sends the same payload, but without the IP options
+    let header_length = ip_packet.ip_header_data().header_length() as usize;
+    let sent_length = IPV4_HEADER_LENGTH + ip_packet.length() as usize - header_length;
+    if sent_length > usize::from(mtu) {
+        Some(mtu)
+    } else {
+        None
+    }
+}
+
+/// Set the "don't fragment" flag of the IPv4 packets sent through the socket.
+///
+/// If set, the host never fragments them, and the routers report when they are too big (the
+/// packets larger than the known path MTU are rejected on send).
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: SockRef, dont_fragment: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let value: libc::c_int = if dont_fragment {
+        libc::IP_PMTUDISC_DO
+    } else {
+        libc::IP_PMTUDISC_DONT
+    };
+    // safe: the option value is a valid int, of the given length
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Return the path MTU known by the host for the destination of the (connected) IPv4 socket.
+#[cfg(target_os = "linux")]
+pub fn get(socket: SockRef) -> io::Result<u16> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // safe: the option value is a valid int, of the given length
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MTU,
+            &mut value as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // the loopback MTU (65536) does not fit
+    Ok(value.clamp(0, i32::from(u16::MAX)) as u16)
+}
+
+/// Indicate whether a send failed because the packet exceeded the path MTU.
+#[cfg(target_os = "linux")]
+pub fn is_too_big(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_socket: SockRef, _dont_fragment: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Path MTU discovery is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get(_socket: SockRef) -> io::Result<u16> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Path MTU discovery is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_too_big(_err: &io::Error) -> bool {
+    false
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use std::net::UdpSocket;
+
+    fn create_packet(flags: u16, payload_length: usize) -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>((20 + 8 + payload_length) as u16)
+            .unwrap(); // total length
+        raw.write_u16::<BigEndian>(0).unwrap(); // identification
+        raw.write_u16::<BigEndian>(flags).unwrap(); // flags and fragment offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x0A000002).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0x7F000001).unwrap(); // destination address
+
+        raw.write_u16::<BigEndian>(1234).unwrap(); // source port
+        raw.write_u16::<BigEndian>(53).unwrap(); // destination port
+        raw.write_u16::<BigEndian>((8 + payload_length) as u16)
+            .unwrap(); // length
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+
+        raw.extend_from_slice(&vec![0x42; payload_length]);
+        raw
+    }
+
+    #[test]
+    fn read_dont_fragment() {
+        let raw = create_packet(0x4000, 8);
+        assert_eq!(Some(true), dont_fragment(&IpHeaderData::parse(&raw)));
+        let raw = create_packet(0, 8);
+        assert_eq!(Some(false), dont_fragment(&IpHeaderData::parse(&raw)));
+    }
+
+    #[test]
+    fn check_loopback_mtu() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        // unknown until connected
+        assert!(get(SockRef::from(&socket)).is_err());
+        socket.connect("127.0.0.1:9").unwrap();
+        set_dont_fragment(SockRef::from(&socket), true).unwrap();
+        // the loopback MTU is 65536
+        assert_eq!(u16::MAX, get(SockRef::from(&socket)).unwrap());
+
+        let mut raw = create_packet(0x4000, 1400);
+        let packet = IpPacket::parse(&mut raw);
+        assert_eq!(None, check(SockRef::from(&socket), &packet));
+    }
+}
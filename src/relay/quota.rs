@@ -0,0 +1,301 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::{Datelike, Local, NaiveDate};
+use log::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::shaper;
+
+const TAG: &str = "Quota";
+
+// 128 kbit/s
+const DEFAULT_THROTTLE_RATE: u64 = 128_000 / 8;
+
+/// What happens to a device once its quota is exhausted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaAction {
+    Block,
+    Throttle,
+}
+
+/// Data allowances of a device, in bytes (both directions).
+#[derive(Clone, Debug)]
+pub struct QuotaPolicy {
+    daily: Option<u64>,
+    monthly: Option<u64>,
+    action: QuotaAction,
+    // bytes per second, in both directions, once throttled
+    throttle_rate: u64,
+}
+
+/// Bytes transferred by a device during the current day and month.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Usage {
+    day: NaiveDate,
+    daily_bytes: u64,
+    // any day of the current month
+    month: NaiveDate,
+    monthly_bytes: u64,
+    dirty: bool,
+}
+
+/// Usage of all the devices, by serial, saved to a state file to survive restarts.
+pub struct QuotaStore {
+    path: Option<PathBuf>,
+    usages: HashMap<String, Rc<RefCell<Usage>>>,
+}
+
+impl Default for QuotaPolicy {
+    fn default() -> Self {
+        Self {
+            daily: None,
+            monthly: None,
+            action: QuotaAction::Block,
+            throttle_rate: DEFAULT_THROTTLE_RATE,
+        }
+    }
+}
+
+impl QuotaPolicy {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not a quota key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "daily-quota" => self.daily = Some(shaper::parse_size(value)?),
+            "monthly-quota" => self.monthly = Some(shaper::parse_size(value)?),
+            "quota-action" => {
+                self.action = match value {
+                    "block" => QuotaAction::Block,
+                    "throttle" => QuotaAction::Throttle,
+                    _ => return Err(format!("invalid quota action \"{}\"", value)),
+                }
+            }
+            "quota-throttle-rate" => self.throttle_rate = shaper::parse_rate(value)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.daily.is_some() || self.monthly.is_some()
+    }
+
+    pub fn action(&self) -> QuotaAction {
+        self.action
+    }
+
+    pub fn throttle_rate(&self) -> u64 {
+        self.throttle_rate
+    }
+}
+
+impl Usage {
+    pub fn new(today: NaiveDate) -> Self {
+        Self {
+            day: today,
+            daily_bytes: 0,
+            month: today,
+            monthly_bytes: 0,
+            dirty: false,
+        }
+    }
+
+    // reset the counters of the elapsed periods
+    fn roll(&mut self, today: NaiveDate) {
+        if self.day != today {
+            self.day = today;
+            self.daily_bytes = 0;
+            self.dirty = true;
+        }
+        if (self.month.year(), self.month.month()) != (today.year(), today.month()) {
+            self.month = today;
+            self.monthly_bytes = 0;
+            self.dirty = true;
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64, today: NaiveDate) {
+        self.roll(today);
+        self.daily_bytes += bytes;
+        self.monthly_bytes += bytes;
+        self.dirty = true;
+    }
+
+    pub fn is_exceeded(&mut self, quota: &QuotaPolicy, today: NaiveDate) -> bool {
+        self.roll(today);
+        quota.daily.is_some_and(|daily| self.daily_bytes >= daily)
+            || quota
+                .monthly
+                .is_some_and(|monthly| self.monthly_bytes >= monthly)
+    }
+
+    // "2026-10-19 1234 2026-10 56789"
+    fn format(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.day.format("%Y-%m-%d"),
+            self.daily_bytes,
+            self.month.format("%Y-%m"),
+            self.monthly_bytes
+        )
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let mut fields = value.split_whitespace();
+        let day = NaiveDate::parse_from_str(fields.next()?, "%Y-%m-%d").ok()?;
+        let daily_bytes = fields.next()?.parse().ok()?;
+        let month =
+            NaiveDate::parse_from_str(&format!("{}-01", fields.next()?), "%Y-%m-%d").ok()?;
+        let monthly_bytes = fields.next()?.parse().ok()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Self {
+            day,
+            daily_bytes,
+            month,
+            monthly_bytes,
+            dirty: false,
+        })
+    }
+}
+
+pub fn today() -> NaiveDate {
+    Local::now().naive_local().date()
+}
+
+impl QuotaStore {
+    /// Load the usages from the state file, if any.
+    ///
+    /// A missing file is not an error: it is created on the first save.
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let mut store = Self {
+            path: path.map(Path::to_path_buf),
+            usages: HashMap::new(),
+        };
+        if let Some(path) = path {
+            match fs::read_to_string(path) {
+                Ok(content) => store.parse(&content),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(store)
+    }
+
+    fn parse(&mut self, content: &str) {
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(serial, usage)| Some((serial, Usage::parse(usage)?)));
+            match parsed {
+                Some((serial, usage)) => {
+                    self.usages
+                        .insert(serial.to_string(), Rc::new(RefCell::new(usage)));
+                }
+                None => warn!(target: TAG, "Ignoring invalid quota state: {}", line),
+            }
+        }
+    }
+
+    /// Return the usage of the device, shared by all its clients.
+    pub fn usage(&mut self, serial: &str) -> Rc<RefCell<Usage>> {
+        self.usages
+            .entry(serial.to_string())
+            .or_insert_with(|| Rc::new(RefCell::new(Usage::new(today()))))
+            .clone()
+    }
+
+    /// Write the state file if any usage changed.
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if !self.usages.values().any(|usage| usage.borrow().dirty) {
+            return Ok(());
+        }
+        let mut content = String::new();
+        let mut serials: Vec<&String> = self.usages.keys().collect();
+        serials.sort();
+        for serial in serials {
+            let mut usage = self.usages[serial].borrow_mut();
+            writeln!(content, "{} {}", serial, usage.format()).unwrap();
+            usage.dirty = false;
+        }
+        // replace the file atomically, so that a crash never leaves a truncated file
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn reset_counters() {
+        let mut quota = QuotaPolicy::default();
+        quota.set("daily-quota", "1k").unwrap();
+        quota.set("monthly-quota", "2k").unwrap();
+
+        let mut usage = Usage::new(date(2026, 10, 30));
+        usage.add(1000, date(2026, 10, 30));
+        assert!(!usage.is_exceeded(&quota, date(2026, 10, 30)));
+        usage.add(100, date(2026, 10, 30));
+        assert!(usage.is_exceeded(&quota, date(2026, 10, 30)));
+
+        // the next day, the daily counter is reset
+        assert!(!usage.is_exceeded(&quota, date(2026, 10, 31)));
+        usage.add(1000, date(2026, 10, 31));
+        // monthly quota exceeded
+        assert!(usage.is_exceeded(&quota, date(2026, 10, 31)));
+
+        // the next month, both are reset
+        assert!(!usage.is_exceeded(&quota, date(2026, 11, 1)));
+    }
+
+    #[test]
+    fn format_and_parse_usage() {
+        let mut usage = Usage::new(date(2026, 10, 19));
+        usage.add(1234, date(2026, 10, 19));
+        usage.dirty = false;
+        let formatted = usage.format();
+        assert_eq!("2026-10-19 1234 2026-10 1234", formatted);
+        let parsed = Usage::parse(&formatted).unwrap();
+        assert_eq!(1234, parsed.daily_bytes);
+        assert_eq!((2026, 10), (parsed.month.year(), parsed.month.month()));
+        assert!(Usage::parse("2026-10-19 1234").is_none());
+    }
+}
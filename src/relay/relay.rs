@@ -17,43 +17,69 @@
 use chrono::Local;
 use log::*;
 use mio::Events;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::max;
+use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+use super::config::Config;
+use super::icmp_socket::{IcmpSocket, IcmpSocketKind};
 use super::selector::Selector;
 use super::tunnel_server::TunnelServer;
-use super::udp_connection::IDLE_TIMEOUT_SECONDS;
 
 const TAG: &str = "Relay";
 const CLEANING_INTERVAL_SECONDS: i64 = 60;
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Relay {
     port: u16,
+    config: Rc<Config>,
 }
 
 impl Relay {
-    pub fn new(port: u16) -> Self {
-        Self { port }
+    pub fn new(port: u16, config: Config) -> Self {
+        Self {
+            port,
+            config: Rc::new(config),
+        }
     }
 
     pub fn run(&self) -> io::Result<()> {
         let mut selector = Selector::create().unwrap();
-        let tunnel_server = TunnelServer::create(self.port, &mut selector)?;
+        let tunnel_server = TunnelServer::create(self.port, self.config.clone(), &mut selector)?;
+        if let Some(path) = self.config.path() {
+            let watcher = ConfigWatcher::new(path.to_path_buf(), tunnel_server.clone());
+            watcher.start(&mut selector);
+        }
         info!(target: TAG, "Relay server started");
+        Self::report_icmp_support();
         self.poll_loop(&mut selector, &tunnel_server)
     }
 
+    fn report_icmp_support() {
+        match IcmpSocket::probe() {
+            Some(IcmpSocketKind::Ping) => {
+                info!(target: TAG, "Ping relayed through unprivileged ping sockets")
+            }
+            Some(IcmpSocketKind::Raw) => info!(target: TAG, "Ping relayed through raw sockets"),
+            None => warn!(
+                target: TAG,
+                "Ping unavailable: allow ping sockets (net.ipv4.ping_group_range) or run with \
+                 CAP_NET_RAW"
+            ),
+        }
+    }
+
     fn poll_loop(
         &self,
         selector: &mut Selector,
         tunnel_server: &Rc<RefCell<TunnelServer>>,
     ) -> io::Result<()> {
         let mut events = Events::with_capacity(1024);
-        // no connection may expire before the UDP idle timeout delay
-        let mut next_cleaning_deadline = Local::now().timestamp() + IDLE_TIMEOUT_SECONDS as i64;
+        let mut next_cleaning_deadline = Local::now().timestamp() + CLEANING_INTERVAL_SECONDS;
         loop {
             retry_on_intr!({
                 let timeout_seconds = max(0, next_cleaning_deadline - Local::now().timestamp());
@@ -61,11 +87,13 @@ impl Relay {
                 selector.poll(&mut events, timeout)
             })?;
 
+            let expired_timers = selector.run_timers();
+
             let now = Local::now().timestamp();
             if now >= next_cleaning_deadline {
                 tunnel_server.borrow_mut().clean_up(selector);
                 next_cleaning_deadline = now + CLEANING_INTERVAL_SECONDS;
-            } else if events.is_empty() {
+            } else if events.is_empty() && expired_timers == 0 {
                 debug!(
                     target: TAG,
                     "Spurious wakeup: poll() returned without any event"
@@ -77,3 +105,60 @@ impl Relay {
         }
     }
 }
+
+/// Reload the configuration file when it is modified.
+struct ConfigWatcher {
+    path: PathBuf,
+    modified: Cell<Option<SystemTime>>,
+    tunnel_server: Rc<RefCell<TunnelServer>>,
+}
+
+impl ConfigWatcher {
+    fn new(path: PathBuf, tunnel_server: Rc<RefCell<TunnelServer>>) -> Rc<Self> {
+        let modified = Cell::new(Self::modified_time(&path));
+        Rc::new(Self {
+            path,
+            modified,
+            tunnel_server,
+        })
+    }
+
+    fn modified_time(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn start(self: Rc<Self>, selector: &mut Selector) {
+        selector.schedule(CONFIG_CHECK_INTERVAL, move |selector: &mut Selector| {
+            self.check();
+            self.clone().start(selector);
+        });
+    }
+
+    fn check(&self) {
+        let modified = Self::modified_time(&self.path);
+        if modified == self.modified.get() {
+            return;
+        }
+        self.modified.set(modified);
+        match Config::load(&self.path) {
+            Ok(config) => {
+                info!(
+                    target: TAG,
+                    "Configuration reloaded from {}",
+                    self.path.display()
+                );
+                self.tunnel_server
+                    .borrow_mut()
+                    .update_config(Rc::new(config));
+            }
+            Err(err) => error!(
+                target: TAG,
+                "Cannot reload configuration from {}: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+}
@@ -18,35 +18,95 @@ use log::*;
 use std::cell::RefCell;
 use std::io;
 use std::rc::{Rc, Weak};
+use std::time::Instant;
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
+use super::config::Config;
+use super::connection::{Connection, ConnectionId, FlowKey};
+use super::device::Device;
+use super::flow_table::FlowTable;
+use super::fragmentation::Reassembler;
 use super::icmp_connection::IcmpConnection;
-use super::ipv4_header::Protocol;
-use super::ipv4_packet::Ipv4Packet;
+use super::icmp_error::{self, IcmpError};
+use super::icmpv6_connection::Icmpv6Connection;
+use super::ip_header::Protocol;
+use super::ip_packet::IpPacket;
+use super::limits::{self, ConnectionCounts, FdBudget, Rejection, RejectionCounters};
+use super::quota::{self, QuotaStore, Usage};
 use super::selector::Selector;
+use super::shaper::TokenBucket;
 use super::tcp_connection::TcpConnection;
+use super::transport_header::{TransportHeader, TransportHeaderData};
 use super::udp_connection::UdpConnection;
+use super::udp_group::{Classifier, Group};
+use super::udp_nat::Mapping;
 
 const TAG: &str = "Router";
 
 pub struct Router {
     client: Weak<RefCell<Client>>,
+    config: Rc<Config>,
     client_string: Option<String>,
-    // there are typically only few connections per client, HashMap would be less efficient
-    connections: Vec<Rc<RefCell<dyn Connection>>>,
+    device: Rc<Device>,
+    quota_store: Rc<RefCell<QuotaStore>>,
+    fd_budget: Rc<FdBudget>,
+    // limit the new connections per second
+    connection_rate: Option<TokenBucket>,
+    // refused connections since the last report
+    rejections: RejectionCounters,
+    // UDP packets dropped for an invalid checksum since the last report
+    invalid_checksums: u64,
+    // detect the UDP datagrams sent to a broadcast or multicast address
+    group_classifier: Classifier,
+    // UDP packets to a group dropped since the last report
+    dropped_broadcasts: u64,
+    dropped_multicasts: u64,
+    // indexed by their 5-tuple: a client may have thousands of connections
+    connections: FlowTable<FlowKey, Rc<RefCell<dyn Connection>>>,
+    // the connections of each protocol, to check the limits without counting them
+    connection_counts: ConnectionCounts,
+    // IPv4 fragments waiting for the rest of their datagram
+    reassembler: Reassembler,
 }
 
 impl Router {
-    pub fn new() -> Self {
+    pub fn new(
+        config: Rc<Config>,
+        quota_store: Rc<RefCell<QuotaStore>>,
+        fd_budget: Rc<FdBudget>,
+    ) -> Self {
+        // the default policy applies until the device serial is known
+        let usage = Rc::new(RefCell::new(Usage::new(quota::today())));
+        let device = Rc::new(Device::new(None, config.device(None).clone(), usage));
+        let connection_rate = Self::create_connection_rate(&device);
         Self {
             client: Weak::new(),
-            connections: Vec::new(),
+            config,
+            connections: FlowTable::default(),
+            connection_counts: ConnectionCounts::default(),
             client_string: None,
+            device,
+            quota_store,
+            fd_budget,
+            connection_rate,
+            rejections: RejectionCounters::default(),
+            invalid_checksums: 0,
+            group_classifier: Classifier::default(),
+            dropped_broadcasts: 0,
+            dropped_multicasts: 0,
+            reassembler: Reassembler::new(),
         }
     }
 
+    fn create_connection_rate(device: &Device) -> Option<TokenBucket> {
+        device
+            .policy()
+            .limits()
+            .max_rate()
+            .map(|rate| TokenBucket::new(rate, rate, Instant::now()))
+    }
+
     // expose client initialization after construction to break cyclic initialization dependencies
     pub fn set_client(&mut self, client: Weak<RefCell<Client>>) {
         self.client = client;
@@ -56,19 +116,81 @@ impl Router {
         self.client_string = Some(client_string);
     }
 
+    pub fn set_serial(&mut self, serial: &str) {
+        let policy = self.config.device(Some(serial)).clone();
+        let usage = self.quota_store.borrow_mut().usage(serial);
+        self.device = Rc::new(Device::new(Some(serial.to_string()), policy, usage));
+        self.connection_rate = Self::create_connection_rate(&self.device);
+    }
+
+    /// Apply a reloaded configuration to the device and its connections.
+    pub fn update_config(&mut self, config: Rc<Config>) {
+        let policy = config.device(self.device.serial()).clone();
+        self.device.set_policy(policy);
+        self.connection_rate = Self::create_connection_rate(&self.device);
+        self.config = config;
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
     pub fn send_to_network(
         &mut self,
         selector: &mut Selector,
         client_channel: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     ) {
-        if ipv4_packet.is_valid() {
-            match self.connection(selector, ipv4_packet) {
-                Ok(index) => {
+        if ip_packet.ip_header_data().is_fragment() {
+            if let Some(mut raw) = self.reassembler.push(ip_packet, Instant::now()) {
+                let reassembled = IpPacket::parse(&mut raw);
+                debug!(
+                    target: TAG,
+                    "Fragments reassembled ({} bytes)",
+                    reassembled.length()
+                );
+                self.route(selector, client_channel, &reassembled);
+            }
+        } else {
+            self.route(selector, client_channel, ip_packet);
+        }
+    }
+
+    fn route(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
+    ) {
+        if self.device.is_blocked() {
+            debug!(target: TAG, "Data quota exhausted, dropping packet");
+        } else if let Some(icmp_type) = Self::unsupported_icmp_type(ip_packet) {
+            debug!(
+                target: TAG,
+                "Dropping ICMP message of type {}, only echo requests are relayed",
+                icmp_type
+            );
+        } else if Self::has_invalid_checksum(ip_packet) {
+            // warn once per report period, to avoid flooding the logs
+            if self.invalid_checksums == 0 {
+                warn!(target: TAG, "Dropping UDP packet with an invalid checksum");
+            } else {
+                debug!(target: TAG, "Dropping UDP packet with an invalid checksum");
+            }
+            self.invalid_checksums += 1;
+        } else if let Some(group) = self.dropped_group(ip_packet) {
+            debug!(target: TAG, "Dropping UDP packet to a {:?} address", group);
+            match group {
+                Group::Broadcast => self.dropped_broadcasts += 1,
+                Group::Multicast => self.dropped_multicasts += 1,
+            }
+        } else if ip_packet.is_valid() {
+            match self.connection(selector, client_channel, ip_packet) {
+                Ok(Some(index)) => {
                     let closed = {
-                        let connection_ref = &self.connections[index];
+                        let connection_ref = self.connections.get(index);
                         let mut connection = connection_ref.borrow_mut();
-                        connection.send_to_network(selector, client_channel, ipv4_packet);
+                        connection.send_to_network(selector, client_channel, ip_packet);
                         if connection.is_closed() {
                             debug!(
                                 target: TAG,
@@ -82,10 +204,14 @@ impl Router {
                     };
                     if closed {
                         // the connection is closed, remove it
-                        self.connections.swap_remove(index);
+                        self.remove_at(index);
                     }
                 }
-                Err(err) => error!(target: TAG, "Cannot create route, dropping packet: {}", err),
+                Ok(None) => (), // the connection has been refused
+                Err(err) => {
+                    error!(target: TAG, "Cannot create route, dropping packet: {}", err);
+                    Self::report_unreachable(selector, client_channel, ip_packet, &err);
+                }
             }
         } else {
             warn!(target: TAG, "Dropping invalid packet");
@@ -93,85 +219,268 @@ impl Router {
                 trace!(
                     target: TAG,
                     "{}",
-                    binary::build_packet_string(ipv4_packet.raw())
+                    binary::build_packet_string(ip_packet.raw())
                 );
             }
         }
     }
 
+    /// Return the type of an ICMP message from the client which is not an echo request.
+    fn unsupported_icmp_type(ip_packet: &IpPacket) -> Option<u8> {
+        match ip_packet.transport_header_data() {
+            Some(TransportHeaderData::Icmp(icmp_header_data))
+                if !icmp_header_data.is_echo_request(ip_packet.ip_header_data().is_ipv6()) =>
+            {
+                Some(icmp_header_data.icmp_type())
+            }
+            _ => None,
+        }
+    }
+
+    /// Indicate whether the packet is a UDP datagram corrupted between the client and the relay.
+    fn has_invalid_checksum(ip_packet: &IpPacket) -> bool {
+        match (ip_packet.transport_header(), ip_packet.payload()) {
+            (Some(TransportHeader::Udp(udp_header)), Some(payload)) => {
+                !udp_header.is_checksum_valid(ip_packet.ip_header_data(), payload)
+            }
+            _ => false,
+        }
+    }
+
+    /// Return the group a UDP packet is sent to, if any.
+    fn udp_group(&mut self, ip_packet: &IpPacket) -> Option<Group> {
+        let ip_header_data = ip_packet.ip_header_data();
+        if ip_header_data.protocol() != Protocol::Udp {
+            return None;
+        }
+        self.group_classifier
+            .classify(ip_header_data.destination(), Instant::now())
+    }
+
+    /// Return the group a UDP packet is sent to, if the datagrams to this group are dropped.
+    fn dropped_group(&mut self, ip_packet: &IpPacket) -> Option<Group> {
+        self.udp_group(ip_packet)
+            .filter(|&group| self.device.policy().udp_groups().bridge(group).is_none())
+    }
+
+    /// Return the index of the connection for the packet, creating it if necessary.
+    ///
+    /// Return `Ok(None)` if the connection is refused (the client has been notified).
     fn connection(
         &mut self,
         selector: &mut Selector,
-        ipv4_packet: &Ipv4Packet,
-    ) -> io::Result<usize> {
-        let (ipv4_header_data, transport_header_data) = ipv4_packet.headers_data();
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
+    ) -> io::Result<Option<usize>> {
+        let (ip_header_data, transport_header_data) = ip_packet.headers_data();
         let transport_header_data = transport_header_data.expect("No transport");
-        let mut id = ConnectionId::from_headers(ipv4_header_data, transport_header_data);
-        id.set_client_string(self.client_string.clone());
+        // the connection id (formatted for the logs) is only built for a new connection
+        let mut key = FlowKey::from_headers(ip_header_data, transport_header_data);
+        // the datagrams to a group are bridged to an interface by a dedicated connection
+        let group = self.udp_group(ip_packet);
+        let any_destination = key.protocol() == Protocol::Udp
+            && group.is_none()
+            && self.device.policy().udp_nat().mapping() == Mapping::EndpointIndependent;
+        if any_destination {
+            // a single connection for all the destinations of the source port
+            key = key.any_destination();
+        }
 
-        let index = match self.find_index(&id) {
+        let index = match self.connections.find(&key) {
             Some(index) => index,
             None => {
-                let connection =
-                    Self::create_connection(selector, id, self.client.clone(), ipv4_packet)?;
-                let index = self.connections.len();
-                self.connections.push(connection);
-                index
+                let mut id = ConnectionId::from_headers(ip_header_data, transport_header_data);
+                if any_destination {
+                    id = id.any_destination();
+                }
+                id.set_client_string(self.client_string.clone());
+                if let Err(rejection) = self.check_limits(id.protocol()) {
+                    self.refuse(selector, client_channel, &id, ip_packet, rejection);
+                    return Ok(None);
+                }
+                let connection = match Self::create_connection(
+                    selector,
+                    id.clone(),
+                    self.client.clone(),
+                    &self.config,
+                    &self.device,
+                    ip_packet,
+                    group,
+                ) {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        self.fd_budget.release(1);
+                        if limits::is_out_of_files(&err) {
+                            let rejection = Rejection::OutOfFiles;
+                            self.refuse(selector, client_channel, &id, ip_packet, rejection);
+                            return Ok(None);
+                        }
+                        return Err(err);
+                    }
+                };
+                self.connection_counts.add(id.protocol());
+                self.connections.insert(key, connection)
             }
         };
-        Ok(index)
+        Ok(Some(index))
+    }
+
+    /// Reserve the resources for a new connection, if the limits allow it.
+    fn check_limits(&mut self, protocol: Protocol) -> Result<(), Rejection> {
+        let policy = self.device.policy();
+        let limits = policy.limits();
+        if let Some(max) = limits.max_connections(protocol) {
+            if self.connection_counts.get(protocol) >= max {
+                return Err(Rejection::TooManyConnections);
+            }
+        }
+        if let Some(ref mut connection_rate) = self.connection_rate {
+            if connection_rate.available(Instant::now()) == 0 {
+                return Err(Rejection::TooFast);
+            }
+        }
+        if !self.fd_budget.try_acquire() {
+            return Err(Rejection::OutOfFiles);
+        }
+        if let Some(ref mut connection_rate) = self.connection_rate {
+            connection_rate.consume(1);
+        }
+        Ok(())
+    }
+
+    /// Notify the client that its new connection is refused: RST for TCP, ICMP error for UDP.
+    fn refuse(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        id: &ConnectionId,
+        ip_packet: &IpPacket,
+        rejection: Rejection,
+    ) {
+        // warn once per report period, to avoid flooding the logs
+        if self.rejections.total() == 0 {
+            cx_warn!(target: TAG, id, "Refusing new connections: {}", rejection);
+        } else {
+            cx_debug!(target: TAG, id, "Refused: {}", rejection);
+        }
+        self.rejections.count(rejection);
+
+        let reply = match id.protocol() {
+            Protocol::Tcp => TcpConnection::forge_reset(ip_packet),
+            Protocol::Udp => Some(icmp_error::forge(
+                ip_packet,
+                IcmpError::AdministrativelyProhibited,
+            )),
+            // a ping just times out
+            _ => None,
+        };
+        if let Some(raw) = reply {
+            Self::reply_to_client(selector, client_channel, raw);
+        }
+    }
+
+    /// Notify the client that the destination cannot be reached from the relay, so that the app
+    /// fails immediately instead of waiting for its own timeout.
+    fn report_unreachable(
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
+        err: &io::Error,
+    ) {
+        let error = IcmpError::from_io_error(err);
+        let reply = match ip_packet.ip_header_data().protocol() {
+            // a refused connection is reset, as the destination host would do
+            Protocol::Tcp => match error {
+                None | Some(IcmpError::PortUnreachable) => TcpConnection::forge_reset(ip_packet),
+                Some(error) => Some(icmp_error::forge(ip_packet, error)),
+            },
+            Protocol::Udp => error.map(|error| icmp_error::forge(ip_packet, error)),
+            // never reply an ICMP error to an ICMP message (RFC 1122 section 3.2.2)
+            _ => None,
+        };
+        if let Some(raw) = reply {
+            debug!(target: TAG, "Reporting unreachable destination to client");
+            Self::reply_to_client(selector, client_channel, raw);
+        }
+    }
+
+    fn reply_to_client(
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        mut raw: Vec<u8>,
+    ) {
+        let reply = IpPacket::parse(&mut raw);
+        if let Err(err) = client_channel.send_to_client(selector, &reply) {
+            warn!(target: TAG, "Cannot send reply to client: {}", err);
+        }
+    }
+
+    fn remove_at(&mut self, index: usize) {
+        // the connection may be borrowed (removing itself), its key is not
+        let protocol = self.connections.key(index).protocol();
+        self.connection_counts.remove(protocol);
+        self.connections.remove_at(index);
+        // the socket is closed once the connection is dropped
+        self.fd_budget.release(1);
     }
 
     fn create_connection(
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
-        ipv4_packet: &Ipv4Packet,
+        config: &Config,
+        device: &Rc<Device>,
+        ip_packet: &IpPacket,
+        group: Option<Group>,
     ) -> io::Result<Rc<RefCell<dyn Connection>>> {
-        let (ipv4_header, transport_header) = ipv4_packet.headers();
+        let (ip_header, transport_header) = ip_packet.headers();
         let transport_header = transport_header.expect("No transport");
         match id.protocol() {
             Protocol::Tcp => Ok(TcpConnection::create(
                 selector,
                 id,
                 client,
-                ipv4_header,
+                config.http_proxy(),
+                device.clone(),
+                ip_header,
                 transport_header,
             )?),
             Protocol::Udp => Ok(UdpConnection::create(
                 selector,
                 id,
                 client,
-                ipv4_header,
+                device.clone(),
+                ip_header,
                 transport_header,
+                group,
             )?),
             Protocol::Icmp => Ok(IcmpConnection::create(
                 selector,
                 id,
                 client,
-                ipv4_header,
+                device.policy().egress(),
+                ip_header,
                 transport_header,
             )?),
-            p => Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unsupported protocol: {:?}", p),
-            )),
+            Protocol::Icmpv6 => Ok(Icmpv6Connection::create(
+                selector,
+                id,
+                client,
+                device.policy().egress(),
+                ip_header,
+                transport_header,
+            )?),
+            p => Err(io::Error::other(format!("Unsupported protocol: {:?}", p))),
         }
     }
 
-    fn find_index(&self, id: &ConnectionId) -> Option<usize> {
-        self.connections
-            .iter()
-            .position(|connection| connection.borrow().id() == id)
-    }
-
     pub fn remove(&mut self, connection: &dyn Connection) {
         let index = self
             .connections
-            .iter()
-            .position(|item| {
-                // compare (thin) pointers to find the connection to remove
-                binary::ptr_data_eq(connection, item.as_ptr())
+            .find(&connection.id().flow_key())
+            .filter(|&index| {
+                // compare (thin) pointers to check that this is the connection to remove
+                binary::ptr_data_eq(connection, self.connections.get(index).as_ptr())
             })
             .expect("Removing an unknown connection");
         debug!(
@@ -179,36 +488,86 @@ impl Router {
             "Self-removing connection from router: {}",
             connection.id()
         );
-        self.connections.swap_remove(index);
+        self.remove_at(index);
     }
 
     pub fn clear(&mut self, selector: &mut Selector) {
-        for connection in &mut self.connections {
+        for connection in self.connections.values() {
             connection.borrow_mut().close(selector);
         }
+        self.fd_budget.release(self.connections.len());
         self.connections.clear();
+        self.connection_counts.clear();
+        self.reassembler.clear();
     }
 
-    pub fn clean_expired_connections(&mut self, selector: &mut Selector) {
+    pub fn clean_expired_connections(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+    ) {
         // remove the last items first, otherwise i might not be less than len() on swap_remove(i)
         for i in (0..self.connections.len()).rev() {
             let expired = {
-                let mut connection = self.connections[i].borrow_mut();
+                let mut connection = self.connections.get(i).borrow_mut();
                 if connection.is_expired() {
                     debug!(
                         target: TAG,
                         "Removing expired connection from router: {}",
                         connection.id()
                     );
-                    connection.close(selector);
+                    connection.expire(selector, client_channel);
                     true
                 } else {
                     false
                 }
             };
             if expired {
-                self.connections.swap_remove(i);
+                self.remove_at(i);
             }
         }
+        self.reassembler.remove_expired(Instant::now());
+        self.report_rejections();
+        self.report_invalid_checksums();
+        self.report_dropped_groups();
+    }
+
+    fn report_rejections(&mut self) {
+        if self.rejections.total() > 0 {
+            info!(
+                target: TAG,
+                "[{}] Refused {} new connections ({})",
+                self.client_string.as_deref().unwrap_or("UNKNOWN_CLIENT"),
+                self.rejections.total(),
+                self.rejections
+            );
+            self.rejections = RejectionCounters::default();
+        }
+    }
+
+    fn report_invalid_checksums(&mut self) {
+        if self.invalid_checksums > 0 {
+            info!(
+                target: TAG,
+                "[{}] Dropped {} UDP packets with an invalid checksum",
+                self.client_string.as_deref().unwrap_or("UNKNOWN_CLIENT"),
+                self.invalid_checksums
+            );
+            self.invalid_checksums = 0;
+        }
+    }
+
+    fn report_dropped_groups(&mut self) {
+        if self.dropped_broadcasts > 0 || self.dropped_multicasts > 0 {
+            info!(
+                target: TAG,
+                "[{}] Dropped {} broadcast and {} multicast UDP packets",
+                self.client_string.as_deref().unwrap_or("UNKNOWN_CLIENT"),
+                self.dropped_broadcasts,
+                self.dropped_multicasts
+            );
+            self.dropped_broadcasts = 0;
+            self.dropped_multicasts = 0;
+        }
     }
 }
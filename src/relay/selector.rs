@@ -17,9 +17,10 @@
 use log::*;
 use mio::{Event, Evented, Events, Poll, PollOpt, Ready, Token};
 use slab::Slab;
+use std::collections::BTreeMap;
 use std::io;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const TAG: &str = "Selector";
 
@@ -36,11 +37,42 @@ where
     }
 }
 
+pub trait TimerHandler {
+    fn on_timeout(&self, selector: &mut Selector);
+}
+
+impl<F> TimerHandler for F
+where
+    F: Fn(&mut Selector),
+{
+    fn on_timeout(&self, selector: &mut Selector) {
+        self(selector);
+    }
+}
+
+/// Identify a scheduled timer, to cancel it.
+///
+/// Ordered by deadline, so that the timers map is sorted by expiration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerToken {
+    deadline: Instant,
+    // disambiguate timers having the same deadline
+    id: u64,
+}
+
+impl TimerToken {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
 pub struct Selector {
     poll: Poll,
     handlers: Slab<Rc<dyn EventHandler>>,
     // tokens to be removed after all the current poll events are executed
     tokens_to_remove: Vec<Token>,
+    timers: BTreeMap<TimerToken, Rc<dyn TimerHandler>>,
+    next_timer_id: u64,
 }
 
 impl Selector {
@@ -49,6 +81,8 @@ impl Selector {
             poll: Poll::new()?,
             handlers: Slab::with_capacity(1024),
             tokens_to_remove: Vec::new(),
+            timers: BTreeMap::new(),
+            next_timer_id: 0,
         })
     }
 
@@ -103,10 +137,53 @@ impl Selector {
         self.tokens_to_remove.clear();
     }
 
+    /// Call `handler` once, after `delay`.
+    pub fn schedule<H>(&mut self, delay: Duration, handler: H) -> TimerToken
+    where
+        H: TimerHandler + 'static,
+    {
+        let timer = TimerToken {
+            deadline: Instant::now() + delay,
+            id: self.next_timer_id,
+        };
+        self.next_timer_id += 1;
+        self.timers.insert(timer, Rc::new(handler));
+        timer
+    }
+
+    /// Cancel a timer. Does nothing if it has already been triggered.
+    pub fn cancel(&mut self, timer: TimerToken) {
+        self.timers.remove(&timer);
+    }
+
     pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<usize> {
+        // wake up for the next timer
+        let timeout = match self.timers.keys().next() {
+            Some(timer) => {
+                let until_deadline = timer.deadline.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(until_deadline, |t| t.min(until_deadline)))
+            }
+            None => timeout,
+        };
         self.poll.poll(events, timeout)
     }
 
+    /// Call the handlers of the expired timers, and return how many were called.
+    pub fn run_timers(&mut self) -> usize {
+        let now = Instant::now();
+        let mut count = 0;
+        // a handler may schedule new timers, so do not iterate on the map directly
+        while let Some(&timer) = self.timers.keys().next() {
+            if timer.deadline > now {
+                break;
+            }
+            let handler = self.timers.remove(&timer).unwrap();
+            handler.on_timeout(self);
+            count += 1;
+        }
+        count
+    }
+
     pub fn run_handlers(&mut self, events: &Events) {
         for event in events {
             debug!(target: TAG, "event={:?}", event);
@@ -0,0 +1,273 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::num::Wrapping;
+use std::time::{Duration, Instant};
+
+// RFC 6298, with a lower minimum (as Linux), the USB link is fast
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+
+// RFC 5681 section 3.2
+const DUPLICATE_ACK_THRESHOLD: u32 = 3;
+
+struct Segment {
+    sequence_number: u32,
+    // in sequence space (a FIN counts for 1)
+    length: u32,
+    packet: Vec<u8>,
+    // None while it waits for space in the client buffer
+    sent_at: Option<Instant>,
+    retransmitted: bool,
+}
+
+impl Segment {
+    fn end(&self) -> u32 {
+        (Wrapping(self.sequence_number) + Wrapping(self.length)).0
+    }
+}
+
+/// The segments sent to the client but not acknowledged yet, and the retransmission timeout
+/// estimation (RFC 6298).
+pub struct SendQueue {
+    segments: VecDeque<Segment>,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    // consecutive retransmissions of the first segment
+    retransmissions: u32,
+    duplicate_acks: u32,
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        Self {
+            segments: VecDeque::new(),
+            srtt: None,
+            rttvar: Duration::from_secs(0),
+            rto: INITIAL_RTO,
+            retransmissions: 0,
+            duplicate_acks: 0,
+        }
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    pub fn retransmissions(&self) -> u32 {
+        self.retransmissions
+    }
+
+    /// Indicate whether a segment delivered to the client waits for its acknowledgement.
+    pub fn is_in_flight(&self) -> bool {
+        self.segments
+            .front()
+            .is_some_and(|segment| segment.sent_at.is_some())
+    }
+
+    /// Keep a copy of a packet sent to the client, for retransmission.
+    ///
+    /// `sent_at` is `None` if the packet could not be delivered yet (the client buffer is full).
+    pub fn push(
+        &mut self,
+        sequence_number: u32,
+        length: u32,
+        packet: Vec<u8>,
+        sent_at: Option<Instant>,
+    ) {
+        self.segments.push_back(Segment {
+            sequence_number,
+            length,
+            packet,
+            sent_at,
+            retransmitted: false,
+        });
+    }
+
+    /// Record the delivery of the last packet, which was waiting for space in the client buffer.
+    pub fn delivered(&mut self, now: Instant) {
+        if let Some(segment) = self.segments.back_mut() {
+            segment.sent_at.get_or_insert(now);
+        }
+    }
+
+    /// Remove the segments fully acknowledged by `acknowledgement_number`.
+    ///
+    /// Return `true` if new data is acknowledged.
+    pub fn ack(&mut self, acknowledgement_number: u32, now: Instant) -> bool {
+        let mut acked = false;
+        while let Some(segment) = self.segments.front() {
+            let distance = (Wrapping(acknowledgement_number) - Wrapping(segment.end())).0 as i32;
+            if distance < 0 {
+                break;
+            }
+            // Karn's algorithm: the RTT of a retransmitted segment is ambiguous
+            if let Some(sent_at) = segment.sent_at.filter(|_| !segment.retransmitted) {
+                self.update_rto(now.saturating_duration_since(sent_at));
+            }
+            self.segments.pop_front();
+            acked = true;
+        }
+        if acked {
+            self.retransmissions = 0;
+            self.duplicate_acks = 0;
+        }
+        acked
+    }
+
+    /// Count a duplicate ACK. Return `true` if the first segment must be fast-retransmitted.
+    pub fn duplicate_ack(&mut self) -> bool {
+        if self.segments.is_empty() {
+            return false;
+        }
+        self.duplicate_acks += 1;
+        self.duplicate_acks == DUPLICATE_ACK_THRESHOLD
+    }
+
+    fn update_rto(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + self.rttvar * 4;
+        self.rto = cmp::min(cmp::max(rto, MIN_RTO), MAX_RTO);
+    }
+
+    /// Return the first unacknowledged packet, to retransmit it.
+    ///
+    /// Return `None` if it has not been delivered yet: it is still pending, not lost.
+    ///
+    /// On timeout (`backoff`), the RTO is doubled (RFC 6298 section 5.5).
+    pub fn retransmit(&mut self, now: Instant, backoff: bool) -> Option<&mut Vec<u8>> {
+        let segment = self
+            .segments
+            .front_mut()
+            .filter(|segment| segment.sent_at.is_some())?;
+        segment.retransmitted = true;
+        segment.sent_at = Some(now);
+        if backoff {
+            self.rto = cmp::min(self.rto * 2, MAX_RTO);
+            self.retransmissions += 1;
+        }
+        Some(&mut segment.packet)
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_segments() {
+        let now = Instant::now();
+        let mut queue = SendQueue::new();
+        queue.push(1000, 100, vec![1], Some(now));
+        queue.push(1100, 100, vec![2], Some(now));
+        queue.push(1200, 1, vec![3], Some(now)); // FIN
+
+        assert!(!queue.ack(1000, now));
+        // partially acknowledged
+        assert!(!queue.ack(1050, now));
+        assert!(queue.ack(1200, now));
+        assert_eq!(Some(&mut vec![3]), queue.retransmit(now, false));
+        assert!(queue.ack(1201, now));
+        assert!(!queue.is_in_flight());
+    }
+
+    #[test]
+    fn estimate_rto() {
+        let now = Instant::now();
+        let mut queue = SendQueue::new();
+        assert_eq!(INITIAL_RTO, queue.rto());
+
+        queue.push(0, 10, vec![], Some(now));
+        queue.ack(10, now + Duration::from_millis(400));
+        // srtt = 400ms, rttvar = 200ms
+        assert_eq!(Duration::from_millis(1200), queue.rto());
+
+        // the minimum RTO applies for very short RTT
+        let mut queue = SendQueue::new();
+        queue.push(0, 10, vec![], Some(now));
+        queue.ack(10, now + Duration::from_millis(1));
+        assert_eq!(MIN_RTO, queue.rto());
+    }
+
+    #[test]
+    fn backoff_and_karn() {
+        let now = Instant::now();
+        let mut queue = SendQueue::new();
+        queue.push(0, 10, vec![], Some(now));
+        assert!(queue.retransmit(now, true).is_some());
+        assert!(queue.retransmit(now, true).is_some());
+        assert_eq!(INITIAL_RTO * 4, queue.rto());
+        assert_eq!(2, queue.retransmissions());
+
+        // no RTT sample from a retransmitted segment
+        assert!(queue.ack(10, now + Duration::from_secs(10)));
+        assert_eq!(INITIAL_RTO * 4, queue.rto());
+        assert_eq!(0, queue.retransmissions());
+    }
+
+    #[test]
+    fn fast_retransmit() {
+        let now = Instant::now();
+        let mut queue = SendQueue::new();
+        assert!(!queue.duplicate_ack());
+        queue.push(0, 10, vec![], Some(now));
+        assert!(!queue.duplicate_ack());
+        assert!(!queue.duplicate_ack());
+        assert!(queue.duplicate_ack());
+        // only once
+        assert!(!queue.duplicate_ack());
+        queue.ack(10, now);
+        queue.push(10, 10, vec![], Some(now));
+        assert!(!queue.duplicate_ack());
+    }
+
+    #[test]
+    fn pending_delivery() {
+        let now = Instant::now();
+        let mut queue = SendQueue::new();
+        // the client buffer is full
+        queue.push(0, 10, vec![1], None);
+        assert!(!queue.is_in_flight());
+        // not lost, so not retransmitted
+        assert!(queue.retransmit(now, true).is_none());
+        assert_eq!(0, queue.retransmissions());
+        assert_eq!(INITIAL_RTO, queue.rto());
+
+        // the RTT is measured from the delivery, not from the queuing
+        queue.delivered(now + Duration::from_secs(5));
+        assert!(queue.is_in_flight());
+        queue.ack(10, now + Duration::from_millis(5400));
+        assert_eq!(Duration::from_millis(1200), queue.rto());
+    }
+}
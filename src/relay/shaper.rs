@@ -0,0 +1,247 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+const DEFAULT_BURST: u64 = 64 * 1024;
+
+/// Bandwidth limits of a device, in bytes per second.
+#[derive(Clone, Debug, Default)]
+pub struct ShapingPolicy {
+    upload_rate: Option<u64>,
+    upload_burst: Option<u64>,
+    download_rate: Option<u64>,
+    download_burst: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    // from the device to the network
+    Upload,
+    // from the network to the device
+    Download,
+}
+
+/// Token bucket, allowing `rate` bytes per second on average, and bursts up to `burst` bytes.
+///
+/// The bucket may go into debt, so that a whole datagram can always be sent once some tokens
+/// are available.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    tokens: i64,
+    last_refill: Instant,
+}
+
+/// Shape the traffic of all the connections of a device.
+#[derive(Debug, Default)]
+pub struct Shaper {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl ShapingPolicy {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not a shaping key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "upload-rate" => self.upload_rate = Some(parse_rate(value)?),
+            "upload-burst" => self.upload_burst = Some(parse_size(value)?),
+            "download-rate" => self.download_rate = Some(parse_rate(value)?),
+            "download-burst" => self.download_burst = Some(parse_size(value)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn upload_rate(&self) -> Option<u64> {
+        self.upload_rate
+    }
+
+    pub fn download_rate(&self) -> Option<u64> {
+        self.download_rate
+    }
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        assert!(rate > 0, "Rate must be positive");
+        Self {
+            rate,
+            burst,
+            tokens: burst as i64,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let added = elapsed.as_nanos() * u128::from(self.rate) / 1_000_000_000;
+        // do not move last_refill while the elapsed time is worth less than 1 token
+        if added > 0 {
+            let tokens = cmp::min(i128::from(self.tokens) + added as i128, self.burst as i128);
+            self.tokens = tokens as i64;
+            self.last_refill = now;
+        }
+    }
+
+    /// Return the number of bytes which may be transferred now.
+    pub fn available(&mut self, now: Instant) -> usize {
+        self.refill(now);
+        cmp::max(self.tokens, 0) as usize
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as i64;
+    }
+
+    /// Return the time to wait before some bytes may be transferred, or `None` if they may be
+    /// transferred now.
+    pub fn delay(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.tokens > 0 {
+            None
+        } else {
+            let missing = (1 - self.tokens) as u128;
+            let nanos = (missing * 1_000_000_000).div_ceil(u128::from(self.rate));
+            let nanos = cmp::min(nanos, u128::from(u64::MAX)) as u64;
+            Some(Duration::from_nanos(nanos))
+        }
+    }
+}
+
+impl Shaper {
+    /// Limit both directions to `rate` bytes per second.
+    pub fn throttled(rate: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            upload: Some(TokenBucket::new(rate, DEFAULT_BURST, now)),
+            download: Some(TokenBucket::new(rate, DEFAULT_BURST, now)),
+        }
+    }
+
+    pub fn new(policy: &ShapingPolicy) -> Self {
+        let now = Instant::now();
+        let bucket = |rate: Option<u64>, burst: Option<u64>| {
+            rate.map(|rate| TokenBucket::new(rate, burst.unwrap_or(DEFAULT_BURST), now))
+        };
+        Self {
+            upload: bucket(policy.upload_rate, policy.upload_burst),
+            download: bucket(policy.download_rate, policy.download_burst),
+        }
+    }
+
+    fn bucket(&mut self, direction: Direction) -> Option<&mut TokenBucket> {
+        match direction {
+            Direction::Upload => self.upload.as_mut(),
+            Direction::Download => self.download.as_mut(),
+        }
+    }
+
+    /// Return the number of bytes which may be transferred now (`usize::MAX` if unlimited).
+    pub fn quota(&mut self, direction: Direction) -> usize {
+        self.bucket(direction)
+            .map_or(usize::MAX, |bucket| bucket.available(Instant::now()))
+    }
+
+    pub fn consume(&mut self, direction: Direction, bytes: usize) {
+        if let Some(bucket) = self.bucket(direction) {
+            bucket.consume(bytes);
+        }
+    }
+
+    /// Return the time to wait before some bytes may be transferred, or `None` if they may be
+    /// transferred now.
+    pub fn delay(&mut self, direction: Direction) -> Option<Duration> {
+        self.bucket(direction)
+            .and_then(|bucket| bucket.delay(Instant::now()))
+    }
+}
+
+// "512k" or "2M": bits per second, like tc; return bytes per second
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    let bits =
+        parse_with_suffix(value, 1000).ok_or_else(|| format!("invalid rate \"{}\"", value))?;
+    let bytes = bits / 8;
+    if bytes == 0 {
+        return Err(format!("rate too low \"{}\"", value));
+    }
+    Ok(bytes)
+}
+
+// "64k" or "1M": bytes
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    match parse_with_suffix(value, 1024) {
+        Some(size) if size > 0 => Ok(size),
+        _ => Err(format!("invalid size \"{}\"", value)),
+    }
+}
+
+fn parse_with_suffix(value: &str, unit: u64) -> Option<u64> {
+    let (number, multiplier) = match value.chars().last()? {
+        'k' | 'K' => (&value[..value.len() - 1], unit),
+        'm' | 'M' => (&value[..value.len() - 1], unit * unit),
+        'g' | 'G' => (&value[..value.len() - 1], unit * unit * unit),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(Ok(64_000), parse_rate("512k"));
+        assert_eq!(Ok(250_000), parse_rate("2M"));
+        assert_eq!(Ok(125), parse_rate("1000"));
+        assert!(parse_rate("1").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert_eq!(Ok(65536), parse_size("64k"));
+        assert!(parse_size("0").is_err());
+    }
+
+    #[test]
+    fn consume_and_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, 500, start);
+        assert_eq!(500, bucket.available(start));
+        bucket.consume(700);
+        assert_eq!(0, bucket.available(start));
+        // 201 tokens are needed to get 1 available byte
+        assert_eq!(Some(Duration::from_millis(201)), bucket.delay(start));
+
+        let later = start + Duration::from_millis(300);
+        assert_eq!(100, bucket.available(later));
+        assert_eq!(None, bucket.delay(later));
+
+        // never more than the burst size
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(500, bucket.available(much_later));
+    }
+
+    #[test]
+    fn unlimited_shaper() {
+        let mut shaper = Shaper::new(&ShapingPolicy::default());
+        shaper.consume(Direction::Upload, 1 << 20);
+        assert_eq!(usize::MAX, shaper.quota(Direction::Upload));
+        assert_eq!(None, shaper.delay(Direction::Download));
+    }
+}
@@ -0,0 +1,299 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use mio::Ready;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use super::icmp_error::IcmpError;
+
+/// An ICMP error received by the host about a packet sent through a socket.
+///
+/// It is read from the error queue of the socket (`IP_RECVERR` and `IPV6_RECVERR`, only
+/// supported on Linux). Unlike the errno reported by a normal read, it tells which host sent the
+/// error (e.g. the router where the TTL expired), so that it can be reported to the client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SocketError {
+    source: IpAddr,
+    icmp_type: u8,
+    code: u8,
+    // type-specific data, e.g. the next-hop MTU for "fragmentation needed"
+    info: u32,
+    // the destination of the packet which caused the error, if known
+    destination: Option<SocketAddr>,
+}
+
+impl SocketError {
+    pub fn new(source: IpAddr, icmp_type: u8, code: u8, info: u32) -> Self {
+        Self {
+            source,
+            icmp_type,
+            code,
+            info,
+            destination: None,
+        }
+    }
+
+    /// Set the destination of the packet which caused the error.
+    pub fn with_destination(mut self, destination: SocketAddr) -> Self {
+        self.destination = Some(destination);
+        self
+    }
+
+    /// The host which sent the error
+    pub fn source(&self) -> IpAddr {
+        self.source
+    }
+
+    /// The destination of the packet which caused the error (useful for unconnected sockets)
+    pub fn destination(&self) -> Option<SocketAddr> {
+        self.destination
+    }
+
+    /// The error to report to the client, if it is relayed.
+    pub fn icmp_error(&self) -> Option<IcmpError> {
+        IcmpError::from_icmp(self.source.is_ipv6(), self.icmp_type, self.code, self.info)
+    }
+}
+
+/// Queue the ICMP errors received about the packets sent through the socket.
+#[cfg(target_os = "linux")]
+pub fn enable<S: std::os::unix::io::AsRawFd>(socket: &S, ipv6: bool) -> io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_RECVERR)
+    };
+    let enable: libc::c_int = 1;
+    // safe: the option value is a valid int, of the given length
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive the next queued error, if any.
+///
+/// The payload of the packet which caused the error is written into `buf`. Return its length
+/// along with the error, or `None` for an error not originating from an ICMP message (e.g. a
+/// local error), which is dequeued anyway.
+#[cfg(target_os = "linux")]
+pub fn recv<S: std::os::unix::io::AsRawFd>(
+    socket: &S,
+    buf: &mut [u8],
+) -> io::Result<Option<(Option<SocketError>, usize)>> {
+    use std::mem;
+
+    const SO_EE_ORIGIN_ICMP: u8 = 2;
+    const SO_EE_ORIGIN_ICMP6: u8 = 3;
+
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 for the alignment of the control messages
+    let mut control = [0u64; 64];
+    // safe: a zeroed sockaddr_storage is valid
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    // safe: a zeroed msghdr is valid
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of_val(&name) as _;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    // safe: the buffers outlive the call
+    let size = unsafe {
+        libc::recvmsg(
+            socket.as_raw_fd(),
+            &mut msg,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        )
+    };
+    if size == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            // the queue is empty
+            return Ok(None);
+        }
+        return Err(err);
+    }
+
+    let mut error = None;
+    // safe: the control messages were written by the kernel, and are bounded by msg_controllen
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            let header = &*cmsg;
+            let is_recverr = (header.cmsg_level == libc::IPPROTO_IP
+                && header.cmsg_type == libc::IP_RECVERR)
+                || (header.cmsg_level == libc::IPPROTO_IPV6
+                    && header.cmsg_type == libc::IPV6_RECVERR);
+            if is_recverr {
+                let data = libc::CMSG_DATA(cmsg);
+                let extended_err = std::ptr::read_unaligned(data as *const libc::sock_extended_err);
+                if extended_err.ee_origin == SO_EE_ORIGIN_ICMP
+                    || extended_err.ee_origin == SO_EE_ORIGIN_ICMP6
+                {
+                    // the address of the sender follows (SO_EE_OFFENDER)
+                    let offender = data.add(mem::size_of::<libc::sock_extended_err>());
+                    if let Some(source) = read_address(offender) {
+                        let mut socket_error = SocketError::new(
+                            source.ip(),
+                            extended_err.ee_type,
+                            extended_err.ee_code,
+                            extended_err.ee_info,
+                        );
+                        // the name is the destination of the packet which caused the error
+                        if msg.msg_namelen > 0 {
+                            let name = &name as *const libc::sockaddr_storage as *const u8;
+                            if let Some(destination) = read_address(name) {
+                                socket_error = socket_error.with_destination(destination);
+                            }
+                        }
+                        error = Some(socket_error);
+                    }
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    Ok(Some((error, size as usize)))
+}
+
+// safe if `raw` points to a sockaddr_in or a sockaddr_in6
+#[cfg(target_os = "linux")]
+unsafe fn read_address(raw: *const u8) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::ptr;
+
+    let family = ptr::read_unaligned(raw as *const libc::sa_family_t);
+    match i32::from(family) {
+        libc::AF_INET => {
+            let address = ptr::read_unaligned(raw as *const libc::sockaddr_in);
+            let ip = Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+            Some(SocketAddr::new(
+                IpAddr::V4(ip),
+                u16::from_be(address.sin_port),
+            ))
+        }
+        libc::AF_INET6 => {
+            let address = ptr::read_unaligned(raw as *const libc::sockaddr_in6);
+            let ip = Ipv6Addr::from(address.sin6_addr.s6_addr);
+            Some(SocketAddr::new(
+                IpAddr::V6(ip),
+                u16::from_be(address.sin6_port),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Indicate whether the readiness signals an error (queued errors are signaled this way).
+#[cfg(target_os = "linux")]
+pub fn is_error(ready: Ready) -> bool {
+    mio::unix::UnixReady::from(ready).is_error()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable<S>(_socket: &S, _ipv6: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ICMP errors are only received on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv<S>(_socket: &S, _buf: &mut [u8]) -> io::Result<Option<(Option<SocketError>, usize)>> {
+    Ok(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_error(_ready: Ready) -> bool {
+    false
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // send a datagram to a closed port of the local host, and return the error received
+    fn send_to_closed_port(localhost: IpAddr) -> (SocketError, Vec<u8>, SocketAddr) {
+        let local_address = SocketAddr::new(localhost, 0);
+        // nothing listens on the port of a closed socket
+        let port = UdpSocket::bind(local_address)
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let socket = UdpSocket::bind(local_address).unwrap();
+        enable(&socket, localhost.is_ipv6()).unwrap();
+        let destination = SocketAddr::new(localhost, port);
+        socket.connect(destination).unwrap();
+        socket.send(b"probe").unwrap();
+
+        let mut buf = [0; 64];
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let (error, length) = loop {
+            if let Some(queued) = recv(&socket, &mut buf).unwrap() {
+                break queued;
+            }
+            assert!(Instant::now() < deadline, "No error received");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(recv(&socket, &mut buf).unwrap().is_none());
+        (error.unwrap(), buf[..length].to_vec(), destination)
+    }
+
+    #[test]
+    fn receive_port_unreachable() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let (error, payload, destination) = send_to_closed_port(localhost);
+        assert_eq!(
+            SocketError::new(localhost, 3, 3, 0).with_destination(destination),
+            error
+        );
+        assert_eq!(Some(IcmpError::PortUnreachable), error.icmp_error());
+        // the payload of the datagram
+        assert_eq!(b"probe", &payload[..]);
+    }
+
+    #[test]
+    fn receive_ipv6_port_unreachable() {
+        let localhost = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let (error, payload, destination) = send_to_closed_port(localhost);
+        assert_eq!(
+            SocketError::new(localhost, 1, 4, 0).with_destination(destination),
+            error
+        );
+        assert_eq!(Some(IcmpError::PortUnreachable), error.icmp_error());
+        assert_eq!(b"probe", &payload[..]);
+    }
+}
@@ -21,27 +21,45 @@ use rand::random;
 use std::cell::RefCell;
 use std::cmp;
 use std::io;
+use std::net::Shutdown;
 use std::num::Wrapping;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
 use super::connection::{Connection, ConnectionId};
-use super::ipv4_header::Ipv4Header;
-use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::device::Device;
+use super::egress::EgressPolicy;
+use super::http_proxy::{HttpConnectHandshake, HttpProxy};
+use super::ip_header::IpHeader;
+use super::ip_packet::{IpPacket, MAX_PACKET_LENGTH};
+use super::out_of_order_queue::OutOfOrderQueue;
 use super::packet_source::PacketSource;
 use super::packetizer::Packetizer;
-use super::selector::Selector;
+use super::selector::{Selector, TimerToken};
+use super::send_queue::SendQueue;
+use super::shaper::Direction;
 use super::stream_buffer::StreamBuffer;
-use super::tcp_header::{self, TcpHeader, TcpHeaderMut};
+use super::tcp_header::{self, TcpHeader, TcpHeaderMut, TcpOptions};
+use super::tcp_timeouts::TcpTimeouts;
 use super::transport_header::{TransportHeader, TransportHeaderMut};
 
 const TAG: &str = "TcpConnection";
 
-// same value as GnirehtetService.MTU in the client
-const MTU: u16 = 0x4000;
-// 20 bytes for IP headers, 20 bytes for TCP headers
-const MAX_PAYLOAD_LENGTH: u16 = MTU - 20 - 20_u16;
+// segments received ahead of a missing one, per connection
+const MAX_OUT_OF_ORDER_SEGMENTS: usize = 64;
+
+// give up (and reset the connection) after this number of consecutive timeouts
+const MAX_RETRANSMISSIONS: u32 = 10;
+
+const CLIENT_TO_NETWORK_CAPACITY: usize = 4 * MAX_PACKET_LENGTH;
+// smallest shift to advertise the whole client_to_network capacity in 16 bits
+const WINDOW_SCALE: u8 = 3;
+
+// default MSS when the client does not send the option (RFC 9293 section 3.7.1)
+const DEFAULT_IPV4_MSS: u16 = 536;
+const DEFAULT_IPV6_MSS: u16 = 1220;
 
 pub struct TcpConnection {
     self_weak: Weak<RefCell<TcpConnection>>,
@@ -51,9 +69,23 @@ pub struct TcpConnection {
     interests: Ready,
     token: Token,
     client_to_network: StreamBuffer,
+    // segments received from the client after a gap
+    out_of_order: OutOfOrderQueue,
     network_to_client: Packetizer,
-    packet_for_client_length: Option<u16>,
+    // packet already counted in the sequence number, waiting for space in the client buffer
+    packet_for_client: Option<Vec<u8>>,
+    // segments sent to the client, not acknowledged yet
+    send_queue: SendQueue,
+    retransmission_timer: Option<TimerToken>,
+    // pending CONNECT exchange, if the stream is connected to an HTTP proxy
+    proxy_handshake: Option<HttpConnectHandshake>,
+    device: Rc<Device>,
+    // pending timer to resume a transfer throttled by the shaper
+    wakeup_timer: Option<TimerToken>,
     closed: bool,
+    // false once the stream is finished in both directions (only the client side remains)
+    stream_registered: bool,
+    idle_since: Instant,
     tcb: Tcb,
 }
 
@@ -66,7 +98,12 @@ struct Tcb {
     their_acknowledgement_number: u32,
     fin_sequence_number: Option<u32>,
     fin_received: bool,
-    client_window: u16,
+    // scaled
+    client_window: u32,
+    // window scaling, 0 if not negotiated
+    client_window_shift: u8,
+    window_shift: u8,
+    client_mss: u16,
 }
 
 // See RFC793: <https://tools.ietf.org/html/rfc793#page-23>
@@ -88,6 +125,18 @@ impl TcpState {
         self != &TcpState::Init && self != &TcpState::SynSent && self != &TcpState::SynReceived
     }
 
+    /// Return the inactivity timeout in this state.
+    fn timeout(&self, tcp_timeouts: &TcpTimeouts) -> Duration {
+        match self {
+            TcpState::Init | TcpState::SynSent | TcpState::SynReceived => tcp_timeouts.handshake(),
+            // the device may still send data for a long time once it has sent its FIN
+            TcpState::Established | TcpState::CloseWait => tcp_timeouts.idle(),
+            // the device must answer the FIN of the relay, but it may have disappeared
+            TcpState::FinWait1 | TcpState::FinWait2 => tcp_timeouts.half_close(),
+            TcpState::Closing | TcpState::LastAck => tcp_timeouts.closing(),
+        }
+    }
+
     fn is_closed(&self) -> bool {
         self == &TcpState::FinWait1
             || self == &TcpState::FinWait2
@@ -107,21 +156,52 @@ impl Tcb {
             fin_sequence_number: None,
             fin_received: false,
             client_window: 0,
+            client_window_shift: 0,
+            window_shift: 0,
+            client_mss: DEFAULT_IPV4_MSS,
         }
     }
 
-    fn remaining_client_window(&self) -> u16 {
+    fn remaining_client_window(&self) -> u32 {
         let wrapped_remaining = Wrapping(self.their_acknowledgement_number)
-            + Wrapping(u32::from(self.client_window))
+            + Wrapping(self.client_window)
             - self.sequence_number;
         let remaining = wrapped_remaining.0;
-        if remaining <= u32::from(self.client_window) {
-            remaining as u16
+        if remaining <= self.client_window {
+            remaining
         } else {
             0
         }
     }
 
+    /// Negotiate the options of the SYN received from the client.
+    fn negotiate(&mut self, options: &TcpOptions, ipv6: bool) {
+        let default_mss = if ipv6 {
+            DEFAULT_IPV6_MSS
+        } else {
+            DEFAULT_IPV4_MSS
+        };
+        self.client_mss = options.mss().unwrap_or(default_mss);
+        // window scaling is enabled only if both sides send the option (RFC 7323 section 2.2)
+        if let Some(client_window_shift) = options.window_scale() {
+            self.client_window_shift = client_window_shift;
+            self.window_shift = WINDOW_SCALE;
+        } else {
+            self.client_window_shift = 0;
+            self.window_shift = 0;
+        }
+    }
+
+    /// The window to advertise to the client.
+    ///
+    /// The data in client_to_network are not acked, so the window always covers its whole
+    /// capacity.
+    fn window(&self, syn: bool) -> u16 {
+        // the window in a SYN segment is never scaled
+        let shift = if syn { 0 } else { self.window_shift };
+        cmp::min(CLIENT_TO_NETWORK_CAPACITY >> shift, 0xFFFF) as u16
+    }
+
     fn numbers(&self) -> String {
         format!(
             "(seq={}, ack={})",
@@ -136,11 +216,28 @@ impl TcpConnection {
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
-        ipv4_header: Ipv4Header,
+        http_proxy: Option<&HttpProxy>,
+        device: Rc<Device>,
+        ip_header: IpHeader,
         transport_header: TransportHeader,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
-        let stream = Self::create_stream(&id)?;
+        let destination = id.rewritten_destination();
+        // the host 'localhost' (10.0.2.2) is never reached through the proxy
+        let http_proxy = http_proxy.filter(|_| !destination.ip().is_loopback());
+        let stream = Self::create_stream(&id, http_proxy, device.policy().egress())?;
+        if let Some(keepalive) = device.policy().tcp_timeouts().keepalive() {
+            stream.set_keepalive(Some(keepalive))?;
+        }
+        let proxy_handshake = http_proxy.map(|http_proxy| {
+            cx_debug!(
+                target: TAG,
+                id,
+                "Connecting through HTTP proxy {}",
+                http_proxy.address()
+            );
+            HttpConnectHandshake::new(http_proxy, &destination)
+        });
 
         let tcp_header = Self::tcp_header_of_transport(transport_header);
 
@@ -159,7 +256,7 @@ impl TcpConnection {
             .bind(&shrinked_tcp_header_raw)
             .into();
 
-        let packetizer = Packetizer::new(&ipv4_header, &shrinked_transport_header);
+        let packetizer = Packetizer::new(&ip_header, &shrinked_transport_header);
 
         // interests will be set on the first packet received
         // set the initial value now so that they won't need to be updated
@@ -171,10 +268,18 @@ impl TcpConnection {
             stream,
             interests,
             token: Token(0), // default value, will be set afterwards
-            client_to_network: StreamBuffer::new(4 * MAX_PACKET_LENGTH),
+            client_to_network: StreamBuffer::new(CLIENT_TO_NETWORK_CAPACITY),
+            out_of_order: OutOfOrderQueue::new(MAX_OUT_OF_ORDER_SEGMENTS),
             network_to_client: packetizer,
-            packet_for_client_length: None,
+            packet_for_client: None,
+            send_queue: SendQueue::new(),
+            retransmission_timer: None,
+            proxy_handshake,
+            device,
+            wakeup_timer: None,
             closed: false,
+            stream_registered: true,
+            idle_since: Instant::now(),
             tcb: Tcb::new(),
         }));
 
@@ -195,8 +300,65 @@ impl TcpConnection {
         Ok(rc)
     }
 
-    fn create_stream(id: &ConnectionId) -> io::Result<TcpStream> {
-        TcpStream::connect(&id.rewritten_destination().into())
+    fn create_stream(
+        id: &ConnectionId,
+        http_proxy: Option<&HttpProxy>,
+        egress: &EgressPolicy,
+    ) -> io::Result<TcpStream> {
+        let address = match http_proxy {
+            Some(http_proxy) => *http_proxy.address(),
+            None => id.rewritten_destination(),
+        };
+        egress.connect_tcp(&address)
+    }
+
+    /// Forge a RST refusing a packet for which no connection is created (RFC 793 section 3.4)
+    ///
+    /// Return `None` if the packet is itself a RST.
+    pub fn forge_reset(ip_packet: &IpPacket) -> Option<Vec<u8>> {
+        let (ip_header, transport_header) = ip_packet.headers();
+        let tcp_header = Self::tcp_header_of_transport(transport_header.expect("No transport"));
+        if tcp_header.is_rst() {
+            return None;
+        }
+        let (sequence_number, acknowledgement_number, flags) = if tcp_header.is_ack() {
+            (tcp_header.acknowledgement_number(), 0, tcp_header::FLAG_RST)
+        } else {
+            // acknowledge the segment, otherwise a client in SYN-SENT state ignores the RST
+            let payload_length = ip_packet.payload().map_or(0, <[u8]>::len) as u32;
+            let control_length = u32::from(tcp_header.is_syn()) + u32::from(tcp_header.is_fin());
+            let acknowledgement_number =
+                Wrapping(tcp_header.sequence_number()) + Wrapping(payload_length + control_length);
+            (
+                0,
+                acknowledgement_number.0,
+                tcp_header::FLAG_RST | tcp_header::FLAG_ACK,
+            )
+        };
+
+        // keep the IP header, but not its options or extension headers, nor the TCP options
+        let mut raw = ip_header.raw().to_vec();
+        let mut ip_header_data = ip_header.data_clone();
+        ip_header_data.bind_mut(&mut raw).shrink_extension_headers();
+        let transport_index = ip_header_data.header_length() as usize;
+        raw.truncate(transport_index);
+        raw.extend_from_slice(&tcp_header.raw()[..20]);
+        let total_length = raw.len() as u16;
+        let mut tcp_header_data = tcp_header.data().clone();
+        {
+            let (ip_header_raw, tcp_header_raw) = raw.split_at_mut(transport_index);
+            let mut ip_header = ip_header_data.bind_mut(ip_header_raw);
+            ip_header.swap_source_and_destination();
+            ip_header.set_total_length(total_length);
+            let mut tcp_header = tcp_header_data.bind_mut(tcp_header_raw);
+            tcp_header.swap_source_and_destination();
+            tcp_header.shrink_options();
+            tcp_header.set_sequence_number(sequence_number);
+            tcp_header.set_acknowledgement_number(acknowledgement_number);
+            tcp_header.set_flags(flags);
+        }
+        IpPacket::parse(&mut raw).compute_checksums();
+        Some(raw)
     }
 
     fn remove_from_router(&self) {
@@ -219,18 +381,29 @@ impl TcpConnection {
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
     fn process(&mut self, selector: &mut Selector, event: Event) -> io::Result<()> {
         if !self.closed {
+            self.touch();
             let ready = event.readiness();
-            if ready.is_readable() || ready.is_writable() {
+            if self.tcb.state == TcpState::SynSent && self.connect_failed() {
+                self.refuse_connection(selector);
+            } else if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
                     if self.tcb.state == TcpState::SynSent {
                         // writable is first triggered when the stream is connected
-                        self.process_connect(selector);
+                        if self.proxy_handshake.is_some() {
+                            self.process_proxy_send(selector)?;
+                        } else {
+                            self.process_connect(selector);
+                        }
                     } else {
                         self.process_send(selector)?;
                     }
                 }
                 if !self.closed && ready.is_readable() {
-                    self.process_receive(selector)?;
+                    if self.proxy_handshake.is_some() {
+                        self.process_proxy_receive(selector)?;
+                    } else {
+                        self.process_receive(selector)?;
+                    }
                 }
                 if !self.closed {
                     self.update_interests(selector);
@@ -253,6 +426,7 @@ impl TcpConnection {
         match self.client_to_network.write_to(&mut self.stream) {
             Ok(w) => {
                 if w != 0 {
+                    self.device.account(Direction::Upload, w);
                     self.tcb.acknowledgement_number += Wrapping(w as u32);
 
                     if self.tcb.fin_received && self.client_to_network.is_empty() {
@@ -299,7 +473,7 @@ impl TcpConnection {
     // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
     fn process_receive(&mut self, selector: &mut Selector) -> io::Result<()> {
         assert!(
-            self.packet_for_client_length.is_none(),
+            self.packet_for_client.is_none(),
             "A pending packet was not sent"
         );
         let remaining_client_window = self.tcb.remaining_client_window();
@@ -307,8 +481,19 @@ impl TcpConnection {
             remaining_client_window > 0,
             "process_received() must not be called when window == 0"
         );
-        let max_payload_length =
-            Some(cmp::min(remaining_client_window, MAX_PAYLOAD_LENGTH) as usize);
+        let quota = self.device.quota(Direction::Download);
+        if quota == 0 {
+            // throttled, update_interests() will schedule a wakeup
+            return Ok(());
+        }
+        // the IP header is longer for IPv6
+        let max_packet_payload_length =
+            self.device.policy().mtu() - self.network_to_client.headers_length();
+        let max_segment_length = cmp::min(max_packet_payload_length, self.tcb.client_mss);
+        let max_payload_length = Some(cmp::min(
+            cmp::min(remaining_client_window, u32::from(max_segment_length)) as usize,
+            quota,
+        ));
         Self::update_headers(
             &mut self.network_to_client,
             &self.tcb,
@@ -318,10 +503,12 @@ impl TcpConnection {
             .network_to_client
             .packetize_read(&mut self.stream, max_payload_length)
         {
-            Ok(Some(ipv4_packet)) => {
-                match Self::send_to_client(&self.client, selector, &ipv4_packet) {
+            Ok(Some(ip_packet)) => {
+                let len = ip_packet.payload().unwrap().len();
+                self.device.account(Direction::Download, len);
+                let packet = ip_packet.raw().to_vec();
+                let delivered = match Self::send_to_client(&self.client, selector, &ip_packet) {
                     Ok(_) => {
-                        let len = ipv4_packet.payload().unwrap().len();
                         cx_debug!(
                             target: TAG,
                             self.id,
@@ -329,7 +516,7 @@ impl TcpConnection {
                             len,
                             self.tcb.numbers()
                         );
-                        self.tcb.sequence_number += Wrapping(len as u32);
+                        true
                     }
                     Err(_) => {
                         // ask to the client to pull when its buffer is not full
@@ -337,9 +524,11 @@ impl TcpConnection {
                         let mut client = client_rc.borrow_mut();
                         let self_rc = self.self_weak.upgrade().unwrap();
                         client.register_pending_packet_source(self_rc);
-                        self.packet_for_client_length = Some(ipv4_packet.length());
+                        self.packet_for_client = Some(packet.clone());
+                        false
                     }
                 };
+                self.on_segment_sent(selector, len as u32, packet, delivered);
             }
             Ok(None) => {
                 self.eof(selector);
@@ -363,22 +552,119 @@ impl TcpConnection {
         Ok(())
     }
 
+    // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
+    fn process_proxy_send(&mut self, selector: &mut Selector) -> io::Result<()> {
+        let handshake = self
+            .proxy_handshake
+            .as_mut()
+            .expect("No pending proxy handshake");
+        match handshake.write_request(&mut self.stream) {
+            Ok(_) => {
+                if handshake.is_request_sent() {
+                    cx_debug!(target: TAG, self.id, "CONNECT request sent to proxy");
+                }
+            }
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    // rethrow
+                    return Err(err);
+                }
+                cx_error!(
+                    target: TAG,
+                    self.id,
+                    "Cannot write to proxy: [{:?}] {}",
+                    err.kind(),
+                    err
+                );
+                self.refuse_connection(selector);
+            }
+        }
+        Ok(())
+    }
+
+    // return Err(err) with err.kind() == io::ErrorKind::WouldBlock on spurious event
+    fn process_proxy_receive(&mut self, selector: &mut Selector) -> io::Result<()> {
+        let handshake = self
+            .proxy_handshake
+            .as_mut()
+            .expect("No pending proxy handshake");
+        match handshake.read_response(&mut self.stream) {
+            Ok(Some(status)) if (200..300).contains(&status) => {
+                cx_debug!(target: TAG, self.id, "Proxy tunnel established");
+                self.proxy_handshake = None;
+                self.process_connect(selector);
+            }
+            Ok(Some(status)) => {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Proxy refused CONNECT with status {}",
+                    status
+                );
+                self.refuse_connection(selector);
+            }
+            Ok(None) => (), // wait for the rest of the response
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    // rethrow
+                    return Err(err);
+                }
+                cx_error!(
+                    target: TAG,
+                    self.id,
+                    "Cannot read proxy response: [{:?}] {}",
+                    err.kind(),
+                    err
+                );
+                self.refuse_connection(selector);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether the asynchronous connection to the network (or to the proxy) failed.
+    fn connect_failed(&mut self) -> bool {
+        let error = match self.stream.take_error() {
+            Ok(error) => error,
+            Err(err) => Some(err),
+        };
+        if let Some(err) = error {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot connect: [{:?}] {}",
+                err.kind(),
+                err
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Reset a connection the client is still trying to open (reply RST to its SYN)
+    fn refuse_connection(&mut self, selector: &mut Selector) {
+        assert_eq!(self.tcb.state, TcpState::SynSent);
+        // the client ignores a RST in SYN-SENT state if it does not acknowledge its SYN
+        self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST | tcp_header::FLAG_ACK);
+        self.close(selector);
+    }
+
     fn process_connect(&mut self, selector: &mut Selector) {
         assert_eq!(self.tcb.state, TcpState::SynSent);
         self.tcb.state = TcpState::SynReceived;
         cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
-        self.send_empty_packet_to_client(selector, tcp_header::FLAG_SYN | tcp_header::FLAG_ACK);
+        self.send_syn_ack_to_client(selector);
         self.tcb.sequence_number += Wrapping(1); // SYN counts for 1 byte
     }
 
     fn send_to_client(
         client: &Weak<RefCell<Client>>,
         selector: &mut Selector,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     ) -> io::Result<()> {
         let client_rc = client.upgrade().expect("Expected client not found");
         let mut client = client_rc.borrow_mut();
-        client.send_to_client(selector, ipv4_packet)
+        client.send_to_client(selector, ip_packet)
     }
 
     /// Borrow self.client and send empty packet to it
@@ -390,6 +676,45 @@ impl TcpConnection {
         self.reply_empty_packet_to_client(selector, &mut client.channel(), flags)
     }
 
+    /// Send the SYN-ACK, with the options negotiated for this connection.
+    fn send_syn_ack_to_client(&mut self, selector: &mut Selector) {
+        let max_segment_length =
+            self.device.policy().mtu() - self.network_to_client.headers_length();
+        // only sent if the client sent it (window_shift is 0 otherwise)
+        let window_scale = Some(self.tcb.window_shift).filter(|&shift| shift > 0);
+        let options = TcpOptions::new(Some(max_segment_length), window_scale);
+        let ip_packet = Self::create_empty_response_packet(
+            &self.id,
+            &mut self.network_to_client,
+            &self.tcb,
+            tcp_header::FLAG_SYN | tcp_header::FLAG_ACK,
+        );
+        let mut raw = Self::append_options(&ip_packet, &options.to_bytes());
+        let ip_packet = IpPacket::parse(&mut raw);
+        if let Err(err) = Self::send_to_client(&self.client, selector, &ip_packet) {
+            cx_warn!(target: TAG, self.id, "Cannot send SYN-ACK to client: {}", err);
+        }
+    }
+
+    fn append_options(ip_packet: &IpPacket, options: &[u8]) -> Vec<u8> {
+        let transport_index = ip_packet.ip_header_data().header_length() as usize;
+        let mut raw = ip_packet.raw().to_vec();
+        raw.extend_from_slice(options);
+        let total_length = raw.len() as u16;
+        let mut ip_header_data = ip_packet.ip_header_data().clone();
+        ip_header_data
+            .bind_mut(&mut raw)
+            .set_total_length(total_length);
+        {
+            let tcp_header_raw = &mut raw[transport_index..];
+            let mut tcp_header_data = tcp_header::TcpHeaderData::parse(tcp_header_raw);
+            let mut tcp_header = tcp_header_data.bind_mut(tcp_header_raw);
+            tcp_header.set_options_length(options.len() as u8);
+        }
+        IpPacket::parse(&mut raw).compute_checksums();
+        raw
+    }
+
     /// Send empty packet to the client channel (that already borrows the client)
     ///
     /// To be used if called by send_to_network() (called by the client, so it is already
@@ -400,13 +725,13 @@ impl TcpConnection {
         client_channel: &mut ClientChannel,
         flags: u16,
     ) {
-        let ipv4_packet = Self::create_empty_response_packet(
+        let ip_packet = Self::create_empty_response_packet(
             &self.id,
             &mut self.network_to_client,
             &self.tcb,
             flags,
         );
-        if let Err(err) = client_channel.send_to_client(selector, &ipv4_packet) {
+        if let Err(err) = client_channel.send_to_client(selector, &ip_packet) {
             // losing such an empty packet will not break the TCP connection
             cx_warn!(
                 target: TAG,
@@ -417,11 +742,139 @@ impl TcpConnection {
         }
     }
 
-    fn eof(&mut self, selector: &mut Selector) {
-        self.send_empty_packet_to_client(selector, tcp_header::FLAG_FIN | tcp_header::FLAG_ACK);
+    /// Send a FIN, which is retransmitted until acknowledged.
+    fn reply_fin_to_client(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        let ip_packet = Self::create_empty_response_packet(
+            &self.id,
+            &mut self.network_to_client,
+            &self.tcb,
+            tcp_header::FLAG_FIN | tcp_header::FLAG_ACK,
+        );
+        let packet = ip_packet.raw().to_vec();
+        if let Err(err) = client_channel.send_to_client(selector, &ip_packet) {
+            cx_warn!(target: TAG, self.id, "Cannot send FIN to client: {}", err);
+        }
         self.tcb.fin_sequence_number = Some(self.tcb.sequence_number.0);
-        self.tcb.sequence_number += Wrapping(1); // FIN counts for 1 byte
+        // a FIN which could not be sent is lost, it will be retransmitted
+        self.on_segment_sent(selector, 1, packet, true); // FIN counts for 1 byte
+    }
+
+    /// Account a segment handed to the client, and keep it until it is acknowledged.
+    ///
+    /// If it is not `delivered` yet (pending in `packet_for_client`), its retransmission timer
+    /// starts only once it is (in `next()`).
+    fn on_segment_sent(
+        &mut self,
+        selector: &mut Selector,
+        length: u32,
+        packet: Vec<u8>,
+        delivered: bool,
+    ) {
+        let sent_at = if delivered {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        self.send_queue
+            .push(self.tcb.sequence_number.0, length, packet, sent_at);
+        self.tcb.sequence_number += Wrapping(length);
+        self.schedule_retransmission(selector);
+    }
+
+    fn schedule_retransmission(&mut self, selector: &mut Selector) {
+        if self.retransmission_timer.is_none() && self.send_queue.is_in_flight() {
+            let weak = self.self_weak.clone();
+            let handler = move |selector: &mut Selector| {
+                if let Some(rc) = weak.upgrade() {
+                    rc.borrow_mut().on_retransmission_timeout(selector);
+                }
+            };
+            let rto = self.send_queue.rto();
+            self.retransmission_timer = Some(selector.schedule(rto, handler));
+        }
+    }
+
+    fn cancel_retransmission(&mut self, selector: &mut Selector) {
+        if let Some(timer) = self.retransmission_timer.take() {
+            selector.cancel(timer);
+        }
+    }
+
+    fn on_retransmission_timeout(&mut self, selector: &mut Selector) {
+        self.retransmission_timer = None;
+        if self.closed || !self.send_queue.is_in_flight() {
+            return;
+        }
+        if self.packet_for_client.is_some() {
+            // the client does not read fast enough, nothing is lost: the timer restarts once the
+            // pending packet is delivered
+            cx_debug!(target: TAG, self.id, "Client buffer full, not retransmitting");
+            return;
+        }
+        if self.send_queue.retransmissions() >= MAX_RETRANSMISSIONS {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "No ACK after {} retransmissions, resetting",
+                MAX_RETRANSMISSIONS
+            );
+            self.send_empty_packet_to_client(selector, tcp_header::FLAG_RST);
+            self.close(selector);
+            // not called from the router, so the connection must remove itself
+            self.remove_from_router();
+            return;
+        }
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Retransmission timeout ({:?})",
+            self.send_queue.rto()
+        );
+        {
+            let client_rc = self.client.upgrade().expect("Expected client not found");
+            let mut client = client_rc.borrow_mut();
+            self.retransmit(selector, &mut client.channel(), true);
+        }
+        self.schedule_retransmission(selector);
+    }
+
+    /// Send the first unacknowledged segment again, with up-to-date ACK number and window.
+    fn retransmit(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        backoff: bool,
+    ) {
+        let acknowledgement_number = self.tcb.acknowledgement_number.0;
+        let window = self.tcb.window(false);
+        if let Some(packet) = self.send_queue.retransmit(Instant::now(), backoff) {
+            let mut ip_packet = IpPacket::parse(packet);
+            if let (_, Some((transport_header, _))) = ip_packet.split_mut() {
+                let mut tcp_header = Self::tcp_header_of_transport_mut(transport_header);
+                tcp_header.set_acknowledgement_number(acknowledgement_number);
+                tcp_header.set_window(window);
+            }
+            ip_packet.compute_checksums();
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "Retransmitting packet {}",
+                Self::tcp_header_of_packet(&ip_packet).sequence_number()
+            );
+            if let Err(err) = client_channel.send_to_client(selector, &ip_packet) {
+                cx_warn!(target: TAG, self.id, "Cannot retransmit to client: {}", err);
+            }
+        }
+    }
+
+    fn eof(&mut self, selector: &mut Selector) {
+        {
+            let client_rc = self.client.upgrade().expect("Expected client not found");
+            let mut client = client_rc.borrow_mut();
+            self.reply_fin_to_client(selector, &mut client.channel());
+        }
         self.tcb.state = if self.tcb.state == TcpState::CloseWait {
+            self.release_stream(selector);
             TcpState::LastAck
         } else {
             TcpState::FinWait1
@@ -448,8 +901,8 @@ impl TcpConnection {
     }
 
     #[inline]
-    fn tcp_header_of_packet<'a>(ipv4_packet: &'a Ipv4Packet) -> TcpHeader<'a> {
-        if let Some(TransportHeader::Tcp(tcp_header)) = ipv4_packet.transport_header() {
+    fn tcp_header_of_packet<'a>(ip_packet: &'a IpPacket) -> TcpHeader<'a> {
+        if let Some(TransportHeader::Tcp(tcp_header)) = ip_packet.transport_header() {
             tcp_header
         } else {
             panic!("Not a TCP packet");
@@ -461,30 +914,34 @@ impl TcpConnection {
         tcp_header.set_sequence_number(tcb.sequence_number.0);
         tcp_header.set_acknowledgement_number(tcb.acknowledgement_number.0);
         tcp_header.set_flags(flags);
+        tcp_header.set_window(tcb.window(flags & tcp_header::FLAG_SYN != 0));
     }
 
     fn handle_packet(
         &mut self,
         selector: &mut Selector,
         client_channel: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     ) {
-        let tcp_header = Self::tcp_header_of_packet(ipv4_packet);
+        let tcp_header = Self::tcp_header_of_packet(ip_packet);
         if self.tcb.state == TcpState::Init {
-            self.handle_first_packet(selector, client_channel, ipv4_packet);
+            self.handle_first_packet(selector, client_channel, ip_packet);
             return;
         }
 
         if tcp_header.is_syn() {
-            self.handle_duplicate_syn(selector, client_channel, ipv4_packet);
+            self.handle_duplicate_syn(selector, client_channel, ip_packet);
             return;
         }
 
-        let expected_packet =
-            (self.tcb.acknowledgement_number + Wrapping(self.client_to_network.size() as u32)).0;
+        let expected_packet = self.expected_sequence_number();
         if tcp_header.sequence_number() != expected_packet {
-            // ignore packet already received or out-of-order, retransmission is already
-            // managed by both sides
+            let ahead = Wrapping(tcp_header.sequence_number()) - Wrapping(expected_packet);
+            if (ahead.0 as i32) > 0 {
+                self.handle_out_of_order_packet(selector, client_channel, ip_packet);
+                return;
+            }
+            // ignore packet already received, retransmission is already managed by both sides
             cx_warn!(
                 target: TAG,
                 self.id,
@@ -497,8 +954,7 @@ impl TcpConnection {
             return;
         }
 
-        self.tcb.client_window = tcp_header.window();
-        self.tcb.their_acknowledgement_number = tcp_header.acknowledgement_number();
+        self.update_acknowledgement(selector, client_channel, ip_packet);
 
         cx_debug!(
             target: TAG,
@@ -521,11 +977,13 @@ impl TcpConnection {
                 tcp_header.acknowledgement_number()
             );
 
-            self.handle_ack(selector, client_channel, ipv4_packet);
+            self.handle_ack(selector, client_channel, ip_packet);
         }
 
         if tcp_header.is_fin() {
             self.handle_fin(selector, client_channel);
+        } else if !self.out_of_order.is_empty() {
+            self.deliver_out_of_order(selector, client_channel);
         }
 
         if let Some(fin_sequence_number) = self.tcb.fin_sequence_number {
@@ -536,20 +994,152 @@ impl TcpConnection {
         }
     }
 
+    fn expected_sequence_number(&self) -> u32 {
+        // the data in client_to_network are not acked yet
+        (self.tcb.acknowledgement_number + Wrapping(self.client_to_network.size() as u32)).0
+    }
+
+    /// Take into account the acknowledgement number and the window advertised by the client.
+    fn update_acknowledgement(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
+    ) {
+        let tcp_header = Self::tcp_header_of_packet(ip_packet);
+        let their_acknowledgement_number = tcp_header.acknowledgement_number();
+        let acked = Wrapping(their_acknowledgement_number)
+            - Wrapping(self.tcb.their_acknowledgement_number);
+        // an older acknowledgement (from a reordered packet) must not shrink the window
+        if (acked.0 as i32) >= 0 {
+            let client_window = u32::from(tcp_header.window()) << self.tcb.client_window_shift;
+            let window_changed = client_window != self.tcb.client_window;
+            self.tcb.client_window = client_window;
+            self.tcb.their_acknowledgement_number = their_acknowledgement_number;
+            if acked.0 > 0 {
+                if self
+                    .send_queue
+                    .ack(their_acknowledgement_number, Instant::now())
+                {
+                    // restart the timer for the remaining segments (RFC 6298 section 5.3)
+                    self.cancel_retransmission(selector);
+                    self.schedule_retransmission(selector);
+                }
+            } else if tcp_header.is_ack()
+                && !tcp_header.is_fin()
+                && !window_changed
+                && ip_packet.payload().is_some_and(<[u8]>::is_empty)
+                && self.send_queue.duplicate_ack()
+            {
+                // RFC 5681 section 3.2
+                cx_debug!(target: TAG, self.id, "Fast retransmit");
+                self.retransmit(selector, client_channel, false);
+            }
+        }
+    }
+
+    fn handle_out_of_order_packet(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
+    ) {
+        let tcp_header = Self::tcp_header_of_packet(ip_packet);
+        // the client may acknowledge data and open its window while retransmitting its own
+        if tcp_header.is_ack() && !tcp_header.is_rst() {
+            self.update_acknowledgement(selector, client_channel, ip_packet);
+            if let Some(fin_sequence_number) = self.tcb.fin_sequence_number {
+                if tcp_header.acknowledgement_number() == fin_sequence_number + 1 {
+                    cx_debug!(target: TAG, self.id, "Received ACK of FIN");
+                    self.handle_fin_ack(selector);
+                    if self.closed {
+                        return;
+                    }
+                }
+            }
+        }
+
+        let payload = ip_packet.payload().expect("No payload");
+        if self.tcb.state.is_connected()
+            && !self.tcb.fin_received
+            && tcp_header.is_ack()
+            && !tcp_header.is_rst()
+            && (!payload.is_empty() || tcp_header.is_fin())
+        {
+            let queued = self.out_of_order.insert(
+                self.expected_sequence_number(),
+                tcp_header.sequence_number(),
+                payload,
+                tcp_header.is_fin(),
+                self.client_to_network.remaining(),
+            );
+            if queued {
+                cx_debug!(
+                    target: TAG,
+                    self.id,
+                    "Queuing out-of-order packet {} ({} bytes); expecting {}",
+                    tcp_header.sequence_number(),
+                    payload.len(),
+                    self.expected_sequence_number()
+                );
+            } else {
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Dropping out-of-order packet {} (queue full or out of window)",
+                    tcp_header.sequence_number()
+                );
+            }
+        }
+        // immediate duplicate ACK, so that the client detects the gap (RFC 5681 section 4.2)
+        self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+    }
+
+    /// Move the queued segments following the data just received to client_to_network.
+    fn deliver_out_of_order(
+        &mut self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+    ) {
+        while let Some((payload, fin)) = self.out_of_order.pop(self.expected_sequence_number()) {
+            cx_debug!(
+                target: TAG,
+                self.id,
+                "Delivering {} queued bytes (fin={})",
+                payload.len(),
+                fin
+            );
+            if self.client_to_network.remaining() < payload.len() {
+                // cannot happen, the window passed on insertion guarantees that it fits
+                cx_warn!(target: TAG, self.id, "Not enough space, dropping queued data");
+                self.out_of_order.clear();
+                break;
+            }
+            self.client_to_network.read_from(&payload);
+            if fin {
+                self.out_of_order.clear();
+                self.handle_fin(selector, client_channel);
+                break;
+            }
+        }
+    }
+
     fn handle_first_packet(
         &mut self,
         selector: &mut Selector,
         client_channel: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     ) {
         cx_debug!(target: TAG, self.id, "handle_first_packet()");
-        let tcp_header = Self::tcp_header_of_packet(ipv4_packet);
+        let tcp_header = Self::tcp_header_of_packet(ip_packet);
         if tcp_header.is_syn() {
             let their_sequence_number = tcp_header.sequence_number();
             self.tcb.acknowledgement_number = Wrapping(their_sequence_number) + Wrapping(1);
             self.tcb.syn_sequence_number = their_sequence_number;
 
             self.tcb.sequence_number = Wrapping(random::<u32>());
+            // nothing is acknowledged yet
+            self.tcb.their_acknowledgement_number = self.tcb.sequence_number.0;
             cx_debug!(
                 target: TAG,
                 self.id,
@@ -557,7 +1147,9 @@ impl TcpConnection {
                 self.tcb.sequence_number,
                 self.tcb.acknowledgement_number
             );
-            self.tcb.client_window = tcp_header.window();
+            // the window in a SYN segment is never scaled
+            self.tcb.client_window = u32::from(tcp_header.window());
+            self.negotiate_options(&tcp_header, ip_packet);
             self.tcb.state = TcpState::SynSent;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else {
@@ -576,19 +1168,34 @@ impl TcpConnection {
         }
     }
 
+    fn negotiate_options(&mut self, tcp_header: &TcpHeader, ip_packet: &IpPacket) {
+        let options = tcp_header.options();
+        self.tcb
+            .negotiate(&options, ip_packet.ip_header_data().is_ipv6());
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "SYN options {:?}; window scaling {}; MSS {}",
+            options,
+            if self.tcb.window_shift > 0 { "enabled" } else { "disabled" },
+            self.tcb.client_mss
+        );
+    }
+
     fn handle_duplicate_syn(
         &mut self,
         selector: &mut Selector,
         client_channel: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     ) {
-        let tcp_header = Self::tcp_header_of_packet(ipv4_packet);
+        let tcp_header = Self::tcp_header_of_packet(ip_packet);
         let their_sequence_number = tcp_header.sequence_number();
         if self.tcb.state == TcpState::SynSent {
             // the connection is not established yet, we can accept this packet as if it were the
             // first SYN
             self.tcb.syn_sequence_number = their_sequence_number;
             self.tcb.acknowledgement_number = Wrapping(their_sequence_number) + Wrapping(1);
+            self.negotiate_options(&tcp_header, ip_packet);
         } else if their_sequence_number != self.tcb.syn_sequence_number {
             // duplicate SYN with different sequence number
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
@@ -620,19 +1227,16 @@ impl TcpConnection {
         self.tcb.acknowledgement_number += Wrapping(1); // received FIN counts for 1 byte
 
         if self.tcb.state == TcpState::Established {
-            self.reply_empty_packet_to_client(
-                selector,
-                client_channel,
-                tcp_header::FLAG_FIN | tcp_header::FLAG_ACK,
-            );
-            self.tcb.fin_sequence_number = Some(self.tcb.sequence_number.0);
-            self.tcb.sequence_number += Wrapping(1); // FIN counts for 1 byte
-                                                     // the connection will be closed by RAII, so switch immediately to LastAck
-                                                     // (bypass CloseWait)
-            self.tcb.state = TcpState::LastAck;
+            // half-close: forward the FIN, the network may still send data to the client until
+            // it closes its side (then eof() sends our FIN)
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+            self.shutdown_network_write();
+            self.tcb.state = TcpState::CloseWait;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else if self.tcb.state == TcpState::FinWait1 {
             self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_ACK);
+            self.shutdown_network_write();
+            self.release_stream(selector);
             self.tcb.state = TcpState::Closing;
             cx_debug!(target: TAG, self.id, "State = {:?}", self.tcb.state);
         } else if self.tcb.state == TcpState::FinWait2 {
@@ -648,6 +1252,38 @@ impl TcpConnection {
         }
     }
 
+    /// Forward the FIN received from the client to the network.
+    fn shutdown_network_write(&mut self) {
+        if let Err(err) = self.stream.shutdown(Shutdown::Write) {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot shutdown write: [{:?}] {}",
+                err.kind(),
+                err
+            );
+        }
+    }
+
+    /// Stop polling the stream, once both directions are finished.
+    ///
+    /// Otherwise, its hang-up would be reported until the client acknowledges our FIN.
+    fn release_stream(&mut self, selector: &mut Selector) {
+        if self.stream_registered {
+            self.stream_registered = false;
+            if let Err(err) = selector.deregister(&self.stream, self.token) {
+                // do not panic, this can happen in mio
+                // see <https://github.com/Genymobile/gnirehtet/issues/136>
+                cx_warn!(
+                    target: TAG,
+                    self.id,
+                    "Fail to deregister TCP stream: {:?}",
+                    err
+                );
+            }
+        }
+    }
+
     fn handle_fin_ack(&mut self, selector: &mut Selector) {
         if self.tcb.state == TcpState::LastAck || self.tcb.state == TcpState::Closing {
             self.close(selector);
@@ -668,7 +1304,7 @@ impl TcpConnection {
         &mut self,
         _selector: &mut Selector,
         _client_channel: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     ) {
         cx_debug!(target: TAG, self.id, "handle_ack()");
         if self.tcb.state == TcpState::SynReceived {
@@ -682,11 +1318,11 @@ impl TcpConnection {
                 target: TAG,
                 self.id,
                 "{}",
-                binary::build_packet_string(ipv4_packet.raw())
+                binary::build_packet_string(ip_packet.raw())
             );
         }
 
-        let payload = ipv4_packet.payload().expect("No payload");
+        let payload = ip_packet.payload().expect("No payload");
         if payload.is_empty() {
             // no data to transmit
             return;
@@ -706,7 +1342,7 @@ impl TcpConnection {
         packetizer: &'a mut Packetizer,
         tcb: &Tcb,
         flags: u16,
-    ) -> Ipv4Packet<'a> {
+    ) -> IpPacket<'a> {
         Self::update_headers(packetizer, tcb, flags);
         cx_debug!(
             target: TAG,
@@ -718,30 +1354,49 @@ impl TcpConnection {
         if (flags & tcp_header::FLAG_ACK) != 0 {
             cx_debug!(target: TAG, id, "Acking {}", tcb.numbers());
         }
-        let ipv4_packet = packetizer.packetize_empty_payload();
+        let ip_packet = packetizer.packetize_empty_payload();
         if log_enabled!(target: TAG, Level::Trace) {
             cx_trace!(
                 target: TAG,
                 id,
                 "{}",
-                binary::build_packet_string(ipv4_packet.raw())
+                binary::build_packet_string(ip_packet.raw())
             );
         }
-        ipv4_packet
+        ip_packet
     }
 
     fn update_interests(&mut self, selector: &mut Selector) {
         assert!(!self.closed);
+        if !self.stream_registered {
+            return;
+        }
         let mut ready = Ready::empty();
         if self.tcb.state == TcpState::SynSent {
-            // waiting for connectable
-            ready = Ready::writable()
+            ready = match self.proxy_handshake {
+                // waiting for the proxy response
+                Some(ref handshake) if handshake.is_request_sent() => Ready::readable(),
+                // waiting for connectable, or for sending the CONNECT request
+                _ => Ready::writable(),
+            }
         } else {
+            let mut wakeup_delay = None;
             if self.may_read() {
-                ready |= Ready::readable()
+                match self.device.delay(Direction::Download) {
+                    None => ready |= Ready::readable(),
+                    Some(delay) => wakeup_delay = Some(delay),
+                }
             }
             if self.may_write() {
-                ready |= Ready::writable()
+                match self.device.delay(Direction::Upload) {
+                    None => ready |= Ready::writable(),
+                    Some(delay) => {
+                        wakeup_delay = Some(wakeup_delay.map_or(delay, |d| cmp::min(d, delay)))
+                    }
+                }
+            }
+            if let Some(delay) = wakeup_delay {
+                self.schedule_wakeup(selector, delay);
             }
         }
         cx_debug!(target: TAG, self.id, "interests: {:?}", ready);
@@ -754,11 +1409,35 @@ impl TcpConnection {
         }
     }
 
+    fn schedule_wakeup(&mut self, selector: &mut Selector, delay: Duration) {
+        if self.wakeup_timer.is_none() {
+            cx_debug!(target: TAG, self.id, "Throttled for {:?}", delay);
+            let weak = self.self_weak.clone();
+            let handler = move |selector: &mut Selector| {
+                if let Some(rc) = weak.upgrade() {
+                    rc.borrow_mut().on_wakeup(selector);
+                }
+            };
+            self.wakeup_timer = Some(selector.schedule(delay, handler));
+        }
+    }
+
+    fn on_wakeup(&mut self, selector: &mut Selector) {
+        self.wakeup_timer = None;
+        if !self.closed {
+            self.update_interests(selector);
+        }
+    }
+
+    fn touch(&mut self) {
+        self.idle_since = Instant::now();
+    }
+
     fn may_read(&self) -> bool {
         if !self.tcb.state.is_connected() || self.tcb.state.is_closed() {
             return false;
         }
-        if self.packet_for_client_length.is_some() {
+        if self.packet_for_client.is_some() {
             // a packet is already pending
             return false;
         }
@@ -779,9 +1458,10 @@ impl Connection for TcpConnection {
         &mut self,
         selector: &mut Selector,
         client_channel: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        ip_packet: &IpPacket,
     ) {
-        self.handle_packet(selector, client_channel, ipv4_packet);
+        self.touch();
+        self.handle_packet(selector, client_channel, ip_packet);
         if !self.closed {
             self.update_interests(selector);
         }
@@ -790,22 +1470,27 @@ impl Connection for TcpConnection {
     fn close(&mut self, selector: &mut Selector) {
         cx_info!(target: TAG, self.id, "Close");
         self.closed = true;
-        if let Err(err) = selector.deregister(&self.stream, self.token) {
-            // do not panic, this can happen in mio
-            // see <https://github.com/Genymobile/gnirehtet/issues/136>
-            cx_warn!(
-                target: TAG,
-                self.id,
-                "Fail to deregister TCP stream: {:?}",
-                err
-            );
+        if let Some(timer) = self.wakeup_timer.take() {
+            selector.cancel(timer);
         }
+        self.cancel_retransmission(selector);
+        self.send_queue.clear();
+        self.release_stream(selector);
         // socket will be closed by RAII
     }
 
+    fn expire(&mut self, selector: &mut Selector, client_channel: &mut ClientChannel) {
+        if self.tcb.state != TcpState::Init {
+            // the client may still be there, do not leave it half-open
+            self.reply_empty_packet_to_client(selector, client_channel, tcp_header::FLAG_RST);
+        }
+        self.close(selector);
+    }
+
     fn is_expired(&self) -> bool {
-        // no external timeout expiration
-        false
+        let policy = self.device.policy();
+        let tcp_timeouts = policy.tcp_timeouts();
+        self.idle_since.elapsed() > self.tcb.state.timeout(tcp_timeouts)
     }
 
     fn is_closed(&self) -> bool {
@@ -814,27 +1499,403 @@ impl Connection for TcpConnection {
 }
 
 impl PacketSource for TcpConnection {
-    fn get(&mut self) -> Option<Ipv4Packet> {
-        if let Some(len) = self.packet_for_client_length {
-            Some(self.network_to_client.inflate(len))
-        } else {
-            None
-        }
+    fn get(&mut self) -> Option<IpPacket<'_>> {
+        self.packet_for_client
+            .as_mut()
+            .map(|raw| IpPacket::parse(raw))
     }
 
     fn next(&mut self, selector: &mut Selector) {
-        let len = self
-            .packet_for_client_length
+        let raw = self
+            .packet_for_client
+            .take()
             .expect("next() called on empty packet source");
         cx_debug!(
             target: TAG,
             self.id,
             "Deferred packet ({} bytes) sent to client {}",
-            len,
+            raw.len(),
             self.tcb.numbers()
         );
-        self.tcb.sequence_number += Wrapping(u32::from(len));
-        self.packet_for_client_length = None;
+        // the round-trip time and the retransmission timeout start now
+        self.send_queue.delivered(Instant::now());
+        self.schedule_retransmission(selector);
         self.update_interests(selector);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::config::Config;
+    use crate::relay::limits::FdBudget;
+    use crate::relay::quota::{self, QuotaStore, Usage};
+    use socket2::SockRef;
+    use std::io::{Read, Write};
+    use std::net::{self, TcpListener};
+    use std::thread;
+
+    const CLIENT_SEQUENCE_NUMBER: u32 = 1000;
+
+    /// A connection from a fake device (reading the client buffer) to a local server.
+    struct Harness {
+        selector: Selector,
+        client: Rc<RefCell<Client>>,
+        connection: Rc<RefCell<TcpConnection>>,
+        // the device side of the client socket
+        _device_stream: net::TcpStream,
+        listener: TcpListener,
+        // of the next packet from the device
+        sequence_number: u32,
+    }
+
+    impl Harness {
+        fn new(config: &str) -> Self {
+            let config = Rc::new(Config::parse(config).unwrap());
+            let mut selector = Selector::create().unwrap();
+
+            let device_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let device_stream =
+                net::TcpStream::connect(device_listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = device_listener.accept().unwrap();
+            let client = Client::create(
+                0,
+                &mut selector,
+                TcpStream::from_stream(stream).unwrap(),
+                config.clone(),
+                Rc::new(RefCell::new(QuotaStore::load(None).unwrap())),
+                Rc::new(FdBudget::new(None)),
+                Box::new(|_: &Client| ()),
+            )
+            .unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let mut raw = create_packet(port, CLIENT_SEQUENCE_NUMBER, 0, tcp_header::FLAG_SYN, &[]);
+            let ip_packet = IpPacket::parse(&mut raw);
+            let (ip_header_data, transport_header_data) = ip_packet.headers_data();
+            let id = ConnectionId::from_headers(ip_header_data, transport_header_data.unwrap());
+            let usage = Rc::new(RefCell::new(Usage::new(quota::today())));
+            let device = Rc::new(Device::new(None, config.device(None).clone(), usage));
+            let (ip_header, transport_header) = ip_packet.headers();
+            let connection = TcpConnection::create(
+                &mut selector,
+                id,
+                Rc::downgrade(&client),
+                None,
+                device,
+                ip_header,
+                transport_header.unwrap(),
+            )
+            .unwrap();
+            Self {
+                selector,
+                client,
+                connection,
+                _device_stream: device_stream,
+                listener,
+                sequence_number: CLIENT_SEQUENCE_NUMBER,
+            }
+        }
+
+        /// Send a packet from the device, acknowledging `acknowledgement_number`.
+        fn send(&mut self, acknowledgement_number: u32, flags: u16, payload: &[u8]) {
+            let port = self.listener.local_addr().unwrap().port();
+            let mut raw = create_packet(
+                port,
+                self.sequence_number,
+                acknowledgement_number,
+                flags,
+                payload,
+            );
+            let control_length =
+                u32::from(flags & (tcp_header::FLAG_SYN | tcp_header::FLAG_FIN) != 0);
+            self.sequence_number += payload.len() as u32 + control_length;
+            let ip_packet = IpPacket::parse(&mut raw);
+            let mut client = self.client.borrow_mut();
+            self.connection.borrow_mut().send_to_network(
+                &mut self.selector,
+                &mut client.channel(),
+                &ip_packet,
+            );
+        }
+
+        /// Send a packet from the device, acknowledging all the data sent by the relay.
+        fn send_ack(&mut self, flags: u16, payload: &[u8]) {
+            let acknowledgement_number = self.connection.borrow().tcb.sequence_number.0;
+            self.send(acknowledgement_number, flags, payload);
+        }
+
+        /// Open the connection, and return the server side.
+        fn handshake(&mut self) -> net::TcpStream {
+            self.send(0, tcp_header::FLAG_SYN, &[]);
+            let (server, _) = self.listener.accept().unwrap();
+            self.connection
+                .borrow_mut()
+                .process_connect(&mut self.selector);
+            self.send_ack(tcp_header::FLAG_ACK, &[]);
+            assert_eq!(TcpState::Established, self.connection.borrow().tcb.state);
+            // the SYN-ACK
+            assert_eq!(1, self.flush().len());
+            server
+        }
+
+        /// Read from the server, once its data are available.
+        fn receive(&mut self) {
+            for _ in 0..100 {
+                match self
+                    .connection
+                    .borrow_mut()
+                    .process_receive(&mut self.selector)
+                {
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                    result => return result.unwrap(),
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("Nothing received from the server");
+        }
+
+        /// Fill the client buffer, as if the device did not read.
+        fn fill_client_buffer(&mut self) {
+            let mut raw = create_packet(0, 0, 0, tcp_header::FLAG_ACK, &[]);
+            let ip_packet = IpPacket::parse(&mut raw);
+            let mut client = self.client.borrow_mut();
+            while client
+                .send_to_client(&mut self.selector, &ip_packet)
+                .is_ok()
+            {}
+        }
+
+        /// Return the packets read by the device.
+        fn flush(&mut self) -> Vec<Vec<u8>> {
+            let data = self.client.borrow_mut().flush_to_client(&mut self.selector);
+            let mut packets = Vec::new();
+            let mut remaining = &data[..];
+            while !remaining.is_empty() {
+                let length = u16::from_be_bytes([remaining[2], remaining[3]]) as usize;
+                packets.push(remaining[..length].to_vec());
+                remaining = &remaining[length..];
+            }
+            packets
+        }
+    }
+
+    fn create_packet(
+        port: u16,
+        sequence_number: u32,
+        acknowledgement_number: u32,
+        flags: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut raw = vec![0u8; 40];
+        raw[0] = 4 << 4 | 5; // version and IHL
+        raw[2..4].copy_from_slice(&(40 + payload.len() as u16).to_be_bytes()); // total length
+        raw[8] = 64; // TTL
+        raw[9] = 6; // protocol (TCP)
+        raw[12..16].copy_from_slice(&[10, 0, 2, 15]); // source address
+        raw[16..20].copy_from_slice(&[127, 0, 0, 1]); // destination address
+        raw[20..22].copy_from_slice(&40000u16.to_be_bytes()); // source port
+        raw[22..24].copy_from_slice(&port.to_be_bytes()); // destination port
+        raw[24..28].copy_from_slice(&sequence_number.to_be_bytes());
+        raw[28..32].copy_from_slice(&acknowledgement_number.to_be_bytes());
+        raw[32..34].copy_from_slice(&(5 << 12 | flags).to_be_bytes()); // data offset and flags
+        raw[34..36].copy_from_slice(&0xFFFFu16.to_be_bytes()); // window
+        raw.extend_from_slice(payload);
+        IpPacket::parse(&mut raw).compute_checksums();
+        raw
+    }
+
+    fn flags_and_payload(mut raw: Vec<u8>) -> (u16, Vec<u8>) {
+        let ip_packet = IpPacket::parse(&mut raw);
+        let flags = TcpConnection::tcp_header_of_packet(&ip_packet).flags();
+        (flags, ip_packet.payload().unwrap().to_vec())
+    }
+
+    #[test]
+    fn wait_for_client_buffer_space() {
+        let mut harness = Harness::new("");
+        let mut server = harness.handshake();
+        harness.fill_client_buffer();
+        server.write_all(b"hello").unwrap();
+        harness.receive();
+        {
+            let connection = harness.connection.borrow();
+            assert!(connection.packet_for_client.is_some());
+            // the segment is not lost, its retransmission timer is not started
+            assert!(connection.retransmission_timer.is_none());
+        }
+
+        // even if the timer was triggered, the pending segment is not retransmitted
+        for _ in 0..=MAX_RETRANSMISSIONS {
+            harness
+                .connection
+                .borrow_mut()
+                .on_retransmission_timeout(&mut harness.selector);
+        }
+        assert!(!harness.connection.borrow().closed);
+
+        // the device reads its buffer, then the pending segment is delivered exactly once
+        harness.flush();
+        let packets = harness.flush();
+        assert_eq!(1, packets.len());
+        let (flags, payload) = flags_and_payload(packets[0].clone());
+        assert_eq!(0, flags & tcp_header::FLAG_RST);
+        assert_eq!(b"hello", &payload[..]);
+        assert!(harness.flush().is_empty());
+
+        let connection = harness.connection.borrow();
+        assert!(connection.retransmission_timer.is_some());
+        assert_eq!(0, connection.send_queue.retransmissions());
+    }
+
+    #[test]
+    fn timeout_per_state() {
+        let mut tcp_timeouts = TcpTimeouts::default();
+        tcp_timeouts.set("tcp-handshake-timeout", "1s").unwrap();
+        tcp_timeouts.set("tcp-idle-timeout", "2s").unwrap();
+        tcp_timeouts.set("tcp-half-close-timeout", "3s").unwrap();
+        tcp_timeouts.set("tcp-closing-timeout", "4s").unwrap();
+        let expected = [
+            (TcpState::Init, 1),
+            (TcpState::SynSent, 1),
+            (TcpState::SynReceived, 1),
+            (TcpState::Established, 2),
+            (TcpState::CloseWait, 2),
+            (TcpState::FinWait1, 3),
+            (TcpState::FinWait2, 3),
+            (TcpState::Closing, 4),
+            (TcpState::LastAck, 4),
+        ];
+        for (state, secs) in expected {
+            assert_eq!(Duration::from_secs(secs), state.timeout(&tcp_timeouts));
+        }
+    }
+
+    #[test]
+    fn reset_device_on_expiry() {
+        let mut harness = Harness::new("");
+        harness.handshake();
+        {
+            let mut client = harness.client.borrow_mut();
+            harness
+                .connection
+                .borrow_mut()
+                .expire(&mut harness.selector, &mut client.channel());
+        }
+        let packets = harness.flush();
+        assert_eq!(1, packets.len());
+        let (flags, _) = flags_and_payload(packets[0].clone());
+        assert_eq!(tcp_header::FLAG_RST, flags);
+        assert!(harness.connection.borrow().closed);
+    }
+
+    #[test]
+    fn expire_without_reset_before_syn() {
+        let mut harness = Harness::new("");
+        {
+            let mut client = harness.client.borrow_mut();
+            harness
+                .connection
+                .borrow_mut()
+                .expire(&mut harness.selector, &mut client.channel());
+        }
+        // the device has not opened any connection, there is nothing to reset
+        assert!(harness.flush().is_empty());
+        assert!(harness.connection.borrow().closed);
+    }
+
+    #[test]
+    fn apply_keepalive() {
+        let harness = Harness::new("");
+        let connection = harness.connection.borrow();
+        assert!(!SockRef::from(&connection.stream).keepalive().unwrap());
+
+        let harness = Harness::new("[device *]\ntcp-keepalive = 30s\n");
+        let connection = harness.connection.borrow();
+        assert!(SockRef::from(&connection.stream).keepalive().unwrap());
+    }
+
+    #[test]
+    fn half_close_by_device() {
+        let mut harness = Harness::new("");
+        let mut server = harness.handshake();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        harness.send_ack(tcp_header::FLAG_FIN | tcp_header::FLAG_ACK, &[]);
+        assert_eq!(TcpState::CloseWait, harness.connection.borrow().tcb.state);
+        let packets = harness.flush();
+        assert_eq!(1, packets.len());
+        assert_eq!(
+            tcp_header::FLAG_ACK,
+            flags_and_payload(packets[0].clone()).0
+        );
+        // the FIN is forwarded to the server
+        assert_eq!(0, server.read(&mut [0; 16]).unwrap());
+
+        // the server may still send data to the device
+        server.write_all(b"response").unwrap();
+        harness.receive();
+        let packets = harness.flush();
+        assert_eq!(1, packets.len());
+        assert_eq!(b"response", &flags_and_payload(packets[0].clone()).1[..]);
+        assert_eq!(TcpState::CloseWait, harness.connection.borrow().tcb.state);
+
+        // until it closes its side
+        server.shutdown(Shutdown::Write).unwrap();
+        harness.receive();
+        assert_eq!(TcpState::LastAck, harness.connection.borrow().tcb.state);
+        let packets = harness.flush();
+        assert_eq!(1, packets.len());
+        let (flags, _) = flags_and_payload(packets[0].clone());
+        assert_eq!(tcp_header::FLAG_FIN | tcp_header::FLAG_ACK, flags);
+
+        harness.send_ack(tcp_header::FLAG_ACK, &[]);
+        assert!(harness.connection.borrow().closed);
+    }
+
+    #[test]
+    fn simultaneous_close() {
+        let mut harness = Harness::new("");
+        let server = harness.handshake();
+        server.shutdown(Shutdown::Write).unwrap();
+        harness.receive();
+        assert_eq!(TcpState::FinWait1, harness.connection.borrow().tcb.state);
+        assert_eq!(1, harness.flush().len());
+
+        // the device sends its FIN before receiving the FIN of the relay
+        let fin_sequence_number = harness.connection.borrow().tcb.fin_sequence_number.unwrap();
+        harness.send(
+            fin_sequence_number,
+            tcp_header::FLAG_FIN | tcp_header::FLAG_ACK,
+            &[],
+        );
+        assert_eq!(TcpState::Closing, harness.connection.borrow().tcb.state);
+        let packets = harness.flush();
+        assert_eq!(1, packets.len());
+        assert_eq!(
+            tcp_header::FLAG_ACK,
+            flags_and_payload(packets[0].clone()).0
+        );
+
+        harness.send_ack(tcp_header::FLAG_ACK, &[]);
+        assert!(harness.connection.borrow().closed);
+    }
+
+    #[test]
+    fn expire_half_closed_by_relay() {
+        let mut tcp_timeouts = TcpTimeouts::default();
+        tcp_timeouts.set("tcp-half-close-timeout", "30s").unwrap();
+        // the device has not answered the FIN of the relay for 2 minutes
+        let idle_since = Instant::now() - Duration::from_secs(120);
+        for state in [TcpState::FinWait1, TcpState::FinWait2] {
+            assert!(idle_since.elapsed() > state.timeout(&tcp_timeouts));
+        }
+        // but it may still be sending data after its own FIN
+        for state in [TcpState::Established, TcpState::CloseWait] {
+            assert!(idle_since.elapsed() < state.timeout(&tcp_timeouts));
+        }
+    }
+}
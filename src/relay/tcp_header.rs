@@ -14,7 +14,7 @@
  * limitations under the License.
  */
 
-use super::ipv4_header::Ipv4HeaderData;
+use super::ip_header::IpHeaderData;
 use byteorder::{BigEndian, ByteOrder};
 use std::mem;
 
@@ -29,8 +29,7 @@ pub struct TcpHeaderMut<'a> {
     data: &'a mut TcpHeaderData,
 }
 
-#[derive(Clone)]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TcpHeaderData {
     source_port: u16,
     destination_port: u16,
@@ -41,12 +40,36 @@ pub struct TcpHeaderData {
     window: u16,
 }
 
+// the header without options
+pub const MIN_HEADER_LENGTH: u8 = 20;
+
 pub const FLAG_FIN: u16 = 1;
 pub const FLAG_SYN: u16 = 1 << 1;
 pub const FLAG_RST: u16 = 1 << 2;
 pub const FLAG_PSH: u16 = 1 << 3;
 pub const FLAG_ACK: u16 = 1 << 4;
 
+// option kinds (RFC 793, RFC 2018 and RFC 7323)
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+const OPTION_WINDOW_SCALE: u8 = 3;
+const OPTION_SACK_PERMITTED: u8 = 4;
+const OPTION_TIMESTAMPS: u8 = 8;
+
+// the shift count is limited to 14 (RFC 7323 section 2.3)
+pub const MAX_WINDOW_SCALE: u8 = 14;
+
+/// The TCP options negotiated in a SYN.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    mss: Option<u16>,
+    window_scale: Option<u8>,
+    sack_permitted: bool,
+    // (TSval, TSecr)
+    timestamps: Option<(u32, u32)>,
+}
+
 #[allow(dead_code)]
 impl TcpHeaderData {
     pub fn parse(raw: &[u8]) -> Self {
@@ -133,6 +156,93 @@ impl TcpHeaderData {
     }
 }
 
+#[allow(dead_code)]
+impl TcpOptions {
+    pub fn new(mss: Option<u16>, window_scale: Option<u8>) -> Self {
+        Self {
+            mss,
+            window_scale,
+            ..Default::default()
+        }
+    }
+
+    /// Parse the options following the fixed header, ignoring the unknown and malformed ones.
+    pub fn parse(raw: &[u8]) -> Self {
+        let mut options = Self::default();
+        let mut i = 0;
+        while i < raw.len() {
+            let kind = raw[i];
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => {
+                    i += 1;
+                    continue;
+                }
+                _ => (),
+            }
+            let length = match raw.get(i + 1) {
+                Some(&length) if length >= 2 && i + length as usize <= raw.len() => length as usize,
+                _ => break,
+            };
+            let value = &raw[i + 2..i + length];
+            match (kind, value.len()) {
+                (OPTION_MSS, 2) => options.mss = Some(BigEndian::read_u16(value)),
+                (OPTION_WINDOW_SCALE, 1) => {
+                    options.window_scale = Some(value[0].min(MAX_WINDOW_SCALE))
+                }
+                (OPTION_SACK_PERMITTED, 0) => options.sack_permitted = true,
+                (OPTION_TIMESTAMPS, 8) => {
+                    options.timestamps = Some((
+                        BigEndian::read_u32(&value[..4]),
+                        BigEndian::read_u32(&value[4..]),
+                    ))
+                }
+                _ => (),
+            }
+            i += length;
+        }
+        options
+    }
+
+    /// Serialize the MSS and window scale options, padded to a multiple of 4 bytes.
+    ///
+    /// SACK-permitted and timestamps are never sent by the relay.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = Vec::new();
+        if let Some(mss) = self.mss {
+            raw.extend_from_slice(&[OPTION_MSS, 4]);
+            raw.extend_from_slice(&mss.to_be_bytes());
+        }
+        if let Some(window_scale) = self.window_scale {
+            raw.extend_from_slice(&[OPTION_NOP, OPTION_WINDOW_SCALE, 3, window_scale]);
+        }
+        while !raw.len().is_multiple_of(4) {
+            raw.push(OPTION_NOP);
+        }
+        raw
+    }
+
+    #[inline]
+    pub fn mss(&self) -> Option<u16> {
+        self.mss
+    }
+
+    #[inline]
+    pub fn window_scale(&self) -> Option<u8> {
+        self.window_scale
+    }
+
+    #[inline]
+    pub fn sack_permitted(&self) -> bool {
+        self.sack_permitted
+    }
+
+    #[inline]
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        self.timestamps
+    }
+}
+
 // shared definition for UdpHeader and UdpHeaderMut
 macro_rules! tcp_header_common {
     ($name:ident, $raw_type:ty, $data_type:ty) => {
@@ -140,10 +250,7 @@ macro_rules! tcp_header_common {
         #[allow(dead_code)]
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
-                Self {
-                    raw,
-                    data,
-                }
+                Self { raw, data }
             }
 
             #[inline]
@@ -186,6 +293,10 @@ macro_rules! tcp_header_common {
                 self.data.window
             }
 
+            pub fn options(&self) -> TcpOptions {
+                TcpOptions::parse(&self.raw[20..self.data.header_length as usize])
+            }
+
             #[inline]
             pub fn flags(&self) -> u16 {
                 self.data.flags
@@ -275,11 +386,26 @@ impl<'a> TcpHeaderMut<'a> {
         BigEndian::write_u16(&mut self.raw[12..14], data_offset_and_flags);
     }
 
+    #[inline]
+    pub fn set_window(&mut self, window: u16) {
+        self.data.window = window;
+        BigEndian::write_u16(&mut self.raw[14..16], window);
+    }
+
     #[inline]
     pub fn shrink_options(&mut self) {
         self.set_data_offset(5);
     }
 
+    /// Set the header length to include `options_length` bytes of options.
+    ///
+    /// The options must already be written after the fixed header.
+    #[inline]
+    pub fn set_options_length(&mut self, options_length: u8) {
+        debug_assert!(options_length.is_multiple_of(4));
+        self.set_data_offset(5 + options_length / 4);
+    }
+
     #[inline]
     fn set_data_offset(&mut self, data_offset: u8) {
         let mut data_offset_and_flags = BigEndian::read_u16(&self.raw[12..14]);
@@ -298,15 +424,11 @@ impl<'a> TcpHeaderMut<'a> {
         BigEndian::write_u16(&mut self.raw[16..18], checksum);
     }
 
-    pub fn update_checksum(&mut self, ipv4_header_data: &Ipv4HeaderData, payload: &[u8]) {
-        // pseudo-header checksum (cf rfc793 section 3.1)
-        let source = ipv4_header_data.source();
-        let destination = ipv4_header_data.destination();
-        let transport_length =
-            ipv4_header_data.total_length() - u16::from(ipv4_header_data.header_length());
+    pub fn update_checksum(&mut self, ip_header_data: &IpHeaderData, payload: &[u8]) {
+        let transport_length = ip_header_data.total_length() - ip_header_data.header_length();
 
         let header_length = self.header_length();
-        debug_assert!(header_length % 2 == 0 && header_length >= 20);
+        debug_assert!(header_length.is_multiple_of(2) && header_length >= 20);
 
         let payload_length = transport_length - u16::from(header_length);
         debug_assert_eq!(
@@ -315,12 +437,8 @@ impl<'a> TcpHeaderMut<'a> {
             "Payload length does not match"
         );
 
-        let mut sum = 6u32; // protocol: TCP = 6
-        sum += source >> 16;
-        sum += source & 0xFFFF;
-        sum += destination >> 16;
-        sum += destination & 0xFFFF;
-        sum += u32::from(transport_length);
+        // pseudo-header checksum (cf rfc793 section 3.1)
+        let mut sum = ip_header_data.pseudo_header_sum(6); // protocol: TCP = 6
 
         // reset checksum field, so that it can be added with other bytes
         self.set_checksum(0);
@@ -347,7 +465,7 @@ impl<'a> TcpHeaderMut<'a> {
                 sum += u32::from(*p.offset(1));
                 p = p.offset(2);
             }
-            if payload_length % 2 != 0 {
+            if !payload_length.is_multiple_of(2) {
                 // if payload length is odd, the last byte is considered high-order
                 hsum += u32::from(*payload.get_unchecked((payload_length - 1) as usize));
             }
@@ -364,15 +482,15 @@ impl<'a> TcpHeaderMut<'a> {
 }
 
 #[cfg(test)]
+#[allow(clippy::identity_op)] // checksums are written word by word for readability
 mod tests {
     use super::*;
-    use crate::relay::ipv4_packet::Ipv4Packet;
+    use crate::relay::ip_packet::IpPacket;
     use crate::relay::transport_header::TransportHeaderMut;
     use byteorder::{BigEndian, WriteBytesExt};
 
     fn create_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(44);
+        let mut raw = Vec::with_capacity(44);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -399,8 +517,7 @@ mod tests {
     }
 
     fn create_odd_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(45);
+        let mut raw = Vec::with_capacity(45);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -429,8 +546,7 @@ mod tests {
     }
 
     fn create_empty_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(40);
+        let mut raw = Vec::with_capacity(40);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -455,8 +571,7 @@ mod tests {
     }
 
     fn create_tcp_header() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(20);
+        let mut raw = Vec::with_capacity(20);
 
         raw.write_u16::<BigEndian>(0x1234).unwrap(); // source port
         raw.write_u16::<BigEndian>(0x5678).unwrap(); // destination port
@@ -516,15 +631,58 @@ mod tests {
         assert_eq!(1111, raw_destination_port);
     }
 
+    #[test]
+    fn parse_syn_options() {
+        let raw = [
+            2, 4, 0x3F, 0xD8, // MSS 16344
+            4, 2, // SACK permitted
+            8, 10, 0, 0, 0, 42, 0, 0, 0, 0, // timestamps
+            1, // NOP
+            3, 3, 20, // window scale (too large)
+        ];
+        let options = TcpOptions::parse(&raw);
+        assert_eq!(Some(16344), options.mss());
+        assert!(options.sack_permitted());
+        assert_eq!(Some((42, 0)), options.timestamps());
+        assert_eq!(Some(MAX_WINDOW_SCALE), options.window_scale());
+    }
+
+    #[test]
+    fn parse_malformed_options() {
+        // the MSS length exceeds the available bytes
+        let options = TcpOptions::parse(&[1, 1, 2, 4, 0x05]);
+        assert_eq!(TcpOptions::default(), options);
+        // no options after the end of option list
+        let options = TcpOptions::parse(&[0, 0, 2, 4, 0x05, 0xB4]);
+        assert_eq!(TcpOptions::default(), options);
+    }
+
+    #[test]
+    fn write_options() {
+        let options = TcpOptions::new(Some(1460), Some(3));
+        let raw = options.to_bytes();
+        assert_eq!(vec![2, 4, 0x05, 0xB4, 1, 3, 3, 3], raw);
+        assert_eq!(options, TcpOptions::parse(&raw));
+    }
+
+    #[test]
+    fn reject_short_data_offset() {
+        let raw = &mut create_packet()[..];
+        // data offset 4 (16 bytes), smaller than the fixed header
+        BigEndian::write_u16(&mut raw[32..34], 4 << 12);
+        let ip_packet = IpPacket::parse(raw);
+        assert!(ip_packet.transport_header_data().is_none());
+    }
+
     #[test]
     fn compute_checksum() {
         let raw = &mut create_packet()[..];
-        let mut ipv4_packet = Ipv4Packet::parse(raw);
-        let (ipv4_header, mut transport) = ipv4_packet.split_mut();
+        let mut ip_packet = IpPacket::parse(raw);
+        let (ip_header, mut transport) = ip_packet.split_mut();
         if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
             // set a fake checksum value to assert that it is correctly computed
             tcp_header.set_checksum(0x79);
-            tcp_header.update_checksum(ipv4_header.data(), payload);
+            tcp_header.update_checksum(&ip_header.data_clone(), payload);
             let checksum = tcp_header.checksum();
 
             let expected_checksum = {
@@ -561,12 +719,12 @@ mod tests {
     #[test]
     fn compute_checksum_odd() {
         let raw = &mut create_odd_packet()[..];
-        let mut ipv4_packet = Ipv4Packet::parse(raw);
-        let (ipv4_header, mut transport) = ipv4_packet.split_mut();
+        let mut ip_packet = IpPacket::parse(raw);
+        let (ip_header, mut transport) = ip_packet.split_mut();
         if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
             // set a fake checksum value to assert that it is correctly computed
             tcp_header.set_checksum(0x79);
-            tcp_header.update_checksum(ipv4_header.data(), payload);
+            tcp_header.update_checksum(&ip_header.data_clone(), payload);
             let checksum = tcp_header.checksum();
 
             let expected_checksum = {
@@ -603,12 +761,12 @@ mod tests {
     #[test]
     fn compute_checksum_empty_payload() {
         let raw = &mut create_empty_packet()[..];
-        let mut ipv4_packet = Ipv4Packet::parse(raw);
-        let (ipv4_header, mut transport) = ipv4_packet.split_mut();
+        let mut ip_packet = IpPacket::parse(raw);
+        let (ip_header, mut transport) = ip_packet.split_mut();
         if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
             // set a fake checksum value to assert that it is correctly computed
             tcp_header.set_checksum(0x79);
-            tcp_header.update_checksum(ipv4_header.data(), payload);
+            tcp_header.update_checksum(&ip_header.data_clone(), payload);
             let checksum = tcp_header.checksum();
 
             let expected_checksum = {
@@ -640,8 +798,7 @@ mod tests {
     }
 
     fn create_long_packet() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(45);
+        let mut raw = Vec::with_capacity(45);
 
         raw.write_u8(4u8 << 4 | 5).unwrap(); // version_and_ihl
         raw.write_u8(0).unwrap(); //ToS
@@ -677,13 +834,13 @@ mod tests {
     #[test]
     fn bench_checksum() {
         let raw = &mut create_long_packet()[..];
-        let mut ipv4_packet = Ipv4Packet::parse(raw);
-        let (ipv4_header, mut transport) = ipv4_packet.split_mut();
+        let mut ip_packet = IpPacket::parse(raw);
+        let (ip_header, mut transport) = ip_packet.split_mut();
         if let Some((TransportHeaderMut::Tcp(ref mut tcp_header), ref payload)) = transport {
             use std::time::Instant;
             let start = Instant::now();
             for _ in 0..5000000 {
-                tcp_header.update_checksum(ipv4_header.data(), payload);
+                tcp_header.update_checksum(&ip_header.data_clone(), payload);
             }
             let duration = start.elapsed();
             let ms = duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000;
@@ -0,0 +1,93 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Duration;
+
+use super::config::parse_duration;
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+const DEFAULT_HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_CLOSING_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Inactivity timeouts of the TCP connections, depending on their state, and keepalive of the
+/// upstream sockets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TcpTimeouts {
+    // until the connection is established
+    handshake: Duration,
+    // once established, or half-closed by the device
+    idle: Duration,
+    // once the relay has sent a FIN (like tcp_fin_timeout on Linux)
+    half_close: Duration,
+    // once both sides have sent a FIN
+    closing: Duration,
+    keepalive: Option<Duration>,
+}
+
+impl Default for TcpTimeouts {
+    fn default() -> Self {
+        Self {
+            handshake: DEFAULT_HANDSHAKE_TIMEOUT,
+            idle: DEFAULT_IDLE_TIMEOUT,
+            half_close: DEFAULT_HALF_CLOSE_TIMEOUT,
+            closing: DEFAULT_CLOSING_TIMEOUT,
+            keepalive: None,
+        }
+    }
+}
+
+impl TcpTimeouts {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not a TCP timeout key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "tcp-handshake-timeout" => self.handshake = parse_duration(value)?,
+            "tcp-idle-timeout" => self.idle = parse_duration(value)?,
+            "tcp-half-close-timeout" => self.half_close = parse_duration(value)?,
+            "tcp-closing-timeout" => self.closing = parse_duration(value)?,
+            "tcp-keepalive" => {
+                self.keepalive = match value {
+                    "none" => None,
+                    _ => Some(parse_duration(value)?),
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn handshake(&self) -> Duration {
+        self.handshake
+    }
+
+    pub fn idle(&self) -> Duration {
+        self.idle
+    }
+
+    pub fn half_close(&self) -> Duration {
+        self.half_close
+    }
+
+    pub fn closing(&self) -> Duration {
+        self.closing
+    }
+
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+    }
+}
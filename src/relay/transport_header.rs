@@ -14,9 +14,9 @@
  * limitations under the License.
  */
 
-use super::icmp_header::{IcmpHeader, IcmpHeaderData, IcmpHeaderMut};
-use super::ipv4_header::{Ipv4HeaderData, Protocol};
-use super::tcp_header::{TcpHeader, TcpHeaderData, TcpHeaderMut};
+use super::icmp_header::{IcmpHeader, IcmpHeaderData, IcmpHeaderMut, ICMP_HEADER_LENGTH};
+use super::ip_header::{IpHeaderData, Protocol};
+use super::tcp_header::{self, TcpHeader, TcpHeaderData, TcpHeaderMut};
 use super::udp_header::{UdpHeader, UdpHeaderData, UdpHeaderMut, UDP_HEADER_LENGTH};
 
 #[derive(Debug)]
@@ -41,13 +41,28 @@ pub enum TransportHeaderData {
 
 #[allow(dead_code)]
 impl TransportHeaderData {
+    /// Parse the transport header at the start of `raw` (the IP payload).
+    ///
+    /// Return `None` if the protocol is not supported or if the header is truncated.
     pub fn parse(protocol: Protocol, raw: &[u8]) -> Option<Self> {
-        match protocol {
-            Protocol::Udp => Some(UdpHeaderData::parse(raw).into()),
-            Protocol::Tcp => Some(TcpHeaderData::parse(raw).into()),
-            Protocol::Icmp => Some(IcmpHeaderData::parse(raw).into()),
-            _ => None,
+        let fixed_length = match protocol {
+            Protocol::Udp => UDP_HEADER_LENGTH as usize,
+            Protocol::Tcp => tcp_header::MIN_HEADER_LENGTH as usize,
+            Protocol::Icmp | Protocol::Icmpv6 => ICMP_HEADER_LENGTH,
+            _ => return None,
+        };
+        if raw.len() < fixed_length {
+            return None;
         }
+        let header: Self = match protocol {
+            Protocol::Udp => UdpHeaderData::parse(raw).into(),
+            Protocol::Tcp => TcpHeaderData::parse(raw).into(),
+            _ => IcmpHeaderData::parse(raw).into(),
+        };
+        // the TCP data offset may not cover the fixed header, and the options must be present
+        Some(header).filter(|header| {
+            (fixed_length..=raw.len()).contains(&(header.header_length() as usize))
+        })
     }
 
     #[inline]
@@ -208,13 +223,13 @@ impl<'a> TransportHeaderMut<'a> {
     }
 
     #[inline]
-    pub fn update_checksum(&mut self, ipv4_header_data: &Ipv4HeaderData, payload: &[u8]) {
+    pub fn update_checksum(&mut self, ip_header_data: &IpHeaderData, payload: &[u8]) {
         match *self {
             TransportHeaderMut::Tcp(ref mut tcp_header) => {
-                tcp_header.update_checksum(ipv4_header_data, payload)
+                tcp_header.update_checksum(ip_header_data, payload)
             }
             TransportHeaderMut::Udp(ref mut udp_header) => {
-                udp_header.update_checksum(ipv4_header_data, payload)
+                udp_header.update_checksum(ip_header_data, payload)
             }
             TransportHeaderMut::Icmp(_) => (), // ICPM
         }
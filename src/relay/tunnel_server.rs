@@ -22,27 +22,48 @@ use std::io;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::ptr;
 use std::rc::{Rc, Weak};
+use std::time::Duration;
 
 use super::client::Client;
-use super::selector::Selector;
+use super::config::Config;
+use super::limits::{self, FdBudget};
+use super::quota::QuotaStore;
+use super::selector::{Selector, TimerToken};
 
 const TAG: &str = "TunnelServer";
 
+// when the relay cannot open more files, wait for some to be closed before accepting again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 pub struct TunnelServer {
     self_weak: Weak<RefCell<TunnelServer>>,
     clients: Vec<Rc<RefCell<Client>>>,
     tcp_listener: TcpListener,
     next_client_id: u32,
+    config: Rc<Config>,
+    quota_store: Rc<RefCell<QuotaStore>>,
+    fd_budget: Rc<FdBudget>,
+    accept_retry_timer: Option<TimerToken>,
 }
 
 impl TunnelServer {
-    pub fn create(port: u16, selector: &mut Selector) -> io::Result<Rc<RefCell<Self>>> {
+    pub fn create(
+        port: u16,
+        config: Rc<Config>,
+        selector: &mut Selector,
+    ) -> io::Result<Rc<RefCell<Self>>> {
         let tcp_listener = Self::start_socket(port)?;
+        let quota_store = QuotaStore::load(config.quota_state_file())?;
+        let fd_budget = FdBudget::new(config.max_open_files());
         let rc = Rc::new(RefCell::new(Self {
             self_weak: Weak::new(),
             clients: Vec::new(),
             tcp_listener,
             next_client_id: 0,
+            config,
+            quota_store: Rc::new(RefCell::new(quota_store)),
+            fd_budget: Rc::new(fd_budget),
+            accept_retry_timer: None,
         }));
 
         // keep a shared reference to this
@@ -69,17 +90,52 @@ impl TunnelServer {
     }
 
     fn on_ready(&mut self, selector: &mut Selector, _: Event) {
-        match self.accept_client(selector) {
-            Ok(_) => debug!(target: TAG, "New client accepted"),
-            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
-                debug!(target: TAG, "Spurious event, ignoring");
+        self.accept_clients(selector);
+    }
+
+    fn accept_clients(&mut self, selector: &mut Selector) {
+        // the listener is edge-triggered, so accept all the pending clients
+        loop {
+            match self.accept_client(selector) {
+                Ok(_) => debug!(target: TAG, "New client accepted"),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref err) if limits::is_out_of_files(err) => {
+                    // the pending clients will not trigger any new event, retry later
+                    error!(
+                        target: TAG,
+                        "Cannot accept client: {}, retrying in {:?}",
+                        err,
+                        ACCEPT_RETRY_DELAY
+                    );
+                    self.schedule_accept_retry(selector);
+                    break;
+                }
+                Err(err) => error!(target: TAG, "Cannot accept client: {}", err),
             }
-            Err(err) => error!(target: TAG, "Cannot accept client: {}", err),
         }
     }
 
+    fn schedule_accept_retry(&mut self, selector: &mut Selector) {
+        if self.accept_retry_timer.is_some() {
+            return;
+        }
+        let weak = self.self_weak.clone();
+        let handler = move |selector: &mut Selector| {
+            if let Some(rc) = weak.upgrade() {
+                let mut tunnel_server = rc.borrow_mut();
+                tunnel_server.accept_retry_timer = None;
+                tunnel_server.accept_clients(selector);
+            }
+        };
+        self.accept_retry_timer = Some(selector.schedule(ACCEPT_RETRY_DELAY, handler));
+    }
+
     fn accept_client(&mut self, selector: &mut Selector) -> io::Result<()> {
         let (stream, _) = self.tcp_listener.accept()?;
+        if !self.fd_budget.try_acquire() {
+            // the stream is closed on drop
+            return Err(io::Error::other("file descriptor budget exhausted"));
+        }
         let client_id = self.next_client_id;
         self.next_client_id += 1;
         let weak = self.self_weak.clone();
@@ -94,7 +150,16 @@ impl TunnelServer {
                 );
             }
         });
-        let client = Client::create(client_id, selector, stream, on_client_closed)?;
+        let client = Client::create(
+            client_id,
+            selector,
+            stream,
+            self.config.clone(),
+            self.quota_store.clone(),
+            self.fd_budget.clone(),
+            on_client_closed,
+        )
+        .inspect_err(|_| self.fd_budget.release(1))?;
         self.clients.push(client);
         info!(target: TAG, "Client #{} connected", client_id);
         Ok(())
@@ -124,11 +189,36 @@ impl TunnelServer {
             })
             .expect("Trying to remove an unknown client");
         self.clients.swap_remove(index);
+        self.fd_budget.release(1);
+        self.save_quota_state();
+    }
+
+    fn save_quota_state(&mut self) {
+        if let Err(err) = self.quota_store.borrow_mut().save() {
+            error!(target: TAG, "Cannot save quota state: {}", err);
+        }
+    }
+
+    /// Use a new configuration for the next clients, and apply the device policies to the
+    /// connected clients (with or without a serial).
+    pub fn update_config(&mut self, config: Rc<Config>) {
+        for client in &self.clients {
+            client.borrow_mut().update_config(config.clone());
+        }
+        self.fd_budget.set_max(config.max_open_files());
+        self.config = config;
     }
 
     pub fn clean_up(&mut self, selector: &mut Selector) {
         for client in &self.clients {
             client.borrow_mut().clean_expired_connections(selector);
         }
+        debug!(
+            target: TAG,
+            "{} clients, {} file descriptors in use",
+            self.clients.len(),
+            self.fd_budget.used()
+        );
+        self.save_quota_state();
     }
 }
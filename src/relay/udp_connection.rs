@@ -17,36 +17,73 @@
 use log::*;
 use mio::net::UdpSocket;
 use mio::{Event, PollOpt, Ready, Token};
+use socket2::SockRef;
 use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::SocketAddr;
 use std::rc::{Rc, Weak};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use super::binary;
 use super::client::{Client, ClientChannel};
-use super::connection::{Connection, ConnectionId};
+use super::connection::{self, Connection, ConnectionId};
+use super::datagram::SendToAdapter;
 use super::datagram_buffer::DatagramBuffer;
-use super::ipv4_header::Ipv4Header;
-use super::ipv4_packet::{Ipv4Packet, MAX_PACKET_LENGTH};
+use super::device::Device;
+use super::dns::{self, PendingQueries};
+use super::egress::EgressPolicy;
+use super::icmp_error::{self, IcmpError};
+use super::ip_header::{IpHeader, IpHeaderData};
+use super::ip_packet::{IpPacket, MAX_PACKET_LENGTH};
 use super::packetizer::Packetizer;
-use super::selector::Selector;
+use super::path_mtu;
+use super::selector::{Selector, TimerToken};
+use super::shaper::Direction;
+use super::socket_error::{self, SocketError};
 use super::transport_header::TransportHeader;
+use super::udp_group::{self, Group};
+use super::udp_nat::{Filtering, Mapping, Remotes};
 
 const TAG: &str = "UdpConnection";
 
-pub const IDLE_TIMEOUT_SECONDS: u64 = 2 * 60;
+// an ICMP error quotes the IP header and the first 8 bytes of the payload (the UDP header)
+const QUOTED_PAYLOAD_LENGTH: usize = 8;
 
 pub struct UdpConnection {
+    self_weak: Weak<RefCell<UdpConnection>>,
     id: ConnectionId,
     client: Weak<RefCell<Client>>,
     socket: UdpSocket,
     interests: Ready,
     token: Token,
     client_to_network: DatagramBuffer,
+    // the destinations of the datagrams in client_to_network (unconnected socket only)
+    destinations: VecDeque<SocketAddr>,
     network_to_client: Packetizer,
+    // the filtering of an endpoint-independent mapping (the socket is not connected), or None
+    filtering: Option<Filtering>,
+    // the group of hosts the datagrams are bridged to (broadcast or multicast), if any
+    group: Option<Group>,
+    // the TTL (or hop limit) of the socket, copied from the client packets
+    ttl: Option<u8>,
+    // the "don't fragment" flag of the socket, copied from the client packets (IPv4 only)
+    dont_fragment: Option<bool>,
+    // the remote endpoints contacted, with the headers to quote in the errors reported to the
+    // client
+    remotes: Remotes,
+    device: Rc<Device>,
+    // pending timer to resume a transfer throttled by the shaper
+    wakeup_timer: Option<TimerToken>,
+    // pending timer to close the flow once idle for idle_timeout
+    expiry_timer: Option<TimerToken>,
     closed: bool,
     idle_since: Instant,
+    // the longest timeout of the destinations contacted
+    idle_timeout: Duration,
+    // the DNS queries waiting for their response (connected to a DNS server only)
+    dns_queries: Option<PendingQueries>,
 }
 
 impl UdpConnection {
@@ -55,28 +92,82 @@ impl UdpConnection {
         selector: &mut Selector,
         id: ConnectionId,
         client: Weak<RefCell<Client>>,
-        ipv4_header: Ipv4Header,
+        device: Rc<Device>,
+        ip_header: IpHeader,
         transport_header: TransportHeader,
+        group: Option<Group>,
     ) -> io::Result<Rc<RefCell<Self>>> {
         cx_info!(target: TAG, id, "Open");
-        let socket = Self::create_socket(&id)?;
-        let packetizer = Packetizer::new(&ipv4_header, &transport_header);
+        let policy = device.policy();
+        let (socket, filtering) = match group {
+            Some(group) => {
+                let interface = policy
+                    .udp_groups()
+                    .bridge(group)
+                    .expect("Datagrams to the group are not bridged");
+                let destination = id.rewritten_destination();
+                let socket = udp_group::bind_udp(
+                    group,
+                    interface,
+                    &destination,
+                    transport_header.source_port(),
+                )?;
+                // any host of the group may respond
+                (socket, Some(Filtering::EndpointIndependent))
+            }
+            None => {
+                let udp_nat = policy.udp_nat();
+                let filtering = match udp_nat.mapping() {
+                    Mapping::EndpointDependent => None,
+                    Mapping::EndpointIndependent => Some(udp_nat.filtering()),
+                };
+                let socket = Self::create_socket(&id, policy.egress(), filtering.is_none())?;
+                (socket, filtering)
+            }
+        };
+        // a flow to a DNS server is closed once all its queries are answered
+        let dns_queries =
+            if filtering.is_none() && id.rewritten_destination().port() == dns::DNS_PORT {
+                Some(PendingQueries::default())
+            } else {
+                None
+            };
+        let idle_timeout = policy.udp_timeouts().idle(&id.rewritten_destination());
+        let mut packetizer = Packetizer::new(&ip_header, &transport_header);
+        if !device.policy().udp_checksum() {
+            packetizer.disable_udp_checksum();
+        }
         let interests = Ready::readable();
         let rc = Rc::new(RefCell::new(Self {
+            self_weak: Weak::new(),
             id,
             client,
             socket,
             interests,
             token: Token(0), // default value, will be set afterwards
             client_to_network: DatagramBuffer::new(4 * MAX_PACKET_LENGTH),
+            destinations: VecDeque::new(),
             network_to_client: packetizer,
+            filtering,
+            group,
+            ttl: None,
+            dont_fragment: None,
+            remotes: Remotes::default(),
+            device,
+            wakeup_timer: None,
+            expiry_timer: None,
             closed: false,
             idle_since: Instant::now(),
+            idle_timeout,
+            dns_queries,
         }));
 
         {
             let mut self_ref = rc.borrow_mut();
 
+            // keep a shared reference to this
+            self_ref.self_weak = Rc::downgrade(&rc);
+
             let rc2 = rc.clone();
             // must anotate selector type: https://stackoverflow.com/a/44004103/1987178
             let handler =
@@ -84,14 +175,25 @@ impl UdpConnection {
             let token =
                 selector.register(&self_ref.socket, handler, interests, PollOpt::level())?;
             self_ref.token = token;
+            self_ref.schedule_expiry(selector, idle_timeout);
         }
         Ok(rc)
     }
 
-    fn create_socket(id: &ConnectionId) -> io::Result<UdpSocket> {
-        let autobind_addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), 0);
-        let udp_socket = UdpSocket::bind(&autobind_addr)?;
-        udp_socket.connect(id.rewritten_destination().into())?;
+    fn create_socket(
+        id: &ConnectionId,
+        egress: &EgressPolicy,
+        connect: bool,
+    ) -> io::Result<UdpSocket> {
+        // for an unconnected socket, the destination is unspecified (only its family matters)
+        let destination = id.rewritten_destination();
+        let udp_socket = egress.bind_udp(&destination)?;
+        if connect {
+            udp_socket.connect(destination)?;
+        }
+        if let Err(err) = socket_error::enable(&udp_socket, destination.is_ipv6()) {
+            cx_debug!(target: TAG, id, "Cannot receive ICMP errors: {}", err);
+        }
         Ok(udp_socket)
     }
 
@@ -118,6 +220,7 @@ impl UdpConnection {
         if !self.closed {
             self.touch();
             let ready = event.readiness();
+            let errors_received = socket_error::is_error(ready) && self.process_errors(selector);
             if ready.is_readable() || ready.is_writable() {
                 if ready.is_writable() {
                     self.process_send(selector)?;
@@ -128,7 +231,7 @@ impl UdpConnection {
                 if !self.closed {
                     self.update_interests(selector);
                 }
-            } else {
+            } else if !errors_received {
                 // error or hup
                 self.close(selector);
             }
@@ -147,6 +250,10 @@ impl UdpConnection {
             Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
                 cx_debug!(target: TAG, self.id, "Spurious event, ignoring");
             }
+            Err(ref err) if path_mtu::is_too_big(err) => {
+                // the path MTU decreased since the datagram was checked
+                cx_debug!(target: TAG, self.id, "Datagram exceeds the path MTU, dropped");
+            }
             Err(err) => {
                 if err.kind() == io::ErrorKind::WouldBlock {
                     // rethrow
@@ -174,6 +281,10 @@ impl UdpConnection {
                     // rethrow
                     return Err(err);
                 }
+                if self.process_errors(selector) {
+                    // the read reported the errno of an error just queued
+                    return Ok(());
+                }
                 cx_error!(
                     target: TAG,
                     self.id,
@@ -188,27 +299,60 @@ impl UdpConnection {
     }
 
     fn read(&mut self, selector: &mut Selector) -> io::Result<()> {
-        let ipv4_packet = self.network_to_client.packetize(&mut self.socket)?;
+        if self.device.quota(Direction::Download) == 0 {
+            // throttled, update_interests() will schedule a wakeup
+            return Ok(());
+        }
+        let ip_packet = match self.filtering {
+            None => self.network_to_client.packetize(&mut self.socket)?,
+            Some(filtering) => {
+                let id = &self.id;
+                let remotes = &self.remotes;
+                let source = |sender| {
+                    if remotes.accepts(filtering, &sender) {
+                        Some(connection::client_address(sender))
+                    } else {
+                        cx_debug!(target: TAG, id, "Datagram from {} filtered out", sender);
+                        None
+                    }
+                };
+                match self
+                    .network_to_client
+                    .packetize_from(&mut self.socket, source)?
+                {
+                    Some(ip_packet) => ip_packet,
+                    None => return Ok(()),
+                }
+            }
+        };
+        let payload = ip_packet.payload().unwrap();
+        let len = payload.len();
+        self.device.account(Direction::Download, len);
+        let answered = self
+            .dns_queries
+            .as_mut()
+            .is_some_and(|dns_queries| dns_queries.on_response(payload));
         let client_rc = self.client.upgrade().expect("Expected client not found");
-        match client_rc
-            .borrow_mut()
-            .send_to_client(selector, &ipv4_packet)
-        {
+        match client_rc.borrow_mut().send_to_client(selector, &ip_packet) {
             Ok(_) => {
                 cx_debug!(
                     target: TAG,
                     self.id,
                     "Packet ({} bytes) sent to client",
-                    ipv4_packet.length()
+                    ip_packet.length()
                 );
                 if log_enabled!(target: TAG, Level::Trace) {
                     cx_trace!(
                         target: TAG,
                         self.id,
                         "{}",
-                        binary::build_packet_string(ipv4_packet.raw())
+                        binary::build_packet_string(ip_packet.raw())
                     );
                 }
+                if answered {
+                    cx_debug!(target: TAG, self.id, "DNS queries answered");
+                    self.close(selector);
+                }
             }
             Err(_) => {
                 cx_warn!(target: TAG, self.id, "Cannot send to client, drop packet");
@@ -217,17 +361,168 @@ impl UdpConnection {
         Ok(())
     }
 
+    /// Report the ICMP errors received about the datagrams sent, and return whether there was
+    /// any (the socket signals them as errors).
+    fn process_errors(&mut self, selector: &mut Selector) -> bool {
+        let mut received = false;
+        loop {
+            // only the error matters, not the payload it is about
+            match socket_error::recv(&self.socket, &mut []) {
+                Ok(Some((error, _))) => {
+                    received = true;
+                    if let Some(error) = error {
+                        self.report_error(selector, error);
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    cx_warn!(target: TAG, self.id, "Cannot receive ICMP error: {}", err);
+                    break;
+                }
+            }
+        }
+        received
+    }
+
+    fn report_error(&mut self, selector: &mut Selector, error: SocketError) {
+        let (destination, source) = match self.filtering {
+            // the socket is connected, the error is about its destination
+            None => (
+                Some(self.id.rewritten_destination()),
+                self.id.error_source(error.source()),
+            ),
+            Some(_) => (
+                error.destination(),
+                connection::client_address(SocketAddr::new(error.source(), 0)).ip(),
+            ),
+        };
+        let headers =
+            destination.and_then(|destination| self.remotes.last_sent_headers(&destination));
+        let (icmp_error, headers) = match (error.icmp_error(), headers) {
+            (Some(icmp_error), Some(headers)) => (icmp_error, headers),
+            _ => {
+                cx_debug!(target: TAG, self.id, "Ignore ICMP error {:?}", error);
+                return;
+            }
+        };
+        cx_debug!(target: TAG, self.id, "{:?} from {}", icmp_error, source);
+        let mut raw = icmp_error::forge_from(headers, source, icmp_error);
+        let client_rc = self.client.upgrade().expect("Expected client not found");
+        let reply = IpPacket::parse(&mut raw);
+        let result = client_rc.borrow_mut().send_to_client(selector, &reply);
+        if let Err(err) = result {
+            cx_warn!(target: TAG, self.id, "Cannot send ICMP error to client: {}", err);
+        }
+    }
+
+    /// Copy the TTL and the "don't fragment" flag of a client packet to the socket.
+    ///
+    /// They apply to the whole socket, so the datagrams already queued are sent first.
+    fn copy_header_fields(&mut self, ip_header: &IpHeaderData) -> io::Result<()> {
+        let ttl = ip_header.ttl();
+        let dont_fragment = path_mtu::dont_fragment(ip_header);
+        if self.ttl == Some(ttl) && self.dont_fragment == dont_fragment {
+            return Ok(());
+        }
+        while !self.client_to_network.is_empty() {
+            if self.device.quota(Direction::Upload) == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    "Throttled, cannot change the socket options",
+                ));
+            }
+            self.write()?;
+        }
+        if self.ttl != Some(ttl) {
+            let ipv6 = self.id.rewritten_destination().is_ipv6();
+            if self.group == Some(Group::Multicast) {
+                udp_group::set_multicast_ttl(SockRef::from(&self.socket), ttl, ipv6)?;
+            } else if ipv6 {
+                SockRef::from(&self.socket).set_unicast_hops_v6(u32::from(ttl))?;
+            } else {
+                self.socket.set_ttl(u32::from(ttl))?;
+            }
+            self.ttl = Some(ttl);
+        }
+        if self.dont_fragment != dont_fragment {
+            if let Some(dont_fragment) = dont_fragment {
+                if let Err(err) =
+                    path_mtu::set_dont_fragment(SockRef::from(&self.socket), dont_fragment)
+                {
+                    cx_debug!(target: TAG, self.id, "Cannot copy the DF flag: {}", err);
+                }
+            }
+            self.dont_fragment = dont_fragment;
+        }
+        Ok(())
+    }
+
+    /// Reply "fragmentation needed" to the client, if the packet does not fit in the path MTU.
+    ///
+    /// Return whether the packet must be dropped.
+    fn check_path_mtu(
+        &self,
+        selector: &mut Selector,
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
+    ) -> bool {
+        let mtu = match path_mtu::check(SockRef::from(&self.socket), ip_packet) {
+            Some(mtu) => mtu,
+            None => return false,
+        };
+        cx_debug!(
+            target: TAG,
+            self.id,
+            "Packet ({} bytes) exceeds the path MTU ({}), drop packet",
+            ip_packet.length(),
+            mtu
+        );
+        let mut raw = icmp_error::forge(ip_packet, IcmpError::FragmentationNeeded(mtu));
+        let reply = IpPacket::parse(&mut raw);
+        if let Err(err) = client_channel.send_to_client(selector, &reply) {
+            cx_warn!(target: TAG, self.id, "Cannot send ICMP error to client: {}", err);
+        }
+        true
+    }
+
     fn write(&mut self) -> io::Result<()> {
-        self.client_to_network.write_to(&mut self.socket)?;
+        if self.device.quota(Direction::Upload) == 0 {
+            // throttled, update_interests() will schedule a wakeup
+            return Ok(());
+        }
+        let w = match self.filtering {
+            None => self.client_to_network.write_to(&mut self.socket)?,
+            Some(_) => {
+                let destination = self
+                    .destinations
+                    .pop_front()
+                    .expect("No destination for the datagram");
+                let mut sender = SendToAdapter::new(&self.socket, destination);
+                self.client_to_network.write_to(&mut sender)?
+            }
+        };
+        self.device.account(Direction::Upload, w);
         Ok(())
     }
 
     fn update_interests(&mut self, selector: &mut Selector) {
-        let ready = if self.client_to_network.is_empty() {
-            Ready::readable()
-        } else {
-            Ready::readable() | Ready::writable()
-        };
+        let mut ready = Ready::empty();
+        let mut wakeup_delay = None;
+        match self.device.delay(Direction::Download) {
+            None => ready |= Ready::readable(),
+            Some(delay) => wakeup_delay = Some(delay),
+        }
+        if !self.client_to_network.is_empty() {
+            match self.device.delay(Direction::Upload) {
+                None => ready |= Ready::writable(),
+                Some(delay) => {
+                    wakeup_delay = Some(wakeup_delay.map_or(delay, |d| cmp::min(d, delay)))
+                }
+            }
+        }
+        if let Some(delay) = wakeup_delay {
+            self.schedule_wakeup(selector, delay);
+        }
         cx_debug!(target: TAG, self.id, "interests: {:?}", ready);
         if self.interests != ready {
             // interests must be changed
@@ -238,6 +533,53 @@ impl UdpConnection {
         }
     }
 
+    fn schedule_wakeup(&mut self, selector: &mut Selector, delay: Duration) {
+        if self.wakeup_timer.is_none() {
+            cx_debug!(target: TAG, self.id, "Throttled for {:?}", delay);
+            let weak = self.self_weak.clone();
+            let handler = move |selector: &mut Selector| {
+                if let Some(rc) = weak.upgrade() {
+                    rc.borrow_mut().on_wakeup(selector);
+                }
+            };
+            self.wakeup_timer = Some(selector.schedule(delay, handler));
+        }
+    }
+
+    fn on_wakeup(&mut self, selector: &mut Selector) {
+        self.wakeup_timer = None;
+        if !self.closed {
+            self.update_interests(selector);
+        }
+    }
+
+    fn schedule_expiry(&mut self, selector: &mut Selector, delay: Duration) {
+        let weak = self.self_weak.clone();
+        let handler = move |selector: &mut Selector| {
+            if let Some(rc) = weak.upgrade() {
+                rc.borrow_mut().on_expiry(selector);
+            }
+        };
+        self.expiry_timer = Some(selector.schedule(delay, handler));
+    }
+
+    fn on_expiry(&mut self, selector: &mut Selector) {
+        self.expiry_timer = None;
+        if self.closed {
+            return;
+        }
+        // the flow may have been active since the timer was scheduled
+        let idle = self.idle_since.elapsed();
+        if idle <= self.idle_timeout {
+            self.schedule_expiry(selector, self.idle_timeout - idle);
+            return;
+        }
+        cx_debug!(target: TAG, self.id, "Idle for {:?}, expired", idle);
+        self.close(selector);
+        // not called from the router, so the connection must remove itself
+        self.remove_from_router();
+    }
+
     fn touch(&mut self) {
         self.idle_since = Instant::now();
     }
@@ -251,14 +593,45 @@ impl Connection for UdpConnection {
     fn send_to_network(
         &mut self,
         selector: &mut Selector,
-        _: &mut ClientChannel,
-        ipv4_packet: &Ipv4Packet,
+        client_channel: &mut ClientChannel,
+        ip_packet: &IpPacket,
     ) {
+        if let Err(err) = self.copy_header_fields(ip_packet.ip_header_data()) {
+            cx_warn!(
+                target: TAG,
+                self.id,
+                "Cannot set socket options, drop packet: {}",
+                err
+            );
+            return;
+        }
+        if self.check_path_mtu(selector, client_channel, ip_packet) {
+            return;
+        }
         match self
             .client_to_network
-            .read_from(ipv4_packet.payload().expect("No payload"))
+            .read_from(ip_packet.payload().expect("No payload"))
         {
             Ok(_) => {
+                let (ip_header_data, transport_header_data) = ip_packet.headers_data();
+                let destination = connection::rewrite_destination(SocketAddr::new(
+                    ip_header_data.destination(),
+                    transport_header_data
+                        .expect("No transport")
+                        .destination_port(),
+                ));
+                if self.filtering.is_some() {
+                    self.destinations.push_back(destination);
+                }
+                let timeout = self.device.policy().udp_timeouts().idle(&destination);
+                self.idle_timeout = cmp::max(self.idle_timeout, timeout);
+                if let Some(dns_queries) = self.dns_queries.as_mut() {
+                    dns_queries.on_query(ip_packet.payload().expect("No payload"));
+                }
+                let headers_length =
+                    ip_header_data.header_length() as usize + QUOTED_PAYLOAD_LENGTH;
+                self.remotes
+                    .record(destination, &ip_packet.raw()[..headers_length]);
                 self.update_interests(selector);
             }
             Err(err) => {
@@ -275,6 +648,12 @@ impl Connection for UdpConnection {
     fn close(&mut self, selector: &mut Selector) {
         cx_info!(target: TAG, self.id, "Close");
         self.closed = true;
+        if let Some(timer) = self.wakeup_timer.take() {
+            selector.cancel(timer);
+        }
+        if let Some(timer) = self.expiry_timer.take() {
+            selector.cancel(timer);
+        }
         if let Err(err) = selector.deregister(&self.socket, self.token) {
             // do not panic, this can happen in mio
             // see <https://github.com/Genymobile/gnirehtet/issues/136>
@@ -289,7 +668,7 @@ impl Connection for UdpConnection {
     }
 
     fn is_expired(&self) -> bool {
-        self.idle_since.elapsed().as_secs() > IDLE_TIMEOUT_SECONDS
+        self.idle_since.elapsed() > self.idle_timeout
     }
 
     fn is_closed(&self) -> bool {
@@ -0,0 +1,351 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use log::*;
+use mio::net::UdpSocket;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const TAG: &str = "UdpGroup";
+
+// the interfaces of the host rarely change
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Destinations reaching a group of hosts rather than a single one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Group {
+    // 255.255.255.255, or the broadcast address of a host interface
+    Broadcast,
+    // 224.0.0.0/4 or ff00::/8
+    Multicast,
+}
+
+/// What happens to the datagrams sent by a device to a group: dropped (by default), or bridged
+/// to a host interface.
+#[derive(Clone, Debug, Default)]
+pub struct UdpGroupPolicy {
+    broadcast_bridge: Option<String>,
+    multicast_bridge: Option<String>,
+}
+
+/// Detect the datagrams sent to a group, knowing the broadcast addresses of the host.
+#[derive(Default)]
+pub struct Classifier {
+    broadcast_addresses: Vec<Ipv4Addr>,
+    updated: Option<Instant>,
+}
+
+impl UdpGroupPolicy {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not a UDP group key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        let bridge = match key {
+            "udp-broadcast" => &mut self.broadcast_bridge,
+            "udp-multicast" => &mut self.multicast_bridge,
+            _ => return Ok(false),
+        };
+        // "drop" or "bridge eth0"
+        let mut words = value.split_whitespace();
+        *bridge = match (words.next(), words.next(), words.next()) {
+            (Some("drop"), None, None) => None,
+            (Some("bridge"), Some(interface), None) => Some(interface.to_string()),
+            _ => {
+                return Err(format!(
+                    "invalid value \"{}\" (expected drop or bridge <interface>)",
+                    value
+                ))
+            }
+        };
+        Ok(true)
+    }
+
+    /// Return the host interface the datagrams sent to `group` are bridged to, or `None` if they
+    /// are dropped.
+    pub fn bridge(&self, group: Group) -> Option<&str> {
+        match group {
+            Group::Broadcast => self.broadcast_bridge.as_deref(),
+            Group::Multicast => self.multicast_bridge.as_deref(),
+        }
+    }
+}
+
+impl Classifier {
+    /// Return the group reached by `destination`, if any.
+    pub fn classify(&mut self, destination: IpAddr, now: Instant) -> Option<Group> {
+        let expired = self
+            .updated
+            .is_none_or(|updated| now.duration_since(updated) >= REFRESH_INTERVAL);
+        if expired {
+            self.broadcast_addresses = match broadcast_addresses() {
+                Ok(addresses) => addresses,
+                Err(err) => {
+                    debug!(target: TAG, "Cannot list the broadcast addresses: {}", err);
+                    Vec::new()
+                }
+            };
+            self.updated = Some(now);
+        }
+        classify(destination, &self.broadcast_addresses)
+    }
+}
+
+fn classify(destination: IpAddr, broadcast_addresses: &[Ipv4Addr]) -> Option<Group> {
+    match destination {
+        IpAddr::V4(ip) if ip.is_multicast() => Some(Group::Multicast),
+        IpAddr::V4(ip) if ip.is_broadcast() || broadcast_addresses.contains(&ip) => {
+            Some(Group::Broadcast)
+        }
+        IpAddr::V6(ip) if ip.is_multicast() => Some(Group::Multicast),
+        _ => None,
+    }
+}
+
+/// Create a socket sending the datagrams of a device to `destination` through `interface`, and
+/// receiving the responses from any host.
+///
+/// To receive the multicast responses, the socket joins the group and, if the device sends from
+/// the port of the group (e.g. 5353 for mDNS), binds to it (shared with the other sockets of the
+/// host).
+#[cfg(target_os = "linux")]
+pub fn bind_udp(
+    group: Group,
+    interface: &str,
+    destination: &SocketAddr,
+    source_port: u16,
+) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(
+        Domain::for_address(*destination),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.bind_device(Some(interface.as_bytes()))?;
+    let mut port = 0;
+    if group == Group::Multicast && source_port == destination.port() {
+        socket.set_reuse_address(true)?;
+        port = source_port;
+    }
+    let bind_address = match destination {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+    };
+    socket.bind(&SocketAddr::new(bind_address, port).into())?;
+    match (group, destination.ip()) {
+        (Group::Broadcast, _) => socket.set_broadcast(true)?,
+        (Group::Multicast, IpAddr::V4(multiaddr)) => {
+            let address = interface_address(interface)?;
+            socket.join_multicast_v4(&multiaddr, &address)?;
+            socket.set_multicast_if_v4(&address)?;
+            // the device must not receive its own datagrams
+            socket.set_multicast_loop_v4(false)?;
+        }
+        (Group::Multicast, IpAddr::V6(multiaddr)) => {
+            let index = interface_index(interface)?;
+            socket.join_multicast_v6(&multiaddr, index)?;
+            socket.set_multicast_if_v6(index)?;
+            socket.set_multicast_loop_v6(false)?;
+        }
+    }
+    UdpSocket::from_socket(socket.into())
+}
+
+/// Set the TTL (or hop limit) of the multicast datagrams sent through the socket.
+#[cfg(target_os = "linux")]
+pub fn set_multicast_ttl(socket: socket2::SockRef, ttl: u8, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        socket.set_multicast_hops_v6(u32::from(ttl))
+    } else {
+        socket.set_multicast_ttl_v4(u32::from(ttl))
+    }
+}
+
+// list the addresses of the host interfaces (name, address, broadcast address)
+#[cfg(target_os = "linux")]
+fn interface_addresses() -> io::Result<Vec<(String, Ipv4Addr, Option<Ipv4Addr>)>> {
+    use std::ffi::CStr;
+    use std::ptr;
+
+    // read an IPv4 address, if `raw` points to one
+    unsafe fn read_ipv4(raw: *const libc::sockaddr) -> Option<Ipv4Addr> {
+        if raw.is_null() || i32::from((*raw).sa_family) != libc::AF_INET {
+            return None;
+        }
+        let address = ptr::read_unaligned(raw as *const libc::sockaddr_in);
+        Some(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)))
+    }
+
+    let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+    // safe: the list is freed below
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addresses = Vec::new();
+    let mut current = ifaddrs;
+    // safe: the list is valid until freeifaddrs()
+    unsafe {
+        while !current.is_null() {
+            let ifaddr = &*current;
+            if let Some(address) = read_ipv4(ifaddr.ifa_addr) {
+                let name = CStr::from_ptr(ifaddr.ifa_name)
+                    .to_string_lossy()
+                    .into_owned();
+                // the broadcast address is not always configured, derive it from the netmask
+                let broadcast = match read_ipv4(ifaddr.ifa_netmask) {
+                    Some(netmask)
+                        if ifaddr.ifa_flags & libc::IFF_BROADCAST as libc::c_uint != 0 =>
+                    {
+                        Some(broadcast_address(address, netmask))
+                    }
+                    _ => None,
+                };
+                addresses.push((name, address, broadcast));
+            }
+            current = ifaddr.ifa_next;
+        }
+        libc::freeifaddrs(ifaddrs);
+    }
+    Ok(addresses)
+}
+
+// the last address of the network, unless the network has no room for it (RFC 3021)
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn broadcast_address(address: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    let netmask = u32::from(netmask);
+    if netmask >= 0xffff_fffe {
+        return Ipv4Addr::BROADCAST;
+    }
+    Ipv4Addr::from(u32::from(address) | !netmask)
+}
+
+#[cfg(target_os = "linux")]
+fn broadcast_addresses() -> io::Result<Vec<Ipv4Addr>> {
+    Ok(interface_addresses()?
+        .into_iter()
+        .filter_map(|(_, _, broadcast)| broadcast)
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+fn interface_address(interface: &str) -> io::Result<Ipv4Addr> {
+    interface_addresses()?
+        .into_iter()
+        .find(|(name, _, _)| name == interface)
+        .map(|(_, address, _)| address)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No IPv4 address on interface {}", interface),
+            )
+        })
+}
+
+#[cfg(target_os = "linux")]
+fn interface_index(interface: &str) -> io::Result<u32> {
+    let name = std::ffi::CString::new(interface)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // safe: the name is a valid C string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(index)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_udp(
+    _group: Group,
+    _interface: &str,
+    _destination: &SocketAddr,
+    _source_port: u16,
+) -> io::Result<UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Bridging broadcast and multicast is only supported on Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_multicast_ttl(_socket: socket2::SockRef, _ttl: u8, _ipv6: bool) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn broadcast_addresses() -> io::Result<Vec<Ipv4Addr>> {
+    // only 255.255.255.255 is detected
+    Ok(Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_policy() {
+        let mut policy = UdpGroupPolicy::default();
+        assert_eq!(None, policy.bridge(Group::Broadcast));
+        assert_eq!(Ok(true), policy.set("udp-multicast", "bridge eth0"));
+        assert_eq!(Ok(true), policy.set("udp-broadcast", "drop"));
+        assert_eq!(Ok(false), policy.set("udp-mapping", "endpoint-independent"));
+        assert!(policy.set("udp-broadcast", "bridge").is_err());
+        assert!(policy.set("udp-multicast", "forward eth0").is_err());
+        assert_eq!(Some("eth0"), policy.bridge(Group::Multicast));
+        assert_eq!(None, policy.bridge(Group::Broadcast));
+    }
+
+    #[test]
+    fn classify_destinations() {
+        let broadcast_addresses = [Ipv4Addr::new(192, 168, 1, 255)];
+        let group =
+            |destination: &str| classify(destination.parse().unwrap(), &broadcast_addresses);
+        assert_eq!(Some(Group::Broadcast), group("255.255.255.255"));
+        assert_eq!(Some(Group::Broadcast), group("192.168.1.255"));
+        assert_eq!(Some(Group::Multicast), group("224.0.0.251"));
+        assert_eq!(Some(Group::Multicast), group("239.255.255.250"));
+        assert_eq!(Some(Group::Multicast), group("ff02::fb"));
+        assert_eq!(None, group("192.168.2.255"));
+        assert_eq!(None, group("10.0.2.2"));
+        assert_eq!(None, group("2001:db8::1"));
+    }
+
+    #[test]
+    fn compute_broadcast_address() {
+        let address = Ipv4Addr::new(192, 168, 1, 42);
+        assert_eq!(
+            Ipv4Addr::new(192, 168, 1, 255),
+            broadcast_address(address, Ipv4Addr::new(255, 255, 255, 0))
+        );
+        assert_eq!(
+            Ipv4Addr::new(192, 168, 1, 63),
+            broadcast_address(address, Ipv4Addr::new(255, 255, 255, 192))
+        );
+        // point-to-point links
+        assert_eq!(
+            Ipv4Addr::BROADCAST,
+            broadcast_address(address, Ipv4Addr::new(255, 255, 255, 254))
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn find_loopback_address() {
+        assert_eq!(Ipv4Addr::LOCALHOST, interface_address("lo").unwrap());
+        assert_eq!(1, interface_index("lo").unwrap());
+        assert!(interface_address("nonexistent0").is_err());
+    }
+}
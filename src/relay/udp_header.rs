@@ -14,7 +14,7 @@
  * limitations under the License.
  */
 
-use super::ipv4_header::Ipv4HeaderData;
+use super::ip_header::IpHeaderData;
 use byteorder::{BigEndian, ByteOrder};
 use std::mem;
 
@@ -31,8 +31,7 @@ pub struct UdpHeaderMut<'a> {
     data: &'a mut UdpHeaderData,
 }
 
-#[derive(Clone)]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct UdpHeaderData {
     source_port: u16,
     destination_port: u16,
@@ -75,10 +74,7 @@ macro_rules! udp_header_common {
         #[allow(dead_code)]
         impl<'a> $name<'a> {
             pub fn new(raw: $raw_type, data: $data_type) -> Self {
-                Self {
-                    raw,
-                    data,
-                }
+                Self { raw, data }
             }
 
             #[inline]
@@ -107,6 +103,19 @@ macro_rules! udp_header_common {
 udp_header_common!(UdpHeader, &'a [u8], &'a UdpHeaderData);
 udp_header_common!(UdpHeaderMut, &'a mut [u8], &'a mut UdpHeaderData);
 
+impl<'a> UdpHeader<'a> {
+    /// Verify the checksum of the datagram.
+    ///
+    /// A zero checksum means that the sender did not compute it, which is allowed for IPv4 only.
+    pub fn is_checksum_valid(&self, ip_header_data: &IpHeaderData, payload: &[u8]) -> bool {
+        if BigEndian::read_u16(&self.raw[6..8]) == 0 {
+            return !ip_header_data.is_ipv6();
+        }
+        // the sum including the checksum is all ones
+        sum(ip_header_data, self.raw, payload) == 0xFFFF
+    }
+}
+
 // additional methods for the mutable version
 #[allow(dead_code)]
 impl<'a> UdpHeaderMut<'a> {
@@ -150,21 +159,46 @@ impl<'a> UdpHeaderMut<'a> {
         BigEndian::write_u16(&mut self.raw[6..8], checksum);
     }
 
-    #[inline]
-    pub fn update_checksum(&mut self, _ipv4_header_data: &Ipv4HeaderData, _payload: &[u8]) {
-        // disable checksum validation
+    /// Disable checksum validation (it is optional for IPv4, but mandatory for IPv6).
+    pub fn disable_checksum(&mut self) {
         self.set_checksum(0);
     }
+
+    pub fn update_checksum(&mut self, ip_header_data: &IpHeaderData, payload: &[u8]) {
+        self.set_checksum(0);
+        // a computed checksum of 0 is transmitted as all ones (rfc768)
+        let checksum = match !sum(ip_header_data, self.raw, payload) {
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+        self.set_checksum(checksum);
+    }
+}
+
+/// Sum the pseudo-header, the UDP header and the payload, in one's complement.
+fn sum(ip_header_data: &IpHeaderData, header: &[u8], payload: &[u8]) -> u16 {
+    // pseudo-header checksum (cf rfc768 and rfc8200 section 8.1)
+    let mut sum = ip_header_data.pseudo_header_sum(17); // protocol: UDP = 17
+    for word in header.chunks(2).chain(payload.chunks(2)) {
+        // if payload length is odd, the last byte is considered high-order
+        sum += u32::from(word[0]) << 8 | word.get(1).copied().map_or(0, u32::from);
+    }
+    while (sum & !0xFFFF) != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::relay::ip_packet::IpPacket;
+    use crate::relay::transport_header::{TransportHeader, TransportHeaderMut};
     use byteorder::{BigEndian, WriteBytesExt};
+    use std::net::Ipv6Addr;
 
     fn create_header() -> Vec<u8> {
-        let mut raw = Vec::new();
-        raw.reserve(8);
+        let mut raw = Vec::with_capacity(8);
         raw.write_u16::<BigEndian>(1234).unwrap(); // source port
         raw.write_u16::<BigEndian>(5678).unwrap(); // destination port
         raw.write_u16::<BigEndian>(42).unwrap(); // length
@@ -214,4 +248,120 @@ mod tests {
         assert_eq!(2222, raw_source_port);
         assert_eq!(1111, raw_destination_port);
     }
+
+    fn create_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u8(4u8 << 4 | 5).unwrap();
+        raw.write_u8(0).unwrap(); // ToS
+        raw.write_u16::<BigEndian>(33).unwrap(); // total length 20 + 8 + 5
+        raw.write_u32::<BigEndian>(0).unwrap(); // id_flags_fragment_offset
+        raw.write_u8(64).unwrap(); // TTL
+        raw.write_u8(17).unwrap(); // protocol (UDP)
+        raw.write_u16::<BigEndian>(0).unwrap(); // checksum
+        raw.write_u32::<BigEndian>(0x12345678).unwrap(); // source address
+        raw.write_u32::<BigEndian>(0xA2A24242).unwrap(); // destination address
+
+        raw.extend_from_slice(&create_header());
+        BigEndian::write_u16(&mut raw[24..26], 13); // length
+
+        raw.extend_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x55]); // payload
+        raw
+    }
+
+    fn create_ipv6_packet() -> Vec<u8> {
+        let mut raw = Vec::new();
+        raw.write_u32::<BigEndian>(6 << 28).unwrap(); // version, traffic class, flow label
+        raw.write_u16::<BigEndian>(13).unwrap(); // payload length 8 + 5
+        raw.write_u8(17).unwrap(); // next header (UDP)
+        raw.write_u8(64).unwrap(); // hop limit
+        raw.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        raw.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+
+        raw.extend_from_slice(&create_header());
+        BigEndian::write_u16(&mut raw[44..46], 13); // length
+
+        raw.extend_from_slice(&[0x11, 0x22, 0x33, 0x44, 0x55]); // payload
+        raw
+    }
+
+    fn is_checksum_valid(ip_packet: &IpPacket) -> bool {
+        if let Some(TransportHeader::Udp(udp_header)) = ip_packet.transport_header() {
+            udp_header.is_checksum_valid(ip_packet.ip_header_data(), ip_packet.payload().unwrap())
+        } else {
+            panic!("Not a UDP packet");
+        }
+    }
+
+    #[test]
+    fn compute_checksum() {
+        let raw = &mut create_packet()[..];
+        let mut ip_packet = IpPacket::parse(raw);
+        let (ip_header, transport) = ip_packet.split_mut();
+        if let Some((TransportHeaderMut::Udp(mut udp_header), payload)) = transport {
+            udp_header.update_checksum(&ip_header.data_clone(), payload);
+            let checksum = BigEndian::read_u16(&udp_header.raw()[6..8]);
+
+            let expected_checksum = {
+                // pseudo-header
+                let mut sum: u32 = 0x1234 + 0x5678 + 0xA2A2 + 0x4242 + 0x0011 + 0x000D;
+
+                // header (with a zero checksum)
+                sum += 0x04D2 + 0x162E + 0x000D;
+
+                // payload (padded with a zero byte)
+                sum += 0x1122 + 0x3344 + 0x5500;
+
+                while (sum & !0xFFFF) != 0 {
+                    sum = (sum & 0xFFFF) + (sum >> 16);
+                }
+                !sum as u16
+            };
+
+            assert_eq!(expected_checksum, checksum);
+        } else {
+            panic!("Not a UDP packet");
+        }
+    }
+
+    #[test]
+    fn validate_checksum() {
+        let raw = &mut create_packet()[..];
+        {
+            // a zero checksum is allowed for IPv4
+            let ip_packet = IpPacket::parse(raw);
+            assert!(is_checksum_valid(&ip_packet));
+        }
+
+        {
+            let mut ip_packet = IpPacket::parse(raw);
+            ip_packet.compute_checksums();
+            assert!(is_checksum_valid(&ip_packet));
+        }
+
+        // corrupt the payload
+        raw[30] ^= 0x01;
+        let ip_packet = IpPacket::parse(raw);
+        assert!(!is_checksum_valid(&ip_packet));
+    }
+
+    #[test]
+    fn validate_ipv6_checksum() {
+        let raw = &mut create_ipv6_packet()[..];
+        {
+            // the checksum is mandatory for IPv6
+            let ip_packet = IpPacket::parse(raw);
+            assert!(!is_checksum_valid(&ip_packet));
+        }
+
+        {
+            let mut ip_packet = IpPacket::parse(raw);
+            ip_packet.compute_checksums();
+            assert!(is_checksum_valid(&ip_packet));
+        }
+
+        // corrupt the payload
+        raw[50] ^= 0x01;
+        let ip_packet = IpPacket::parse(raw);
+        assert!(!is_checksum_valid(&ip_packet));
+    }
 }
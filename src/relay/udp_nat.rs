@@ -0,0 +1,177 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// How the UDP flows of a device are mapped to upstream sockets (RFC 4787 section 4.1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mapping {
+    // one connected socket per destination, only the destination may reply
+    EndpointDependent,
+    // one unconnected socket per source port of the device, for all the destinations
+    EndpointIndependent,
+}
+
+/// Which remote hosts may send datagrams to an endpoint-independent mapping (RFC 4787
+/// section 5).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filtering {
+    // any host ("full cone")
+    EndpointIndependent,
+    // the hosts the device has sent datagrams to, from any port
+    AddressDependent,
+    // the addresses and ports the device has sent datagrams to
+    AddressAndPortDependent,
+}
+
+/// NAT behavior of the UDP connections of a device.
+#[derive(Clone, Debug)]
+pub struct UdpNatPolicy {
+    mapping: Mapping,
+    filtering: Filtering,
+}
+
+/// The remote endpoints an upstream socket has sent datagrams to, along with the headers of the
+/// last packet sent to each of them (to be quoted in the ICMP errors reported to the client).
+#[derive(Default)]
+pub struct Remotes {
+    headers: HashMap<SocketAddr, Vec<u8>>,
+}
+
+impl Default for UdpNatPolicy {
+    fn default() -> Self {
+        Self {
+            mapping: Mapping::EndpointDependent,
+            filtering: Filtering::AddressAndPortDependent,
+        }
+    }
+}
+
+impl UdpNatPolicy {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not a UDP NAT key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "udp-mapping" => {
+                self.mapping = match value {
+                    "endpoint-dependent" => Mapping::EndpointDependent,
+                    "endpoint-independent" => Mapping::EndpointIndependent,
+                    _ => return Err(format!("invalid UDP mapping \"{}\"", value)),
+                }
+            }
+            "udp-filtering" => {
+                self.filtering = match value {
+                    "endpoint-independent" => Filtering::EndpointIndependent,
+                    "address-dependent" => Filtering::AddressDependent,
+                    "address-and-port-dependent" => Filtering::AddressAndPortDependent,
+                    _ => return Err(format!("invalid UDP filtering \"{}\"", value)),
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    pub fn mapping(&self) -> Mapping {
+        self.mapping
+    }
+
+    /// Filtering of the endpoint-independent mappings (an endpoint-dependent mapping only
+    /// accepts datagrams from its destination)
+    pub fn filtering(&self) -> Filtering {
+        self.filtering
+    }
+}
+
+impl Remotes {
+    /// Remember that a packet has been sent to `remote` (as seen from the relay).
+    pub fn record(&mut self, remote: SocketAddr, headers: &[u8]) {
+        let last_headers = self.headers.entry(remote).or_default();
+        last_headers.clear();
+        last_headers.extend_from_slice(headers);
+    }
+
+    /// Return the headers of the last packet sent to `remote`, if any.
+    pub fn last_sent_headers(&self, remote: &SocketAddr) -> Option<&[u8]> {
+        self.headers.get(remote).map(Vec::as_slice)
+    }
+
+    /// Indicate whether a datagram from `sender` passes the filter.
+    pub fn accepts(&self, filtering: Filtering, sender: &SocketAddr) -> bool {
+        match filtering {
+            Filtering::EndpointIndependent => true,
+            Filtering::AddressDependent => {
+                self.headers.keys().any(|remote| remote.ip() == sender.ip())
+            }
+            Filtering::AddressAndPortDependent => self.headers.contains_key(sender),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_policy() {
+        let mut policy = UdpNatPolicy::default();
+        assert_eq!(Mapping::EndpointDependent, policy.mapping());
+        assert_eq!(Ok(true), policy.set("udp-mapping", "endpoint-independent"));
+        assert_eq!(Ok(true), policy.set("udp-filtering", "address-dependent"));
+        assert_eq!(Ok(false), policy.set("udp-checksum", "off"));
+        assert!(policy.set("udp-mapping", "full-cone").is_err());
+        assert!(policy.set("udp-filtering", "none").is_err());
+        assert_eq!(Mapping::EndpointIndependent, policy.mapping());
+        assert_eq!(Filtering::AddressDependent, policy.filtering());
+    }
+
+    #[test]
+    fn filter_senders() {
+        let mut remotes = Remotes::default();
+        remotes.record("192.0.2.1:3478".parse().unwrap(), &[0x45]);
+
+        let same = "192.0.2.1:3478".parse().unwrap();
+        let other_port = "192.0.2.1:3479".parse().unwrap();
+        let other_host = "198.51.100.7:3478".parse().unwrap();
+
+        let filtering = Filtering::AddressAndPortDependent;
+        assert!(remotes.accepts(filtering, &same));
+        assert!(!remotes.accepts(filtering, &other_port));
+        assert!(!remotes.accepts(filtering, &other_host));
+
+        let filtering = Filtering::AddressDependent;
+        assert!(remotes.accepts(filtering, &same));
+        assert!(remotes.accepts(filtering, &other_port));
+        assert!(!remotes.accepts(filtering, &other_host));
+
+        assert!(remotes.accepts(Filtering::EndpointIndependent, &other_host));
+    }
+
+    #[test]
+    fn keep_last_sent_headers() {
+        let mut remotes = Remotes::default();
+        let remote = "192.0.2.1:53".parse().unwrap();
+        remotes.record(remote, &[1, 2, 3]);
+        remotes.record(remote, &[4, 5]);
+        assert_eq!(Some(&[4u8, 5][..]), remotes.last_sent_headers(&remote));
+        assert_eq!(
+            None,
+            remotes.last_sent_headers(&"192.0.2.1:54".parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,235 @@
+/*
+ * Copyright (C) 2017 Genymobile
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use super::config::parse_duration;
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+// DNS and NTP: a request and its response
+const DEFAULT_SHORT_TIMEOUT: Duration = Duration::from_secs(10);
+// QUIC and RTP: long-lived flows, possibly quiet for a while
+const DEFAULT_LONG_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Inactivity timeouts of the UDP flows, depending on their destination.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpTimeouts {
+    // for the destinations matching no rule
+    idle: Duration,
+    // the last matching rule applies
+    rules: Vec<TimeoutRule>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct TimeoutRule {
+    // the rule applies to a destination matching any of them
+    destinations: Vec<DestinationMatcher>,
+    timeout: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DestinationMatcher {
+    // inclusive range
+    Ports(u16, u16),
+    // network address and prefix length
+    Network(IpAddr, u8),
+}
+
+impl Default for UdpTimeouts {
+    fn default() -> Self {
+        Self {
+            idle: DEFAULT_IDLE_TIMEOUT,
+            // the rules of the configuration are added after, so they take precedence
+            rules: vec![
+                TimeoutRule {
+                    destinations: vec![
+                        DestinationMatcher::Ports(53, 53),
+                        DestinationMatcher::Ports(123, 123),
+                    ],
+                    timeout: DEFAULT_SHORT_TIMEOUT,
+                },
+                TimeoutRule {
+                    destinations: vec![
+                        DestinationMatcher::Ports(443, 443),
+                        DestinationMatcher::Ports(16384, 32767),
+                    ],
+                    timeout: DEFAULT_LONG_TIMEOUT,
+                },
+            ],
+        }
+    }
+}
+
+impl UdpTimeouts {
+    /// Set the value for `key`.
+    ///
+    /// Return `Ok(false)` if the key is not a UDP timeout key.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        match key {
+            "udp-idle-timeout" => self.idle = parse_duration(value)?,
+            // e.g. "53, 123 10s", "16384-32767 10m" or "10.0.0.0/8 30s"
+            "udp-timeout" => {
+                let (destinations, timeout) = value
+                    .rsplit_once(char::is_whitespace)
+                    .ok_or_else(|| format!("expected \"destinations timeout\": \"{}\"", value))?;
+                let destinations = destinations
+                    .split(',')
+                    .map(|item| DestinationMatcher::parse(item.trim()))
+                    .collect::<Result<_, _>>()?;
+                let timeout = parse_duration(timeout)?;
+                self.rules.push(TimeoutRule {
+                    destinations,
+                    timeout,
+                });
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Return the inactivity timeout of a flow to `destination`.
+    pub fn idle(&self, destination: &SocketAddr) -> Duration {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.destinations.iter().any(|d| d.matches(destination)))
+            .map_or(self.idle, |rule| rule.timeout)
+    }
+}
+
+impl DestinationMatcher {
+    // "53", "16384-32767", "10.0.0.0/8" or "2001:db8::/32"
+    fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid port, port range or network \"{}\"", value);
+        if let Some((address, prefix_length)) = value.split_once('/') {
+            let address: IpAddr = address.parse().map_err(|_| invalid())?;
+            let max_prefix_length = if address.is_ipv4() { 32 } else { 128 };
+            return match prefix_length.parse() {
+                Ok(prefix_length) if prefix_length <= max_prefix_length => {
+                    Ok(DestinationMatcher::Network(address, prefix_length))
+                }
+                _ => Err(invalid()),
+            };
+        }
+        if let Ok(address) = value.parse::<IpAddr>() {
+            let prefix_length = if address.is_ipv4() { 32 } else { 128 };
+            return Ok(DestinationMatcher::Network(address, prefix_length));
+        }
+        let (first, last) = value.split_once('-').unwrap_or((value, value));
+        match (first.trim().parse(), last.trim().parse()) {
+            (Ok(first), Ok(last)) if first <= last => Ok(DestinationMatcher::Ports(first, last)),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn matches(&self, destination: &SocketAddr) -> bool {
+        match *self {
+            DestinationMatcher::Ports(first, last) => (first..=last).contains(&destination.port()),
+            DestinationMatcher::Network(network, prefix_length) => {
+                match (network, destination.ip()) {
+                    (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                        let mask = u32::MAX
+                            .checked_shl(32 - u32::from(prefix_length))
+                            .unwrap_or(0);
+                        u32::from(network) & mask == u32::from(ip) & mask
+                    }
+                    (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                        let mask = u128::MAX
+                            .checked_shl(128 - u32::from(prefix_length))
+                            .unwrap_or(0);
+                        u128::from(network) & mask == u128::from(ip) & mask
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(entries: &[(&str, &str)]) -> UdpTimeouts {
+        let mut timeouts = UdpTimeouts::default();
+        for (key, value) in entries {
+            assert_eq!(Ok(true), timeouts.set(key, value));
+        }
+        timeouts
+    }
+
+    #[test]
+    fn match_ports() {
+        let timeouts = parse(&[
+            ("udp-idle-timeout", "1m"),
+            ("udp-timeout", "53, 123 10s"),
+            ("udp-timeout", "16384-32767 10m"),
+        ]);
+        let idle = |destination: &str| timeouts.idle(&destination.parse().unwrap());
+        assert_eq!(Duration::from_secs(10), idle("8.8.8.8:53"));
+        assert_eq!(Duration::from_secs(10), idle("[2001:db8::1]:123"));
+        assert_eq!(Duration::from_secs(10 * 60), idle("192.0.2.1:20000"));
+        assert_eq!(Duration::from_secs(60), idle("192.0.2.1:5000"));
+    }
+
+    #[test]
+    fn match_networks() {
+        let timeouts = parse(&[
+            ("udp-timeout", "10.0.0.0/8, 2001:db8::/32 30s"),
+            ("udp-timeout", "10.1.2.3 5m"),
+            ("udp-timeout", "0.0.0.0/0 1h"),
+            // the last matching rule applies
+            ("udp-timeout", "10.9.0.0/16 1s"),
+        ]);
+        let idle = |destination: &str| timeouts.idle(&destination.parse().unwrap());
+        assert_eq!(Duration::from_secs(1), idle("10.9.8.7:53"));
+        assert_eq!(Duration::from_secs(60 * 60), idle("10.1.2.3:53"));
+        assert_eq!(Duration::from_secs(30), idle("[2001:db8:1::1]:53"));
+        assert_eq!(DEFAULT_IDLE_TIMEOUT, idle("[2001:db9::1]:5000"));
+    }
+
+    #[test]
+    fn default_rules() {
+        let timeouts = UdpTimeouts::default();
+        let idle = |destination: &str| timeouts.idle(&destination.parse().unwrap());
+        assert_eq!(DEFAULT_SHORT_TIMEOUT, idle("8.8.8.8:53"));
+        assert_eq!(DEFAULT_SHORT_TIMEOUT, idle("[2001:db8::1]:123"));
+        assert_eq!(DEFAULT_LONG_TIMEOUT, idle("192.0.2.1:443"));
+        assert_eq!(DEFAULT_LONG_TIMEOUT, idle("192.0.2.1:20000"));
+        assert_eq!(DEFAULT_IDLE_TIMEOUT, idle("192.0.2.1:5000"));
+
+        // overridden by the configuration
+        let timeouts = parse(&[("udp-timeout", "0.0.0.0/0 1m")]);
+        assert_eq!(
+            Duration::from_secs(60),
+            timeouts.idle(&"8.8.8.8:53".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_invalid_rules() {
+        let mut timeouts = UdpTimeouts::default();
+        assert_eq!(Ok(false), timeouts.set("tcp-idle-timeout", "1m"));
+        assert!(timeouts.set("udp-timeout", "53").is_err());
+        assert!(timeouts.set("udp-timeout", "53 soon").is_err());
+        assert!(timeouts.set("udp-timeout", "70000 10s").is_err());
+        assert!(timeouts.set("udp-timeout", "200-100 10s").is_err());
+        assert!(timeouts.set("udp-timeout", "10.0.0.0/33 10s").is_err());
+        assert!(timeouts.set("udp-idle-timeout", "forever").is_err());
+        assert_eq!(UdpTimeouts::default(), timeouts);
+    }
+}